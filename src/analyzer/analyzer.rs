@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as fmtResult};
+use std::path::Path;
+
+use crate::cpu::{Platform, Quirks, PROG_START};
+use crate::database::{sha1_hex, RomInfo};
+
+// Below this the analysis is reported but not applied automatically.
+pub const MIN_CONFIDENCE: f32 = 0.5;
+
+// What one instruction suggests about the platform the ROM was written for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Hint {
+    // Uses an opcode only SUPER-CHIP (or later) implements.
+    Schip,
+    // Uses an opcode only XO-CHIP implements.
+    XoChip,
+    // 8XY6/8XYE with a meaningful VY: expects VY to be shifted into VX.
+    ShiftOff,
+    // 8X06/8X0E with V0 never set: a CHIP-48 style in-place shift.
+    ShiftOn,
+    // FX55/FX65 relying on I moving past the registers it transferred.
+    MemoryIncrements,
+    // BXNN where only VX is ever set.
+    JumpVx,
+    // BNNN where V0 is set.
+    JumpV0,
+}
+
+impl Hint {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Hint::Schip => "SUPER-CHIP opcode",
+            Hint::XoChip => "XO-CHIP opcode",
+            Hint::ShiftOff => "shift reads VY",
+            Hint::ShiftOn => "shift in place",
+            Hint::MemoryIncrements => "load/store assumes I increments",
+            Hint::JumpVx => "jump offset by VX",
+            Hint::JumpV0 => "jump offset by V0",
+        }
+    }
+
+    fn is_original(&self) -> bool {
+        matches!(self, Hint::ShiftOff | Hint::MemoryIncrements | Hint::JumpV0)
+    }
+
+    fn is_modern(&self) -> bool {
+        matches!(self, Hint::ShiftOn | Hint::JumpVx)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Evidence {
+    pub addr: usize,
+    pub opcode: u16,
+    pub hint: Hint,
+}
+
+impl Display for Evidence {
+    fn fmt(&self, fmt: &mut Formatter) -> fmtResult {
+        write!(fmt, "{:#05X}: {:04X} {}", self.addr, self.opcode, self.hint.describe())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Analysis {
+    // Number of instructions reachable from the entry point.
+    pub reachable: usize,
+    pub evidence: Vec<Evidence>,
+    pub platform: Option<Platform>,
+    pub quirks: Quirks,
+    // 0.0 (no idea) to 1.0.
+    pub confidence: f32,
+}
+
+impl Analysis {
+    pub fn count(&self, hint: Hint) -> usize {
+        self.evidence.iter().filter(|e| e.hint == hint).count()
+    }
+
+    // A profile for ROMs the database doesn't know, if the analysis is
+    // sure enough to act on.
+    pub fn rom_info(&self, rom: &[u8], path: &Path) -> Option<RomInfo> {
+        if self.confidence < MIN_CONFIDENCE {
+            return None;
+        }
+        let platform = self.platform?;
+        Some(RomInfo {
+            sha1: sha1_hex(rom),
+            title: path.file_stem()?.to_string_lossy().to_string(),
+            description: None,
+            platform: Some(platform),
+            quirks: self.quirks,
+            tickrate: Some(platform.tickrate()),
+            palette: None,
+            keys: HashMap::new(),
+        })
+    }
+}
+
+fn opcode_at(rom: &[u8], addr: usize) -> Option<u16> {
+    let i = addr.checked_sub(PROG_START)?;
+    if i + 1 < rom.len() { Some((rom[i] as u16) << 8 | rom[i + 1] as u16) } else { None }
+}
+
+// Instructions that point I somewhere new.
+fn sets_i(op: u16) -> bool {
+    op & 0xF000 == 0xA000 || (op & 0xF000 == 0xF000 && matches!(op & 0xFF, 0x1E | 0x29 | 0x30))
+}
+
+fn is_skip(op: u16) -> bool {
+    match op & 0xF000 {
+        0x3000 | 0x4000 => true,
+        0x5000 | 0x9000 => op & 0xF == 0,
+        0xE000 => matches!(op & 0xFF, 0x9E | 0xA1),
+        _ => false,
+    }
+}
+
+// Registers an instruction writes, as a bit mask.
+fn writes(op: u16) -> u16 {
+    let x = (op >> 8) & 0xF;
+    match op & 0xF000 {
+        0x6000 | 0x7000 | 0xC000 => 1 << x,
+        0x8000 => 1 << x | 1 << 0xF,
+        0xF000 => match op & 0xFF {
+            0x07 | 0x0A => 1 << x,
+            0x65 | 0x85 => ((1u32 << (x + 1)) - 1) as u16,
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+// Platform-specific opcodes, and whether execution can fall through to the
+// next instruction. `None` means the word isn't an instruction at all.
+fn classify(op: u16) -> Option<(Option<Hint>, bool)> {
+    let x = (op >> 8) & 0xF;
+    let n = op & 0xF;
+    Some(match op & 0xF000 {
+        0x0000 => match op {
+            0x0000 => return None,
+            0x00EE => (None, false),
+            0x00FD => (Some(Hint::Schip), false),
+            0x00FB | 0x00FC | 0x00FE | 0x00FF => (Some(Hint::Schip), true),
+            _ if op & 0xFFF0 == 0x00C0 => (Some(Hint::Schip), true),
+            _ if op & 0xFFF0 == 0x00D0 => (Some(Hint::XoChip), true),
+            _ => (None, true),
+        },
+        0x1000 | 0xB000 => (None, false),
+        0x5000 => match n {
+            0x0 => (None, true),
+            0x2 | 0x3 => (Some(Hint::XoChip), true),
+            _ => return None,
+        },
+        0x8000 => match n {
+            0x0..=0x7 | 0xE => (None, true),
+            _ => return None,
+        },
+        0x9000 => if n == 0 { (None, true) } else { return None },
+        0xD000 => (if n == 0 { Some(Hint::Schip) } else { None }, true),
+        0xE000 => if is_skip(op) { (None, true) } else { return None },
+        0xF000 => match op & 0xFF {
+            0x07 | 0x0A | 0x15 | 0x18 | 0x1E | 0x29 | 0x33 | 0x55 | 0x65 => (None, true),
+            0x30 | 0x75 | 0x85 => (Some(Hint::Schip), true),
+            0x00 if x == 0 => (Some(Hint::XoChip), true),
+            0x01 | 0x02 | 0x3A => (Some(Hint::XoChip), true),
+            _ => return None,
+        },
+        _ => (None, true),
+    })
+}
+
+// Length of the instruction at `addr`; XO-CHIP's F000 NNNN is four bytes.
+fn length(op: u16) -> usize {
+    if op == 0xF000 { 4 } else { 2 }
+}
+
+// Every instruction reachable from the entry point, in address order.
+// Computed jumps (BNNN) can't be followed, so code only reachable through
+// them is missed.
+pub fn reachable(rom: &[u8]) -> Vec<(usize, u16)> {
+    let mut seen: HashMap<usize, u16> = HashMap::new();
+    let mut work = vec![PROG_START];
+    while let Some(addr) = work.pop() {
+        if seen.contains_key(&addr) {
+            continue;
+        }
+        let op = match opcode_at(rom, addr) {
+            Some(op) => op,
+            None => continue,
+        };
+        let falls_through = match classify(op) {
+            Some((_, falls_through)) => falls_through,
+            None => continue,
+        };
+        seen.insert(addr, op);
+
+        let next = addr + length(op);
+        match op & 0xF000 {
+            0x1000 => work.push((op & 0xFFF) as usize),
+            0x2000 => {
+                work.push((op & 0xFFF) as usize);
+                work.push(next);
+            },
+            _ if is_skip(op) => {
+                work.push(next);
+                let skipped = opcode_at(rom, next).map(length).unwrap_or(2);
+                work.push(next + skipped);
+            },
+            _ if falls_through => work.push(next),
+            _ => {},
+        }
+    }
+    let mut code: Vec<(usize, u16)> = seen.into_iter().collect();
+    code.sort_unstable();
+    code
+}
+
+pub fn analyze(rom: &[u8]) -> Analysis {
+    let code = reachable(rom);
+    let written = code.iter().fold(0u16, |mask, &(_, op)| mask | writes(op));
+    let is_written = |reg: u16| written & (1 << reg) != 0;
+    let mut evidence = Vec::new();
+
+    for (i, &(addr, op)) in code.iter().enumerate() {
+        let x = (op >> 8) & 0xF;
+        let y = (op >> 4) & 0xF;
+        let mut hint = classify(op).and_then(|(hint, _)| hint);
+
+        match op & 0xF00F {
+            0x8006 | 0x800E if x != y => {
+                if y != 0 {
+                    hint = Some(Hint::ShiftOff);
+                } else if !is_written(0) {
+                    hint = Some(Hint::ShiftOn);
+                }
+            },
+            0xF005 if matches!(op & 0xFF, 0x55 | 0x65) && relies_on_increment(&code, i) => {
+                hint = Some(Hint::MemoryIncrements);
+            },
+            _ => {},
+        }
+        if op & 0xF000 == 0xB000 {
+            if x != 0 && is_written(x) && !is_written(0) {
+                hint = Some(Hint::JumpVx);
+            } else if is_written(0) {
+                hint = Some(Hint::JumpV0);
+            }
+        }
+
+        if let Some(hint) = hint {
+            evidence.push(Evidence { addr, opcode: op, hint });
+        }
+    }
+
+    let mut analysis = Analysis {
+        reachable: code.len(),
+        evidence,
+        platform: None,
+        quirks: Quirks::default(),
+        confidence: 0.0,
+    };
+    score(&mut analysis);
+    analysis
+}
+
+// True if the FX55/FX65 at `code[i]` is followed by another transfer, or
+// sits in a loop, without I being pointed anywhere else in between.
+fn relies_on_increment(code: &[(usize, u16)], i: usize) -> bool {
+    let (addr, _) = code[i];
+
+    // Straight-line code after it, up to the first branch.
+    let mut expected = addr + 2;
+    for &(next_addr, op) in &code[i + 1..] {
+        if next_addr != expected || sets_i(op) {
+            break;
+        }
+        if op & 0xF0FF == 0xF055 || op & 0xF0FF == 0xF065 {
+            return true;
+        }
+        if classify(op).map(|(_, falls_through)| !falls_through).unwrap_or(true)
+            || op & 0xF000 == 0x2000 {
+            break;
+        }
+        expected = next_addr + length(op);
+    }
+
+    // A backward jump over it whose body never touches I.
+    code.iter().any(|&(jump_addr, op)| {
+        let target = (op & 0xFFF) as usize;
+        op & 0xF000 == 0x1000 && jump_addr > addr && target <= addr
+            && !code.iter()
+                    .filter(|&&(a, _)| a >= target && a <= jump_addr)
+                    .any(|&(_, body)| sets_i(body) || body & 0xF000 == 0x2000)
+    })
+}
+
+fn score(analysis: &mut Analysis) {
+    let xochip = analysis.count(Hint::XoChip);
+    let schip = analysis.count(Hint::Schip);
+    let original = analysis.evidence.iter().filter(|e| e.hint.is_original()).count();
+    let modern = analysis.evidence.iter().filter(|e| e.hint.is_modern()).count();
+
+    // Opcodes only a later platform implements are hard to argue with;
+    // quirk-dependent idioms only tip the balance.
+    let (platform, confidence) = if xochip > 0 {
+        (Platform::XoChip, 0.95)
+    } else if schip > 0 {
+        let agree = (schip + modern) as f32 / (schip + modern + original) as f32;
+        (Platform::Superchip, 0.6 + 0.3 * agree)
+    } else if original != modern {
+        let total = (original + modern) as f32;
+        let margin = (original as f32 - modern as f32).abs() / total;
+        let certainty = total / (total + 2.0);
+        let platform = if original > modern { Platform::OriginalChip8 } else { Platform::Superchip };
+        (platform, margin * certainty)
+    } else {
+        return;
+    };
+
+    let mut quirks = platform.quirks();
+    let vote = |yes: usize, no: usize| if yes > no { Some(true) } else if no > yes { Some(false) } else { None };
+    if let Some(shift) = vote(analysis.count(Hint::ShiftOn), analysis.count(Hint::ShiftOff)) {
+        quirks.shift = shift;
+    }
+    if analysis.count(Hint::MemoryIncrements) > 0 {
+        quirks.memory_increment_by_x = false;
+        quirks.memory_leave_i_unchanged = false;
+    }
+    if let Some(jump) = vote(analysis.count(Hint::JumpVx), analysis.count(Hint::JumpV0)) {
+        quirks.jump = jump;
+    }
+
+    analysis.platform = Some(platform);
+    analysis.quirks = quirks;
+    analysis.confidence = confidence;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]).collect()
+    }
+
+    #[test]
+    fn analyzer_follows_branches() {
+        // CALL 0x206; JP 0x202 (spin); data; RET
+        let code = reachable(&rom(&[0x2206, 0x1202, 0xFFFF, 0x00EE]));
+        let addrs: Vec<usize> = code.iter().map(|&(addr, _)| addr).collect();
+        assert_eq!(addrs, vec![0x200, 0x202, 0x206]);
+    }
+
+    #[test]
+    fn analyzer_skips_both_ways() {
+        // SE V0, 0; JP 0x200; CLS; JP 0x206
+        let code = reachable(&rom(&[0x3000, 0x1200, 0x00E0, 0x1206]));
+        assert_eq!(code.len(), 4);
+    }
+
+    #[test]
+    fn analyzer_no_evidence() {
+        let analysis = analyze(&rom(&[0x6001, 0x1202]));
+        assert_eq!(analysis.platform, None);
+        assert_eq!(analysis.confidence, 0.0);
+        assert!(analysis.rom_info(&[], Path::new("x.ch8")).is_none());
+    }
+
+    #[test]
+    fn analyzer_schip_opcodes() {
+        let analysis = analyze(&rom(&[0x00FF, 0x00FD]));
+        assert_eq!(analysis.platform, Some(Platform::Superchip));
+        assert_eq!(analysis.count(Hint::Schip), 2);
+        assert!(analysis.confidence >= MIN_CONFIDENCE);
+    }
+
+    #[test]
+    fn analyzer_xochip_long_load() {
+        // I := long 0x1234 is four bytes; the analysis must step over it.
+        let analysis = analyze(&rom(&[0xF000, 0x1234, 0x1204]));
+        assert_eq!(analysis.platform, Some(Platform::XoChip));
+        assert_eq!(analysis.reachable, 2);
+    }
+
+    #[test]
+    fn analyzer_original_idioms() {
+        let analysis = analyze(&rom(&[
+            0x6105,         // V1 := 5
+            0x8216,         // V2 := V1 >> 1
+            0xA300,         // I := 0x300
+            0xF155,         // save V0..V1
+            0xF155,         // save again, relying on I having moved
+            0x1200,
+        ]));
+        assert_eq!(analysis.count(Hint::ShiftOff), 1);
+        assert_eq!(analysis.count(Hint::MemoryIncrements), 1);
+        assert_eq!(analysis.platform, Some(Platform::OriginalChip8));
+        assert!(!analysis.quirks.shift);
+        assert!(!analysis.quirks.memory_leave_i_unchanged);
+        assert!(analysis.confidence > 0.0);
+    }
+
+    #[test]
+    fn analyzer_jump_vx() {
+        // V2 := 4; jump0 0x208 + V2; only V2 is ever set.
+        let analysis = analyze(&rom(&[0x6204, 0xB208]));
+        assert_eq!(analysis.count(Hint::JumpVx), 1);
+        assert!(analysis.quirks.jump);
+    }
+
+    #[test]
+    fn analyzer_loop_without_i_reset() {
+        // I := 0x300; loop { load V0..V3; V4 += 1; SE V4, 4; jump loop }
+        let analysis = analyze(&rom(&[0xA300, 0xF365, 0x7401, 0x3404, 0x1202, 0x120A]));
+        assert_eq!(analysis.count(Hint::MemoryIncrements), 1);
+    }
+}
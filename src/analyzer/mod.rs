@@ -0,0 +1,3 @@
+mod analyzer;
+
+pub use self::analyzer::{analyze, reachable, Analysis, Evidence, Hint, MIN_CONFIDENCE};
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use crate::cpu::PROG_START;
+use crate::symbols::SymbolMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand<'a> {
+    V(u16),
+    I,
+    // [I], the memory I points at.
+    AtI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    R,
+    B,
+    // The 16-bit address of XO-CHIP's F000 NNNN.
+    Long(&'a str),
+    // A number or label, resolved once every label is known.
+    Value(&'a str),
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<Operand<'a>>,
+}
+
+fn parse_operand(text: &str) -> Operand<'_> {
+    let upper = text.to_ascii_uppercase();
+    if upper.len() == 2 && upper.starts_with('V') {
+        if let Ok(x) = u16::from_str_radix(&upper[1..], 16) {
+            return Operand::V(x);
+        }
+    }
+    match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::AtI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "R" => Operand::R,
+        "B" => Operand::B,
+        _ if upper.starts_with("LONG ") => Operand::Long(text[5..].trim()),
+        _ => Operand::Value(text),
+    }
+}
+
+pub(super) fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Bytes a statement assembles to, known before labels are resolved.
+fn size(statement: &Statement) -> usize {
+    match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+        ("DB", operands) => operands.len(),
+        ("DW", operands) => operands.len() * 2,
+        ("LD", [Operand::I, Operand::Long(_)]) => 4,
+        _ => 2,
+    }
+}
+
+// Turns source in the syntax `listing` produces into a ROM loaded at 0x200.
+// Errors name the offending line.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    assemble_with_symbols(source).map(|(rom, _)| rom)
+}
+
+// Also returns the labels, and the line each statement was assembled from.
+pub fn assemble_with_symbols(source: &str) -> Result<(Vec<u8>, SymbolMap), String> {
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = PROG_START;
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let mut text = text.split(';').next().unwrap().trim();
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_label(label) {
+                return Err(format!("line {}: bad label {:?}", line, label));
+            }
+            if labels.insert(label.to_string(), addr).is_some() {
+                return Err(format!("line {}: {} is already defined", line, label));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = match text.find(char::is_whitespace) {
+            Some(space) => (&text[..space], text[space..].trim()),
+            None => (text, ""),
+        };
+        let operands = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',').map(|operand| parse_operand(operand.trim())).collect()
+        };
+        let statement = Statement { line, mnemonic: mnemonic.to_ascii_uppercase(), operands };
+        addr += size(&statement);
+        statements.push(statement);
+    }
+
+    let mut symbols = SymbolMap::default();
+    let mut sorted: Vec<(&String, &usize)> = labels.iter().collect();
+    sorted.sort_by_key(|&(name, &addr)| (addr, name));
+    for (name, &addr) in sorted {
+        symbols.add_label(addr, name);
+    }
+    let mut rom = Vec::new();
+    for statement in &statements {
+        let words = encode(statement, &labels).map_err(|e| format!("line {}: {}", statement.line, e))?;
+        symbols.add_line(PROG_START + rom.len(), statement.line);
+        rom.extend(words);
+    }
+    Ok((rom, symbols))
+}
+
+fn encode(statement: &Statement, labels: &HashMap<String, usize>) -> Result<Vec<u8>, String> {
+    use self::Operand::*;
+
+    let value = |text: &str, max: u32| -> Result<u16, String> {
+        let value = match parse_number(text) {
+            Some(value) => value,
+            None if is_label(text) => match labels.get(text) {
+                Some(&addr) => addr as u32,
+                None => return Err(format!("unknown label {}", text)),
+            },
+            None => return Err(format!("bad number {:?}", text)),
+        };
+        if value > max {
+            return Err(format!("{} is out of range (max {:#X})", text, max));
+        }
+        Ok(value as u16)
+    };
+    let addr = |text| value(text, 0xFFF);
+    let byte = |text| value(text, 0xFF);
+    let nibble = |text| value(text, 0xF);
+    let xy = |x: u16, y: u16| x << 8 | y << 4;
+
+    let op = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+        ("DB", operands) => {
+            return operands.iter().map(|operand| match operand {
+                Value(text) => byte(text).map(|b| b as u8),
+                _ => Err("DB takes numbers".to_string()),
+            }).collect();
+        },
+        ("DW", operands) => {
+            let mut bytes = Vec::new();
+            for operand in operands {
+                match operand {
+                    Value(text) => bytes.extend_from_slice(&value(text, 0xFFFF)?.to_be_bytes()),
+                    _ => return Err("DW takes numbers".to_string()),
+                }
+            }
+            return Ok(bytes);
+        },
+        ("LD", [I, Long(text)]) => {
+            let mut bytes = vec![0xF0, 0x00];
+            bytes.extend_from_slice(&value(text, 0xFFFF)?.to_be_bytes());
+            return Ok(bytes);
+        },
+
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SCR", []) => 0x00FB,
+        ("SCL", []) => 0x00FC,
+        ("EXIT", []) => 0x00FD,
+        ("LOW", []) => 0x00FE,
+        ("HIGH", []) => 0x00FF,
+        ("AUDIO", []) => 0xF002,
+        ("SCD", [Value(n)]) => 0x00C0 | nibble(n)?,
+        ("SCU", [Value(n)]) => 0x00D0 | nibble(n)?,
+        ("SYS", [Value(a)]) => addr(a)?,
+        ("JP", [Value(a)]) => 0x1000 | addr(a)?,
+        ("JP", [V(0), Value(a)]) => 0xB000 | addr(a)?,
+        ("CALL", [Value(a)]) => 0x2000 | addr(a)?,
+        ("SE", [V(x), V(y)]) => 0x5000 | xy(*x, *y),
+        ("SE", [V(x), Value(k)]) => 0x3000 | x << 8 | byte(k)?,
+        ("SNE", [V(x), V(y)]) => 0x9000 | xy(*x, *y),
+        ("SNE", [V(x), Value(k)]) => 0x4000 | x << 8 | byte(k)?,
+        ("SAVE", [V(x), V(y)]) => 0x5002 | xy(*x, *y),
+        ("LOAD", [V(x), V(y)]) => 0x5003 | xy(*x, *y),
+
+        ("LD", [V(x), V(y)]) => 0x8000 | xy(*x, *y),
+        ("LD", [V(x), Dt]) => 0xF007 | x << 8,
+        ("LD", [V(x), K]) => 0xF00A | x << 8,
+        ("LD", [V(x), AtI]) => 0xF065 | x << 8,
+        ("LD", [V(x), R]) => 0xF085 | x << 8,
+        ("LD", [V(x), Value(k)]) => 0x6000 | x << 8 | byte(k)?,
+        ("LD", [I, Value(a)]) => 0xA000 | addr(a)?,
+        ("LD", [Dt, V(x)]) => 0xF015 | x << 8,
+        ("LD", [St, V(x)]) => 0xF018 | x << 8,
+        ("LD", [F, V(x)]) => 0xF029 | x << 8,
+        ("LD", [Hf, V(x)]) => 0xF030 | x << 8,
+        ("LD", [B, V(x)]) => 0xF033 | x << 8,
+        ("LD", [AtI, V(x)]) => 0xF055 | x << 8,
+        ("LD", [R, V(x)]) => 0xF075 | x << 8,
+
+        ("ADD", [V(x), V(y)]) => 0x8004 | xy(*x, *y),
+        ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+        ("ADD", [V(x), Value(k)]) => 0x7000 | x << 8 | byte(k)?,
+        ("OR", [V(x), V(y)]) => 0x8001 | xy(*x, *y),
+        ("AND", [V(x), V(y)]) => 0x8002 | xy(*x, *y),
+        ("XOR", [V(x), V(y)]) => 0x8003 | xy(*x, *y),
+        ("SUB", [V(x), V(y)]) => 0x8005 | xy(*x, *y),
+        ("SHR", [V(x)]) => 0x8006 | x << 8,
+        ("SHR", [V(x), V(y)]) => 0x8006 | xy(*x, *y),
+        ("SUBN", [V(x), V(y)]) => 0x8007 | xy(*x, *y),
+        ("SHL", [V(x)]) => 0x800E | x << 8,
+        ("SHL", [V(x), V(y)]) => 0x800E | xy(*x, *y),
+        ("RND", [V(x), Value(k)]) => 0xC000 | x << 8 | byte(k)?,
+        ("DRW", [V(x), V(y), Value(n)]) => 0xD000 | xy(*x, *y) | nibble(n)?,
+        ("SKP", [V(x)]) => 0xE09E | x << 8,
+        ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+        ("PLANE", [Value(n)]) => 0xF001 | nibble(n)? << 8,
+        ("PITCH", [V(x)]) => 0xF03A | x << 8,
+
+        (mnemonic, _) => return Err(format!("can't assemble {} with those operands", mnemonic)),
+    };
+    Ok(op.to_be_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{listing, mnemonic};
+
+    #[test]
+    fn asm_labels_and_data() {
+        let source = "
+            start:  LD I, sprite    ; point at the data
+                    DRW V0, V1, 1
+            loop:   JP loop
+            sprite: DB 0b10000001, 0xFF
+                    DW 0x1234
+        ";
+        assert_eq!(assemble(source).unwrap(),
+                   [0xA2, 0x06, 0xD0, 0x11, 0x12, 0x04, 0x81, 0xFF, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn asm_symbols() {
+        let source = "start:\n  LD I, sprite\n  DRW V0, V1, 1\nloop: JP loop\nsprite: DB 0xFF, 0x81\n";
+        let (_, symbols) = assemble_with_symbols(source).unwrap();
+        assert_eq!(symbols.labels().collect::<Vec<_>>(), [(0x200, "start"), (0x204, "loop"), (0x206, "sprite")]);
+        assert_eq!(symbols.line(0x200), Some(2));
+        assert_eq!(symbols.line(0x204), Some(4));
+        assert_eq!(symbols.line(0x206), Some(5));
+        assert_eq!(symbols.describe(0x202).as_deref(), Some("start+2"));
+    }
+
+    #[test]
+    fn asm_errors_name_the_line() {
+        assert_eq!(assemble("CLS\nJP nowhere").unwrap_err(), "line 2: unknown label nowhere");
+        assert_eq!(assemble("LD V0, 0x100").unwrap_err(), "line 1: 0x100 is out of range (max 0xFF)");
+        assert!(assemble("a: CLS\na: CLS").is_err());
+        assert!(assemble("FOO V1").is_err());
+    }
+
+    #[test]
+    fn asm_every_mnemonic_round_trips() {
+        for op in 0..=0xFFFFu16 {
+            let text = mnemonic(op);
+            let expected = if op == 0xF000 { vec![0xF0, 0x00, 0x0A, 0xBC] } else { op.to_be_bytes().to_vec() };
+            let text = if op == 0xF000 { format!("{} 0xABC", text) } else { text };
+            assert_eq!(assemble(&text).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn asm_listing_round_trips() {
+        let rom = [0x00, 0xE0, 0xA2, 0x0A, 0x60, 0x05, 0xD0, 0x05, 0x12, 0x08,
+                   0xF0, 0x90, 0x90, 0x90, 0xF0, 0x01, 0xF0, 0x00, 0x12];
+        assert_eq!(assemble(&listing(&rom)).unwrap(), rom);
+    }
+}
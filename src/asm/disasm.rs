@@ -0,0 +1,278 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::analyzer::reachable;
+use crate::cpu::PROG_START;
+use crate::symbols::SymbolMap;
+use super::asm::is_label;
+
+// Data bytes per DB line in a listing.
+const DB_WIDTH: usize = 8;
+
+// One line of a listing: an instruction, or a run of data bytes that
+// nothing was seen executing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+    pub label: Option<String>,
+    pub text: String,
+}
+
+// The instruction `op` in assembler syntax, with numeric addresses. Words
+// that aren't instructions come back as `DW`.
+pub fn mnemonic(op: u16) -> String {
+    mnemonic_with_symbols(op, &SymbolMap::default())
+}
+
+// With addresses that have labels going by them.
+pub fn mnemonic_with_symbols(op: u16, symbols: &SymbolMap) -> String {
+    let name = |addr: usize| symbols.label(addr).map(str::to_string).unwrap_or_else(|| format!("{:#05X}", addr));
+    format(op, None, &name).unwrap_or_else(|| format!("DW {:#06X}", op))
+}
+
+// `long` is the word after F000, which is part of the same instruction.
+fn format(op: u16, long: Option<u16>, addr: &dyn Fn(usize) -> String) -> Option<String> {
+    let x = (op >> 8) & 0xF;
+    let y = (op >> 4) & 0xF;
+    let n = op & 0xF;
+    let kk = op & 0xFF;
+    let nnn = (op & 0xFFF) as usize;
+
+    Some(match op & 0xF000 {
+        0x0000 => match op {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            0x00FB => "SCR".to_string(),
+            0x00FC => "SCL".to_string(),
+            0x00FD => "EXIT".to_string(),
+            0x00FE => "LOW".to_string(),
+            0x00FF => "HIGH".to_string(),
+            _ if op & 0xFFF0 == 0x00C0 => format!("SCD {}", n),
+            _ if op & 0xFFF0 == 0x00D0 => format!("SCU {}", n),
+            _ => format!("SYS {}", addr(nnn)),
+        },
+        0x1000 => format!("JP {}", addr(nnn)),
+        0x2000 => format!("CALL {}", addr(nnn)),
+        0x3000 => format!("SE V{:X}, {:#04X}", x, kk),
+        0x4000 => format!("SNE V{:X}, {:#04X}", x, kk),
+        0x5000 => match n {
+            0x0 => format!("SE V{:X}, V{:X}", x, y),
+            0x2 => format!("SAVE V{:X}, V{:X}", x, y),
+            0x3 => format!("LOAD V{:X}, V{:X}", x, y),
+            _ => return None,
+        },
+        0x6000 => format!("LD V{:X}, {:#04X}", x, kk),
+        0x7000 => format!("ADD V{:X}, {:#04X}", x, kk),
+        0x8000 => {
+            let name = match n {
+                0x0 => "LD",
+                0x1 => "OR",
+                0x2 => "AND",
+                0x3 => "XOR",
+                0x4 => "ADD",
+                0x5 => "SUB",
+                0x6 => "SHR",
+                0x7 => "SUBN",
+                0xE => "SHL",
+                _ => return None,
+            };
+            format!("{} V{:X}, V{:X}", name, x, y)
+        },
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0x9000 => return None,
+        0xA000 => format!("LD I, {}", addr(nnn)),
+        0xB000 => format!("JP V0, {}", addr(nnn)),
+        0xC000 => format!("RND V{:X}, {:#04X}", x, kk),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match kk {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => return None,
+        },
+        _ => match kk {
+            0x00 if x == 0 => match long {
+                Some(long) => format!("LD I, LONG {}", addr(long as usize)),
+                None => "LD I, LONG".to_string(),
+            },
+            0x01 => format!("PLANE {}", x),
+            0x02 if x == 0 => "AUDIO".to_string(),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x3A => format!("PITCH V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => return None,
+        },
+    })
+}
+
+// Addresses an instruction refers to, worth a label.
+fn target(op: u16, long: Option<u16>) -> Option<usize> {
+    match op & 0xF000 {
+        0x1000 | 0x2000 | 0xA000 | 0xB000 => Some((op & 0xFFF) as usize),
+        0xF000 if op == 0xF000 => long.map(|long| long as usize),
+        _ => None,
+    }
+}
+
+fn word(rom: &[u8], addr: usize) -> Option<u16> {
+    let i = addr.checked_sub(PROG_START)?;
+    if i + 1 < rom.len() { Some((rom[i] as u16) << 8 | rom[i + 1] as u16) } else { None }
+}
+
+// Splits a ROM into code, following execution from the entry point, and
+// data for everything else. Addresses referred to by the code get labels.
+pub fn disassemble(rom: &[u8]) -> Vec<Line> {
+    disassemble_with_symbols(rom, &SymbolMap::default())
+}
+
+// Labels from the symbols go where they say, and take the place of the
+// made up ones.
+pub fn disassemble_with_symbols(rom: &[u8], symbols: &SymbolMap) -> Vec<Line> {
+    let end = PROG_START + rom.len();
+    // A long load cut off by the end of the ROM is left as data.
+    let code: HashMap<usize, u16> = reachable(rom).into_iter()
+                                                  .filter(|&(addr, op)| op != 0xF000 || addr + 4 <= end)
+                                                  .collect();
+    let long = |addr: usize| word(rom, addr + 2);
+    let targets: BTreeSet<usize> = code.iter()
+                                       .filter_map(|(&addr, &op)| target(op, long(addr)))
+                                       .chain(symbols.labels().map(|(addr, _)| addr))
+                                       .filter(|&addr| addr >= PROG_START && addr < end)
+                                       .collect();
+
+    let mut lines = Vec::new();
+    let mut addr = PROG_START;
+    while addr < end {
+        let len = match code.get(&addr) {
+            Some(&0xF000) => 4,
+            Some(_) => 2,
+            None => (addr + 1..end).take(DB_WIDTH - 1)
+                                   .position(|a| code.contains_key(&a) || targets.contains(&a))
+                                   .map(|n| n + 1)
+                                   .unwrap_or_else(|| (end - addr).min(DB_WIDTH)),
+        };
+        lines.push(Line {
+            addr,
+            bytes: rom[addr - PROG_START..addr - PROG_START + len].to_vec(),
+            label: None,
+            text: String::new(),
+        });
+        addr += len;
+    }
+
+    // Only addresses that start a line can be labelled. Symbols the
+    // assembler couldn't read back, like Octo's `draw-sprite`, get their
+    // odd characters swapped for underscores.
+    let mut labels: HashMap<usize, String> = HashMap::new();
+    for line in lines.iter().filter(|line| targets.contains(&line.addr)) {
+        let symbol = symbols.label(line.addr).map(label_name).filter(|name| !labels.values().any(|used| used == name));
+        labels.insert(line.addr, symbol.unwrap_or_else(|| format!("L{:03X}", line.addr)));
+    }
+    let name = |addr: usize| labels.get(&addr).cloned().unwrap_or_else(|| format!("{:#05X}", addr));
+
+    for line in &mut lines {
+        line.label = labels.get(&line.addr).cloned();
+        line.text = match code.get(&line.addr) {
+            Some(&op) => {
+                let long = if line.bytes.len() == 4 { long(line.addr) } else { None };
+                format(op, long, &name).unwrap_or_else(|| format!("DW {:#06X}", op))
+            },
+            None => {
+                let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+                format!("DB {}", bytes.join(", "))
+            },
+        };
+    }
+    lines
+}
+
+fn label_name(symbol: &str) -> String {
+    let name: String = symbol.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '_' }).collect();
+    if is_label(&name) { name } else { format!("_{}", name) }
+}
+
+// A listing that `assemble` turns back into the same ROM.
+pub fn listing(rom: &[u8]) -> String {
+    listing_with_symbols(rom, &SymbolMap::default())
+}
+
+// With the symbols' labels, and the source line each instruction came from
+// after its bytes.
+pub fn listing_with_symbols(rom: &[u8], symbols: &SymbolMap) -> String {
+    let mut out = String::new();
+    for line in disassemble_with_symbols(rom, symbols) {
+        if let Some(label) = &line.label {
+            out.push_str(&format!("{}:\n", label));
+        }
+        let hex: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut text = format!("    {:<28}; {:03X}  {}", line.text, line.addr, hex.join(" "));
+        if let Some(location) = symbols.location(line.addr) {
+            text.push_str(&format!("  {}", location));
+        }
+        out.push_str(&text);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disasm_mnemonics() {
+        assert_eq!(mnemonic(0x00E0), "CLS");
+        assert_eq!(mnemonic(0x1234), "JP 0x234");
+        assert_eq!(mnemonic(0x8A1E), "SHL VA, V1");
+        assert_eq!(mnemonic(0xD125), "DRW V1, V2, 5");
+        assert_eq!(mnemonic(0xF355), "LD [I], V3");
+        assert_eq!(mnemonic(0xF000), "LD I, LONG");
+        assert_eq!(mnemonic(0x5121), "DW 0x5121");
+    }
+
+    #[test]
+    fn disasm_code_and_data() {
+        // LD I, data; DRW; JP self; then two sprite bytes.
+        let rom = [0xA2, 0x06, 0xD0, 0x02, 0x12, 0x04, 0xFF, 0x81];
+        let lines = disassemble(&rom);
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, ["LD I, L206", "DRW V0, V0, 2", "JP L204", "DB 0xFF, 0x81"]);
+        assert_eq!(lines[2].label.as_deref(), Some("L204"));
+        assert_eq!(lines[3].addr, 0x206);
+    }
+
+    #[test]
+    fn disasm_with_symbols() {
+        // LD I, data; DRW; JP self; then four sprite bytes, the last two
+        // under a label of their own.
+        let rom = [0xA2, 0x06, 0xD0, 0x02, 0x12, 0x04, 0xFF, 0x81, 0x81, 0xFF];
+        let mut symbols = SymbolMap::default();
+        symbols.add_label(0x200, "main");
+        symbols.add_label(0x204, "wait-here");
+        symbols.add_label(0x206, "sprite");
+        symbols.add_label(0x208, "sprite_2");
+        symbols.add_line(0x204, 7);
+        symbols.source = Some("demo/game.8o".to_string());
+
+        let lines = disassemble_with_symbols(&rom, &symbols);
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, ["LD I, sprite", "DRW V0, V0, 2", "JP wait_here", "DB 0xFF, 0x81", "DB 0x81, 0xFF"]);
+        assert_eq!(lines[0].label.as_deref(), Some("main"));
+
+        let listing = listing_with_symbols(&rom, &symbols);
+        assert!(listing.contains("wait_here:\n    JP wait_here                ; 204  12 04  game.8o:7\n"));
+        assert_eq!(crate::asm::assemble(&listing).unwrap(), rom);
+
+        assert_eq!(mnemonic_with_symbols(0x2206, &symbols), "CALL sprite");
+        assert_eq!(mnemonic_with_symbols(0x2300, &symbols), "CALL 0x300");
+    }
+}
@@ -0,0 +1,5 @@
+mod asm;
+mod disasm;
+
+pub use self::asm::{assemble, assemble_with_symbols};
+pub use self::disasm::{disassemble, disassemble_with_symbols, listing, listing_with_symbols, mnemonic, mnemonic_with_symbols, Line};
@@ -1,49 +1,94 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
 use sdl2::audio::{AudioDevice, AudioCallback, AudioSpecDesired};
 
+use super::beeper::{Beeper, SampleSink, SAMPLE_RATE};
+
+// Roughly four frames of audio; anything queued beyond this is latency
+// from the host running ahead, so the oldest samples get dropped.
+const MAX_QUEUED: usize = SAMPLE_RATE as usize / 15;
+
 pub struct Chip8Audio {
-    device: AudioDevice<SquareWave>
+    device: AudioDevice<SampleQueue>,
+    sink: DeviceSink,
+    beeper: Beeper,
+    enabled: bool,
 }
 
 impl Chip8Audio {
-    pub fn new(sdl: &sdl2::Sdl) -> Self {
+    pub fn new(sdl: &sdl2::Sdl, fps: u32) -> Self {
         let audio_subsystem = sdl.audio().unwrap();
 
         let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
+            freq: Some(SAMPLE_RATE as i32),
             channels: Some(1),
             samples: None,
         };
 
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let mut freq = SAMPLE_RATE;
         let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-            println!("{:?}", spec);
+            freq = spec.freq as u32;
 
-            SquareWave {
-                phase_inc: 240.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
+            SampleQueue {
+                samples: queue.clone(),
             }
         }).unwrap();
+        device.resume();
+
+        Chip8Audio {
+            device,
+            sink: DeviceSink { queue },
+            beeper: Beeper::new(freq, fps),
+            enabled: true,
+        }
+    }
+
+    // Queue one emulated frame of audio: tone if the sound timer was
+    // running during the frame, silence otherwise.
+    pub fn frame(&mut self, beep: bool) {
+        self.beeper.frame(beep && self.enabled, &mut self.sink);
+    }
 
-        Chip8Audio { device: device }
+    pub fn set_tone(&mut self, tone: f32, volume: f32) {
+        self.beeper.set_tone(tone, volume);
     }
 
-    pub fn play(&self) { self.device.resume(); }
-    pub fn stop(&self) { self.device.pause(); }
+    // A disabled device keeps running but only ever plays silence.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn pause(&self) { self.device.pause(); }
+    pub fn resume(&self) { self.device.resume(); }
+}
+
+struct DeviceSink {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+}
+
+impl SampleSink for DeviceSink {
+    fn queue(&mut self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples.iter());
+        while queue.len() > MAX_QUEUED {
+            queue.pop_front();
+        }
+    }
 }
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+struct SampleQueue {
+    samples: Arc<Mutex<VecDeque<f32>>>,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for SampleQueue {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
+        let mut samples = self.samples.lock().unwrap();
         for x in out.iter_mut() {
-            *x = self.volume * if self.phase < 0.5 { 1.0 } else { -1.0 };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            *x = samples.pop_front().unwrap_or(0.0);
         }
     }
 }
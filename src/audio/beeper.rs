@@ -0,0 +1,121 @@
+pub const SAMPLE_RATE: u32 = 44100;
+pub const TONE: f32 = 240.0;
+pub const VOLUME: f32 = 0.25;
+
+pub trait SampleSink {
+    fn queue(&mut self, samples: &[f32]);
+}
+
+// Collects samples in memory, for running without an audio device.
+#[derive(Default)]
+pub struct BufferSink {
+    pub samples: Vec<f32>,
+}
+
+impl BufferSink {
+    pub fn new() -> Self {
+        BufferSink::default()
+    }
+}
+
+impl SampleSink for BufferSink {
+    fn queue(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+// Square wave generator stepped once per emulated frame. Every frame
+// produces the same number of samples whether the tone is on or not, so
+// the output stays locked to the emulated timeline rather than the host's.
+pub struct Beeper {
+    sample_rate: u32,
+    fps: u32,
+    phase: f32,
+    phase_inc: f32,
+    volume: f32,
+    remainder: u32,
+    buffer: Vec<f32>,
+}
+
+impl Beeper {
+    pub fn new(sample_rate: u32, fps: u32) -> Self {
+        Beeper {
+            sample_rate,
+            fps,
+            phase: 0.0,
+            phase_inc: TONE / sample_rate as f32,
+            volume: VOLUME,
+            remainder: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 { self.sample_rate }
+
+    // Square wave pitch in Hz and amplitude from 0.0 to 1.0.
+    pub fn set_tone(&mut self, tone: f32, volume: f32) {
+        self.phase_inc = tone / self.sample_rate as f32;
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn frame(&mut self, on: bool, sink: &mut dyn SampleSink) {
+        // Carry the fractional part so rates that don't divide evenly by the
+        // frame rate still average out to exactly `sample_rate` per second.
+        let total = self.sample_rate + self.remainder;
+        let count = (total / self.fps) as usize;
+        self.remainder = total % self.fps;
+
+        self.buffer.clear();
+        for _ in 0..count {
+            let sample = if on {
+                self.volume * if self.phase < 0.5 { 1.0 } else { -1.0 }
+            } else {
+                0.0
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+            self.buffer.push(sample);
+        }
+        sink.queue(&self.buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beeper_samples_per_frame() {
+        let mut beeper = Beeper::new(44100, 60);
+        let mut sink = BufferSink::new();
+        beeper.frame(true, &mut sink);
+        assert_eq!(sink.samples.len(), 735);
+    }
+
+    #[test]
+    fn beeper_remainder_carried() {
+        let mut beeper = Beeper::new(48000, 70);
+        let mut sink = BufferSink::new();
+        for _ in 0..70 {
+            beeper.frame(false, &mut sink);
+        }
+        assert_eq!(sink.samples.len(), 48000);
+    }
+
+    #[test]
+    fn beeper_silent_when_off() {
+        let mut beeper = Beeper::new(44100, 60);
+        let mut sink = BufferSink::new();
+        beeper.frame(false, &mut sink);
+        assert!(sink.samples.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn beeper_single_frame_tone() {
+        let mut beeper = Beeper::new(44100, 60);
+        let mut sink = BufferSink::new();
+        beeper.frame(true, &mut sink);
+        beeper.frame(false, &mut sink);
+        assert!(sink.samples[..735].iter().all(|&s| s.abs() == VOLUME));
+        assert!(sink.samples[735..].iter().all(|&s| s == 0.0));
+    }
+}
@@ -1,3 +1,7 @@
 mod audio;
+mod beeper;
+mod wav;
 
 pub use self::audio::Chip8Audio;
+pub use self::beeper::{Beeper, BufferSink, SampleSink, SAMPLE_RATE, TONE, VOLUME};
+pub use self::wav::WavWriter;
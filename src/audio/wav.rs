@@ -0,0 +1,133 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Error, Result, Seek, SeekFrom, Write};
+
+use super::beeper::SampleSink;
+
+// The RIFF size field counts everything after it: the rest of the header
+// and the samples.
+const HEADER_REST: u64 = 36;
+
+// Streams queued samples out as 16-bit mono PCM. The sizes in the header
+// are filled in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    // Bytes of samples written so far.
+    data_len: u64,
+    // The first write that failed; `queue` can't report it.
+    error: Option<Error>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &str, sample_rate: u32) -> Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?; // block align
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, data_len: 0, error: None })
+    }
+
+    pub fn len(&self) -> u64 { self.data_len / 2 }
+    pub fn is_empty(&self) -> bool { self.data_len == 0 }
+
+    // Patches the sizes into the header. A WAV file can't describe more
+    // than 4 GiB, so a longer capture is an error rather than a file with
+    // the wrong sizes.
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let riff_len = u32::try_from(HEADER_REST + self.data_len).map_err(|_| too_long())?;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&riff_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_REST + 4))?;
+        self.out.write_all(&(self.data_len as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        let len = samples.len() as u64 * 2;
+        if HEADER_REST + self.data_len + len > u32::MAX as u64 {
+            return Err(too_long());
+        }
+        for &sample in samples {
+            let clamped = sample.clamp(-1.0, 1.0);
+            self.out.write_all(&((clamped * i16::MAX as f32) as i16).to_le_bytes())?;
+        }
+        self.data_len += len;
+        Ok(())
+    }
+}
+
+fn too_long() -> Error {
+    Error::other("audio is longer than the 4 GiB a WAV file can hold")
+}
+
+impl<W: Write + Seek> SampleSink for WavWriter<W> {
+    fn queue(&mut self, samples: &[f32]) {
+        if self.error.is_none() {
+            self.error = self.write_samples(samples).err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, ErrorKind};
+
+    #[test]
+    fn wav_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.queue(&[0.0, 1.0, -1.0]);
+        let out = wav.finish().unwrap().into_inner();
+
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &42u32.to_le_bytes());
+        assert_eq!(&out[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&out[40..44], &6u32.to_le_bytes());
+    }
+
+    #[test]
+    fn wav_samples() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.queue(&[0.0, 1.0, -1.0, 2.0]);
+        let out = wav.finish().unwrap().into_inner();
+
+        assert_eq!(&out[44..46], &0i16.to_le_bytes());
+        assert_eq!(&out[46..48], &i16::MAX.to_le_bytes());
+        assert_eq!(&out[48..50], &(-i16::MAX).to_le_bytes());
+        assert_eq!(&out[50..52], &i16::MAX.to_le_bytes());
+    }
+
+    #[test]
+    fn wav_refuses_over_4_gib() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        // Pretend nearly 4 GiB went out already.
+        wav.data_len = u32::MAX as u64 - HEADER_REST - 2;
+        wav.queue(&[0.0]);
+        assert!(wav.error.is_none());
+        wav.queue(&[0.0]);
+        assert_eq!(wav.finish().unwrap_err().kind(), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const ROM_EXTENSIONS: [&str; 8] = ["ch8", "c8", "sc8", "xo8", "8o", "rom", "zip", "gif"];
+// Largest file worth listing; anything bigger can't be a CHIP-8 program.
+const MAX_ROM_SIZE: u64 = 0x10000;
+const MAX_RECENT: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub title: String,
+    pub size: u64,
+    pub recent: bool,
+    // Extra detail shown next to the title, e.g. platform and quirks.
+    pub info: Option<String>,
+}
+
+impl RomEntry {
+    pub fn new(path: &Path, recent: bool) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        let title = path.file_stem()?.to_string_lossy().to_string();
+        Some(RomEntry {
+            path: path.to_path_buf(),
+            title,
+            size: meta.len(),
+            recent,
+            info: None,
+        })
+    }
+
+    pub fn label(&self) -> String {
+        let marker = if self.recent { "* " } else { "  " };
+        match self.info {
+            Some(ref info) => format!("{}{} - {}", marker, self.title, info),
+            None => format!("{}{} ({} bytes)", marker, self.title, self.size),
+        }
+    }
+}
+
+// ROM packs often ship files without an extension, so those count too.
+pub fn is_rom_path(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ROM_EXTENSIONS.iter().any(|rom| ext.eq_ignore_ascii_case(rom)),
+        None => !path.file_name()
+                     .map(|name| name.to_string_lossy().starts_with('.'))
+                     .unwrap_or(true),
+    }
+}
+
+pub fn scan_dir(dir: &Path) -> io::Result<Vec<RomEntry>> {
+    let mut entries: Vec<RomEntry> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_rom_path(path))
+        .filter_map(|path| RomEntry::new(&path, false))
+        .filter(|entry| entry.size > 0 && entry.size <= MAX_ROM_SIZE)
+        .collect();
+    entries.sort_by_key(|entry| entry.title.to_lowercase());
+    Ok(entries)
+}
+
+// Most recently played first, persisted as one path per line.
+pub struct RecentRoms {
+    file: Option<PathBuf>,
+    paths: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    pub fn load() -> Self {
+        let file = dirs::config_dir().map(|dir| dir.join("chip8").join("recent"));
+        let paths = file.as_ref()
+                        .and_then(|file| fs::read_to_string(file).ok())
+                        .map(|text| text.lines()
+                                        .filter(|line| !line.trim().is_empty())
+                                        .map(PathBuf::from)
+                                        .collect())
+                        .unwrap_or_default();
+        RecentRoms { file, paths }
+    }
+
+    pub fn in_memory() -> Self {
+        RecentRoms { file: None, paths: Vec::new() }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] { &self.paths }
+
+    pub fn add(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.paths.retain(|p| *p != path);
+        self.paths.insert(0, path);
+        self.paths.truncate(MAX_RECENT);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(ref file) = self.file {
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut out = fs::File::create(file)?;
+            for path in &self.paths {
+                writeln!(out, "{}", path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Selection state for the in-window ROM list.
+pub struct RomBrowser {
+    entries: Vec<RomEntry>,
+    selected: usize,
+    scroll: usize,
+}
+
+impl RomBrowser {
+    // Recent ROMs that still exist come first, followed by the contents of
+    // `dir` (if any) without repeating them.
+    pub fn new(dir: Option<&Path>, recent: &RecentRoms) -> Self {
+        let mut entries: Vec<RomEntry> = recent.paths()
+                                               .iter()
+                                               .filter_map(|path| RomEntry::new(path, true))
+                                               .collect();
+        if let Some(dir) = dir {
+            for entry in scan_dir(dir).unwrap_or_default() {
+                let path = fs::canonicalize(&entry.path).unwrap_or_else(|_| entry.path.clone());
+                if !entries.iter().any(|e| e.path == path) {
+                    entries.push(entry);
+                }
+            }
+        }
+        RomBrowser::from_entries(entries)
+    }
+
+    pub fn from_entries(entries: Vec<RomEntry>) -> Self {
+        RomBrowser { entries, selected: 0, scroll: 0 }
+    }
+
+    pub fn entries(&self) -> &[RomEntry] { &self.entries }
+    pub fn entries_mut(&mut self) -> &mut [RomEntry] { &mut self.entries }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+    pub fn selected_index(&self) -> usize { self.selected }
+
+    pub fn selected(&self) -> Option<&RomEntry> {
+        self.entries.get(self.selected)
+    }
+
+    pub fn move_by(&mut self, delta: isize) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let last = self.entries.len() as isize - 1;
+        self.selected = (self.selected as isize + delta).clamp(0, last) as usize;
+    }
+
+    // The slice of entries that fits in `rows` lines, scrolled to keep the
+    // selection in view, along with the index of the first one.
+    pub fn visible(&mut self, rows: usize) -> (usize, &[RomEntry]) {
+        let rows = rows.max(1);
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+        let end = (self.scroll + rows).min(self.entries.len());
+        (self.scroll, &self.entries[self.scroll..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(title: &str) -> RomEntry {
+        RomEntry {
+            path: PathBuf::from(title),
+            title: title.to_string(),
+            size: 100,
+            recent: false,
+            info: None,
+        }
+    }
+
+    #[test]
+    fn browser_rom_paths() {
+        assert!(is_rom_path(Path::new("roms/PONG")));
+        assert!(is_rom_path(Path::new("roms/pong.ch8")));
+        assert!(is_rom_path(Path::new("roms/pong.CH8")));
+        assert!(!is_rom_path(Path::new("roms/readme.txt")));
+        assert!(!is_rom_path(Path::new("roms/.hidden")));
+    }
+
+    #[test]
+    fn browser_move_clamped() {
+        let mut browser = RomBrowser::from_entries(vec![entry("A"), entry("B"), entry("C")]);
+        browser.move_by(-1);
+        assert_eq!(browser.selected_index(), 0);
+        browser.move_by(10);
+        assert_eq!(browser.selected_index(), 2);
+        assert_eq!(browser.selected().unwrap().title, "C");
+    }
+
+    #[test]
+    fn browser_visible_scrolls() {
+        let entries = (0..10).map(|i| entry(&i.to_string())).collect();
+        let mut browser = RomBrowser::from_entries(entries);
+        assert_eq!(browser.visible(4).0, 0);
+        browser.move_by(5);
+        let (first, rows) = browser.visible(4);
+        assert_eq!(first, 2);
+        assert_eq!(rows.len(), 4);
+        browser.move_by(-5);
+        assert_eq!(browser.visible(4).0, 0);
+    }
+
+    #[test]
+    fn browser_recent_dedup() {
+        let mut recent = RecentRoms::in_memory();
+        recent.add(Path::new("/nonexistent/a"));
+        recent.add(Path::new("/nonexistent/b"));
+        recent.add(Path::new("/nonexistent/a"));
+        assert_eq!(recent.paths(), &[PathBuf::from("/nonexistent/a"), PathBuf::from("/nonexistent/b")]);
+    }
+
+    #[test]
+    fn browser_labels() {
+        let mut rom = entry("PONG");
+        assert_eq!(rom.label(), "  PONG (100 bytes)");
+        rom.recent = true;
+        rom.info = Some("CHIP-8".to_string());
+        assert_eq!(rom.label(), "* PONG - CHIP-8");
+    }
+}
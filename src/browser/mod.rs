@@ -0,0 +1,3 @@
+mod browser;
+
+pub use self::browser::{is_rom_path, scan_dir, RecentRoms, RomBrowser, RomEntry};
@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Error, Write};
+use std::path::Path;
+
+use crate::cpu::{HEIGHT, WIDTH};
+use crate::display::Palette;
+
+// An RGBA8 image, row-major with no padding between rows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+pub fn vram_to_rgba(vram: &[[u8; WIDTH]; HEIGHT], palette: &Palette, scale: u32) -> Image {
+    let scale = scale.max(1) as usize;
+    let width = WIDTH * scale;
+    let height = HEIGHT * scale;
+    let mut pixels = Vec::with_capacity(width * height * 4);
+
+    for row in vram.iter() {
+        for _ in 0..scale {
+            for &pix in row.iter() {
+                let [r, g, b] = palette.color(pix);
+                for _ in 0..scale {
+                    pixels.extend_from_slice(&[r, g, b, 0xFF]);
+                }
+            }
+        }
+    }
+
+    Image { width, height, pixels }
+}
+
+// The screen as text, one line per row: '#' for lit pixels, '.' otherwise.
+// Used to check headless runs against a known-good screen.
+pub fn vram_to_text(vram: &[[u8; WIDTH]; HEIGHT]) -> String {
+    let mut text = String::with_capacity((WIDTH + 1) * HEIGHT);
+    for row in vram.iter() {
+        text.extend(row.iter().map(|&pix| if pix != 0 { '#' } else { '.' }));
+        text.push('\n');
+    }
+    text
+}
+
+impl Image {
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * self.width + x) * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn write_png<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(Error::other)?;
+        writer.write_image_data(&self.pixels).map_err(Error::other)?;
+        writer.finish().map_err(Error::other)
+    }
+
+    pub fn write_ppm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for rgba in self.pixels.chunks(4) {
+            out.write_all(&rgba[..3])?;
+        }
+        Ok(())
+    }
+
+    // PBM is 1-bit, where 1 is black; pixels darker than mid-grey become 1.
+    pub fn write_pbm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "P4\n{} {}\n", self.width, self.height)?;
+        let mut row = vec![0u8; self.width.div_ceil(8)];
+        for y in 0..self.height {
+            for byte in row.iter_mut() { *byte = 0; }
+            for x in 0..self.width {
+                let [r, g, b, _] = self.pixel(x, y);
+                let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+                if luma < 128 {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            out.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    // Picks the format from the extension: .pbm, .ppm, otherwise PNG.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let ext = Path::new(path).extension()
+                                 .and_then(|ext| ext.to_str())
+                                 .map(|ext| ext.to_ascii_lowercase());
+        let mut out = BufWriter::new(File::create(path)?);
+        match ext.as_deref() {
+            Some("pbm") => self.write_pbm(&mut out)?,
+            Some("ppm") => self.write_ppm(&mut out)?,
+            _ => self.write_png(&mut out)?,
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_vram() -> [[u8; WIDTH]; HEIGHT] {
+        let mut vram = [[0; WIDTH]; HEIGHT];
+        vram[0][0] = 1;
+        vram[HEIGHT - 1][WIDTH - 1] = 1;
+        vram
+    }
+
+    #[test]
+    fn image_vram_to_rgba() {
+        let palette = Palette::new([1, 2, 3], [4, 5, 6]);
+        let image = vram_to_rgba(&test_vram(), &palette, 2);
+        assert_eq!(image.width, WIDTH * 2);
+        assert_eq!(image.height, HEIGHT * 2);
+        assert_eq!(image.pixels.len(), WIDTH * HEIGHT * 16);
+        assert_eq!(image.pixel(0, 0), [4, 5, 6, 0xFF]);
+        assert_eq!(image.pixel(1, 1), [4, 5, 6, 0xFF]);
+        assert_eq!(image.pixel(2, 0), [1, 2, 3, 0xFF]);
+        assert_eq!(image.pixel(WIDTH * 2 - 1, HEIGHT * 2 - 1), [4, 5, 6, 0xFF]);
+    }
+
+    #[test]
+    fn image_vram_to_text() {
+        let text = vram_to_text(&test_vram());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), HEIGHT);
+        assert!(lines[0].starts_with("#."));
+        assert!(lines[HEIGHT - 1].ends_with(".#"));
+    }
+
+    #[test]
+    fn image_pbm() {
+        let image = vram_to_rgba(&test_vram(), &Palette::default(), 1);
+        let mut out = Vec::new();
+        image.write_pbm(&mut out).unwrap();
+
+        let header = b"P4\n64 32\n";
+        assert_eq!(&out[..header.len()], header);
+        let data = &out[header.len()..];
+        assert_eq!(data.len(), 8 * HEIGHT);
+        // Default palette draws lit pixels white, so everything else is black.
+        assert_eq!(data[0], 0x7F);
+        assert_eq!(data[1], 0xFF);
+        assert_eq!(data[data.len() - 1], 0xFE);
+    }
+
+    #[test]
+    fn image_ppm() {
+        let image = vram_to_rgba(&test_vram(), &Palette::default(), 1);
+        let mut out = Vec::new();
+        image.write_ppm(&mut out).unwrap();
+
+        let header = b"P6\n64 32\n255\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(out.len(), header.len() + WIDTH * HEIGHT * 3);
+        assert_eq!(&out[header.len()..header.len() + 6], &[255, 255, 255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn image_png_signature() {
+        let image = vram_to_rgba(&test_vram(), &Palette::default(), 1);
+        let mut out = Vec::new();
+        image.write_png(&mut out).unwrap();
+        assert_eq!(&out[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+    }
+}
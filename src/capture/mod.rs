@@ -0,0 +1,5 @@
+mod image;
+mod recorder;
+
+pub use self::image::{vram_to_rgba, vram_to_text, Image};
+pub use self::recorder::{RecordFormat, Recorder};
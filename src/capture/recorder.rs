@@ -0,0 +1,229 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::{HEIGHT, WIDTH};
+use crate::display::Palette;
+use super::image::vram_to_rgba;
+
+const FPS: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    Gif,
+    Ppm,
+    Png,
+}
+
+impl RecordFormat {
+    // A `.gif` path records a single animation; anything else is treated as
+    // a directory of numbered PNG frames.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => RecordFormat::Gif,
+            _ => RecordFormat::Png,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gif" => Some(RecordFormat::Gif),
+            "ppm" => Some(RecordFormat::Ppm),
+            "png" => Some(RecordFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+enum Output {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        pending: Option<Vec<u8>>,
+        pending_frames: usize,
+        size: (u16, u16),
+    },
+    Frames {
+        dir: PathBuf,
+        extension: &'static str,
+    },
+}
+
+// Captures every emulated frame at a fixed 60Hz. GIF output merges runs of
+// identical frames into one longer frame to keep the file small.
+pub struct Recorder {
+    output: Output,
+    format: RecordFormat,
+    scale: usize,
+    palette: Palette,
+    frames: usize,
+}
+
+impl Recorder {
+    pub fn start(path: &str, format: RecordFormat, scale: u32, palette: Palette) -> io::Result<Self> {
+        let scale = scale.max(1) as usize;
+        let output = match format {
+            RecordFormat::Gif => {
+                let size = match (u16::try_from(WIDTH * scale), u16::try_from(HEIGHT * scale)) {
+                    (Ok(width), Ok(height)) => (width, height),
+                    _ => return Err(Error::other(format!("frames at scale {} are too big for a GIF", scale))),
+                };
+                let file = BufWriter::new(File::create(path)?);
+                let colors: Vec<u8> = palette.background.iter()
+                                             .chain(palette.foreground.iter())
+                                             .cloned()
+                                             .collect();
+                let mut encoder = gif::Encoder::new(file, size.0, size.1, &colors).map_err(gif_error)?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(gif_error)?;
+                Output::Gif { encoder, pending: None, pending_frames: 0, size }
+            },
+            RecordFormat::Ppm | RecordFormat::Png => {
+                fs::create_dir_all(path)?;
+                Output::Frames {
+                    dir: PathBuf::from(path),
+                    extension: if format == RecordFormat::Ppm { "ppm" } else { "png" },
+                }
+            },
+        };
+
+        Ok(Recorder { output, format, scale, palette, frames: 0 })
+    }
+
+    pub fn format(&self) -> RecordFormat { self.format }
+    pub fn frames(&self) -> usize { self.frames }
+
+    pub fn frame(&mut self, vram: &[[u8; WIDTH]; HEIGHT]) -> io::Result<()> {
+        let frame = self.frames;
+        self.frames += 1;
+
+        match self.output {
+            Output::Gif { ref mut encoder, ref mut pending, ref mut pending_frames, size } => {
+                let indices = scale_indices(vram, self.scale);
+                if pending.as_ref() == Some(&indices) {
+                    *pending_frames += 1;
+                    return Ok(());
+                }
+                if let Some(previous) = pending.take() {
+                    write_gif_frame(encoder, previous, *pending_frames, frame, size)?;
+                }
+                *pending = Some(indices);
+                *pending_frames = 1;
+                Ok(())
+            },
+            Output::Frames { ref dir, extension } => {
+                let image = vram_to_rgba(vram, &self.palette, self.scale as u32);
+                let path = dir.join(format!("frame_{:06}.{}", frame, extension));
+                let mut out = BufWriter::new(File::create(path)?);
+                if extension == "ppm" {
+                    image.write_ppm(&mut out)?;
+                } else {
+                    image.write_png(&mut out)?;
+                }
+                out.flush()
+            },
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        if let Output::Gif { mut encoder, pending, pending_frames, size } = self.output {
+            if let Some(previous) = pending {
+                write_gif_frame(&mut encoder, previous, pending_frames, self.frames, size)?;
+            }
+            encoder.into_inner()?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn scale_indices(vram: &[[u8; WIDTH]; HEIGHT], scale: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(WIDTH * HEIGHT * scale * scale);
+    for row in vram.iter() {
+        for _ in 0..scale {
+            for &pix in row.iter() {
+                for _ in 0..scale {
+                    out.push(if pix == 0 { 0 } else { 1 });
+                }
+            }
+        }
+    }
+    out
+}
+
+// GIF delays are in hundredths of a second, so 60Hz frames can't be
+// represented exactly. Deriving each delay from the absolute frame count
+// keeps the animation's total length from drifting. A delay too long for
+// one frame is spread over several copies of it.
+fn write_gif_frame<W: Write>(encoder: &mut gif::Encoder<W>,
+                             indices: Vec<u8>,
+                             frames: usize,
+                             end_frame: usize,
+                             (width, height): (u16, u16)) -> io::Result<()> {
+    let start_frame = end_frame - frames;
+    let mut delay = ((end_frame * 100 / FPS) - (start_frame * 100 / FPS)).max(1);
+
+    while delay > 0 {
+        let part = delay.min(u16::MAX as usize);
+        let frame = gif::Frame {
+            width,
+            height,
+            delay: part as u16,
+            buffer: Cow::Borrowed(&indices),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).map_err(gif_error)?;
+        delay -= part;
+    }
+    Ok(())
+}
+
+fn gif_error(e: gif::EncodingError) -> Error {
+    Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_format_from_path() {
+        assert_eq!(RecordFormat::from_path("clip.gif"), RecordFormat::Gif);
+        assert_eq!(RecordFormat::from_path("clip.GIF"), RecordFormat::Gif);
+        assert_eq!(RecordFormat::from_path("frames"), RecordFormat::Png);
+    }
+
+    #[test]
+    fn recorder_scale_indices() {
+        let mut vram = [[0; WIDTH]; HEIGHT];
+        vram[0][1] = 1;
+        let indices = scale_indices(&vram, 2);
+        assert_eq!(indices.len(), WIDTH * HEIGHT * 4);
+        assert_eq!(&indices[0..4], &[0, 0, 1, 1]);
+        assert_eq!(&indices[WIDTH * 2..WIDTH * 2 + 4], &[0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn recorder_refuses_oversized_gifs() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.gif", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(Recorder::start(path, RecordFormat::Gif, 2000, Palette::default()).is_err());
+        assert!(!Path::new(path).exists());
+    }
+
+    #[test]
+    fn recorder_splits_long_delays() {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 1, 1, &[0, 0, 0, 255, 255, 255]).unwrap();
+            // Twenty minutes without a change is 120000 hundredths.
+            let frames = 20 * 60 * FPS;
+            write_gif_frame(&mut encoder, vec![1], frames, frames, (1, 1)).unwrap();
+        }
+        let mut decoder = gif::DecodeOptions::new().read_info(gif.as_slice()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays.iter().map(|&delay| delay as usize).collect::<Vec<_>>(), [65535, 120000 - 65535]);
+    }
+}
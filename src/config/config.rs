@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{TONE, VOLUME};
+use crate::cpu::{Platform, Quirks};
+use crate::database::{sha1_hex, RomInfo};
+use crate::display::{Palette, SCALE};
+use crate::input::{Hotkey, DEFAULT_KEYMAP};
+
+// Speed for ROMs nothing else has an opinion about.
+pub const DEFAULT_SPEED: u32 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaletteLayer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub foreground: Option<String>,
+}
+
+impl PaletteLayer {
+    fn is_empty(&self) -> bool { *self == PaletteLayer::default() }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioLayer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tone: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f32>,
+}
+
+impl AudioLayer {
+    fn is_empty(&self) -> bool { *self == AudioLayer::default() }
+}
+
+// One set of overrides: the top of the config file, a [rom."..."] section
+// or the command line. Anything left out falls through to the layer below.
+// Plain values have to come before the tables for TOML output.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    // Instructions per frame.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<u32>,
+    // Quirk and speed preset, by chip-8-database platform id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fullscreen: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub quirks: BTreeMap<String, bool>,
+    #[serde(skip_serializing_if = "PaletteLayer::is_empty")]
+    pub palette: PaletteLayer,
+    #[serde(skip_serializing_if = "AudioLayer::is_empty")]
+    pub audio: AudioLayer,
+    // SDL key name to keypad key.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub keymap: BTreeMap<String, u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    pub base: ConfigLayer,
+    // Keyed by file name, file stem or SHA-1.
+    pub roms: BTreeMap<String, ConfigLayer>,
+}
+
+impl ConfigFile {
+    // $XDG_CONFIG_HOME/chip8/config.toml, or the platform's equivalent.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("chip8").join("config.toml"))
+    }
+
+    // A missing file is an empty config, not an error.
+    pub fn load(path: &Path) -> Result<ConfigFile, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => ConfigFile::parse(&text),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(ConfigFile::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Every layer is checked up front so mistakes show at startup rather
+    // than when the matching ROM is eventually loaded.
+    pub fn parse(text: &str) -> Result<ConfigFile, String> {
+        let mut table: toml::value::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+        let roms: BTreeMap<String, ConfigLayer> = match table.remove("rom") {
+            Some(roms) => roms.try_into().map_err(|e| format!("[rom]: {}", e))?,
+            None => BTreeMap::new(),
+        };
+        let base: ConfigLayer = toml::Value::Table(table).try_into().map_err(|e| e.to_string())?;
+
+        Config::default().apply(&base)?;
+        for (name, layer) in &roms {
+            Config::default().apply(layer).map_err(|e| format!("[rom.{:?}]: {}", name, e))?;
+        }
+        Ok(ConfigFile { base, roms })
+    }
+
+    pub fn rom_layer(&self, path: &Path, sha1: &str) -> Option<&ConfigLayer> {
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string());
+        let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string());
+        self.roms.iter()
+                 .find(|(key, _)| {
+                     key.eq_ignore_ascii_case(sha1)
+                         || name.as_deref().map(|name| key.eq_ignore_ascii_case(name)).unwrap_or(false)
+                         || stem.as_deref().map(|stem| key.eq_ignore_ascii_case(stem)).unwrap_or(false)
+                 })
+                 .map(|(_, layer)| layer)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioConfig {
+    pub enabled: bool,
+    pub tone: f32,
+    pub volume: f32,
+}
+
+// The effective settings for one ROM after every layer is applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub speed: u32,
+    pub platform: Option<Platform>,
+    pub quirks: Quirks,
+    pub palette: Palette,
+    pub scale: u32,
+    pub fullscreen: bool,
+    pub audio: AudioConfig,
+    pub keymap: BTreeMap<String, u8>,
+    // Arrow/action key bindings from the ROM database.
+    pub game_keys: HashMap<String, u8>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            speed: DEFAULT_SPEED,
+            platform: None,
+            quirks: Quirks::default(),
+            palette: Palette::default(),
+            scale: SCALE,
+            fullscreen: false,
+            audio: AudioConfig { enabled: true, tone: TONE, volume: VOLUME },
+            keymap: DEFAULT_KEYMAP.iter().map(|&(name, key)| (name.to_string(), key)).collect(),
+            game_keys: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    // Lowest to highest priority: built-in defaults, the config file, the
+    // ROM's database or analysis profile, the file's section for the ROM,
+    // then the command line.
+    pub fn resolve(file: &ConfigFile,
+                   rom: Option<(&Path, &[u8])>,
+                   profile: Option<&RomInfo>,
+                   cli: &ConfigLayer) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.apply(&file.base)?;
+        if let Some(profile) = profile {
+            config.apply_profile(profile);
+        }
+        if let Some((path, bytes)) = rom {
+            if let Some(layer) = file.rom_layer(path, &sha1_hex(bytes)) {
+                config.apply(layer)?;
+            }
+        }
+        config.apply(cli)?;
+        Ok(config)
+    }
+
+    // A platform resets quirks and speed to its own before the layer's
+    // explicit values are applied on top.
+    pub fn apply(&mut self, layer: &ConfigLayer) -> Result<(), String> {
+        if let Some(ref id) = layer.platform {
+            let platform = Platform::from_id(id).ok_or_else(|| format!("unknown platform {:?}", id))?;
+            self.platform = Some(platform);
+            self.quirks = platform.quirks();
+            self.speed = platform.tickrate();
+        }
+        if let Some(speed) = layer.speed {
+            if speed == 0 {
+                return Err("speed must be at least 1".to_string());
+            }
+            self.speed = speed;
+        }
+        for (name, &value) in &layer.quirks {
+            if !self.quirks.set(name, value) {
+                return Err(format!("unknown quirk {:?}", name));
+            }
+        }
+
+        let color = |text: &str| Palette::parse_color(text).ok_or_else(|| format!("bad colour {:?}", text));
+        if let Some(ref background) = layer.palette.background {
+            self.palette.background = color(background)?;
+        }
+        if let Some(ref foreground) = layer.palette.foreground {
+            self.palette.foreground = color(foreground)?;
+        }
+
+        if let Some(scale) = layer.scale {
+            if scale == 0 {
+                return Err("scale must be at least 1".to_string());
+            }
+            self.scale = scale;
+        }
+        if let Some(fullscreen) = layer.fullscreen {
+            self.fullscreen = fullscreen;
+        }
+
+        if let Some(enabled) = layer.audio.enabled {
+            self.audio.enabled = enabled;
+        }
+        if let Some(tone) = layer.audio.tone {
+            if tone <= 0.0 {
+                return Err(format!("tone {} must be above 0 Hz", tone));
+            }
+            self.audio.tone = tone;
+        }
+        if let Some(volume) = layer.audio.volume {
+            if !(0.0..=1.0).contains(&volume) {
+                return Err(format!("volume {} must be between 0.0 and 1.0", volume));
+            }
+            self.audio.volume = volume;
+        }
+
+        for (name, &key) in &layer.keymap {
+            if key >= 16 {
+                return Err(format!("key {:?} mapped to {:#X}, past the keypad", name, key));
+            }
+            if let Some(hotkey) = Hotkey::from_key_name(name) {
+                return Err(format!("key {:?} is already the {:?} hotkey", name, hotkey));
+            }
+            self.keymap.insert(name.clone(), key);
+        }
+        Ok(())
+    }
+
+    pub fn apply_profile(&mut self, profile: &RomInfo) {
+        if profile.platform.is_some() {
+            self.platform = profile.platform;
+        }
+        self.quirks = profile.quirks;
+        if let Some(tickrate) = profile.tickrate {
+            self.speed = tickrate;
+        }
+        if let Some(palette) = profile.palette {
+            self.palette = palette;
+        }
+        self.game_keys = profile.keys.clone();
+    }
+
+    // Every setting spelled out, in config file syntax.
+    pub fn to_layer(&self) -> ConfigLayer {
+        let hex = |[r, g, b]: [u8; 3]| format!("#{:02X}{:02X}{:02X}", r, g, b);
+        ConfigLayer {
+            speed: Some(self.speed),
+            platform: self.platform.map(|platform| platform.id().to_string()),
+            scale: Some(self.scale),
+            fullscreen: Some(self.fullscreen),
+            quirks: Quirks::NAMES.iter()
+                                 .map(|&name| (name.to_string(), self.quirks.get(name).unwrap()))
+                                 .collect(),
+            palette: PaletteLayer {
+                background: Some(hex(self.palette.background)),
+                foreground: Some(hex(self.palette.foreground)),
+            },
+            audio: AudioLayer {
+                enabled: Some(self.audio.enabled),
+                tone: Some(self.audio.tone),
+                volume: Some(self.audio.volume),
+            },
+            keymap: self.keymap.clone(),
+        }
+    }
+
+    pub fn show(&self) -> String {
+        toml::to_string(&self.to_layer()).expect("config is always representable as TOML")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r##"
+        speed = 12
+        scale = 8
+
+        [quirks]
+        vblank = true
+
+        [palette]
+        foreground = "#33FF66"
+
+        [keymap]
+        Up = 5
+
+        [rom.PONG]
+        speed = 7
+
+        [rom."a9993e364706816aba3e25717850c26c9cd0d89d"]
+        platform = "chip48"
+    "##;
+
+    #[test]
+    fn config_defaults() {
+        let config = Config::resolve(&ConfigFile::default(), None, None, &ConfigLayer::default()).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.keymap["Q"], 0x4);
+    }
+
+    #[test]
+    fn config_file_layer() {
+        let file = ConfigFile::parse(FILE).unwrap();
+        let config = Config::resolve(&file, None, None, &ConfigLayer::default()).unwrap();
+        assert_eq!(config.speed, 12);
+        assert_eq!(config.scale, 8);
+        assert!(config.quirks.vblank);
+        assert_eq!(config.palette.foreground, [0x33, 0xFF, 0x66]);
+        assert_eq!(config.keymap["Up"], 5);
+        assert_eq!(config.keymap["W"], 5);
+    }
+
+    #[test]
+    fn config_rom_sections() {
+        let file = ConfigFile::parse(FILE).unwrap();
+        let pong = Config::resolve(&file, Some((Path::new("roms/pong.ch8"), b"x")), None,
+                                   &ConfigLayer::default()).unwrap();
+        assert_eq!(pong.speed, 7);
+
+        let by_hash = Config::resolve(&file, Some((Path::new("other"), b"abc")), None,
+                                      &ConfigLayer::default()).unwrap();
+        assert_eq!(by_hash.platform, Some(Platform::Chip48));
+        assert_eq!(by_hash.quirks, Platform::Chip48.quirks());
+        assert_eq!(by_hash.speed, 30);
+    }
+
+    #[test]
+    fn config_precedence() {
+        let file = ConfigFile::parse(FILE).unwrap();
+        let profile = RomInfo {
+            sha1: String::new(),
+            title: "Pong".to_string(),
+            description: None,
+            platform: Some(Platform::OriginalChip8),
+            quirks: Platform::OriginalChip8.quirks(),
+            tickrate: Some(15),
+            palette: None,
+            keys: HashMap::new(),
+        };
+        let rom = Some((Path::new("PONG"), &b"x"[..]));
+
+        let config = Config::resolve(&file, rom, Some(&profile), &ConfigLayer::default()).unwrap();
+        assert_eq!(config.speed, 7);
+        assert_eq!(config.quirks, Platform::OriginalChip8.quirks());
+
+        let cli = ConfigLayer { speed: Some(3), ..ConfigLayer::default() };
+        let config = Config::resolve(&file, rom, Some(&profile), &cli).unwrap();
+        assert_eq!(config.speed, 3);
+    }
+
+    #[test]
+    fn config_errors() {
+        assert!(ConfigFile::parse("sped = 3").is_err());
+        assert!(ConfigFile::parse("[quirks]\nbogus = true").is_err());
+        assert!(ConfigFile::parse("[palette]\nbackground = \"red\"").is_err());
+        assert!(ConfigFile::parse("[rom.X]\nplatform = \"nes\"").is_err());
+        assert!(ConfigFile::parse("[keymap]\nQ = 16").is_err());
+        assert_eq!(ConfigFile::parse("[keymap]\ntab = 1").unwrap_err(), "key \"tab\" is already the FastForward hotkey");
+        assert!(ConfigFile::parse("[keymap]\n\"=\" = 1").is_err());
+    }
+
+    #[test]
+    fn config_show_round_trips() {
+        let file = ConfigFile::parse(FILE).unwrap();
+        let config = Config::resolve(&file, None, None, &ConfigLayer::default()).unwrap();
+        let shown = ConfigFile::parse(&config.show()).unwrap();
+        let again = Config::resolve(&shown, None, None, &ConfigLayer::default()).unwrap();
+        assert_eq!(again, config);
+    }
+}
@@ -0,0 +1,149 @@
+// Instructions-per-frame steps offered by the speed hotkeys.
+const SPEED_STEPS: [u32; 13] = [1, 2, 3, 5, 8, 10, 12, 15, 20, 30, 50, 100, 200];
+const FAST_FORWARD_FRAMES: u32 = 4;
+
+// Frontend run state driven by the hotkeys: whether the emulator is
+// paused, how many instructions it runs per frame, and how many emulated
+// frames to run for each host frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunControl {
+    paused: bool,
+    advance: bool,
+    fast_forward: bool,
+    ipf: u32,
+    fps: u32,
+}
+
+impl RunControl {
+    pub fn new(ipf: u32, fps: u32) -> Self {
+        RunControl {
+            paused: false,
+            advance: false,
+            fast_forward: false,
+            ipf: ipf.max(1),
+            fps,
+        }
+    }
+
+    pub fn paused(&self) -> bool { self.paused }
+    pub fn fast_forward(&self) -> bool { self.fast_forward }
+    pub fn ipf(&self) -> u32 { self.ipf }
+    pub fn ips(&self) -> u32 { self.ipf * self.fps }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance = false;
+    }
+
+    // Queues a single frame to run; only meaningful while paused.
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance = true;
+        }
+    }
+
+    pub fn set_fast_forward(&mut self, held: bool) { self.fast_forward = held; }
+
+    pub fn speed_up(&mut self) {
+        if let Some(&next) = SPEED_STEPS.iter().find(|&&step| step > self.ipf) {
+            self.ipf = next;
+        }
+    }
+
+    pub fn speed_down(&mut self) {
+        if let Some(&next) = SPEED_STEPS.iter().rev().find(|&&step| step < self.ipf) {
+            self.ipf = next;
+        }
+    }
+
+    // Emulated frames to run during this host frame.
+    pub fn frames_to_run(&mut self) -> u32 {
+        if self.paused {
+            if self.advance {
+                self.advance = false;
+                1
+            } else {
+                0
+            }
+        } else if self.fast_forward {
+            FAST_FORWARD_FRAMES
+        } else {
+            1
+        }
+    }
+
+    pub fn title(&self) -> String {
+        let state = if self.paused {
+            "Paused".to_string()
+        } else if self.fast_forward {
+            format!("Fast forward x{}", FAST_FORWARD_FRAMES)
+        } else {
+            "Running".to_string()
+        };
+        format!("CHIP-8 - {} - {} IPS", state, self.ips())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_running() {
+        let mut control = RunControl::new(1, 60);
+        assert_eq!(control.frames_to_run(), 1);
+        assert_eq!(control.frames_to_run(), 1);
+    }
+
+    #[test]
+    fn control_pause_and_advance() {
+        let mut control = RunControl::new(1, 60);
+        control.toggle_pause();
+        assert_eq!(control.frames_to_run(), 0);
+        control.advance_frame();
+        assert_eq!(control.frames_to_run(), 1);
+        assert_eq!(control.frames_to_run(), 0);
+        control.toggle_pause();
+        assert_eq!(control.frames_to_run(), 1);
+    }
+
+    #[test]
+    fn control_advance_ignored_while_running() {
+        let mut control = RunControl::new(1, 60);
+        control.advance_frame();
+        control.toggle_pause();
+        assert_eq!(control.frames_to_run(), 0);
+    }
+
+    #[test]
+    fn control_fast_forward() {
+        let mut control = RunControl::new(1, 60);
+        control.set_fast_forward(true);
+        assert_eq!(control.frames_to_run(), FAST_FORWARD_FRAMES);
+        control.set_fast_forward(false);
+        assert_eq!(control.frames_to_run(), 1);
+    }
+
+    #[test]
+    fn control_speed_steps() {
+        let mut control = RunControl::new(4, 60);
+        control.speed_up();
+        assert_eq!(control.ipf(), 5);
+        control.speed_down();
+        control.speed_down();
+        assert_eq!(control.ipf(), 2);
+        for _ in 0..10 { control.speed_down(); }
+        assert_eq!(control.ipf(), 1);
+        for _ in 0..20 { control.speed_up(); }
+        assert_eq!(control.ipf(), 200);
+        assert_eq!(control.ips(), 12000);
+    }
+
+    #[test]
+    fn control_title() {
+        let mut control = RunControl::new(10, 60);
+        assert_eq!(control.title(), "CHIP-8 - Running - 600 IPS");
+        control.toggle_pause();
+        assert_eq!(control.title(), "CHIP-8 - Paused - 600 IPS");
+    }
+}
@@ -1,15 +1,21 @@
-use std::fs::File;
-use std::io::{Read, Result};
+use std::fs;
 use std::fmt::{Display, Formatter, Result as fmtResult};
 
 extern crate rand;
 use rand::{SeedableRng, RngCore, Rng};
 extern crate rand_pcg;
-
-const RAM: usize = 4096;
-const HEIGHT: usize = 32;
-const WIDTH: usize = 64;
-const PROG_START: usize = 0x200;
+use rand_pcg::Pcg32;
+
+use super::quirks::Quirks;
+use crate::profile::Profiler;
+use crate::trace::{TraceRecord, Tracer};
+
+pub const RAM: usize = 4096;
+pub const HEIGHT: usize = 32;
+pub const WIDTH: usize = 64;
+pub const PROG_START: usize = 0x200;
+// Program bytes that fit between PROG_START and the end of RAM.
+pub const MAX_ROM_SIZE: usize = RAM - PROG_START;
 const FONT: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0,
     0x20, 0x60, 0x20, 0x20, 0x70,
@@ -85,6 +91,32 @@ impl From<u16> for Opcode {
     }
 }
 
+// The registers as a debugger shows and edits them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub sp: usize,
+    pub dt: u8,
+    pub st: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A program's read or write of one byte of RAM, with the value read or
+// written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: usize,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
 pub struct Chip8Cpu {
     reg_v: [u8; 16],
     reg_i: usize,
@@ -97,6 +129,21 @@ pub struct Chip8Cpu {
     pub vram: [[u8; WIDTH]; HEIGHT],
     pub vram_update: bool,
     pub beep: bool,
+    quirks: Quirks,
+    // Set by a draw when the vblank quirk is on; ends the current frame.
+    vblank_wait: bool,
+    // Instructions executed since the CPU was created.
+    cycles: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    // Set when the program does something the machine can't, like
+    // returning with an empty stack. The CPU stops until it's reset.
+    fault: Option<String>,
+    // What the last instruction read and wrote, for watchpoints and the
+    // like. Instruction fetches don't count.
+    accesses: Vec<MemoryAccess>,
+    // CXNN draws from this, so a seeded CPU runs the same way every time.
+    rng: Pcg32,
 }
 
 impl Chip8Cpu {
@@ -117,40 +164,225 @@ impl Chip8Cpu {
             ram: ram,
             vram: [[0; WIDTH]; HEIGHT],
             vram_update: false,
-            beep: false
+            beep: false,
+            quirks: Quirks::default(),
+            vblank_wait: false,
+            cycles: 0,
+            tracer: None,
+            profiler: None,
+            fault: None,
+            accesses: Vec::new(),
+            rng: Pcg32::seed_from_u64(rand::thread_rng().gen()),
         }
     }
 
-    pub fn load_rom(&mut self, path: &str) {
-        if let Ok(rom) = self.read_rom(path) {
-            let rom_data: &[u8] = &rom;
-            for (i, &data) in rom_data.iter().enumerate() {
-                let mem_addr = PROG_START + i;
-                if mem_addr < 4096 {
-                    self.ram[mem_addr] = data;
-                }
-            }
-        } else {
-            println!("COULD NOT LOAD ROM {}", path);
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Pcg32::seed_from_u64(seed);
+    }
+
+    // Everything needed to carry on from this point later, random number
+    // generator included. The copy has no tracer or profiler.
+    pub fn snapshot(&self) -> Chip8Cpu {
+        Chip8Cpu {
+            reg_v: self.reg_v,
+            reg_i: self.reg_i,
+            reg_d: self.reg_d,
+            reg_s: self.reg_s,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            ram: self.ram,
+            vram: self.vram,
+            vram_update: self.vram_update,
+            beep: self.beep,
+            quirks: self.quirks,
+            vblank_wait: self.vblank_wait,
+            cycles: self.cycles,
+            tracer: None,
+            profiler: None,
+            fault: self.fault.clone(),
+            accesses: Vec::new(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    // Goes back to a snapshot, keeping this CPU's tracer and profiler. The
+    // screen is redrawn, since it has most likely changed.
+    pub fn restore(&mut self, snapshot: &Chip8Cpu) {
+        let (tracer, profiler) = (self.tracer.take(), self.profiler.take());
+        *self = snapshot.snapshot();
+        self.tracer = tracer;
+        self.profiler = profiler;
+        self.vram_update = true;
+    }
+
+    pub fn quirks(&self) -> Quirks { self.quirks }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn cycles(&self) -> u64 { self.cycles }
+
+    pub fn fault(&self) -> Option<&str> { self.fault.as_deref() }
+
+    pub fn pc(&self) -> usize { self.pc }
+
+    // True once a draw has ended the frame under the vblank quirk.
+    pub fn waiting_for_vblank(&self) -> bool { self.vblank_wait }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            v: self.reg_v,
+            i: self.reg_i,
+            pc: self.pc,
+            sp: self.sp,
+            dt: self.reg_d,
+            st: self.reg_s,
+        }
+    }
+
+    // Return addresses, innermost last.
+    pub fn stack(&self) -> &[usize] { &self.stack[..self.sp] }
+
+    // PC wraps like it does when running, and SP can't point past the stack.
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.reg_v = regs.v;
+        self.reg_i = regs.i;
+        self.pc = regs.pc % RAM;
+        self.sp = regs.sp.min(self.stack.len());
+        self.reg_d = regs.dt;
+        self.reg_s = regs.st;
+    }
+
+    // Memory as a debugger sees it, without counting as a program access.
+    pub fn peek(&self, addr: usize) -> u8 {
+        self.ram[addr % RAM]
+    }
+
+    pub fn poke(&mut self, addr: usize, value: u8) {
+        self.ram[addr % RAM] = value;
+    }
+
+    pub fn accesses(&self) -> &[MemoryAccess] { &self.accesses }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // The state a trace shows for the next instruction, with `memory`
+    // bytes from I.
+    pub fn trace_record(&self, memory: usize) -> TraceRecord {
+        let mem_start = self.reg_i.min(RAM);
+        TraceRecord {
+            cycle: self.cycles,
+            pc: self.pc,
+            opcode: self.fetch_opcode().opcode,
+            v: self.reg_v,
+            i: self.reg_i,
+            sp: self.sp,
+            dt: self.reg_d,
+            st: self.reg_s,
+            mem: self.ram[mem_start..(mem_start + memory).min(RAM)].to_vec(),
         }
     }
 
-    fn read_rom(&self, path: &str) -> Result<Vec<u8>> {
-        let mut file = File::open(path)?;
+    // Reads a ROM file and loads it like load_rom_bytes. Errors name the
+    // file.
+    pub fn load_rom(&mut self, path: &str) -> Result<(), String> {
+        fs::read(path).map_err(|e| e.to_string())
+                      .and_then(|rom| self.load_rom_bytes(&rom))
+                      .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Copies a program to PROG_START. Nothing is loaded if it doesn't fit.
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), String> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(format!("ROM is {} bytes, but at most {} fit in memory", rom.len(), MAX_ROM_SIZE));
+        }
+        self.ram[PROG_START..PROG_START + rom.len()].copy_from_slice(rom);
+        Ok(())
+    }
 
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
+    // Every access the program makes goes through these. Addresses wrap
+    // around the 4K of memory, as on the VIP.
+    fn read(&mut self, addr: usize) -> u8 {
+        let addr = addr % RAM;
+        let value = self.ram[addr];
+        self.accesses.push(MemoryAccess { addr, value, kind: AccessKind::Read });
+        value
+    }
 
-        return Ok(data);
+    fn write(&mut self, addr: usize, value: u8) {
+        let addr = addr % RAM;
+        self.ram[addr] = value;
+        self.accesses.push(MemoryAccess { addr, value, kind: AccessKind::Write });
     }
 
     fn fetch_opcode(&self) -> Opcode {
-        Opcode::new((self.ram[self.pc] as u16) << 8 | (self.ram[self.pc + 1] as u16))
+        Opcode::new((self.peek(self.pc) as u16) << 8 | (self.peek(self.pc + 1) as u16))
+    }
+
+    fn halt(&mut self, reason: &str) {
+        let reason = format!("{} at {:#05X}", reason, self.pc);
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.fault(&reason);
+        }
+        self.fault = Some(reason);
     }
 
     pub fn tick(&mut self, input: [bool; 16]) {
-        self.decode_opcode(self.fetch_opcode(), input);
+        self.step(input);
+        self.tick_timers();
+    }
+
+    // One 60Hz frame: `ipf` instructions followed by a timer tick.
+    pub fn run_frame(&mut self, input: [bool; 16], ipf: u32) {
+        for _ in 0..ipf {
+            self.step(input);
+            if self.vblank_wait || self.fault.is_some() {
+                break;
+            }
+        }
+        self.tick_timers();
+    }
 
+    pub fn step(&mut self, input: [bool; 16]) {
+        if self.fault.is_some() {
+            return;
+        }
+        if let Some(memory) = self.tracer.as_ref().filter(|tracer| tracer.wants(self.pc)).map(Tracer::memory) {
+            let record = self.trace_record(memory);
+            self.tracer.as_mut().unwrap().record(record);
+        }
+        self.accesses.clear();
+        let (pc, op, depth) = (self.pc, self.fetch_opcode(), self.sp);
+        let opcode = op.opcode;
+        self.decode_opcode(op, input);
+        self.pc %= RAM;
+        self.cycles += 1;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, opcode, &self.accesses, (depth, self.sp), self.pc);
+        }
+    }
+
+    // Called once per emulated frame (60Hz). `beep` reports whether the
+    // sound timer was running for this frame, so the frontend can queue
+    // exactly one frame's worth of tone.
+    pub fn tick_timers(&mut self) {
+        self.vblank_wait = false;
         if self.reg_d > 0 { self.reg_d -= 1; }
         if self.reg_s > 0 {
             self.reg_s -= 1;
@@ -161,7 +393,6 @@ impl Chip8Cpu {
     }
 
     fn decode_opcode(&mut self, op: Opcode, input: [bool; 16]) {
-        println!("Opcode: {}", op);
         match op.nibbles {
             (0x0, 0x0, 0xE, 0x0) => self.cls_00E0(),
             (0x0, 0x0, 0xE, 0xE) => self.ret_00EE(),
@@ -198,7 +429,9 @@ impl Chip8Cpu {
             (0xF, _, 0x5, 0x5) => self.ld_i_vx_Fx55(op),
             (0xF, _, 0x6, 0x5) => self.ld_vx_i_Fx65(op),
             _ => {
-                println!("Unimplemented opcode: {}", op);
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.fault(&format!("unimplemented opcode {} at {:#05X}", op, self.pc));
+                }
                 self.pc += 2;
             }
         }
@@ -215,6 +448,9 @@ impl Chip8Cpu {
     }
 
     fn ret_00EE(&mut self) {
+        if self.sp == 0 {
+            return self.halt("return with an empty stack");
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
     }
@@ -224,6 +460,9 @@ impl Chip8Cpu {
     }
 
     fn call_addr_2nnn(&mut self, op: Opcode) {
+        if self.sp == self.stack.len() {
+            return self.halt("stack overflow");
+        }
         self.stack[self.sp] = self.pc + 2;
         self.sp += 1;
         self.pc = op.nnn;
@@ -259,49 +498,63 @@ impl Chip8Cpu {
 
     fn or_vx_vy_8xy1(&mut self, op: Opcode) {
         self.reg_v[op.x] |= self.reg_v[op.y];
+        if self.quirks.logic { self.reg_v[0xF] = 0; }
         self.pc += 2;
     }
 
     fn and_vx_vy_8xy2(&mut self, op: Opcode) {
         self.reg_v[op.x] &= self.reg_v[op.y];
+        if self.quirks.logic { self.reg_v[0xF] = 0; }
         self.pc += 2;
     }
 
     fn xor_vx_vy_8xy3(&mut self, op: Opcode) {
         self.reg_v[op.x] ^= self.reg_v[op.y];
+        if self.quirks.logic { self.reg_v[0xF] = 0; }
         self.pc += 2;
     }
 
+    // Like the shifts, the arithmetic writes VF last, so the flag wins
+    // when X is F.
     fn add_vx_vy_8xy4(&mut self, op: Opcode) {
         let tmp = (self.reg_v[op.x] as u16).wrapping_add(self.reg_v[op.y] as u16);
-        self.reg_v[0xF] = if tmp > 255 { 1 } else { 0 };
         self.reg_v[op.x] = tmp as u8;
+        self.reg_v[0xF] = if tmp > 255 { 1 } else { 0 };
         self.pc += 2;
     }
 
+    // VF is 1 when there's no borrow, which includes VX == VY.
     fn sub_vx_vy_8xy5(&mut self, op: Opcode) {
-        self.reg_v[0xF] = if self.reg_v[op.x] > self.reg_v[op.y] { 1 } else { 0 };
+        let flag = if self.reg_v[op.x] >= self.reg_v[op.y] { 1 } else { 0 };
         let tmp = (self.reg_v[op.x] as u16).wrapping_sub(self.reg_v[op.y] as u16);
         self.reg_v[op.x] = tmp as u8;
+        self.reg_v[0xF] = flag;
         self.pc += 2;
     }
 
+    // The shifts write VF after the result, so the flag wins when X is F,
+    // as on the VIP and SCHIP. This interpreter used to write it first.
     fn shr_vx_8xy6(&mut self, op: Opcode) {
-        self.reg_v[0xF] = self.reg_v[op.x] & 0x1;
+        if !self.quirks.shift { self.reg_v[op.x] = self.reg_v[op.y]; }
+        let flag = self.reg_v[op.x] & 0x1;
         self.reg_v[op.x] >>= 1;
+        self.reg_v[0xF] = flag;
         self.pc += 2;
     }
 
     fn subn_vx_vy_8xy7(&mut self, op: Opcode) {
-        self.reg_v[0xF] = if self.reg_v[op.y] > self.reg_v[op.x] { 1 } else { 0 };
+        let flag = if self.reg_v[op.y] >= self.reg_v[op.x] { 1 } else { 0 };
         let tmp = (self.reg_v[op.y] as u16).wrapping_sub(self.reg_v[op.x] as u16);
         self.reg_v[op.x] = tmp as u8;
+        self.reg_v[0xF] = flag;
         self.pc += 2;
     }
 
     fn shl_vx_8xyE(&mut self, op: Opcode) {
-        self.reg_v[0xF] = (self.reg_v[op.x] & 0x80) >> 7;
+        if !self.quirks.shift { self.reg_v[op.x] = self.reg_v[op.y]; }
+        let flag = (self.reg_v[op.x] & 0x80) >> 7;
         self.reg_v[op.x] <<= 1;
+        self.reg_v[0xF] = flag;
         self.pc += 2;
     }
 
@@ -315,35 +568,45 @@ impl Chip8Cpu {
     }
 
     fn jp_v0_addr_Bnnn(&mut self, op: Opcode) {
-        self.pc = op.nnn + (self.reg_v[0] as usize);
+        let offset = if self.quirks.jump { self.reg_v[op.x] } else { self.reg_v[0] };
+        self.pc = op.nnn + (offset as usize);
     }
 
     fn rnd_vx_kk_Cxkk(&mut self, op: Opcode) {
-        let mut rng = rand::thread_rng();
-        let tmp = (rng.next_u32() as u8) & op.kk;
+        let tmp = (self.rng.next_u32() as u8) & op.kk;
         self.reg_v[op.x] = tmp as u8;
         self.pc += 2;
     }
 
     fn drw_vx_vy_n_Dxyn(&mut self, op: Opcode) {
+        // The starting position always wraps; the wrap quirk decides whether
+        // the rest of the sprite follows it around or is clipped. It's read
+        // before VF is cleared, in case X or Y is F.
+        let top = self.reg_v[op.y] as usize % HEIGHT;
+        let left = self.reg_v[op.x] as usize % WIDTH;
         self.reg_v[0xF] = 0;
         for byte in 0..op.n {
-            let y = (self.reg_v[op.y] as usize + byte) % HEIGHT as usize;
+            if !self.quirks.wrap && top + byte >= HEIGHT { break; }
+            let y = (top + byte) % HEIGHT;
+            let sprite = self.read(self.reg_i + byte);
             for bit in 0..8 {
-                let x = (self.reg_v[op.x] as usize + bit) % WIDTH as usize;
-                let color = (self.ram[self.reg_i + byte] >> (7 - bit)) & 1;
+                if !self.quirks.wrap && left + bit >= WIDTH { break; }
+                let x = (left + bit) % WIDTH;
+                let color = (sprite >> (7 - bit)) & 1;
                 self.reg_v[0xF] |= color & self.vram[y][x];
                 self.vram[y][x] ^= color;
             }
         }
         self.vram_update = true;
+        self.vblank_wait = self.quirks.vblank;
         self.pc += 2;
     }
 
     fn skp_vx_Ex9E(&mut self, op: Opcode, input: [bool; 16]) {
         let mut skip: bool = false;
 
-        if input[self.reg_v[op.x] as usize] { skip = true; }
+        // Only the low nibble picks a key.
+        if input[(self.reg_v[op.x] & 0xF) as usize] { skip = true; }
 
         self.pc += if skip { 4 } else { 2 };
 
@@ -352,7 +615,7 @@ impl Chip8Cpu {
     fn sknp_vx_ExA1(&mut self, op: Opcode, input: [bool; 16]) {
         let mut skip: bool = false;
 
-        if !input[self.reg_v[op.x] as usize] { skip = true; }
+        if !input[(self.reg_v[op.x] & 0xF) as usize] { skip = true; }
 
         self.pc += if skip { 4 } else { 2 };
 
@@ -363,12 +626,12 @@ impl Chip8Cpu {
         self.pc += 2;
     }
 
+    // Waits by not moving on until a key is down. With several down, the
+    // lowest wins.
     fn ld_vx_k_Fx0A(&mut self, op: Opcode, input: [bool; 16]) {
-        for i in 0..input.len() {
-            if input[i] {
-                self.reg_v[op.x] = i as u8;
-                self.pc += 2;
-            }
+        if let Some(key) = input.iter().position(|&down| down) {
+            self.reg_v[op.x] = key as u8;
+            self.pc += 2;
         }
     }
 
@@ -388,31 +651,43 @@ impl Chip8Cpu {
         self.pc += 2;
     }
 
+    // Only the low nibble has a glyph.
     fn ld_f_vx_Fx29(&mut self, op: Opcode) {
-        self.reg_i = 5 * (self.reg_v[op.x] as usize);
+        self.reg_i = 5 * (self.reg_v[op.x] & 0xF) as usize;
         self.pc += 2;
     }
 
     fn ld_b_vx_Fx33(&mut self, op: Opcode) {
-        self.ram[self.reg_i] = self.reg_v[op.x] / 100;
-        self.ram[self.reg_i + 1] = (self.reg_v[op.x] % 100) / 10;
-        self.ram[self.reg_i + 2] = self.reg_v[op.x] % 10;
+        let vx = self.reg_v[op.x];
+        self.write(self.reg_i, vx / 100);
+        self.write(self.reg_i + 1, (vx % 100) / 10);
+        self.write(self.reg_i + 2, vx % 10);
         self.pc += 2;
     }
 
     fn ld_i_vx_Fx55(&mut self, op: Opcode) {
         for i in 0..op.x + 1 {
-            self.ram[self.reg_i + i] = self.reg_v[i];
+            self.write(self.reg_i + i, self.reg_v[i]);
         }
+        self.advance_i(op.x);
         self.pc += 2;
     }
 
     fn ld_vx_i_Fx65(&mut self, op: Opcode) {
         for i in 0..op.x + 1 {
-             self.reg_v[i] = self.ram[self.reg_i + i];
+             self.reg_v[i] = self.read(self.reg_i + i);
         }
+        self.advance_i(op.x);
         self.pc += 2;
     }
+
+    // Where I ends up after FX55/FX65 transferred V0..=VX.
+    fn advance_i(&mut self, x: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        self.reg_i += if self.quirks.memory_increment_by_x { x } else { x + 1 };
+    }
 }
 
 #[cfg(test)]
@@ -530,18 +805,43 @@ mod tests {
             0x80, 0x10
         ];
         let mut cpu = Chip8Cpu::new();
-        cpu.load_rom(rom_path);
+        cpu.load_rom(rom_path).unwrap();
         for i in 0..34 {
             assert_eq!(mem[i], cpu.ram[PROG_START + i]);
         }
     }
 
+    #[test]
+    fn cpu_load_rom_bytes() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&[0xA2, 0x1E]).unwrap();
+        assert_eq!(cpu.fetch_opcode(), Opcode::new(0xA21E));
+
+        let mut cpu = Chip8Cpu::new();
+        assert!(cpu.load_rom_bytes(&vec![0xFF; MAX_ROM_SIZE]).is_ok());
+        assert!(cpu.load_rom_bytes(&vec![0xFF; MAX_ROM_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn cpu_load_rom_reports_errors() {
+        let path = std::env::temp_dir().join(format!("chip8-oversized-{}.ch8", std::process::id()));
+        fs::write(&path, vec![0xFF; MAX_ROM_SIZE + 1]).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let mut cpu = Chip8Cpu::new();
+        let loaded = cpu.load_rom(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Err(format!("{}: ROM is {} bytes, but at most {} fit in memory", path, MAX_ROM_SIZE + 1, MAX_ROM_SIZE)));
+        assert_eq!(cpu.ram[PROG_START], 0);
+
+        assert!(cpu.load_rom("/nonexistent/game.ch8").unwrap_err().starts_with("/nonexistent/game.ch8: "));
+    }
+
     #[test]
     fn cpu_fetch_opcode() {
         let rom_path = "/Users/bryce/dev/chip8/roms/c8games/MAZE";
         let op = Opcode::new(0xA21E);
         let mut cpu = Chip8Cpu::new();
-        cpu.load_rom(rom_path);
+        cpu.load_rom(rom_path).unwrap();
 
         assert_eq!(op, cpu.fetch_opcode());
     }
@@ -579,9 +879,14 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn cpu_cls_00E0() {
-        panic!("TODO: CLS test");
+        let mut cpu = Chip8Cpu::new();
+        cpu.vram[0][0] = 1;
+        cpu.vram[HEIGHT - 1][WIDTH - 1] = 1;
+        cpu.cls_00E0();
+        assert_eq!(cpu.vram, [[0; WIDTH]; HEIGHT]);
+        assert!(cpu.vram_update);
+        assert_eq!(cpu.pc, PROG_START + 2);
     }
 
     #[test]
@@ -749,6 +1054,56 @@ mod tests {
         assert_eq!(cpu.pc, PROG_START + 2);
     }
 
+    // Equal operands don't borrow. VF used to be 0 here.
+    #[test]
+    fn cpu_sub_vx_vy_8xy5_equal() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0] = 0x42;
+        cpu.reg_v[1] = 0x42;
+        cpu.sub_vx_vy_8xy5(Opcode::new(0x8015));
+        assert_eq!(cpu.reg_v[0], 0x0);
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+    }
+
+    #[test]
+    fn cpu_subn_vx_vy_8xy7_equal() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0] = 0x42;
+        cpu.reg_v[1] = 0x42;
+        cpu.subn_vx_vy_8xy7(Opcode::new(0x8017));
+        assert_eq!(cpu.reg_v[0], 0x0);
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+    }
+
+    // With X = F the flag replaces the result. It used to be the other
+    // way round.
+    #[test]
+    fn cpu_add_vx_vy_8xy4_flag_wins_for_vf() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0xF] = 0xFF;
+        cpu.reg_v[1] = 0x01;
+        cpu.add_vx_vy_8xy4(Opcode::new(0x8F14));
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+    }
+
+    #[test]
+    fn cpu_sub_vx_vy_8xy5_flag_wins_for_vf() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0xF] = 0x10;
+        cpu.reg_v[1] = 0x01;
+        cpu.sub_vx_vy_8xy5(Opcode::new(0x8F15));
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+    }
+
+    #[test]
+    fn cpu_subn_vx_vy_8xy7_flag_wins_for_vf() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0xF] = 0x10;
+        cpu.reg_v[1] = 0x01;
+        cpu.subn_vx_vy_8xy7(Opcode::new(0x8F17));
+        assert_eq!(cpu.reg_v[0xF], 0x0);
+    }
+
     #[test]
     fn cpu_sub_vx_vy_8xy5_borrow() {
         let mut cpu = Chip8Cpu::new();
@@ -822,6 +1177,45 @@ mod tests {
         assert_eq!(cpu.pc, PROG_START + 2);
     }
 
+    #[test]
+    fn cpu_shr_vx_8xy6_vy_without_shift_quirk() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.set_quirks(Quirks::none());
+        cpu.reg_v[0] = 0x10;
+        cpu.reg_v[1] = 0x3;
+        cpu.shr_vx_8xy6(Opcode::new(0x8016));
+        assert_eq!(cpu.reg_v[0], 0x1);
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+    }
+
+    #[test]
+    fn cpu_shr_vx_8xy6_flag_wins_for_vf() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0xF] = 0x3;
+        cpu.shr_vx_8xy6(Opcode::new(0x8F06));
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+    }
+
+    #[test]
+    fn cpu_shl_vx_8xyE_flag_wins_for_vf() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0xF] = 0x81;
+        cpu.shl_vx_8xyE(Opcode::new(0x8FFE));
+        assert_eq!(cpu.reg_v[0xF], 0x1);
+        cpu.reg_v[0xF] = 0x7F;
+        cpu.shl_vx_8xyE(Opcode::new(0x8FFE));
+        assert_eq!(cpu.reg_v[0xF], 0x0);
+    }
+
+    #[test]
+    fn cpu_or_vx_vy_8xy1_logic_quirk() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.set_quirks(Quirks { logic: true, ..Quirks::none() });
+        cpu.reg_v[0xF] = 0x1;
+        cpu.or_vx_vy_8xy1(Opcode::new(0x8011));
+        assert_eq!(cpu.reg_v[0xF], 0x0);
+    }
+
     #[test]
     fn cpu_sne_vx_vy_9xy0_neq() {
         let mut cpu = Chip8Cpu::new();
@@ -854,30 +1248,117 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
+    fn cpu_jp_vx_addr_Bxnn_jump_quirk() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.set_quirks(Quirks { jump: true, ..Quirks::none() });
+        cpu.reg_v[0] = 0x11;
+        cpu.reg_v[5] = 0x55;
+        cpu.jp_v0_addr_Bnnn(Opcode::new(0xB500));
+        assert_eq!(cpu.pc, 0x555);
+    }
+
+    // The value is random, but the same seed gives the same one.
+    #[test]
     fn cpu_rnd_vx_kk_Cxkk() {
         let mut cpu = Chip8Cpu::new();
+        cpu.seed(0x48);
         cpu.rnd_vx_kk_Cxkk(Opcode::new(0xC0FF));
-        assert_eq!(cpu.reg_v[0], 0x48);
+        assert_eq!(cpu.reg_v[0], Pcg32::seed_from_u64(0x48).next_u32() as u8);
         assert_eq!(cpu.pc, PROG_START + 2);
     }
 
     #[test]
-    #[ignore]
+    fn cpu_rnd_vx_kk_Cxkk_mask() {
+        let mut cpu = Chip8Cpu::new();
+        for _ in 0..64 {
+            cpu.rnd_vx_kk_Cxkk(Opcode::new(0xC10F));
+            assert_eq!(cpu.reg_v[1] & 0xF0, 0);
+        }
+        cpu.rnd_vx_kk_Cxkk(Opcode::new(0xC100));
+        assert_eq!(cpu.reg_v[1], 0);
+        assert_eq!(cpu.pc, PROG_START + 2 * 65);
+    }
+
+    #[test]
     fn cpu_drw_vx_vy_n_Dxyn() {
+        let mut cpu = Chip8Cpu::new();
+        // The 0 glyph at (2, 1).
+        cpu.reg_v[0] = 2;
+        cpu.reg_v[1] = 1;
+        cpu.drw_vx_vy_n_Dxyn(Opcode::new(0xD015));
+        assert_eq!(cpu.vram[1][2..6], [1, 1, 1, 1]);
+        assert_eq!(cpu.vram[2][2..6], [1, 0, 0, 1]);
+        assert_eq!(cpu.vram[1][6], 0);
+        assert_eq!(cpu.reg_v[0xF], 0);
+        assert!(cpu.vram_update);
+
+        // Drawing it again erases it and reports the collision.
+        cpu.drw_vx_vy_n_Dxyn(Opcode::new(0xD015));
+        assert_eq!(cpu.vram, [[0; WIDTH]; HEIGHT]);
+        assert_eq!(cpu.reg_v[0xF], 1);
+        assert_eq!(cpu.pc, PROG_START + 4);
+    }
 
+    #[test]
+    fn cpu_drw_vx_vy_n_Dxyn_edges() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_i = 0x300;
+        cpu.ram[0x300] = 0xFF;
+        // At x = 60 the right half wraps to the left edge...
+        cpu.reg_v[0] = 60;
+        cpu.drw_vx_vy_n_Dxyn(Opcode::new(0xD011));
+        assert_eq!(cpu.vram[0][60..], [1, 1, 1, 1]);
+        assert_eq!(cpu.vram[0][..4], [1, 1, 1, 1]);
+
+        // ...unless the wrap quirk is off, when it's clipped.
+        let mut cpu = Chip8Cpu::new();
+        cpu.set_quirks(Quirks { wrap: false, ..Quirks::none() });
+        cpu.reg_i = 0x300;
+        cpu.ram[0x300] = 0xFF;
+        cpu.reg_v[0] = 60;
+        cpu.drw_vx_vy_n_Dxyn(Opcode::new(0xD011));
+        assert_eq!(cpu.vram[0][60..], [1, 1, 1, 1]);
+        assert_eq!(cpu.vram[0][..4], [0, 0, 0, 0]);
     }
 
+    // Regression: VF used to be cleared before VX and VY were read, so
+    // DFy_ and DxF_ drew at 0.
     #[test]
-    #[ignore]
-    fn cpu_skp_vx_Ex9E() {
+    fn cpu_drw_vx_vy_n_Dxyn_reads_vf_first() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0xF] = 8;
+        cpu.drw_vx_vy_n_Dxyn(Opcode::new(0xDFF1));
+        assert_eq!(cpu.vram[8][8..12], [1, 1, 1, 1]);
+        assert_eq!(cpu.vram[0][..4], [0, 0, 0, 0]);
+        assert_eq!(cpu.reg_v[0xF], 0);
+    }
 
+    #[test]
+    fn cpu_skp_vx_Ex9E() {
+        let mut cpu = Chip8Cpu::new();
+        let mut keys = [false; 16];
+        cpu.reg_v[3] = 0xA;
+        cpu.skp_vx_Ex9E(Opcode::new(0xE39E), keys);
+        assert_eq!(cpu.pc, PROG_START + 2);
+        keys[0xA] = true;
+        cpu.skp_vx_Ex9E(Opcode::new(0xE39E), keys);
+        assert_eq!(cpu.pc, PROG_START + 6);
+        // Only the low nibble of VX picks the key.
+        cpu.reg_v[3] = 0xFA;
+        cpu.skp_vx_Ex9E(Opcode::new(0xE39E), keys);
+        assert_eq!(cpu.pc, PROG_START + 10);
     }
 
     #[test]
-    #[ignore]
     fn cpu_sknp_vx_ExA1() {
-
+        let mut cpu = Chip8Cpu::new();
+        let mut keys = [false; 16];
+        cpu.reg_v[3] = 0xA;
+        cpu.sknp_vx_ExA1(Opcode::new(0xE3A1), keys);
+        assert_eq!(cpu.pc, PROG_START + 4);
+        keys[0xA] = true;
+        cpu.sknp_vx_ExA1(Opcode::new(0xE3A1), keys);
+        assert_eq!(cpu.pc, PROG_START + 6);
     }
 
     #[test]
@@ -890,9 +1371,30 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn cpu_ld_vx_key_Fx0A() {
+        let mut cpu = Chip8Cpu::new();
+        let mut keys = [false; 16];
+        cpu.ld_vx_k_Fx0A(Opcode::new(0xF20A), keys);
+        assert_eq!(cpu.pc, PROG_START);
+        keys[0x9] = true;
+        keys[0x4] = true;
+        cpu.ld_vx_k_Fx0A(Opcode::new(0xF20A), keys);
+        assert_eq!(cpu.reg_v[2], 0x4);
+        assert_eq!(cpu.pc, PROG_START + 2);
+    }
 
+    // Regression: with several keys down the highest used to win, and the
+    // PC moved on once for each of them.
+    #[test]
+    fn cpu_ld_vx_k_Fx0A_lowest_key_wins() {
+        let mut cpu = Chip8Cpu::new();
+        let mut keys = [false; 16];
+        keys[0x3] = true;
+        keys[0x7] = true;
+        keys[0xF] = true;
+        cpu.ld_vx_k_Fx0A(Opcode::new(0xF50A), keys);
+        assert_eq!(cpu.reg_v[5], 0x3);
+        assert_eq!(cpu.pc, PROG_START + 2);
     }
 
     #[test]
@@ -932,6 +1434,20 @@ mod tests {
         assert_eq!(cpu.pc, PROG_START + 2);
     }
 
+    // Regression: the whole of VX used to index the font, pointing I past
+    // it for values over F.
+    #[test]
+    fn cpu_ld_f_vx_Fx29_low_nibble() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.reg_v[0] = 0x3B;
+        cpu.ld_f_vx_Fx29(Opcode::new(0xF029));
+        assert_eq!(cpu.reg_i, 5 * 0xB);
+        cpu.reg_v[0] = 0xFF;
+        cpu.ld_f_vx_Fx29(Opcode::new(0xF029));
+        assert_eq!(cpu.reg_i, 5 * 0xF);
+        assert_eq!(cpu.pc, PROG_START + 4);
+    }
+
     #[test]
     fn cpu_ld_b_vx_Fx33() {
         let mut cpu = Chip8Cpu::new();
@@ -956,6 +1472,110 @@ mod tests {
         assert_eq!(cpu.pc, PROG_START + 2);
     }
 
+    #[test]
+    fn cpu_ld_i_vx_Fx55_memory_quirks() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.set_quirks(Quirks::none());
+        cpu.reg_i = 0x300;
+        cpu.ld_i_vx_Fx55(Opcode::new(0xF355));
+        assert_eq!(cpu.reg_i, 0x304);
+        cpu.set_quirks(Quirks { memory_increment_by_x: true, ..Quirks::none() });
+        cpu.ld_i_vx_Fx55(Opcode::new(0xF355));
+        assert_eq!(cpu.reg_i, 0x307);
+    }
+
+    #[test]
+    fn cpu_memory_accesses() {
+        let mut cpu = Chip8Cpu::new();
+        // LD I, 0xFFF; LD B, V0; LD V1, [I]; DRW V0, V0, 2; CLS
+        cpu.load_rom_bytes(&[0xAF, 0xFF, 0xF0, 0x33, 0xF1, 0x65, 0xD0, 0x02, 0x00, 0xE0]).unwrap();
+        cpu.reg_v[0] = 123;
+        cpu.step([false; 16]);
+        assert!(cpu.accesses().is_empty());
+
+        // Addresses wrap, as they're reported.
+        cpu.step([false; 16]);
+        let write = |addr, value| MemoryAccess { addr, value, kind: AccessKind::Write };
+        let read = |addr, value| MemoryAccess { addr, value, kind: AccessKind::Read };
+        assert_eq!(cpu.accesses(), [write(0xFFF, 1), write(0x000, 2), write(0x001, 3)]);
+
+        cpu.step([false; 16]);
+        assert_eq!(cpu.accesses(), [read(0xFFF, 1), read(0x000, 2)]);
+        cpu.reg_i = 0x300;
+        cpu.step([false; 16]);
+        assert_eq!(cpu.accesses(), [read(0x300, 0), read(0x301, 0)]);
+        cpu.step([false; 16]);
+        assert!(cpu.accesses().is_empty());
+    }
+
+    #[test]
+    fn cpu_snapshot_restore() {
+        let mut cpu = Chip8Cpu::new();
+        // RND V0, 0xFF; LD [I], V0; JP 0x200
+        cpu.load_rom_bytes(&[0xC0, 0xFF, 0xF0, 0x55, 0x12, 0x00]).unwrap();
+        cpu.seed(7);
+        let snapshot = cpu.snapshot();
+        let values: Vec<u8> = (0..30).map(|_| { cpu.step([false; 16]); cpu.reg_v[0] }).collect();
+
+        // Running again from the snapshot draws the same numbers.
+        cpu.restore(&snapshot);
+        assert_eq!(cpu.cycles(), 0);
+        assert_eq!(cpu.peek(0), FONT[0]);
+        assert!(cpu.vram_update);
+        let again: Vec<u8> = (0..30).map(|_| { cpu.step([false; 16]); cpu.reg_v[0] }).collect();
+        assert_eq!(values, again);
+    }
+
+    #[test]
+    fn cpu_set_registers() {
+        let mut cpu = Chip8Cpu::new();
+        let mut regs = cpu.registers();
+        regs.v[0xF] = 1;
+        regs.pc = RAM + 0x300;
+        regs.sp = 20;
+        cpu.set_registers(regs);
+        assert_eq!(cpu.reg_v[0xF], 1);
+        assert_eq!(cpu.pc, 0x300);
+        assert_eq!(cpu.sp, 16);
+    }
+
+    #[test]
+    fn cpu_vblank_quirk_ends_frame() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.set_quirks(Quirks { vblank: true, ..Quirks::none() });
+        // DRW V0, V0, 0 followed by a jump back to it.
+        cpu.ram[PROG_START..PROG_START + 4].copy_from_slice(&[0xD0, 0x00, 0x12, 0x00]);
+        cpu.run_frame([false; 16], 10);
+        assert_eq!(cpu.pc, PROG_START + 2);
+        cpu.run_frame([false; 16], 10);
+        assert_eq!(cpu.pc, PROG_START + 2);
+    }
+
+    #[test]
+    fn cpu_trace_steps() {
+        use crate::trace::TraceOptions;
+
+        let path = std::env::temp_dir().join(format!("chip8-trace-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut cpu = Chip8Cpu::new();
+        // LD V1, 0x2A; an unimplemented opcode; JP back to the start.
+        cpu.load_rom_bytes(&[0x61, 0x2A, 0x51, 0x21, 0x12, 0x00]).unwrap();
+        cpu.set_tracer(Some(Tracer::create(path, TraceOptions::default()).unwrap()));
+        for _ in 0..4 {
+            cpu.step([false; 16]);
+        }
+        assert_eq!(cpu.cycles(), 4);
+        cpu.take_tracer().unwrap().finish().unwrap();
+
+        let trace = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].starts_with("CYC:1 PC:0202 OP:5121 V0:00 V1:2A"));
+        assert_eq!(lines[2], "# unimplemented opcode 0x5121 at 0x202");
+        assert!(lines[4].starts_with("CYC:3 PC:0200"));
+    }
+
     #[test]
     fn cpu_ld_vx_i_Fx65() {
         let mut cpu = Chip8Cpu::new();
@@ -969,3 +1589,511 @@ mod tests {
         assert_eq!(cpu.pc, PROG_START + 2);
     }
 }
+
+// Random machine states and instruction streams, run under every quirk
+// profile. Nothing may panic, and arithmetic and skips must agree with a
+// reference model written from the spec rather than from the code above.
+#[cfg(test)]
+mod fuzz {
+    use super::*;
+    use super::super::quirks::Platform;
+    use proptest::prelude::*;
+    use proptest::collection::vec;
+
+    // Just the instructions that only touch V and PC.
+    struct Reference {
+        v: [u8; 16],
+        pc: usize,
+    }
+
+    impl Reference {
+        // Runs `op`, or returns None if the model doesn't cover it.
+        fn step(&mut self, op: u16, quirks: Quirks, keys: [bool; 16]) -> Option<()> {
+            let x = (op >> 8 & 0xF) as usize;
+            let y = (op >> 4 & 0xF) as usize;
+            let kk = op as u8;
+            let (vx, vy) = (self.v[x], self.v[y]);
+            let mut skip = false;
+            match (op >> 12, op & 0xF) {
+                (0x3, _) => skip = vx == kk,
+                (0x4, _) => skip = vx != kk,
+                (0x5, 0x0) => skip = vx == vy,
+                (0x9, 0x0) => skip = vx != vy,
+                (0x6, _) => self.v[x] = kk,
+                (0x7, _) => self.v[x] = vx.wrapping_add(kk),
+                (0x8, 0x0) => self.v[x] = vy,
+                (0x8, 0x1..=0x3) => {
+                    self.v[x] = match op & 0xF { 0x1 => vx | vy, 0x2 => vx & vy, _ => vx ^ vy };
+                    if quirks.logic { self.v[0xF] = 0; }
+                },
+                // The flag is written last, so it wins when X is F.
+                (0x8, 0x4) => {
+                    self.v[x] = vx.wrapping_add(vy);
+                    self.v[0xF] = (vx as u16 + vy as u16 > 0xFF) as u8;
+                },
+                (0x8, 0x5) => {
+                    self.v[x] = vx.wrapping_sub(vy);
+                    self.v[0xF] = (vx >= vy) as u8;
+                },
+                (0x8, 0x7) => {
+                    self.v[x] = vy.wrapping_sub(vx);
+                    self.v[0xF] = (vy >= vx) as u8;
+                },
+                (0x8, 0x6) | (0x8, 0xE) => {
+                    let value = if quirks.shift { vx } else { vy };
+                    if op & 0xF == 0x6 {
+                        self.v[x] = value >> 1;
+                        self.v[0xF] = value & 1;
+                    } else {
+                        self.v[x] = value << 1;
+                        self.v[0xF] = value >> 7;
+                    }
+                },
+                (0xE, _) if kk == 0x9E => skip = keys[(vx & 0xF) as usize],
+                (0xE, _) if kk == 0xA1 => skip = !keys[(vx & 0xF) as usize],
+                _ => return None,
+            }
+            self.pc = (self.pc + if skip { 4 } else { 2 }) % RAM;
+            Some(())
+        }
+    }
+
+    pub(super) fn profiles() -> Vec<Quirks> {
+        let mut profiles = vec![Quirks::default(), Quirks::none()];
+        profiles.extend(Platform::ALL.iter().map(Platform::quirks));
+        profiles
+    }
+
+    // Opcodes weighted towards the ones the model checks.
+    fn opcode() -> impl Strategy<Value = u16> {
+        prop_oneof![
+            1 => any::<u16>(),
+            3 => 0x3000u16..0xA000,
+            1 => (0u16..0x10).prop_map(|x| 0xE09E | x << 8),
+            1 => (0u16..0x10).prop_map(|x| 0xE0A1 | x << 8),
+            1 => (0u16..0x1000).prop_map(|nnn| 0x2000 | nnn),
+            1 => Just(0x00EE),
+        ]
+    }
+
+    #[derive(Clone, Debug)]
+    struct Machine {
+        v: [u8; 16],
+        i: usize,
+        pc: usize,
+        sp: usize,
+        stack: [usize; 16],
+        timers: (u8, u8),
+        ram: Vec<u8>,
+    }
+
+    fn machine() -> impl Strategy<Value = Machine> {
+        (any::<[u8; 16]>(), 0..0x10000usize, 0..RAM, 0..=16usize,
+         prop::array::uniform16(0..RAM), any::<(u8, u8)>(), vec(any::<u8>(), RAM))
+            .prop_map(|(v, i, pc, sp, stack, timers, ram)| Machine { v, i, pc, sp, stack, timers, ram })
+    }
+
+    impl Machine {
+        fn cpu(&self, quirks: Quirks, program: &[u16]) -> Chip8Cpu {
+            let mut cpu = Chip8Cpu::new();
+            cpu.set_quirks(quirks);
+            cpu.reg_v = self.v;
+            cpu.reg_i = self.i;
+            cpu.pc = self.pc;
+            cpu.sp = self.sp;
+            cpu.stack = self.stack;
+            cpu.reg_d = self.timers.0;
+            cpu.reg_s = self.timers.1;
+            cpu.ram.copy_from_slice(&self.ram);
+            for (n, op) in program.iter().enumerate() {
+                cpu.ram[(self.pc + 2 * n) % RAM] = (op >> 8) as u8;
+                cpu.ram[(self.pc + 2 * n + 1) % RAM] = *op as u8;
+            }
+            cpu
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(200))]
+
+        #[test]
+        fn fuzz_against_reference(machine in machine(), program in vec(opcode(), 1..48), keys in any::<[bool; 16]>()) {
+            for quirks in profiles() {
+                let mut cpu = machine.cpu(quirks, &program);
+                for _ in 0..program.len() * 2 {
+                    let op = cpu.fetch_opcode().opcode;
+                    let mut reference = Reference { v: cpu.reg_v, pc: cpu.pc };
+                    let running = cpu.fault().is_none();
+                    cpu.step(keys);
+                    if running && cpu.fault().is_none() && reference.step(op, quirks, keys).is_some() {
+                        prop_assert_eq!(cpu.reg_v, reference.v, "{:04X} under {:?}", op, quirks);
+                        prop_assert_eq!(cpu.pc, reference.pc, "{:04X} under {:?}", op, quirks);
+                    }
+                    if cpu.cycles() % 8 == 0 {
+                        cpu.tick_timers();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The full effect of each instruction, run through decode_opcode from
+// random machine states under every quirk profile: every register, flag,
+// byte of memory and pixel that should change, and nothing else.
+#[cfg(test)]
+mod properties {
+    use super::*;
+    use super::fuzz::profiles;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+    use rand::{Rng, RngCore, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    // Everything an instruction can touch.
+    #[derive(Clone, Debug, PartialEq)]
+    struct State {
+        v: [u8; 16],
+        i: usize,
+        pc: usize,
+        sp: usize,
+        stack: [usize; 16],
+        dt: u8,
+        st: u8,
+        ram: Vec<u8>,
+        vram: Vec<[u8; WIDTH]>,
+        fault: bool,
+    }
+
+    impl State {
+        fn of(cpu: &Chip8Cpu) -> Self {
+            State {
+                v: cpu.reg_v,
+                i: cpu.reg_i,
+                pc: cpu.pc,
+                sp: cpu.sp,
+                stack: cpu.stack,
+                dt: cpu.reg_d,
+                st: cpu.reg_s,
+                ram: cpu.ram.to_vec(),
+                vram: cpu.vram.to_vec(),
+                fault: cpu.fault.is_some(),
+            }
+        }
+
+        fn next(&self, n: usize) -> usize {
+            (self.pc + n) % RAM
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct Setup {
+        v: [u8; 16],
+        i: usize,
+        pc: usize,
+        sp: usize,
+        stack: [usize; 16],
+        timers: (u8, u8),
+        // Fills memory and the screen; generating them byte by byte is slow.
+        seed: u64,
+        quirks: Quirks,
+    }
+
+    fn setup() -> impl Strategy<Value = Setup> {
+        // PC stays even and below the last word so the instruction doesn't
+        // wrap, but a skip or jump from near the top of memory still does.
+        (any::<[u8; 16]>(), 0..RAM, (PROG_START / 2..RAM / 2).prop_map(|pc| pc * 2), 0..16usize,
+         prop::array::uniform16(0..RAM), any::<(u8, u8)>(), any::<u64>(), select(profiles()))
+            .prop_map(|(v, i, pc, sp, stack, timers, seed, quirks)| {
+                Setup { v, i, pc, sp, stack, timers, seed, quirks }
+            })
+    }
+
+    impl Setup {
+        // A CPU in this state with `op` at PC.
+        fn cpu(&self, op: u16) -> Chip8Cpu {
+            let mut cpu = Chip8Cpu::new();
+            cpu.set_quirks(self.quirks);
+            cpu.reg_v = self.v;
+            cpu.reg_i = self.i;
+            cpu.pc = self.pc;
+            cpu.sp = self.sp;
+            cpu.stack = self.stack;
+            cpu.reg_d = self.timers.0;
+            cpu.reg_s = self.timers.1;
+            let mut rng = Pcg32::seed_from_u64(self.seed);
+            rng.fill_bytes(&mut cpu.ram);
+            for pixel in cpu.vram.iter_mut().flat_map(|row| row.iter_mut()) {
+                *pixel = rng.gen_range(0, 2);
+            }
+            cpu.ram[self.pc] = (op >> 8) as u8;
+            cpu.ram[self.pc + 1] = op as u8;
+            cpu
+        }
+    }
+
+    // Runs `op` and returns the state before and after.
+    fn run(setup: &Setup, op: u16, keys: [bool; 16]) -> (State, Chip8Cpu) {
+        let mut cpu = setup.cpu(op);
+        let before = State::of(&cpu);
+        cpu.step(keys);
+        (before, cpu)
+    }
+
+    fn reg() -> impl Strategy<Value = usize> {
+        0..16usize
+    }
+
+    // Draws the way the spec describes it, pixel by pixel. True on a
+    // collision.
+    fn draw(vram: &mut [[u8; WIDTH]], sprite: &[u8], left: u8, top: u8, wrap: bool) -> bool {
+        let mut collision = false;
+        for (row, byte) in sprite.iter().enumerate() {
+            for bit in 0..8 {
+                let x = left as usize % WIDTH + bit;
+                let y = top as usize % HEIGHT + row;
+                if !wrap && (x >= WIDTH || y >= HEIGHT) {
+                    continue;
+                }
+                if byte >> (7 - bit) & 1 == 1 {
+                    let pixel = &mut vram[y % HEIGHT][x % WIDTH];
+                    collision |= *pixel == 1;
+                    *pixel ^= 1;
+                }
+            }
+        }
+        collision
+    }
+
+    proptest! {
+        #[test]
+        fn prop_cls(setup in setup()) {
+            let (mut expected, cpu) = run(&setup, 0x00E0, [false; 16]);
+            expected.vram = vec![[0; WIDTH]; HEIGHT];
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+            prop_assert!(cpu.vram_update);
+        }
+
+        #[test]
+        fn prop_ret(setup in setup()) {
+            let (mut expected, cpu) = run(&setup, 0x00EE, [false; 16]);
+            if setup.sp == 0 {
+                expected.fault = true;
+            } else {
+                expected.sp -= 1;
+                expected.pc = setup.stack[expected.sp];
+            }
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_jp(setup in setup(), nnn in 0..0x1000u16) {
+            let (mut expected, cpu) = run(&setup, 0x1000 | nnn, [false; 16]);
+            expected.pc = nnn as usize;
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_call(setup in setup(), nnn in 0..0x1000u16, full in any::<bool>()) {
+            let setup = Setup { sp: if full { 16 } else { setup.sp }, ..setup };
+            let (mut expected, cpu) = run(&setup, 0x2000 | nnn, [false; 16]);
+            if full {
+                expected.fault = true;
+            } else {
+                expected.stack[expected.sp] = expected.pc + 2;
+                expected.sp += 1;
+                expected.pc = nnn as usize;
+            }
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_skips(setup in setup(), x in reg(), y in reg(), kk in any::<u8>(), family in select(vec![3u16, 4, 5, 9])) {
+            // Half the time make the comparison come out equal.
+            let mut setup = setup;
+            if kk & 1 == 0 {
+                setup.v[x] = if family == 3 || family == 4 { kk } else { setup.v[y] };
+            }
+            let op = match family {
+                3 | 4 => family << 12 | (x as u16) << 8 | kk as u16,
+                _ => family << 12 | (x as u16) << 8 | (y as u16) << 4,
+            };
+            let (mut expected, cpu) = run(&setup, op, [false; 16]);
+            let equal = match family {
+                3 | 4 => setup.v[x] == kk,
+                _ => setup.v[x] == setup.v[y],
+            };
+            let skip = if family == 3 || family == 5 { equal } else { !equal };
+            expected.pc = expected.next(if skip { 4 } else { 2 });
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_ld_add_kk(setup in setup(), x in reg(), kk in any::<u8>(), add in any::<bool>()) {
+            let op = if add { 0x7000 } else { 0x6000 } | (x as u16) << 8 | kk as u16;
+            let (mut expected, cpu) = run(&setup, op, [false; 16]);
+            // 7XKK never touches the carry flag.
+            expected.v[x] = if add { setup.v[x].wrapping_add(kk) } else { kk };
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_alu(setup in setup(), x in reg(), y in reg(), n in select(vec![0u16, 1, 2, 3, 4, 5, 6, 7, 0xE])) {
+            let op = 0x8000 | (x as u16) << 8 | (y as u16) << 4 | n;
+            let (mut expected, cpu) = run(&setup, op, [false; 16]);
+            let (vx, vy) = (setup.v[x], setup.v[y]);
+            let quirks = setup.quirks;
+            let shifted = if quirks.shift { vx } else { vy };
+            // The result, and the flag if there is one. The flag is written
+            // after the result, so it's what's left when X is F.
+            let (result, flag) = match n {
+                0 => (vy, None),
+                1 => (vx | vy, if quirks.logic { Some(0) } else { None }),
+                2 => (vx & vy, if quirks.logic { Some(0) } else { None }),
+                3 => (vx ^ vy, if quirks.logic { Some(0) } else { None }),
+                4 => (vx.wrapping_add(vy), Some((vx as u16 + vy as u16 > 0xFF) as u8)),
+                5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+                6 => (shifted >> 1, Some(shifted & 1)),
+                7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+                _ => (shifted << 1, Some(shifted >> 7)),
+            };
+            expected.v[x] = result;
+            if let Some(flag) = flag {
+                expected.v[0xF] = flag;
+            }
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_unassigned(setup in setup(), xy in 0..0x100u16,
+                           (family, n) in prop_oneof![(Just(5u16), 1..0x10u16),
+                                                      (Just(9u16), 1..0x10u16),
+                                                      (Just(8u16), select(vec![8u16, 9, 0xA, 0xB, 0xC, 0xD, 0xF]))]) {
+            let (mut expected, cpu) = run(&setup, family << 12 | xy << 4 | n, [false; 16]);
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_ld_i(setup in setup(), nnn in 0..0x1000u16) {
+            let (mut expected, cpu) = run(&setup, 0xA000 | nnn, [false; 16]);
+            expected.i = nnn as usize;
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_jp_offset(setup in setup(), nnn in 0..0x1000u16) {
+            let (mut expected, cpu) = run(&setup, 0xB000 | nnn, [false; 16]);
+            let x = (nnn >> 8) as usize;
+            let offset = if setup.quirks.jump { setup.v[x] } else { setup.v[0] };
+            expected.pc = (nnn as usize + offset as usize) % RAM;
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_rnd(setup in setup(), x in reg(), kk in any::<u8>()) {
+            let (mut expected, cpu) = run(&setup, 0xC000 | (x as u16) << 8 | kk as u16, [false; 16]);
+            prop_assert_eq!(cpu.reg_v[x] & !kk, 0);
+            expected.v[x] = cpu.reg_v[x];
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_drw(setup in setup(), x in reg(), y in reg(), n in 0..0x10u16) {
+            let (mut expected, cpu) = run(&setup, 0xD000 | (x as u16) << 8 | (y as u16) << 4 | n, [false; 16]);
+            let sprite: Vec<u8> = (0..n as usize).map(|row| expected.ram[(setup.i + row) % RAM]).collect();
+            let collision = draw(&mut expected.vram, &sprite, setup.v[x], setup.v[y], setup.quirks.wrap);
+            expected.v[0xF] = collision as u8;
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+            prop_assert!(cpu.vram_update);
+            prop_assert_eq!(cpu.vblank_wait, setup.quirks.vblank);
+        }
+
+        #[test]
+        fn prop_key_skips(setup in setup(), x in reg(), keys in any::<[bool; 16]>(), pressed in any::<bool>()) {
+            let op = if pressed { 0xE09E } else { 0xE0A1 } | (x as u16) << 8;
+            let (mut expected, cpu) = run(&setup, op, keys);
+            let down = keys[(setup.v[x] & 0xF) as usize];
+            expected.pc = expected.next(if down == pressed { 4 } else { 2 });
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_wait_key(setup in setup(), x in reg(), keys in any::<[bool; 16]>()) {
+            let (mut expected, cpu) = run(&setup, 0xF00A | (x as u16) << 8, keys);
+            // Without a key, PC stays put and the instruction runs again.
+            if let Some(key) = keys.iter().position(|&down| down) {
+                expected.v[x] = key as u8;
+                expected.pc = expected.next(2);
+            }
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_timers(setup in setup(), x in reg(), kk in select(vec![0x07u16, 0x15, 0x18])) {
+            let (mut expected, cpu) = run(&setup, 0xF000 | (x as u16) << 8 | kk, [false; 16]);
+            match kk {
+                0x07 => expected.v[x] = setup.timers.0,
+                0x15 => expected.dt = setup.v[x],
+                _ => expected.st = setup.v[x],
+            }
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_add_i(setup in setup(), x in reg()) {
+            let (mut expected, cpu) = run(&setup, 0xF01E | (x as u16) << 8, [false; 16]);
+            // VF flags I going past 0xF00.
+            expected.i = setup.i + setup.v[x] as usize;
+            expected.v[0xF] = (expected.i > 0xF00) as u8;
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_font(setup in setup(), x in reg()) {
+            let (mut expected, cpu) = run(&setup, 0xF029 | (x as u16) << 8, [false; 16]);
+            expected.i = 5 * (setup.v[x] & 0xF) as usize;
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_bcd(setup in setup(), x in reg()) {
+            let (mut expected, cpu) = run(&setup, 0xF033 | (x as u16) << 8, [false; 16]);
+            let vx = setup.v[x];
+            for (n, digit) in [vx / 100, vx / 10 % 10, vx % 10].iter().enumerate() {
+                expected.ram[(setup.i + n) % RAM] = *digit;
+            }
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+
+        #[test]
+        fn prop_store_load(setup in setup(), x in reg(), store in any::<bool>()) {
+            let op = if store { 0xF055 } else { 0xF065 } | (x as u16) << 8;
+            let (mut expected, cpu) = run(&setup, op, [false; 16]);
+            for n in 0..=x {
+                let addr = (setup.i + n) % RAM;
+                if store {
+                    expected.ram[addr] = setup.v[n];
+                } else {
+                    expected.v[n] = expected.ram[addr];
+                }
+            }
+            let quirks = setup.quirks;
+            if !quirks.memory_leave_i_unchanged {
+                expected.i += if quirks.memory_increment_by_x { x } else { x + 1 };
+            }
+            expected.pc = expected.next(2);
+            prop_assert_eq!(State::of(&cpu), expected);
+        }
+    }
+}
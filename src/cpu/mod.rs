@@ -1,3 +1,5 @@
 mod cpu;
+mod quirks;
 
-pub use self::cpu::Chip8Cpu;
+pub use self::cpu::{AccessKind, Chip8Cpu, MemoryAccess, Registers, HEIGHT, MAX_ROM_SIZE, PROG_START, RAM, WIDTH};
+pub use self::quirks::{Platform, Quirks};
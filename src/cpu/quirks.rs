@@ -0,0 +1,225 @@
+// Behaviour that differs between CHIP-8 interpreters. Field names follow the
+// community chip-8-database's quirk names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place instead of shifting VY into VX.
+    pub shift: bool,
+    // FX55/FX65 leave I increased by X rather than X + 1.
+    pub memory_increment_by_x: bool,
+    // FX55/FX65 leave I unchanged.
+    pub memory_leave_i_unchanged: bool,
+    // Sprites wrap around the screen edges instead of being clipped.
+    pub wrap: bool,
+    // BNNN jumps to XNN + VX instead of NNN + V0.
+    pub jump: bool,
+    // DXYN waits for the next frame, so at most one sprite per frame.
+    pub vblank: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0.
+    pub logic: bool,
+}
+
+// What this interpreter has always done, so a CPU without a profile keeps
+// running ROMs the way it did before quirks were configurable. The one
+// difference is that the shifts now write VF last.
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: true,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+impl Quirks {
+    pub const NAMES: [&'static str; 7] = [
+        "shift",
+        "memoryIncrementByX",
+        "memoryLeaveIUnchanged",
+        "wrap",
+        "jump",
+        "vblank",
+        "logic",
+    ];
+
+    pub fn none() -> Self {
+        Quirks {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: false,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "shift" => Some(self.shift),
+            "memoryIncrementByX" => Some(self.memory_increment_by_x),
+            "memoryLeaveIUnchanged" => Some(self.memory_leave_i_unchanged),
+            "wrap" => Some(self.wrap),
+            "jump" => Some(self.jump),
+            "vblank" => Some(self.vblank),
+            "logic" => Some(self.logic),
+            _ => None,
+        }
+    }
+
+    // Returns false for an unknown quirk name.
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        let field = match name {
+            "shift" => &mut self.shift,
+            "memoryIncrementByX" => &mut self.memory_increment_by_x,
+            "memoryLeaveIUnchanged" => &mut self.memory_leave_i_unchanged,
+            "wrap" => &mut self.wrap,
+            "jump" => &mut self.jump,
+            "vblank" => &mut self.vblank,
+            "logic" => &mut self.logic,
+            _ => return false,
+        };
+        *field = value;
+        true
+    }
+
+    // Comma-separated names of the enabled quirks, or "none".
+    pub fn describe(&self) -> String {
+        let enabled: Vec<&str> = Quirks::NAMES.iter()
+                                              .cloned()
+                                              .filter(|name| self.get(name) == Some(true))
+                                              .collect();
+        if enabled.is_empty() { "none".to_string() } else { enabled.join(", ") }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    OriginalChip8,
+    HybridVip,
+    ModernChip8,
+    Chip8x,
+    Chip48,
+    Superchip1,
+    Superchip,
+    Megachip8,
+    XoChip,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 9] = [
+        Platform::OriginalChip8,
+        Platform::HybridVip,
+        Platform::ModernChip8,
+        Platform::Chip8x,
+        Platform::Chip48,
+        Platform::Superchip1,
+        Platform::Superchip,
+        Platform::Megachip8,
+        Platform::XoChip,
+    ];
+
+    // Identifier used by the chip-8-database.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Platform::OriginalChip8 => "originalChip8",
+            Platform::HybridVip => "hybridVIP",
+            Platform::ModernChip8 => "modernChip8",
+            Platform::Chip8x => "chip8x",
+            Platform::Chip48 => "chip48",
+            Platform::Superchip1 => "superchip1",
+            Platform::Superchip => "superchip",
+            Platform::Megachip8 => "megachip8",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Self> {
+        Platform::ALL.iter().cloned().find(|platform| platform.id().eq_ignore_ascii_case(id))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::OriginalChip8 => "COSMAC VIP CHIP-8",
+            Platform::HybridVip => "COSMAC VIP hybrid CHIP-8",
+            Platform::ModernChip8 => "Modern CHIP-8",
+            Platform::Chip8x => "CHIP-8X",
+            Platform::Chip48 => "CHIP-48",
+            Platform::Superchip1 => "SUPER-CHIP 1.0",
+            Platform::Superchip => "SUPER-CHIP 1.1",
+            Platform::Megachip8 => "MEGA-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        let none = Quirks::none();
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip | Platform::Chip8x => {
+                Quirks { vblank: true, logic: true, ..none }
+            },
+            Platform::ModernChip8 => none,
+            Platform::Chip48 => {
+                Quirks { shift: true, memory_increment_by_x: true, jump: true, ..none }
+            },
+            Platform::Superchip1 | Platform::Superchip | Platform::Megachip8 => {
+                Quirks { shift: true, memory_leave_i_unchanged: true, jump: true, ..none }
+            },
+            Platform::XoChip => Quirks { wrap: true, ..none },
+        }
+    }
+
+    // Instructions per frame the platform's reference interpreter ran at.
+    pub fn tickrate(&self) -> u32 {
+        match self {
+            Platform::OriginalChip8 | Platform::HybridVip | Platform::Chip8x => 15,
+            Platform::ModernChip8 => 12,
+            Platform::Chip48 | Platform::Superchip1 | Platform::Superchip => 30,
+            Platform::Megachip8 => 1000,
+            Platform::XoChip => 100,
+        }
+    }
+
+    // Bytes available to a program loaded at 0x200.
+    pub fn max_rom_size(&self) -> usize {
+        match self {
+            Platform::XoChip => 0x10000 - 0x200,
+            Platform::Megachip8 => 0x1000000 - 0x200,
+            _ => 0x1000 - 0x200,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quirks_names_round_trip() {
+        let mut quirks = Quirks::none();
+        for name in Quirks::NAMES.iter() {
+            assert_eq!(quirks.get(name), Some(false));
+            assert!(quirks.set(name, true));
+            assert_eq!(quirks.get(name), Some(true));
+        }
+        assert!(!quirks.set("bogus", true));
+    }
+
+    #[test]
+    fn quirks_describe() {
+        assert_eq!(Quirks::none().describe(), "none");
+        assert_eq!(Platform::Chip48.quirks().describe(), "shift, memoryIncrementByX, jump");
+    }
+
+    #[test]
+    fn platform_ids() {
+        for platform in Platform::ALL.iter() {
+            assert_eq!(Platform::from_id(platform.id()), Some(*platform));
+        }
+        assert_eq!(Platform::from_id("unknown"), None);
+    }
+}
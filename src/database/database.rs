@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::cpu::{Platform, Quirks};
+use crate::display::Palette;
+use crate::rom::read_rom;
+
+// Snapshot of the community chip-8-database
+// (https://github.com/chip-8/chip-8-database). Refresh by copying its
+// programs.json and platforms.json over these files.
+const EMBEDDED_PROGRAMS: &str = include_str!("../../data/database/programs.json");
+const EMBEDDED_PLATFORMS: &str = include_str!("../../data/database/platforms.json");
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl QuirkOverrides {
+    fn apply(&self, quirks: &mut Quirks) {
+        let fields = [
+            (&mut quirks.shift, self.shift),
+            (&mut quirks.memory_increment_by_x, self.memory_increment_by_x),
+            (&mut quirks.memory_leave_i_unchanged, self.memory_leave_i_unchanged),
+            (&mut quirks.wrap, self.wrap),
+            (&mut quirks.jump, self.jump),
+            (&mut quirks.vblank, self.vblank),
+            (&mut quirks.logic, self.logic),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatformJson {
+    id: String,
+    default_tickrate: Option<u32>,
+    #[serde(default)]
+    quirks: QuirkOverrides,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct ColorsJson {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomJson {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<u32>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    #[serde(default)]
+    colors: ColorsJson,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ProgramJson {
+    title: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    roms: HashMap<String, RomJson>,
+}
+
+// Everything the database knows about one ROM, resolved against its
+// platform so it can be applied directly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub sha1: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub platform: Option<Platform>,
+    pub quirks: Quirks,
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    // Keypad indices for named game buttons ("up", "a", ...).
+    pub keys: HashMap<String, u8>,
+}
+
+impl RomInfo {
+    // One line for menus and `info` output.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(platform) = self.platform {
+            parts.push(platform.name().to_string());
+        }
+        if let Some(tickrate) = self.tickrate {
+            parts.push(format!("{} IPF", tickrate));
+        }
+        parts.push(format!("quirks: {}", self.quirks.describe()));
+        parts.join(", ")
+    }
+}
+
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+pub struct RomDatabase {
+    programs: Vec<ProgramJson>,
+    // SHA-1 to (program index, rom); later additions replace earlier ones.
+    index: HashMap<String, (usize, RomJson)>,
+    platforms: HashMap<String, PlatformJson>,
+}
+
+impl RomDatabase {
+    pub fn empty() -> Self {
+        RomDatabase {
+            programs: Vec::new(),
+            index: HashMap::new(),
+            platforms: HashMap::new(),
+        }
+    }
+
+    pub fn embedded() -> Self {
+        let mut db = RomDatabase::empty();
+        db.add_programs(EMBEDDED_PROGRAMS).expect("embedded programs.json is invalid");
+        db.add_platforms(EMBEDDED_PLATFORMS).expect("embedded platforms.json is invalid");
+        db
+    }
+
+    // The embedded database, with any programs.json / platforms.json found
+    // in the user's config directory layered on top.
+    pub fn load() -> Self {
+        let mut db = RomDatabase::embedded();
+        if let Some(dir) = RomDatabase::local_dir() {
+            db.add_dir(&dir);
+        }
+        db
+    }
+
+    pub fn local_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("chip8").join("database"))
+    }
+
+    // Missing files are fine; broken ones are reported and skipped.
+    pub fn add_dir(&mut self, dir: &Path) {
+        let programs = dir.join("programs.json");
+        if let Ok(text) = fs::read_to_string(&programs) {
+            if let Err(e) = self.add_programs(&text) {
+                eprintln!("COULD NOT LOAD ROM DATABASE {}: {}", programs.display(), e);
+            }
+        }
+        let platforms = dir.join("platforms.json");
+        if let Ok(text) = fs::read_to_string(&platforms) {
+            if let Err(e) = self.add_platforms(&text) {
+                eprintln!("COULD NOT LOAD ROM DATABASE {}: {}", platforms.display(), e);
+            }
+        }
+    }
+
+    // Later programs win when the same hash appears more than once.
+    pub fn add_programs(&mut self, json: &str) -> Result<(), String> {
+        let programs: Vec<ProgramJson> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        for program in programs {
+            let index = self.programs.len();
+            for (hash, rom) in &program.roms {
+                self.index.insert(hash.to_ascii_lowercase(), (index, rom.clone()));
+            }
+            self.programs.push(program);
+        }
+        Ok(())
+    }
+
+    pub fn add_platforms(&mut self, json: &str) -> Result<(), String> {
+        let platforms: Vec<PlatformJson> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        for platform in platforms {
+            self.platforms.insert(platform.id.clone(), platform);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize { self.index.len() }
+    pub fn is_empty(&self) -> bool { self.index.is_empty() }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<RomInfo> {
+        self.lookup_hash(&sha1_hex(rom))
+    }
+
+    // Archives holding more than one ROM aren't looked up.
+    pub fn lookup_file(&self, path: &Path) -> Option<RomInfo> {
+        read_rom(&path.to_string_lossy(), &mut |_| None).ok().and_then(|rom| self.lookup(&rom))
+    }
+
+    pub fn lookup_hash(&self, sha1: &str) -> Option<RomInfo> {
+        let sha1 = sha1.to_ascii_lowercase();
+        let (program, rom) = self.index.get(&sha1)?;
+        let program = &self.programs[*program];
+
+        // The first platform listed is the one the ROM was written for.
+        let platform_id = rom.platforms.first();
+        let platform = platform_id.and_then(|id| Platform::from_id(id));
+        let known = platform_id.and_then(|id| self.platforms.get(id));
+
+        let mut quirks = platform.map(|p| p.quirks()).unwrap_or_default();
+        if let Some(known) = known {
+            known.quirks.apply(&mut quirks);
+        }
+        if let Some(overrides) = platform_id.and_then(|id| rom.quirky_platforms.get(id)) {
+            overrides.apply(&mut quirks);
+        }
+
+        let tickrate = rom.tickrate
+                          .or_else(|| known.and_then(|p| p.default_tickrate))
+                          .or_else(|| platform.map(|p| p.tickrate()));
+
+        let colors: Vec<[u8; 3]> = rom.colors.pixels.iter()
+                                      .filter_map(|c| Palette::parse_color(c))
+                                      .collect();
+        let palette = if colors.len() >= 2 { Some(Palette::new(colors[0], colors[1])) } else { None };
+
+        Some(RomInfo {
+            sha1,
+            title: program.title.clone(),
+            description: program.description.clone(),
+            platform,
+            quirks,
+            tickrate,
+            palette,
+            keys: rom.keys.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = b"\x00\xE0\x12\x00";
+
+    fn programs() -> String {
+        format!(r##"[
+            {{
+                "title": "Test Game",
+                "roms": {{
+                    "{}": {{
+                        "file": "test.ch8",
+                        "platforms": ["chip48"],
+                        "quirkyPlatforms": {{ "chip48": {{ "memoryIncrementByX": false }} }},
+                        "keys": {{ "up": 5, "a": 6 }},
+                        "colors": {{ "pixels": ["#000000", "#FF8000"] }}
+                    }}
+                }}
+            }}
+        ]"##, sha1_hex(ROM).to_uppercase())
+    }
+
+    #[test]
+    fn database_sha1() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn database_embedded_parses() {
+        let db = RomDatabase::embedded();
+        assert_eq!(db.platforms.len(), Platform::ALL.len());
+    }
+
+    #[test]
+    fn database_embedded_platforms_match_builtin() {
+        let db = RomDatabase::embedded();
+        for platform in Platform::ALL.iter() {
+            let known = &db.platforms[platform.id()];
+            let mut quirks = Quirks::none();
+            known.quirks.apply(&mut quirks);
+            assert_eq!(quirks, platform.quirks(), "{}", platform.id());
+            assert_eq!(known.default_tickrate, Some(platform.tickrate()));
+        }
+    }
+
+    #[test]
+    fn database_lookup() {
+        let mut db = RomDatabase::embedded();
+        db.add_programs(&programs()).unwrap();
+        let info = db.lookup(ROM).unwrap();
+        assert_eq!(info.title, "Test Game");
+        assert_eq!(info.platform, Some(Platform::Chip48));
+        assert_eq!(info.quirks, Quirks { shift: true, jump: true, ..Quirks::none() });
+        assert_eq!(info.tickrate, Some(30));
+        assert_eq!(info.palette, Some(Palette::new([0, 0, 0], [255, 128, 0])));
+        assert_eq!(info.keys["up"], 5);
+        assert!(db.lookup(b"other").is_none());
+    }
+
+    #[test]
+    fn database_local_overrides_embedded() {
+        let mut db = RomDatabase::embedded();
+        db.add_programs(&programs()).unwrap();
+        let local = programs().replace("Test Game", "Local Title")
+                              .replace("\"chip48\"]", "\"chip48\"], \"tickrate\": 7");
+        db.add_programs(&local).unwrap();
+        let info = db.lookup(ROM).unwrap();
+        assert_eq!(info.title, "Local Title");
+        assert_eq!(info.tickrate, Some(7));
+    }
+
+    #[test]
+    fn database_invalid_json() {
+        assert!(RomDatabase::empty().add_programs("{").is_err());
+    }
+}
@@ -0,0 +1,3 @@
+mod database;
+
+pub use self::database::{sha1_hex, RomDatabase, RomInfo};
@@ -0,0 +1,1002 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::asm::mnemonic_with_symbols;
+use crate::cpu::{AccessKind, Chip8Cpu, RAM};
+use crate::symbols::{parse_address, SymbolMap};
+use super::debugger::{Breakpoint, DebugServer, Debugger, HitCondition, Stop, WatchKind, Watchpoint};
+use super::expr::{Expr, LogMessage};
+
+// There's only the one thread.
+const THREAD: i64 = 1;
+// variablesReference values for the two scopes.
+const REGISTERS: i64 = 1;
+const STACK: i64 = 2;
+
+// Reads a Content-Length framed message. None at the end of the stream.
+pub fn read_message(reader: &mut dyn BufRead) -> Result<Option<Value>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>().map_err(|_| format!("bad header {}", header))?);
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| e.to_string())
+}
+
+pub fn write_message(writer: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+// A Debug Adapter Protocol server, for editors like VS Code. Requests
+// come in on a channel, so the emulator never blocks on the client, and
+// responses and events go out framed. Like GdbServer, the emulator calls
+// frame() instead of running the CPU, which doesn't start until the
+// client has sent its configuration.
+pub struct DapServer {
+    requests: Receiver<Value>,
+    out: Box<dyn Write>,
+    seq: u64,
+    debugger: Debugger,
+    symbols: SymbolMap,
+    // Requests that came before the launch, handled once there's a CPU.
+    pending: VecDeque<Value>,
+    // The launch request, answered once the ROM has loaded.
+    launch: Option<Value>,
+    stop_on_entry: bool,
+    // Each kind is replaced by its own request.
+    source_breakpoints: Vec<(usize, Breakpoint)>,
+    instruction_breakpoints: Vec<(usize, Breakpoint)>,
+    configured: bool,
+    halted: bool,
+    killed: bool,
+    // Set at launch and when the CPU is reset, until it runs: nothing has
+    // checked for a breakpoint on the instruction it starts at.
+    entry: bool,
+    // The CPU's cycle count after the last frame. Fewer means a reset.
+    cycles: u64,
+}
+
+impl DapServer {
+    pub fn new(requests: Receiver<Value>, out: Box<dyn Write>) -> Self {
+        DapServer {
+            requests,
+            out,
+            seq: 1,
+            debugger: Debugger::default(),
+            symbols: SymbolMap::default(),
+            pending: VecDeque::new(),
+            launch: None,
+            stop_on_entry: false,
+            source_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            configured: false,
+            halted: false,
+            killed: false,
+            entry: false,
+            cycles: 0,
+        }
+    }
+
+    // Talks to the client over stdin and stdout, which nothing else may
+    // then print to.
+    pub fn stdio() -> Self {
+        let (sender, requests) = channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut reader = stdin.lock();
+            while let Ok(Some(request)) = read_message(&mut reader) {
+                if sender.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+        DapServer::new(requests, Box::new(io::stdout()))
+    }
+
+    // Answers initialize and waits for the launch request, returning the
+    // program to run. None if the client leaves first.
+    pub fn wait_for_launch(&mut self) -> Option<String> {
+        while let Ok(request) = self.requests.recv() {
+            match request["command"].as_str() {
+                Some("initialize") => self.respond(&request, Ok(capabilities())),
+                Some("launch") => match request["arguments"]["program"].as_str() {
+                    Some(program) => {
+                        let program = program.to_string();
+                        self.stop_on_entry = request["arguments"]["stopOnEntry"].as_bool().unwrap_or(false);
+                        self.launch = Some(request);
+                        return Some(program);
+                    },
+                    None => self.respond(&request, Err("launch needs a program".to_string())),
+                },
+                Some("disconnect") | Some("terminate") => {
+                    self.respond(&request, Ok(json!({})));
+                    self.killed = true;
+                    return None;
+                },
+                _ => self.pending.push_back(request),
+            }
+        }
+        None
+    }
+
+    // Answers the launch request with whether the program loaded. Once it
+    // has, the client can send breakpoints.
+    pub fn launched(&mut self, result: Result<SymbolMap, String>) {
+        let request = match self.launch.take() {
+            Some(request) => request,
+            None => return,
+        };
+        match result {
+            Ok(symbols) => {
+                self.symbols = symbols;
+                self.respond(&request, Ok(json!({})));
+                self.event("initialized", json!({}));
+            },
+            Err(e) => {
+                self.respond(&request, Err(e));
+                self.killed = true;
+            },
+        }
+    }
+
+    fn handle(&mut self, request: &Value, cpu: &mut Chip8Cpu, input: [bool; 16]) {
+        let args = &request["arguments"];
+        // A stopped event to send after the response.
+        let mut stopped = None;
+        let result = match request["command"].as_str().unwrap_or("") {
+            "initialize" => Ok(capabilities()),
+            "launch" => Err("already launched".to_string()),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "dataBreakpointInfo" => Ok(data_breakpoint_info(args)),
+            "setDataBreakpoints" => Ok(self.set_data_breakpoints(args)),
+            "configurationDone" => {
+                self.configured = true;
+                self.entry = !self.stop_on_entry;
+                if self.stop_on_entry {
+                    self.halted = true;
+                    stopped = Some(stopped_body("entry", None));
+                }
+                Ok(json!({}))
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace(cpu, args)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "presentationHint": "registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+            ] })),
+            "variables" => self.variables(cpu, args),
+            "evaluate" => self.evaluate(cpu, args),
+            "continue" => {
+                self.halted = false;
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" => {
+                let stop = self.debugger.step_over(cpu, input);
+                stopped = self.resume(stop);
+                Ok(json!({}))
+            },
+            "stepIn" => {
+                let stop = self.debugger.step(cpu, input);
+                stopped = self.resume(Some(stop));
+                Ok(json!({}))
+            },
+            "stepOut" => {
+                let stop = self.debugger.step_out(cpu, input);
+                stopped = self.resume(stop);
+                Ok(json!({}))
+            },
+            // Going back is instant, so these stop straight away.
+            "stepBack" => {
+                let stop = self.debugger.reverse_step(cpu);
+                stopped = self.resume(Some(stop));
+                Ok(json!({}))
+            },
+            "reverseContinue" => {
+                let stop = self.debugger.reverse_continue(cpu);
+                stopped = self.resume(Some(stop));
+                Ok(json!({}))
+            },
+            "pause" => {
+                self.halted = true;
+                stopped = Some(stopped_body("pause", None));
+                Ok(json!({}))
+            },
+            "readMemory" => read_memory(cpu, args),
+            "disassemble" => self.disassemble(cpu, args),
+            "disconnect" | "terminate" => {
+                self.killed = true;
+                Ok(json!({}))
+            },
+            command => Err(format!("{} isn't supported", command)),
+        };
+        self.respond(request, result);
+        self.flush_log();
+        if let Some(body) = stopped {
+            self.event("stopped", body);
+        }
+    }
+
+    // After a step: either it stopped, or the CPU runs on until it does.
+    fn resume(&mut self, stop: Option<Stop>) -> Option<Value> {
+        self.halted = stop.is_some();
+        stop.map(|stop| self.stop_body(&stop))
+    }
+
+    // Runs a frame unless the CPU is stopped. Returns whether it ran.
+    fn run(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16], ipf: u32) -> bool {
+        if self.killed || self.halted || !self.configured {
+            return false;
+        }
+        // Breakpoints are checked after each instruction, so nothing has
+        // checked the one the CPU starts at.
+        if std::mem::take(&mut self.entry) && cpu.cycles() == 0 {
+            if let Some(stop) = self.debugger.check_breakpoint(cpu) {
+                self.flush_log();
+                self.stopped(&stop);
+                return false;
+            }
+        }
+        let stop = self.debugger.run_frame(cpu, input, ipf);
+        self.flush_log();
+        if let Some(stop) = stop {
+            self.stopped(&stop);
+        }
+        true
+    }
+
+    fn stopped(&mut self, stop: &Stop) {
+        self.halted = true;
+        let body = self.stop_body(stop);
+        self.event("stopped", body);
+    }
+
+    fn stop_body(&self, stop: &Stop) -> Value {
+        match stop {
+            Stop::Step => stopped_body("step", None),
+            Stop::Breakpoint(addr) if !self.source_breakpoints.iter().any(|(placed, _)| placed == addr) => {
+                stopped_body("instruction breakpoint", None)
+            },
+            Stop::Breakpoint(_) => stopped_body("breakpoint", None),
+            Stop::Watch(_, access) => {
+                let verb = match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "written",
+                };
+                let text = format!("{} was {}: {:#04X}", address(access.addr as i64), verb, access.value);
+                stopped_body("data breakpoint", Some(text))
+            },
+            Stop::Fault(fault) => stopped_body("exception", Some(fault.clone())),
+            Stop::HistoryStart => stopped_body("step", Some("Reached the start of the recorded history".to_string())),
+        }
+    }
+
+    // Watches, hovers and the debug console all take debugger expressions.
+    // The console also answers `last-write ADDR`.
+    fn evaluate(&self, cpu: &Chip8Cpu, args: &Value) -> Result<Value, String> {
+        let expression = args["expression"].as_str().unwrap_or("");
+        if let Some(addr) = expression.trim().strip_prefix("last-write ").filter(|_| args["context"] == "repl") {
+            let addr = Expr::parse(addr)?.eval(cpu)?.rem_euclid(RAM as i64) as usize;
+            let result = match self.debugger.last_write(addr) {
+                Some(write) => format!("{} was last written with {:#04X} by {} ({}), cycle {}",
+                                       address(addr as i64), write.value, self.describe(write.pc), address(write.pc as i64), write.cycle),
+                None => format!("No write to {} is recorded", address(addr as i64)),
+            };
+            return Ok(json!({ "result": result, "variablesReference": 0 }));
+        }
+        let value = Expr::parse(expression)?.eval(cpu)?;
+        Ok(json!({ "result": format!("{} ({:#X})", value, value), "variablesReference": 0 }))
+    }
+
+    // Lines only resolve in the program's own source. The rest are
+    // answered unverified.
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let ours = self.symbols.source.as_deref().is_some_and(|source| same_file(source, path));
+        let mut placed = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            match (self.symbols.line_address(line), parse_breakpoint(breakpoint)) {
+                (Some(_), Err(e)) => results.push(json!({ "verified": false, "line": line, "message": e })),
+                (Some((addr, line)), Ok(breakpoint)) if ours => {
+                    placed.push((addr, breakpoint));
+                    results.push(json!({ "verified": true, "line": line, "instructionReference": address(addr as i64) }));
+                },
+                (Some(_), _) => results.push(json!({ "verified": false, "line": line, "message": "Not the program being debugged" })),
+                (None, _) => results.push(json!({ "verified": false, "line": line, "message": "No code on or after this line" })),
+            }
+        }
+        if ours {
+            self.source_breakpoints = placed;
+            self.update_breakpoints();
+        }
+        json!({ "breakpoints": results })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        let mut placed = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let addr = breakpoint["instructionReference"].as_str()
+                                                          .and_then(parse_reference)
+                                                          .map(|addr| addr + breakpoint["offset"].as_i64().unwrap_or(0));
+            match (addr.filter(|addr| (0..RAM as i64).contains(addr)), parse_breakpoint(breakpoint)) {
+                (Some(_), Err(e)) => results.push(json!({ "verified": false, "message": e })),
+                (Some(addr), Ok(breakpoint)) => {
+                    placed.push((addr as usize, breakpoint));
+                    results.push(json!({ "verified": true, "instructionReference": address(addr) }));
+                },
+                (None, _) => results.push(json!({ "verified": false, "message": "Not an address in memory" })),
+            }
+        }
+        self.instruction_breakpoints = placed;
+        self.update_breakpoints();
+        json!({ "breakpoints": results })
+    }
+
+    // Replaces every watchpoint, since the client always sends them all.
+    fn set_data_breakpoints(&mut self, args: &Value) -> Value {
+        self.debugger.clear_watchpoints();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let kind = match breakpoint["accessType"].as_str() {
+                Some("read") => WatchKind::Read,
+                Some("readWrite") => WatchKind::Access,
+                _ => WatchKind::Write,
+            };
+            match breakpoint["dataId"].as_str().and_then(parse_data_id) {
+                Some((start, len)) => {
+                    self.debugger.add_watchpoint(Watchpoint { start, len, kind });
+                    results.push(json!({ "verified": true }));
+                },
+                None => results.push(json!({ "verified": false, "message": "Not memory" })),
+            }
+        }
+        json!({ "breakpoints": results })
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+        for (addr, breakpoint) in self.source_breakpoints.iter().chain(&self.instruction_breakpoints) {
+            self.debugger.set_breakpoint(*addr, breakpoint.clone());
+        }
+    }
+
+    // The innermost frame is PC. Each return address on the stack makes
+    // another, at the CALL before it.
+    fn stack_trace(&self, cpu: &Chip8Cpu, args: &Value) -> Value {
+        let calls = cpu.stack().iter().rev().map(|&ret| (ret + RAM - 2) % RAM);
+        let frames: Vec<Value> = std::iter::once(cpu.pc())
+            .chain(calls)
+            .enumerate()
+            .map(|(id, addr)| self.stack_frame(id, addr))
+            .collect();
+        let total = frames.len();
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => total,
+        };
+        let frames: Vec<Value> = frames.into_iter().skip(start).take(levels).collect();
+        json!({ "stackFrames": frames, "totalFrames": total })
+    }
+
+    fn stack_frame(&self, id: usize, addr: usize) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": self.describe(addr),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": address(addr as i64),
+        });
+        if let (Some(line), Some(source)) = (self.symbols.line(addr), self.symbols.source.as_deref()) {
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+            frame["source"] = source_json(source);
+        }
+        frame
+    }
+
+    // The nearest label and how far past it, or just the address.
+    fn describe(&self, addr: usize) -> String {
+        self.symbols.describe(addr).unwrap_or_else(|| address(addr as i64))
+    }
+
+    fn variables(&self, cpu: &Chip8Cpu, args: &Value) -> Result<Value, String> {
+        let regs = cpu.registers();
+        let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS) => {
+                let mut variables: Vec<Value> = regs.v.iter().enumerate().map(|(x, &v)| {
+                    variable(&format!("V{:X}", x), format!("{:#04X} ({})", v, v))
+                }).collect();
+                let mut i = variable("I", address(regs.i as i64));
+                i["memoryReference"] = json!(address(regs.i as i64));
+                variables.push(i);
+                let mut pc = variable("PC", format!("{} {}", address(regs.pc as i64), self.describe(regs.pc)));
+                pc["memoryReference"] = json!(address(regs.pc as i64));
+                variables.push(pc);
+                variables.push(variable("SP", regs.sp.to_string()));
+                variables.push(variable("DT", regs.dt.to_string()));
+                variables.push(variable("ST", regs.st.to_string()));
+                variables
+            },
+            Some(STACK) => cpu.stack().iter().enumerate().map(|(level, &ret)| {
+                variable(&level.to_string(), format!("{} {}", address(ret as i64), self.describe(ret)))
+            }).collect(),
+            _ => return Err("no such variables".to_string()),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn disassemble(&self, cpu: &Chip8Cpu, args: &Value) -> Result<Value, String> {
+        let start = args["memoryReference"].as_str().and_then(parse_reference).ok_or("bad memory reference")?
+            + args["offset"].as_i64().unwrap_or(0)
+            + args["instructionOffset"].as_i64().unwrap_or(0) * 2;
+        let count = args["instructionCount"].as_i64().unwrap_or(0).max(0);
+        let instructions: Vec<Value> = (0..count).map(|n| start + n * 2).map(|addr| {
+            if !(0..RAM as i64).contains(&addr) {
+                return json!({ "address": address(addr), "instruction": "", "presentationHint": "invalid" });
+            }
+            let addr = addr as usize;
+            let op = (cpu.peek(addr) as u16) << 8 | cpu.peek(addr + 1) as u16;
+            let mut instruction = json!({
+                "address": address(addr as i64),
+                "instructionBytes": format!("{:02X} {:02X}", op >> 8, op & 0xFF),
+                "instruction": mnemonic_with_symbols(op, &self.symbols),
+            });
+            if let Some(label) = self.symbols.label(addr) {
+                instruction["symbol"] = json!(label);
+            }
+            if let (Some(line), Some(source)) = (self.symbols.line(addr), self.symbols.source.as_deref()) {
+                instruction["line"] = json!(line);
+                instruction["location"] = source_json(source);
+            }
+            instruction
+        }).collect();
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    // Log-point messages go to the debug console.
+    fn flush_log(&mut self) {
+        for message in self.debugger.take_log() {
+            self.output(&message);
+        }
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    // A client that can't be written to is gone.
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        if write_message(&mut *self.out, &message).is_err() {
+            self.killed = true;
+        }
+    }
+}
+
+impl DebugServer for DapServer {
+    fn frame(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16], ipf: u32) -> bool {
+        if cpu.cycles() < self.cycles {
+            self.entry = true;
+        }
+        while let Some(request) = self.pending.pop_front() {
+            self.handle(&request, cpu, input);
+        }
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => self.handle(&request, cpu, input),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.killed = true;
+                    break;
+                },
+            }
+        }
+
+        let ran = self.run(cpu, input, ipf);
+        self.cycles = cpu.cycles();
+        ran
+    }
+
+    fn killed(&self) -> bool { self.killed }
+
+    fn output(&mut self, text: &str) {
+        self.event("output", json!({ "category": "console", "output": format!("{}\n", text) }));
+    }
+}
+
+// However the session ends, the client hears that it's over.
+impl Drop for DapServer {
+    fn drop(&mut self) {
+        self.event("exited", json!({ "exitCode": 0 }));
+        self.event("terminated", json!({}));
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsReadMemoryRequest": true,
+        "supportsDisassembleRequest": true,
+        "supportsConditionalBreakpoints": true,
+        "supportsHitConditionalBreakpoints": true,
+        "supportsLogPoints": true,
+        "supportsEvaluateForHovers": true,
+        "supportsDataBreakpoints": true,
+        "supportsDataBreakpointBytes": true,
+        "supportsTerminateRequest": true,
+        "supportsStepBack": true,
+    })
+}
+
+// The condition, hit condition and log message any breakpoint can have.
+fn parse_breakpoint(args: &Value) -> Result<Breakpoint, String> {
+    let text = |key: &str| args[key].as_str().filter(|text| !text.trim().is_empty()).map(str::to_string);
+    let mut breakpoint = Breakpoint::default();
+    breakpoint.condition = text("condition").map(|text| Expr::parse(&text)).transpose()?;
+    breakpoint.hit_condition = text("hitCondition").map(|text| HitCondition::parse(&text)).transpose()?;
+    breakpoint.log_message = text("logMessage").map(|text| LogMessage::parse(&text)).transpose()?;
+    Ok(breakpoint)
+}
+
+// Only memory can be watched, so only addresses get a dataId: the start
+// and length of the range.
+fn data_breakpoint_info(args: &Value) -> Value {
+    let start = match args["name"].as_str().and_then(parse_reference) {
+        Some(start) if args["asAddress"] == true && (0..RAM as i64).contains(&start) => start as usize,
+        _ => return json!({ "dataId": null, "description": "Only memory addresses can be watched" }),
+    };
+    let len = args["bytes"].as_u64().unwrap_or(1).clamp(1, RAM as u64) as usize;
+    json!({
+        "dataId": format!("{}/{}", address(start as i64), len),
+        "description": format!("{} bytes at {}", len, address(start as i64)),
+        "accessTypes": ["read", "write", "readWrite"],
+    })
+}
+
+fn parse_data_id(id: &str) -> Option<(usize, usize)> {
+    let (start, len) = id.split_once('/')?;
+    let start = parse_reference(start).filter(|start| (0..RAM as i64).contains(start))?;
+    Some((start as usize, len.parse().ok()?))
+}
+
+fn stopped_body(reason: &str, text: Option<String>) -> Value {
+    let mut body = json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true });
+    if let Some(text) = text {
+        body["text"] = json!(text);
+    }
+    body
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn source_json(path: &str) -> Value {
+    let name = Path::new(path).file_name().map(|name| name.to_string_lossy().to_string());
+    json!({ "name": name.unwrap_or_else(|| path.to_string()), "path": path })
+}
+
+// Memory references are hex addresses, like the ones we hand out.
+fn address(addr: i64) -> String {
+    if addr < 0 {
+        format!("-{:#06X}", -addr)
+    } else {
+        format!("{:#06X}", addr)
+    }
+}
+
+// Signed, since a reference's offset can take it below 0.
+fn parse_reference(text: &str) -> Option<i64> {
+    i64::try_from(parse_address(text).ok()?).ok()
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Only what's inside RAM is readable. The rest is counted as unreadable.
+fn read_memory(cpu: &Chip8Cpu, args: &Value) -> Result<Value, String> {
+    let start = args["memoryReference"].as_str().and_then(parse_reference).ok_or("bad memory reference")?
+        + args["offset"].as_i64().unwrap_or(0);
+    let count = args["count"].as_i64().unwrap_or(0).max(0);
+    let first = start.clamp(0, RAM as i64);
+    let end = (start + count).clamp(first, RAM as i64);
+    let data: Vec<u8> = (first..end).map(|addr| cpu.peek(addr as usize)).collect();
+    Ok(json!({
+        "address": address(first),
+        "data": base64(&data),
+        "unreadableBytes": count - data.len() as i64,
+    }))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::sync::mpsc::Sender;
+    use crate::asm::mnemonic;
+    use crate::cpu::Registers;
+    use crate::octo::compile_with_symbols;
+
+    const SOURCE: &str = ": main
+  v0 := 5
+  loop
+    v0 += 1
+    sub
+  again
+
+: sub
+  i := 0x300
+  save v0
+  return
+";
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Output {
+        // Everything sent since the last call.
+        fn take(&self) -> Vec<Value> {
+            let bytes = std::mem::take(&mut *self.0.borrow_mut());
+            let mut reader = Cursor::new(bytes);
+            let mut messages = Vec::new();
+            while let Some(message) = read_message(&mut reader).unwrap() {
+                messages.push(message);
+            }
+            messages
+        }
+    }
+
+    struct Session {
+        requests: Sender<Value>,
+        server: DapServer,
+        output: Output,
+        cpu: Chip8Cpu,
+        seq: u64,
+        // Events that came with responses, for stopped() to find.
+        events: Vec<Value>,
+        // Debug console output seen by stopped().
+        console: Vec<String>,
+    }
+
+    impl Session {
+        // Launches the test program and runs through configuration.
+        fn start(launch: Value, breakpoints: &[usize]) -> Self {
+            let (requests, receiver) = channel();
+            let output = Output::default();
+            let server = DapServer::new(receiver, Box::new(output.clone()));
+            let mut session = Session { requests, server, output, cpu: Chip8Cpu::new(), seq: 0, events: Vec::new(), console: Vec::new() };
+
+            session.send("initialize", json!({ "adapterID": "chip8" }));
+            session.send("launch", launch);
+            assert_eq!(session.server.wait_for_launch().as_deref(), Some("game.8o"));
+            let (rom, mut symbols) = compile_with_symbols(SOURCE).unwrap();
+            symbols.source = Some("game.8o".to_string());
+            session.cpu.load_rom_bytes(&rom).unwrap();
+            session.server.launched(Ok(symbols));
+            let messages = session.output.take();
+            assert_eq!(messages[0]["body"]["supportsInstructionBreakpoints"], true);
+            assert_eq!(messages[1]["command"], "launch");
+            assert_eq!(messages[2]["event"], "initialized");
+
+            let lines: Vec<Value> = breakpoints.iter().map(|line| json!({ "line": line })).collect();
+            session.request("setBreakpoints", json!({ "source": { "path": "game.8o" }, "breakpoints": lines }));
+            session.send("configurationDone", json!({}));
+            session
+        }
+
+        fn send(&mut self, command: &str, arguments: Value) {
+            self.seq += 1;
+            self.requests.send(json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })).unwrap();
+        }
+
+        // Sends a request and returns the response's body.
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.send(command, arguments);
+            self.frame();
+            let (responses, events): (Vec<Value>, Vec<Value>) =
+                self.output.take().into_iter().partition(|message| message["request_seq"] == self.seq);
+            self.events.extend(events);
+            assert_eq!(responses[0]["success"], true, "{}", responses[0]);
+            responses[0]["body"].clone()
+        }
+
+        fn frame(&mut self) -> bool {
+            self.server.frame(&mut self.cpu, [false; 16], 100)
+        }
+
+        // Runs until the server reports a stop.
+        fn stopped(&mut self) -> Value {
+            for _ in 0..100 {
+                let mut messages = std::mem::take(&mut self.events);
+                self.frame();
+                messages.extend(self.output.take());
+                self.console.extend(messages.iter()
+                                            .filter(|message| message["event"] == "output")
+                                            .map(|message| message["body"]["output"].as_str().unwrap().to_string()));
+                if let Some(event) = messages.iter().find(|message| message["event"] == "stopped") {
+                    return event["body"].clone();
+                }
+            }
+            panic!("never stopped");
+        }
+    }
+
+    #[test]
+    fn dap_framing() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &json!({ "seq": 1, "text": "é" })).unwrap();
+        assert!(bytes.starts_with(b"Content-Length: 21\r\n\r\n"));
+        let mut reader = Cursor::new(bytes);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "seq": 1, "text": "é" })));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+        assert_eq!(base64(b"CHIP-8"), "Q0hJUC04");
+        assert_eq!(base64(&[0xFF, 0x00]), "/wA=");
+    }
+
+    #[test]
+    fn dap_source_breakpoints_and_inspection() {
+        // Neither line 7 nor the label on 8 has code, so that breakpoint
+        // moves to the first line of sub.
+        let mut session = Session::start(json!({ "program": "game.8o" }), &[]);
+        let body = session.request("setBreakpoints", json!({
+            "source": { "path": "game.8o" },
+            "breakpoints": [{ "line": 7 }, { "line": 40 }],
+        }));
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        assert_eq!(body["breakpoints"][0]["line"], 9);
+        assert_eq!(body["breakpoints"][1]["verified"], false);
+
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        let trace = session.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["totalFrames"], 2);
+        let frames = &trace["stackFrames"];
+        assert_eq!(frames[0]["name"], "sub");
+        assert_eq!(frames[0]["line"], 9);
+        assert_eq!(frames[0]["source"]["path"], "game.8o");
+        assert_eq!(frames[1]["name"], "main+4");
+        assert_eq!(frames[1]["line"], 5);
+
+        let registers = session.request("variables", json!({ "variablesReference": REGISTERS }));
+        assert_eq!(registers["variables"][0]["value"], "0x06 (6)");
+        let stack = session.request("variables", json!({ "variablesReference": STACK }));
+        assert_eq!(stack["variables"][0]["value"], "0x0206 main+6");
+
+        // Step over the store, then read what it wrote.
+        session.send("stepIn", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "step");
+        session.send("next", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "step");
+        let memory = session.request("readMemory", json!({ "memoryReference": "0x300", "count": 2 }));
+        assert_eq!(memory["data"], base64(&[6, 0]));
+        assert_eq!(memory["unreadableBytes"], 0);
+
+        let disassembly = session.request("disassemble", json!({
+            "memoryReference": "0x200",
+            "instructionCount": 2,
+        }));
+        let instructions = &disassembly["instructions"];
+        assert_eq!(instructions[0]["instruction"], mnemonic(0x6005));
+        assert_eq!(instructions[0]["symbol"], "main");
+        assert_eq!(instructions[1]["line"], 4);
+
+        // Out of sub and back to main, where the next call stops again.
+        session.send("stepOut", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "step");
+        assert_eq!(session.cpu.pc(), 0x206);
+        session.send("continue", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!(session.cpu.registers().v[0], 7);
+    }
+
+    #[test]
+    fn dap_breakpoint_on_entry() {
+        // Line 2 is main's first instruction, at 0x200.
+        let mut session = Session::start(json!({ "program": "game.8o" }), &[2]);
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!((session.cpu.pc(), session.cpu.cycles()), (0x200, 0));
+        let trace = session.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["stackFrames"][0]["line"], 2);
+
+        // Continuing runs on from it, without stopping there again.
+        session.request("continue", json!({ "threadId": 1 }));
+        for _ in 0..5 {
+            assert!(session.frame());
+        }
+        assert!(session.output.take().iter().all(|message| message["event"] != "stopped"));
+        assert!(session.cpu.cycles() > 0);
+
+        // A reset starts at the breakpoint again.
+        let (rom, _) = compile_with_symbols(SOURCE).unwrap();
+        session.cpu = Chip8Cpu::new();
+        session.cpu.load_rom_bytes(&rom).unwrap();
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!((session.cpu.pc(), session.cpu.cycles()), (0x200, 0));
+    }
+
+    #[test]
+    fn dap_conditions_and_log_points() {
+        let mut session = Session::start(json!({ "program": "game.8o" }), &[]);
+        let body = session.request("setBreakpoints", json!({
+            "source": { "path": "game.8o" },
+            "breakpoints": [
+                { "line": 4, "logMessage": "v0 is {v0}", "hitCondition": "% 2" },
+                { "line": 9, "condition": "v0 == 9" },
+                { "line": 10, "condition": "v0 ==" },
+            ],
+        }));
+        assert_eq!(body["breakpoints"][1]["verified"], true);
+        assert_eq!(body["breakpoints"][2]["verified"], false);
+        assert_eq!(body["breakpoints"][2]["message"], "unexpected end of expression");
+
+        // The log-point on line 4 sees V0 count 5, 6, 7, 8 before sub is
+        // called with 9.
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!(session.cpu.registers().v[0], 9);
+        assert_eq!(session.console, ["v0 is 6\n", "v0 is 8\n"]);
+
+        let value = session.request("evaluate", json!({ "expression": "v0 * 2 + [0x300]", "context": "watch" }));
+        assert_eq!(value["result"], "26 (0x1A)");
+        session.send("evaluate", json!({ "expression": "v0 +" }));
+        session.frame();
+        assert_eq!(session.output.take()[0]["success"], false);
+    }
+
+    #[test]
+    fn dap_step_back_and_last_write() {
+        let mut session = Session::start(json!({ "program": "game.8o" }), &[9]);
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        session.send("continue", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!(session.cpu.registers().v[0], 7);
+
+        // Back over the call, then to the breakpoint the time before.
+        session.send("stepBack", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "step");
+        assert_eq!(session.cpu.pc(), 0x204);
+        let write = session.request("evaluate", json!({ "expression": "last-write 0x300", "context": "repl" }));
+        assert_eq!(write["result"], "0x0300 was last written with 0x06 by sub+2 (0x020A), cycle 4");
+        session.send("reverseContinue", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!(session.cpu.registers().v[0], 6);
+        let write = session.request("evaluate", json!({ "expression": "last-write 0x300", "context": "repl" }));
+        assert_eq!(write["result"], "No write to 0x0300 is recorded");
+
+        session.send("reverseContinue", json!({ "threadId": 1 }));
+        let stop = session.stopped();
+        assert_eq!(stop["text"], "Reached the start of the recorded history");
+        assert_eq!(session.cpu.pc(), 0x200);
+        session.send("continue", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "breakpoint");
+        assert_eq!(session.cpu.registers().v[0], 6);
+    }
+
+    #[test]
+    fn dap_entry_pause_and_faults() {
+        let mut session = Session::start(json!({ "program": "game.8o", "stopOnEntry": true }), &[]);
+        assert_eq!(session.stopped()["reason"], "entry");
+        assert!(!session.frame());
+        assert_eq!(session.cpu.pc(), 0x200);
+
+        let body = session.request("setInstructionBreakpoints", json!({
+            "breakpoints": [{ "instructionReference": "0x200", "offset": 4 }, { "instructionReference": "nowhere" }],
+        }));
+        assert_eq!(body["breakpoints"][0]["instructionReference"], "0x0204");
+        assert_eq!(body["breakpoints"][1]["verified"], false);
+        session.send("continue", json!({ "threadId": 1 }));
+        assert_eq!(session.stopped()["reason"], "instruction breakpoint");
+        assert_eq!(session.cpu.pc(), 0x204);
+
+        // sub stores V0 at 0x300.
+        let info = session.request("dataBreakpointInfo", json!({ "name": "0x300", "asAddress": true, "bytes": 2 }));
+        assert_eq!(info["dataId"], "0x0300/2");
+        let info = session.request("dataBreakpointInfo", json!({ "name": "V0", "variablesReference": REGISTERS }));
+        assert_eq!(info["dataId"], Value::Null);
+        session.request("setInstructionBreakpoints", json!({ "breakpoints": [] }));
+        let body = session.request("setDataBreakpoints", json!({
+            "breakpoints": [{ "dataId": "0x0300/2", "accessType": "write" }],
+        }));
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        session.send("continue", json!({ "threadId": 1 }));
+        let stop = session.stopped();
+        assert_eq!(stop["reason"], "data breakpoint");
+        assert_eq!(stop["text"], "0x0300 was written: 0x06");
+        session.request("setDataBreakpoints", json!({ "breakpoints": [] }));
+        let regs = session.cpu.registers();
+        session.cpu.set_registers(Registers { pc: 0x204, sp: 0, ..regs });
+
+        // A RET in place of the loop's CALL faults on the empty stack.
+        session.request("setInstructionBreakpoints", json!({ "breakpoints": [] }));
+        session.cpu.poke(0x204, 0x00);
+        session.cpu.poke(0x205, 0xEE);
+        session.send("continue", json!({ "threadId": 1 }));
+        let stop = session.stopped();
+        assert_eq!(stop["reason"], "exception");
+        assert!(stop["text"].as_str().unwrap().contains("empty stack"));
+
+        session.request("disconnect", json!({}));
+        assert!(session.server.killed());
+        drop(session.server);
+        let events: Vec<Value> = session.output.take().into_iter().map(|message| message["event"].clone()).collect();
+        assert_eq!(events, [json!("exited"), json!("terminated")]);
+    }
+}
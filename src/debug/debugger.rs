@@ -0,0 +1,495 @@
+use std::collections::BTreeMap;
+
+use crate::cpu::{AccessKind, Chip8Cpu, MemoryAccess, RAM};
+use super::expr::{Expr, LogMessage};
+use super::history::{History, WriteRecord};
+
+// Why the debugger stopped the CPU.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    // A single step finished.
+    Step,
+    // PC reached a breakpoint.
+    Breakpoint(usize),
+    // The program accessed memory a watchpoint covers.
+    Watch(Watchpoint, MemoryAccess),
+    Fault(String),
+    // Running backward reached the oldest point recorded.
+    HistoryStart,
+}
+
+// Which hits of a breakpoint count, once its condition holds: the Nth and
+// after, only the Nth, or every Nth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HitCondition {
+    From(u64),
+    Equal(u64),
+    Every(u64),
+}
+
+impl HitCondition {
+    // "N" or ">= N", "> N", "== N" and "% N".
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (make, number): (fn(u64) -> Self, &str) = if let Some(n) = text.strip_prefix(">=") {
+            (HitCondition::From, n)
+        } else if let Some(n) = text.strip_prefix('>') {
+            (|n| HitCondition::From(n + 1), n)
+        } else if let Some(n) = text.strip_prefix("==") {
+            (HitCondition::Equal, n)
+        } else if let Some(n) = text.strip_prefix('%') {
+            (HitCondition::Every, n)
+        } else {
+            (HitCondition::From, text)
+        };
+        match number.trim().parse() {
+            Ok(n) if n > 0 || make(0) != HitCondition::Every(0) => Ok(make(n)),
+            _ => Err(format!("bad hit count {}", text)),
+        }
+    }
+
+    fn matches(self, hits: u64) -> bool {
+        match self {
+            HitCondition::From(n) => hits >= n,
+            HitCondition::Equal(n) => hits == n,
+            HitCondition::Every(n) => hits.is_multiple_of(n),
+        }
+    }
+}
+
+// A breakpoint only counts a hit when its condition holds, and only stops
+// on the hits its hit condition picks. One with a log message never stops,
+// and logs it instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Breakpoint {
+    pub condition: Option<Expr>,
+    pub hit_condition: Option<HitCondition>,
+    pub log_message: Option<LogMessage>,
+    hits: u64,
+}
+
+impl Breakpoint {
+    pub fn hits(&self) -> u64 { self.hits }
+
+    // A condition that can't be evaluated stops, to say why.
+    fn hit(&mut self, cpu: &Chip8Cpu, log: &mut Vec<String>) -> bool {
+        match self.condition.as_ref().map(|condition| (condition, condition.eval(cpu))) {
+            Some((_, Ok(0))) => return false,
+            Some((condition, Err(e))) => {
+                log.push(format!("Breakpoint condition {} failed: {}", condition, e));
+                return true;
+            },
+            _ => {},
+        }
+        self.hits += 1;
+        if !self.hit_condition.is_none_or(|hit_condition| hit_condition.matches(self.hits)) {
+            return false;
+        }
+        match &self.log_message {
+            Some(message) => {
+                log.push(message.format(cpu));
+                false
+            },
+            None => true,
+        }
+    }
+}
+
+// Which accesses a watchpoint stops on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+// A range of memory to stop on accesses to. Writes stop it even when they
+// don't change the value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub len: usize,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind = match (self.kind, access.kind) {
+            (WatchKind::Access, _) => true,
+            (WatchKind::Read, kind) => kind == AccessKind::Read,
+            (WatchKind::Write, kind) => kind == AccessKind::Write,
+        };
+        // Ranges wrap around memory like addresses do.
+        kind && (access.addr + RAM - self.start % RAM) % RAM < self.len
+    }
+}
+
+// A debugger frontend that the emulator hands each frame to instead of
+// running the CPU itself.
+pub trait DebugServer {
+    // Serves the client and runs a frame unless it has the CPU halted.
+    // Returns whether the CPU ran.
+    fn frame(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16], ipf: u32) -> bool;
+
+    // True once the client has ended the session.
+    fn killed(&self) -> bool;
+
+    // A message for the user, which goes to the client when stdout is
+    // the client's connection.
+    fn output(&mut self, text: &str) {
+        println!("{}", text);
+    }
+}
+
+// Runs a CPU an instruction or a frame at a time, stopping where the user
+// asked to. The frontends (GDB, DAP) only translate their protocol into
+// calls on this.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Where step_over or step_out stops: an address, at a stack depth.
+    finish: Option<(usize, usize)>,
+    // Messages from log-points, for the frontend to show.
+    log: Vec<String>,
+    history: History,
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.set_breakpoint(addr, Breakpoint::default());
+    }
+
+    // Replaces any breakpoint already there, and its hit count.
+    pub fn set_breakpoint(&mut self, addr: usize, breakpoint: Breakpoint) {
+        self.breakpoints.insert(addr, breakpoint);
+    }
+
+    pub fn breakpoint(&self, addr: usize) -> Option<&Breakpoint> {
+        self.breakpoints.get(&addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watchpoint) {
+        self.watchpoints.push(watch);
+    }
+
+    pub fn remove_watchpoint(&mut self, watch: Watchpoint) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watch);
+        self.watchpoints.len() != before
+    }
+
+    pub fn take_log(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.log)
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Runs one instruction. Stops with Step unless something else happened.
+    pub fn step(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16]) -> Stop {
+        self.execute(cpu, input).unwrap_or(Stop::Step)
+    }
+
+    // Steps, but runs a CALL until it returns. None means the CPU is
+    // running toward the return, and run_frame will stop there.
+    pub fn step_over(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16]) -> Option<Stop> {
+        if cpu.peek(cpu.pc()) >> 4 != 0x2 {
+            return Some(self.step(cpu, input));
+        }
+        self.finish = Some(((cpu.pc() + 2) % RAM, cpu.stack().len()));
+        None
+    }
+
+    // Runs until the current subroutine returns, like step_over. Steps if
+    // there's nothing to return from.
+    pub fn step_out(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16]) -> Option<Stop> {
+        match cpu.stack().last() {
+            Some(&ret) => {
+                self.finish = Some((ret, cpu.stack().len() - 1));
+                None
+            },
+            None => Some(self.step(cpu, input)),
+        }
+    }
+
+    // One frame, like Chip8Cpu::run_frame, unless something stops it
+    // first. The timers only tick once the frame is complete. After going
+    // back in time, frames run as they were recorded until they catch up.
+    pub fn run_frame(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16], ipf: u32) -> Option<Stop> {
+        if let Some(fault) = cpu.fault() {
+            return Some(Stop::Fault(fault.to_string()));
+        }
+        let mut steps = 0;
+        while steps < ipf || self.history.replaying() {
+            if self.history.frame_ends() {
+                break;
+            }
+            if let Some(stop) = self.execute(cpu, input) {
+                return Some(stop);
+            }
+            steps += 1;
+            if cpu.waiting_for_vblank() && !self.history.replaying() {
+                break;
+            }
+        }
+        self.history.tick(cpu);
+        None
+    }
+
+    // Goes back to before the last instruction.
+    pub fn reverse_step(&mut self, cpu: &mut Chip8Cpu) -> Stop {
+        self.finish = None;
+        match self.history.last_step() {
+            Some(time) => {
+                self.history.travel(cpu, time);
+                Stop::Step
+            },
+            None => self.history_start(cpu),
+        }
+    }
+
+    // Goes back to the last place running forward would have stopped:
+    // after an instruction that hit a watchpoint, or at a breakpoint whose
+    // condition held. Hit counts and log-points don't matter going back.
+    pub fn reverse_continue(&mut self, cpu: &mut Chip8Cpu) -> Stop {
+        self.finish = None;
+        let (breakpoints, watchpoints) = (&self.breakpoints, &self.watchpoints);
+        let found = self.history.find_back(|cpu| {
+            for access in cpu.accesses() {
+                if let Some(watch) = watchpoints.iter().find(|watch| watch.matches(access)) {
+                    return Some(Stop::Watch(*watch, *access));
+                }
+            }
+            breakpoints.get(&cpu.pc())
+                       .filter(|breakpoint| breakpoint.log_message.is_none())
+                       .filter(|breakpoint| breakpoint.condition.as_ref().is_none_or(|condition| condition.eval(cpu) != Ok(0)))
+                       .map(|_| Stop::Breakpoint(cpu.pc()))
+        });
+        match found {
+            Some((time, stop)) => {
+                self.history.travel(cpu, time);
+                stop
+            },
+            None => self.history_start(cpu),
+        }
+    }
+
+    // The last write the program made to an address, as far back as the
+    // history goes.
+    pub fn last_write(&self, addr: usize) -> Option<&WriteRecord> {
+        self.history.last_write(addr)
+    }
+
+    // Frontends call this after changing the CPU's registers or memory, so
+    // going back and forward again keeps the change.
+    pub fn edited(&mut self, cpu: &Chip8Cpu) {
+        self.history.edited(cpu);
+    }
+
+    // Stops at a breakpoint on the instruction the CPU is about to run,
+    // counting the hit.
+    pub fn check_breakpoint(&mut self, cpu: &Chip8Cpu) -> Option<Stop> {
+        let breakpoint = self.breakpoints.get_mut(&cpu.pc())?;
+        if breakpoint.hit(cpu, &mut self.log) { Some(Stop::Breakpoint(cpu.pc())) } else { None }
+    }
+
+    fn history_start(&mut self, cpu: &mut Chip8Cpu) -> Stop {
+        self.history.travel(cpu, 0);
+        Stop::HistoryStart
+    }
+
+    // Breakpoints are checked after each instruction, so running from one
+    // carries on past it, and one on the instruction a program starts at
+    // needs check_breakpoint before the first step.
+    // Any stop ends a step_over or step_out, wherever it happens.
+    fn execute(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16]) -> Option<Stop> {
+        self.history.step(cpu, input);
+        let stop = self.check(cpu);
+        if stop.is_some() {
+            self.finish = None;
+        }
+        stop
+    }
+
+    fn check(&mut self, cpu: &Chip8Cpu) -> Option<Stop> {
+        if let Some(fault) = cpu.fault() {
+            return Some(Stop::Fault(fault.to_string()));
+        }
+        for access in cpu.accesses() {
+            if let Some(watch) = self.watchpoints.iter().find(|watch| watch.matches(access)) {
+                return Some(Stop::Watch(*watch, *access));
+            }
+        }
+        if let Some(stop) = self.check_breakpoint(cpu) {
+            return Some(stop);
+        }
+        if self.finish == Some((cpu.pc(), cpu.stack().len())) {
+            return Some(Stop::Step);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::PROG_START;
+
+    // LD V0, 5; ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x202
+    const PROGRAM: [u8; 10] = [0x60, 0x05, 0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02];
+
+    fn cpu() -> Chip8Cpu {
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&PROGRAM).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn debugger_breakpoints() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint(0x206);
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Breakpoint(0x206)));
+        assert_eq!(cpu.pc(), 0x206);
+
+        // Carrying on runs past it and round the loop back to it.
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Breakpoint(0x206)));
+        assert_eq!(cpu.registers().v[0], 7);
+
+        assert!(debugger.remove_breakpoint(0x206));
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 8), None);
+        assert_eq!(debugger.step(&mut cpu, [false; 16]), Stop::Step);
+    }
+
+    #[test]
+    fn debugger_conditions_and_hit_counts() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        // V0 counts up from 6 each time round the loop.
+        let condition = Some(Expr::parse("v0 >= 8 && [i] == v0 - 1").unwrap());
+        let hit_condition = Some(HitCondition::Every(2));
+        debugger.set_breakpoint(0x204, Breakpoint { condition, hit_condition, ..Breakpoint::default() });
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Breakpoint(0x204)));
+        assert_eq!(cpu.registers().v[0], 9);
+        assert_eq!(debugger.breakpoint(0x204).unwrap().hits(), 2);
+
+        // A log-point logs each hit and carries on.
+        let log_message = Some(LogMessage::parse("v0={v0}").unwrap());
+        let hit_condition = Some(HitCondition::parse("> 1").unwrap());
+        debugger.set_breakpoint(0x204, Breakpoint { log_message, hit_condition, ..Breakpoint::default() });
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 16), None);
+        assert_eq!(debugger.take_log(), ["v0=11", "v0=12", "v0=13"]);
+        assert!(debugger.take_log().is_empty());
+
+        let condition = Some(Expr::parse("1 / (v0 - 14)").unwrap());
+        debugger.set_breakpoint(0x204, Breakpoint { condition, ..Breakpoint::default() });
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Breakpoint(0x204)));
+        assert_eq!(debugger.take_log(), ["Breakpoint condition 1 / (v0 - 14) failed: division by zero"]);
+    }
+
+    #[test]
+    fn debugger_hit_conditions() {
+        assert_eq!(HitCondition::parse("100"), Ok(HitCondition::From(100)));
+        assert_eq!(HitCondition::parse(">= 3"), Ok(HitCondition::From(3)));
+        assert_eq!(HitCondition::parse(">3"), Ok(HitCondition::From(4)));
+        assert_eq!(HitCondition::parse("== 3"), Ok(HitCondition::Equal(3)));
+        assert_eq!(HitCondition::parse("%3"), Ok(HitCondition::Every(3)));
+        assert!(HitCondition::parse("% 0").is_err());
+        assert!(HitCondition::parse("lots").is_err());
+        assert!(HitCondition::Every(3).matches(6));
+        assert!(!HitCondition::Equal(3).matches(4));
+    }
+
+    #[test]
+    fn debugger_watchpoints() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::default();
+        let watch = Watchpoint { start: 0x300, len: 1, kind: WatchKind::Write };
+        debugger.add_watchpoint(watch);
+        let access = MemoryAccess { addr: 0x300, value: 6, kind: AccessKind::Write };
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Watch(watch, access)));
+        assert_eq!(cpu.pc(), PROG_START + 8);
+        assert_eq!(cpu.peek(0x300), 6);
+        assert!(debugger.remove_watchpoint(watch));
+        assert!(!debugger.remove_watchpoint(watch));
+    }
+
+    #[test]
+    fn debugger_read_watchpoints() {
+        let mut cpu = Chip8Cpu::new();
+        // LD I, 0x300; LD [I], V1; LD I, 0x301; LD V0, [I]
+        cpu.load_rom_bytes(&[0xA3, 0x00, 0xF1, 0x55, 0xA3, 0x01, 0xF0, 0x65]).unwrap();
+        let mut debugger = Debugger::default();
+        // The store writes 0x300 and 0x301, but only the load reads.
+        let watch = Watchpoint { start: 0x2FF, len: 3, kind: WatchKind::Read };
+        debugger.add_watchpoint(watch);
+        let access = MemoryAccess { addr: 0x301, value: 0, kind: AccessKind::Read };
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Watch(watch, access)));
+        assert_eq!(cpu.pc(), PROG_START + 8);
+
+        let watch = Watchpoint { start: 0xFFF, len: 2, kind: WatchKind::Access };
+        assert!(watch.matches(&MemoryAccess { addr: 0x000, value: 0, kind: AccessKind::Write }));
+        assert!(!watch.matches(&MemoryAccess { addr: 0x001, value: 0, kind: AccessKind::Read }));
+    }
+
+    #[test]
+    fn debugger_step_over_and_out() {
+        // CALL 0x206; JP 0x202; JP 0x204; ADD V0, 1; CALL 0x20C; RET; ADD V1, 1; RET
+        let program = [0x22, 0x06, 0x12, 0x02, 0x12, 0x04, 0x70, 0x01,
+                       0x22, 0x0C, 0x00, 0xEE, 0x71, 0x01, 0x00, 0xEE];
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&program).unwrap();
+        let mut debugger = Debugger::default();
+        assert_eq!(debugger.step_over(&mut cpu, [false; 16]), None);
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Step));
+        assert_eq!(cpu.pc(), 0x202);
+        assert_eq!(cpu.registers().v[..2], [1, 1]);
+        assert_eq!(debugger.step_over(&mut cpu, [false; 16]), Some(Stop::Step));
+
+        // Out of the inner call only, then the outer one.
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&program).unwrap();
+        for _ in 0..3 {
+            debugger.step(&mut cpu, [false; 16]);
+        }
+        assert_eq!(cpu.pc(), 0x20C);
+        assert_eq!(debugger.step_out(&mut cpu, [false; 16]), None);
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Step));
+        assert_eq!(cpu.pc(), 0x20A);
+        assert_eq!(debugger.step_out(&mut cpu, [false; 16]), None);
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 100), Some(Stop::Step));
+        assert_eq!(cpu.pc(), 0x202);
+        // Nothing left to return from.
+        assert_eq!(debugger.step_out(&mut cpu, [false; 16]), Some(Stop::Step));
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn debugger_faults() {
+        let mut cpu = Chip8Cpu::new();
+        // RET with nothing to return to.
+        cpu.load_rom_bytes(&[0x00, 0xEE]).unwrap();
+        let mut debugger = Debugger::default();
+        let stop = debugger.run_frame(&mut cpu, [false; 16], 10);
+        assert!(matches!(stop, Some(Stop::Fault(_))));
+        assert_eq!(debugger.run_frame(&mut cpu, [false; 16], 10), stop);
+    }
+}
@@ -0,0 +1,406 @@
+use std::fmt;
+
+use crate::cpu::{Chip8Cpu, RAM};
+
+// Debugger expressions, like `pc == 0x2A4 && v3 > 10 && [i+2] == 0`. The
+// operators are C's, with C's precedence, over 64-bit integers. Names are
+// registers: v0-vf, i, pc, sp, dt, st. [addr] is the byte at addr.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    text: String,
+    node: Node,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    fn from_token(token: &str) -> Option<(Self, u8)> {
+        Some(match token {
+            "||" => (BinaryOp::Or, 1),
+            "&&" => (BinaryOp::And, 2),
+            "|" => (BinaryOp::BitOr, 3),
+            "^" => (BinaryOp::BitXor, 4),
+            "&" => (BinaryOp::BitAnd, 5),
+            "==" => (BinaryOp::Eq, 6),
+            "!=" => (BinaryOp::Ne, 6),
+            "<" => (BinaryOp::Lt, 7),
+            "<=" => (BinaryOp::Le, 7),
+            ">" => (BinaryOp::Gt, 7),
+            ">=" => (BinaryOp::Ge, 7),
+            "<<" => (BinaryOp::Shl, 8),
+            ">>" => (BinaryOp::Shr, 8),
+            "+" => (BinaryOp::Add, 9),
+            "-" => (BinaryOp::Sub, 9),
+            "*" => (BinaryOp::Mul, 10),
+            "/" => (BinaryOp::Div, 10),
+            "%" => (BinaryOp::Rem, 10),
+            _ => return None,
+        })
+    }
+
+    // && and || are short-circuited before they get here.
+    fn apply(self, a: i64, b: i64) -> Result<i64, String> {
+        Ok(match self {
+            BinaryOp::Or => (a != 0 || b != 0) as i64,
+            BinaryOp::And => (a != 0 && b != 0) as i64,
+            BinaryOp::BitOr => a | b,
+            BinaryOp::BitXor => a ^ b,
+            BinaryOp::BitAnd => a & b,
+            BinaryOp::Eq => (a == b) as i64,
+            BinaryOp::Ne => (a != b) as i64,
+            BinaryOp::Lt => (a < b) as i64,
+            BinaryOp::Le => (a <= b) as i64,
+            BinaryOp::Gt => (a > b) as i64,
+            BinaryOp::Ge => (a >= b) as i64,
+            BinaryOp::Shl | BinaryOp::Shr if !(0..64).contains(&b) => 0,
+            BinaryOp::Shl => a << b,
+            BinaryOp::Shr => a >> b,
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Sub => a.wrapping_sub(b),
+            BinaryOp::Mul => a.wrapping_mul(b),
+            BinaryOp::Div | BinaryOp::Rem if b == 0 => return Err("division by zero".to_string()),
+            BinaryOp::Div => a.wrapping_div(b),
+            BinaryOp::Rem => a.wrapping_rem(b),
+        })
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let node = parser.binary(1)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {}", token));
+        }
+        Ok(Expr { text: text.trim().to_string(), node })
+    }
+
+    // Reading memory here doesn't count as a program access.
+    pub fn eval(&self, cpu: &Chip8Cpu) -> Result<i64, String> {
+        eval(&self.node, cpu)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn eval(node: &Node, cpu: &Chip8Cpu) -> Result<i64, String> {
+    Ok(match node {
+        Node::Number(n) => *n,
+        Node::Register(register) => {
+            let regs = cpu.registers();
+            match register {
+                Register::V(x) => regs.v[*x] as i64,
+                Register::I => regs.i as i64,
+                Register::Pc => regs.pc as i64,
+                Register::Sp => regs.sp as i64,
+                Register::Dt => regs.dt as i64,
+                Register::St => regs.st as i64,
+            }
+        },
+        Node::Memory(addr) => cpu.peek(eval(addr, cpu)?.rem_euclid(RAM as i64) as usize) as i64,
+        Node::Unary(op, operand) => {
+            let value = eval(operand, cpu)?;
+            match op {
+                UnaryOp::Neg => value.wrapping_neg(),
+                UnaryOp::Not => (value == 0) as i64,
+                UnaryOp::BitNot => !value,
+            }
+        },
+        Node::Binary(BinaryOp::And, a, b) => (eval(a, cpu)? != 0 && eval(b, cpu)? != 0) as i64,
+        Node::Binary(BinaryOp::Or, a, b) => (eval(a, cpu)? != 0 || eval(b, cpu)? != 0) as i64,
+        Node::Binary(op, a, b) => op.apply(eval(a, cpu)?, eval(b, cpu)?)?,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longest first, so `<=` isn't read as `<` then `=`.
+const SYMBOLS: [&str; 24] = ["==", "!=", "<=", ">=", "&&", "||", "<<", ">>",
+                             "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "<", ">",
+                             "(", ")", "[", "]"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let word = &rest[..len];
+            tokens.push(if c.is_ascii_digit() {
+                Token::Number(number(word).ok_or_else(|| format!("bad number {}", word))?)
+            } else {
+                Token::Name(word.to_ascii_lowercase())
+            });
+            len
+        } else {
+            let symbol = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol))
+                                .ok_or_else(|| format!("unexpected {}", c))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+fn number(word: &str) -> Option<i64> {
+    let word = word.to_ascii_lowercase().replace('_', "");
+    if let Some(hex) = word.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = word.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        word.parse().ok()
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    // Operators binding at least as tightly as `min`.
+    fn binary(&mut self, min: u8) -> Result<Node, String> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.peek_binary().filter(|&(_, precedence)| precedence >= min) {
+            self.pos += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn peek_binary(&self) -> Option<(BinaryOp, u8)> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Symbol(symbol)) => BinaryOp::from_token(symbol),
+            _ => None,
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let op = match self.next()? {
+            Token::Symbol("-") => UnaryOp::Neg,
+            Token::Symbol("!") => UnaryOp::Not,
+            Token::Symbol("~") => UnaryOp::BitNot,
+            Token::Symbol("(") => {
+                let node = self.binary(1)?;
+                self.expect(")")?;
+                return Ok(node);
+            },
+            Token::Symbol("[") => {
+                let node = self.binary(1)?;
+                self.expect("]")?;
+                return Ok(Node::Memory(Box::new(node)));
+            },
+            Token::Number(n) => return Ok(Node::Number(n)),
+            Token::Name(name) => return register(&name).map(Node::Register).ok_or(format!("unknown name {}", name)),
+            token => return Err(format!("unexpected {}", token)),
+        };
+        Ok(Node::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.next()? {
+            Token::Symbol(s) if s == symbol => Ok(()),
+            token => Err(format!("expected {} but found {}", symbol, token)),
+        }
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    Some(match name {
+        "i" => Register::I,
+        "pc" => Register::Pc,
+        "sp" => Register::Sp,
+        "dt" => Register::Dt,
+        "st" => Register::St,
+        _ => {
+            let x = name.strip_prefix('v').filter(|x| x.len() == 1)?;
+            Register::V(usize::from_str_radix(x, 16).ok()?)
+        },
+    })
+}
+
+// A log-point's message: text with expressions in braces, printed in
+// decimal, or in hex as {expr:x}. {{ and }} are literal braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogMessage {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Value(Expr, bool),
+}
+
+impl LogMessage {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push(c);
+                rest = &rest[2..];
+            } else if c == '{' {
+                let end = rest.find('}').ok_or("unclosed {")?;
+                let (expr, hex) = match rest[1..end].strip_suffix(":x") {
+                    Some(expr) => (expr, true),
+                    None => (&rest[1..end], false),
+                };
+                if !literal.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut literal)));
+                }
+                pieces.push(Piece::Value(Expr::parse(expr)?, hex));
+                rest = &rest[end + 1..];
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Text(literal));
+        }
+        Ok(LogMessage { pieces })
+    }
+
+    // An expression that can't be evaluated shows why in its place.
+    pub fn format(&self, cpu: &Chip8Cpu) -> String {
+        self.pieces.iter().map(|piece| match piece {
+            Piece::Text(text) => text.clone(),
+            Piece::Value(expr, hex) => match expr.eval(cpu) {
+                Ok(value) if *hex => format!("{:#X}", value),
+                Ok(value) => value.to_string(),
+                Err(e) => format!("<{}>", e),
+            },
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Registers;
+
+    fn cpu() -> Chip8Cpu {
+        let mut cpu = Chip8Cpu::new();
+        let mut v = [0; 16];
+        v[3] = 12;
+        v[0xF] = 1;
+        cpu.set_registers(Registers { v, i: 0x300, pc: 0x2A4, sp: 1, dt: 30, st: 0 });
+        cpu.poke(0x302, 7);
+        cpu
+    }
+
+    fn eval(text: &str) -> Result<i64, String> {
+        Expr::parse(text).and_then(|expr| expr.eval(&cpu()))
+    }
+
+    #[test]
+    fn expr_evaluates() {
+        assert_eq!(eval("pc == 0x2A4 && v3 > 10 && [i+2] == 7"), Ok(1));
+        assert_eq!(eval("PC == 0x2a4 && V3 > 12"), Ok(0));
+        assert_eq!(eval("1 + 2 * 3 - 4 / 2"), Ok(5));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 | 2 == 2"), Ok(1));
+        assert_eq!(eval("1 << 4 >> 2"), Ok(4));
+        assert_eq!(eval("-vf + ~0 + !dt + !st"), Ok(-1));
+        assert_eq!(eval("0b1010 ^ 0xF & 6 % 4"), Ok(8));
+        assert_eq!(eval("[-0xD00 + 2]"), Ok(7));
+        assert_eq!(eval("sp >= 1 || 1 / 0"), Ok(1));
+        assert_eq!(eval("1 % (vf - 1)"), Err("division by zero".to_string()));
+    }
+
+    #[test]
+    fn expr_rejects() {
+        assert_eq!(eval("v3 >"), Err("unexpected end of expression".to_string()));
+        assert_eq!(eval("vg"), Err("unknown name vg".to_string()));
+        assert_eq!(eval("(v3"), Err("unexpected end of expression".to_string()));
+        assert_eq!(eval("[i)"), Err("expected ] but found )".to_string()));
+        assert_eq!(eval("v3 v4"), Err("unexpected v4".to_string()));
+        assert_eq!(eval("0xZZ"), Err("bad number 0xZZ".to_string()));
+        assert_eq!(eval("v3 = 1"), Err("unexpected =".to_string()));
+        assert_eq!(Expr::parse(" v3 + 1 ").unwrap().to_string(), "v3 + 1");
+    }
+
+    #[test]
+    fn expr_log_messages() {
+        let message = LogMessage::parse("v3={v3} at {pc:x}, {{[i+2]}}={[i+2]} {1/0}").unwrap();
+        assert_eq!(message.format(&cpu()), "v3=12 at 0x2A4, {[i+2]}=7 <division by zero>");
+        assert!(LogMessage::parse("{v3").is_err());
+        assert!(LogMessage::parse("{v3 +}").is_err());
+    }
+}
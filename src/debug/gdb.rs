@@ -0,0 +1,723 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::{Chip8Cpu, Registers, RAM};
+use crate::symbols::SymbolMap;
+use super::debugger::{DebugServer, Debugger, Stop, WatchKind, Watchpoint};
+
+// V0-VF, I, PC, SP, DT, ST, in the order of target_xml().
+const REGISTERS: usize = 21;
+const PACKET_SIZE: usize = 0x1000;
+
+// Describes the registers to the client, since GDB has no CHIP-8
+// architecture of its own.
+pub fn target_xml() -> String {
+    let mut regs: Vec<String> = (0..16).map(|x| format!(r#"<reg name="v{:x}" bitsize="8" type="uint8"/>"#, x))
+                                       .collect();
+    regs.push(r#"<reg name="i" bitsize="16" type="data_ptr"/>"#.to_string());
+    regs.push(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#.to_string());
+    for name in &["sp", "dt", "st"] {
+        regs.push(format!(r#"<reg name="{}" bitsize="8" type="uint8"/>"#, name));
+    }
+    format!("<?xml version=\"1.0\"?>\n\
+             <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n\
+             <feature name=\"org.chip8.cpu\">\n{}\n</feature>\n\
+             </target>\n",
+            regs.join("\n"))
+}
+
+fn register_size(n: usize) -> usize {
+    if n == 16 || n == 17 { 2 } else { 1 }
+}
+
+fn register(regs: &Registers, n: usize) -> usize {
+    match n {
+        0..=15 => regs.v[n] as usize,
+        16 => regs.i,
+        17 => regs.pc,
+        18 => regs.sp,
+        19 => regs.dt as usize,
+        _ => regs.st as usize,
+    }
+}
+
+fn set_register(regs: &mut Registers, n: usize, value: usize) {
+    match n {
+        0..=15 => regs.v[n] = value as u8,
+        16 => regs.i = value,
+        17 => regs.pc = value,
+        18 => regs.sp = value,
+        19 => regs.dt = value as u8,
+        _ => regs.st = value as u8,
+    }
+}
+
+// Registers go over the wire little-endian.
+fn register_hex(regs: &Registers, n: usize) -> String {
+    let value = register(regs, n);
+    hex(&(0..register_size(n)).map(|byte| (value >> (8 * byte)) as u8).collect::<Vec<u8>>())
+}
+
+fn register_value(bytes: &[u8]) -> usize {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as usize)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// "addr,len" as in m and M packets.
+fn range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((number(addr)?, number(len)?))
+}
+
+// Z2, Z3 and Z4.
+fn watch_kind(kind: usize) -> WatchKind {
+    match kind {
+        2 => WatchKind::Write,
+        3 => WatchKind::Read,
+        _ => WatchKind::Access,
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Step => "S05".to_string(),
+        Stop::Breakpoint(_) => "T05swbreak:;".to_string(),
+        Stop::Watch(watch, access) => {
+            let kind = match watch.kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write => "watch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T05{}:{:x};", kind, access.addr)
+        },
+        // SIGSEGV, the nearest thing to a stack fault.
+        Stop::Fault(_) => "S0b".to_string(),
+        Stop::HistoryStart => "T05replaylog:begin;".to_string(),
+    }
+}
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    // Cleared by QStartNoAckMode.
+    ack: bool,
+}
+
+impl Client {
+    // Reads whatever has arrived. Err once the client has gone.
+    fn receive(&mut self) -> io::Result<Vec<Incoming>> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut incoming = Vec::new();
+        loop {
+            match self.buffer.first() {
+                None => break,
+                Some(0x03) => {
+                    self.buffer.remove(0);
+                    incoming.push(Incoming::Interrupt);
+                },
+                Some(b'$') => {
+                    let hash = match self.buffer.iter().position(|&b| b == b'#') {
+                        Some(hash) if self.buffer.len() >= hash + 3 => hash,
+                        _ => break,
+                    };
+                    let packet: Vec<u8> = self.buffer.drain(..hash + 3).collect();
+                    let data = &packet[1..hash];
+                    let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+                    let expected = std::str::from_utf8(&packet[hash + 1..]).ok().and_then(|cs| u8::from_str_radix(cs, 16).ok());
+                    if !self.ack {
+                        incoming.push(Incoming::Packet(String::from_utf8_lossy(data).into_owned()));
+                    } else if expected == Some(sum) {
+                        self.send_raw(b"+")?;
+                        incoming.push(Incoming::Packet(String::from_utf8_lossy(data).into_owned()));
+                    } else {
+                        self.send_raw(b"-")?;
+                    }
+                },
+                // Acks, and anything else between packets.
+                Some(_) => {
+                    self.buffer.remove(0);
+                },
+            }
+        }
+        Ok(incoming)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.send_raw(format!("${}#{:02x}", data, sum).as_bytes())
+    }
+
+    // The socket is non-blocking for reads; replies are small enough to
+    // just wait for.
+    fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let sent = self.stream.write_all(bytes);
+        self.stream.set_nonblocking(true)?;
+        sent
+    }
+}
+
+// A GDB remote serial protocol server on localhost. The emulator calls
+// frame() instead of running the CPU itself, and the server handles
+// whatever the client sent before running the frame, unless it's halted.
+// The CPU starts halted, waiting for a client to continue it.
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<Client>,
+    debugger: Debugger,
+    halted: bool,
+    killed: bool,
+    // Labels and source lines, for the monitor commands.
+    symbols: SymbolMap,
+}
+
+impl GdbServer {
+    // Port 0 picks a free one.
+    pub fn bind(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(GdbServer {
+            listener,
+            client: None,
+            debugger: Debugger::default(),
+            halted: true,
+            killed: false,
+            symbols: SymbolMap::default(),
+        })
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = symbols;
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|addr| addr.port()).unwrap_or(0)
+    }
+
+    fn accept(&mut self) {
+        if self.client.is_some() {
+            return;
+        }
+        if let Ok((stream, _)) = self.listener.accept() {
+            let _ = stream.set_nodelay(true);
+            if stream.set_nonblocking(true).is_ok() {
+                self.client = Some(Client { stream, buffer: Vec::new(), ack: true });
+                self.halted = true;
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.halted = false;
+    }
+
+    fn reply(&mut self, data: &str) {
+        if let Some(Err(_)) = self.client.as_mut().map(|client| client.send(data)) {
+            self.disconnect();
+        }
+    }
+
+    fn stopped(&mut self, stop: &Stop) {
+        self.halted = true;
+        if let Stop::Fault(fault) = stop {
+            // Console output, which GDB prints.
+            self.reply(&format!("O{}", hex(format!("CPU fault: {}\n", fault).as_bytes())));
+        }
+        self.reply(&stop_reply(stop));
+    }
+
+    // The reply to a packet, if it gets one now. Continuing replies when
+    // the CPU stops.
+    fn handle(&mut self, packet: &str, cpu: &mut Chip8Cpu, input: [bool; 16]) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => {
+                let regs = cpu.registers();
+                (0..REGISTERS).map(|n| register_hex(&regs, n)).collect()
+            },
+            "G" => match unhex(args) {
+                Some(bytes) if bytes.len() == (0..REGISTERS).map(register_size).sum::<usize>() => {
+                    let mut regs = cpu.registers();
+                    let mut bytes = &bytes[..];
+                    for n in 0..REGISTERS {
+                        let (value, rest) = bytes.split_at(register_size(n));
+                        set_register(&mut regs, n, register_value(value));
+                        bytes = rest;
+                    }
+                    cpu.set_registers(regs);
+                    self.debugger.edited(cpu);
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            "p" => match number(args) {
+                Some(n) if n < REGISTERS => register_hex(&cpu.registers(), n),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| Some((number(n)?, unhex(value)?)));
+                match parsed {
+                    Some((n, value)) if n < REGISTERS && value.len() == register_size(n) => {
+                        let mut regs = cpu.registers();
+                        set_register(&mut regs, n, register_value(&value));
+                        cpu.set_registers(regs);
+                        self.debugger.edited(cpu);
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => match range(args) {
+                Some((addr, len)) if addr < RAM => {
+                    let end = addr.saturating_add(len).min(RAM).min(addr + PACKET_SIZE / 2);
+                    hex(&(addr..end).map(|addr| cpu.peek(addr)).collect::<Vec<u8>>())
+                },
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range_text, data)| Some((range(range_text)?, unhex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len && addr.checked_add(len).is_some_and(|end| end <= RAM) => {
+                        for (offset, byte) in data.iter().enumerate() {
+                            cpu.poke(addr + offset, *byte);
+                        }
+                        self.debugger.edited(cpu);
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "s" => {
+                if let Some(addr) = number(args) {
+                    let regs = cpu.registers();
+                    cpu.set_registers(Registers { pc: addr, ..regs });
+                    self.debugger.edited(cpu);
+                }
+                let stop = self.debugger.step(cpu, input);
+                self.stopped(&stop);
+                return None;
+            },
+            "c" => {
+                if let Some(addr) = number(args) {
+                    let regs = cpu.registers();
+                    cpu.set_registers(Registers { pc: addr, ..regs });
+                    self.debugger.edited(cpu);
+                }
+                self.halted = false;
+                return None;
+            },
+            // bs and bc, reverse-stepi and reverse-continue. They finish
+            // at once, since the history is already recorded.
+            "b" if args == "s" || args == "c" => {
+                let stop = match args {
+                    "s" => self.debugger.reverse_step(cpu),
+                    _ => self.debugger.reverse_continue(cpu),
+                };
+                self.stopped(&stop);
+                return None;
+            },
+            "D" => {
+                self.reply("OK");
+                self.disconnect();
+                return None;
+            },
+            "k" => {
+                self.killed = true;
+                return None;
+            },
+            // There's only one thread.
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            "v" if packet == "vKill" || packet.starts_with("vKill;") => {
+                self.killed = true;
+                "OK".to_string()
+            },
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    // Z/z type,addr,kind. Software and hardware breakpoints are the same
+    // thing here; kind is the length for watchpoints.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let fields: Vec<Option<usize>> = args.split(',').map(number).collect();
+        let (kind, addr, len) = match fields[..] {
+            [Some(kind), Some(addr), Some(len)] => (kind, addr, len),
+            _ => return "E01".to_string(),
+        };
+        let watch = |kind| Watchpoint { start: addr, len, kind };
+        match (kind, insert) {
+            (0, true) | (1, true) => self.debugger.add_breakpoint(addr),
+            (0, false) | (1, false) => {
+                self.debugger.remove_breakpoint(addr);
+            },
+            (2..=4, true) => self.debugger.add_watchpoint(watch(watch_kind(kind))),
+            (2..=4, false) => {
+                self.debugger.remove_watchpoint(watch(watch_kind(kind)));
+            },
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            return match unhex(command) {
+                Some(command) => {
+                    let output = self.monitor(&String::from_utf8_lossy(&command));
+                    self.reply(&format!("O{}", hex(output.as_bytes())));
+                    "OK".to_string()
+                },
+                None => "E01".to_string(),
+            };
+        }
+        let (name, args) = packet.split_once(':').unwrap_or((packet, ""));
+        match name {
+            "qSupported" => format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;\
+                                     ReverseStep+;ReverseContinue+",
+                                    PACKET_SIZE),
+            "qXfer" => match args.strip_prefix("features:read:target.xml:").and_then(range) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = offset.min(xml.len());
+                    let end = (start + len).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &xml[start..end])
+                },
+                None => "E00".to_string(),
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qSymbol" => "OK".to_string(),
+            "QStartNoAckMode" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // What `monitor` commands print.
+    fn monitor(&self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words[..] {
+            ["last-write", addr] => match self.address(addr) {
+                Some(addr) => match self.debugger.last_write(addr) {
+                    Some(write) => format!("{:#06x} was last written with {:#04x} by the instruction at {}, cycle {}\n",
+                                           write.addr, write.value, self.describe(write.pc), write.cycle),
+                    None => format!("No write to {:#06x} is recorded\n", addr % RAM),
+                },
+                None => format!("Bad address {}\n", addr),
+            },
+            ["symbol", addr] => match self.address(addr) {
+                Some(addr) => {
+                    let symbol = self.symbols.describe(addr);
+                    let location = self.symbols.location(addr).map(|location| format!("from {}", location));
+                    let found: Vec<String> = symbol.into_iter().chain(location).collect();
+                    if found.is_empty() {
+                        format!("No symbol for {:#06x}\n", addr)
+                    } else {
+                        format!("{:#06x} is {}\n", addr, found.join(", "))
+                    }
+                },
+                None => format!("Bad address {}\n", addr),
+            },
+            _ => "Commands: last-write ADDR, symbol ADDR (ADDR is hex or a label)\n".to_string(),
+        }
+    }
+
+    // A label, or a hex address with or without 0x.
+    fn address(&self, text: &str) -> Option<usize> {
+        self.symbols.address_of(text).or_else(|| number(text.strip_prefix("0x").unwrap_or(text)))
+    }
+
+    // An address with its symbol, like "0x0206 (main_loop+4)".
+    fn describe(&self, addr: usize) -> String {
+        match self.symbols.describe(addr) {
+            Some(symbol) => format!("{:#06x} ({})", addr, symbol),
+            None => format!("{:#06x}", addr),
+        }
+    }
+}
+
+impl DebugServer for GdbServer {
+    // True once the client has killed the program.
+    fn killed(&self) -> bool { self.killed }
+
+    fn frame(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16], ipf: u32) -> bool {
+        self.accept();
+        let incoming = match self.client.as_mut().map(Client::receive) {
+            Some(Ok(incoming)) => incoming,
+            // Without a client, whatever was being debugged runs free.
+            Some(Err(_)) => {
+                self.disconnect();
+                Vec::new()
+            },
+            None => Vec::new(),
+        };
+        for message in incoming {
+            match message {
+                Incoming::Interrupt => {
+                    self.halted = true;
+                    self.reply("S02");
+                },
+                Incoming::Packet(packet) => {
+                    if let Some(reply) = self.handle(&packet, cpu, input) {
+                        self.reply(&reply);
+                    }
+                    // Acknowledged with OK in the old mode first.
+                    if packet == "QStartNoAckMode" {
+                        if let Some(client) = self.client.as_mut() {
+                            client.ack = false;
+                        }
+                    }
+                },
+            }
+        }
+
+        if self.halted || self.killed {
+            return false;
+        }
+        if let Some(stop) = self.debugger.run_frame(cpu, input, ipf) {
+            self.stopped(&stop);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    // A minimal RSP client, driving the server's frames while it waits.
+    struct TestClient {
+        stream: TcpStream,
+        received: Vec<u8>,
+    }
+
+    impl TestClient {
+        fn connect(server: &GdbServer) -> Self {
+            let stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            TestClient { stream, received: Vec::new() }
+        }
+
+        fn send(&mut self, packet: &str) {
+            let sum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            self.stream.set_nonblocking(false).unwrap();
+            write!(self.stream, "${}#{:02x}", packet, sum).unwrap();
+            self.stream.set_nonblocking(true).unwrap();
+        }
+
+        // The next reply, acked.
+        fn reply(&mut self, server: &mut GdbServer, cpu: &mut Chip8Cpu) -> String {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                server.frame(cpu, [false; 16], 10);
+                let mut chunk = [0; 4096];
+                if let Ok(n) = self.stream.read(&mut chunk) {
+                    self.received.extend_from_slice(&chunk[..n]);
+                }
+                while self.received.first() == Some(&b'+') {
+                    self.received.remove(0);
+                }
+                let text = String::from_utf8_lossy(&self.received).into_owned();
+                if let (Some(0), Some(hash)) = (text.find('$'), text.find('#')) {
+                    if text.len() >= hash + 3 {
+                        self.received.drain(..hash + 3);
+                        self.stream.set_nonblocking(false).unwrap();
+                        self.stream.write_all(b"+").unwrap();
+                        self.stream.set_nonblocking(true).unwrap();
+                        return text[1..hash].to_string();
+                    }
+                }
+                assert!(Instant::now() < deadline, "no reply from the server");
+                sleep(Duration::from_millis(1));
+            }
+        }
+
+        fn ask(&mut self, server: &mut GdbServer, cpu: &mut Chip8Cpu, packet: &str) -> String {
+            self.send(packet);
+            self.reply(server, cpu)
+        }
+    }
+
+    fn setup() -> (GdbServer, Chip8Cpu, TestClient) {
+        let server = GdbServer::bind(0).unwrap();
+        let mut cpu = Chip8Cpu::new();
+        // LD V0, 5; ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x202
+        cpu.load_rom_bytes(&[0x60, 0x05, 0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02]).unwrap();
+        let client = TestClient::connect(&server);
+        (server, cpu, client)
+    }
+
+    #[test]
+    fn gdb_registers_and_memory() {
+        let (mut server, mut cpu, mut client) = setup();
+        // The CPU waits for the client.
+        for _ in 0..10 {
+            assert!(!server.frame(&mut cpu, [false; 16], 10));
+        }
+        assert!(client.ask(&mut server, &mut cpu, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        let xml = client.ask(&mut server, &mut cpu, "qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#));
+        assert_eq!(client.ask(&mut server, &mut cpu, "?"), "S05");
+
+        let regs = client.ask(&mut server, &mut cpu, "g");
+        assert_eq!(regs.len(), 2 * 23);
+        assert_eq!(&regs[32..40], "00000002");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p11"), "0002");
+
+        assert_eq!(client.ask(&mut server, &mut cpu, "s"), "S05");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p11"), "0202");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p0"), "05");
+
+        assert_eq!(client.ask(&mut server, &mut cpu, "P0=2a"), "OK");
+        assert_eq!(cpu.registers().v[0], 0x2A);
+        assert_eq!(client.ask(&mut server, &mut cpu, "P11=0803"), "OK");
+        assert_eq!(cpu.pc(), 0x308);
+        assert_eq!(client.ask(&mut server, &mut cpu, "p15"), "E01");
+
+        assert_eq!(client.ask(&mut server, &mut cpu, "m200,4"), "60057001");
+        assert_eq!(client.ask(&mut server, &mut cpu, "M300,2:abcd"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "m300,2"), "abcd");
+        assert_eq!(client.ask(&mut server, &mut cpu, "m1000,1"), "E01");
+        // Lengths that would overflow are cut short or refused.
+        assert_eq!(client.ask(&mut server, &mut cpu, "mffe,ffffffffffffffff"), "0000");
+        assert_eq!(client.ask(&mut server, &mut cpu, "Mffe,3:000000"), "E01");
+        assert_eq!(client.ask(&mut server, &mut cpu, "vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn gdb_breakpoints_and_watchpoints() {
+        let (mut server, mut cpu, mut client) = setup();
+        assert_eq!(client.ask(&mut server, &mut cpu, "Z0,204,2"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05swbreak:;");
+        assert_eq!(cpu.pc(), 0x204);
+
+        assert_eq!(client.ask(&mut server, &mut cpu, "z0,204,2"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "Z2,300,1"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05watch:300;");
+        assert_eq!(client.ask(&mut server, &mut cpu, "m300,1"), "06");
+        assert_eq!(client.ask(&mut server, &mut cpu, "z2,300,1"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "Z4,2ff,2"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05awatch:300;");
+        assert_eq!(client.ask(&mut server, &mut cpu, "Z5,300,1"), "");
+
+        // With nothing to stop it, it runs until interrupted.
+        assert_eq!(client.ask(&mut server, &mut cpu, "z4,2ff,2"), "OK");
+        client.send("c");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.frame(&mut cpu, [false; 16], 10) {
+            assert!(Instant::now() < deadline);
+        }
+        for _ in 0..10 {
+            assert!(server.frame(&mut cpu, [false; 16], 10));
+        }
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(&mut server, &mut cpu), "S02");
+
+        client.send("k");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.killed() {
+            assert!(Instant::now() < deadline);
+            server.frame(&mut cpu, [false; 16], 10);
+        }
+    }
+
+    #[test]
+    fn gdb_reverse_execution() {
+        let (mut server, mut cpu, mut client) = setup();
+        assert!(client.ask(&mut server, &mut cpu, "qSupported").contains("ReverseStep+;ReverseContinue+"));
+        // There's nothing before the start.
+        assert_eq!(client.ask(&mut server, &mut cpu, "bs"), "T05replaylog:begin;");
+
+        assert_eq!(client.ask(&mut server, &mut cpu, "Z0,206,2"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05swbreak:;");
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05swbreak:;");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p0"), "07");
+        assert_eq!(client.ask(&mut server, &mut cpu, "bs"), "S05");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p11"), "0402");
+        assert_eq!(client.ask(&mut server, &mut cpu, "bc"), "T05swbreak:;");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p0"), "06");
+        assert_eq!(client.ask(&mut server, &mut cpu, "m300,1"), "00");
+
+        // Forward again replays up to where it was.
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05swbreak:;");
+        assert_eq!(client.ask(&mut server, &mut cpu, "p0"), "07");
+        assert_eq!(client.ask(&mut server, &mut cpu, "m300,1"), "06");
+        let output = client.ask(&mut server, &mut cpu, &format!("qRcmd,{}", hex(b"last-write 0x300")));
+        let text = String::from_utf8(unhex(&output[1..]).unwrap()).unwrap();
+        assert_eq!(text, "0x0300 was last written with 0x06 by the instruction at 0x0206, cycle 3\n");
+        assert_eq!(client.reply(&mut server, &mut cpu), "OK");
+    }
+
+    #[test]
+    fn gdb_monitor_symbols() {
+        let (mut server, mut cpu, mut client) = setup();
+        let mut symbols = SymbolMap::default();
+        symbols.add_label(0x200, "main");
+        symbols.add_label(0x202, "loop");
+        symbols.add_label(0x300, "counter");
+        symbols.add_line(0x206, 4);
+        symbols.source = Some("game.asm".to_string());
+        server.set_symbols(symbols);
+
+        assert_eq!(server.monitor("symbol 0x206"), "0x0206 is loop+4, from game.asm:4\n");
+        assert_eq!(server.monitor("symbol counter"), "0x0300 is counter\n");
+        assert_eq!(server.monitor("symbol 100"), "No symbol for 0x0100\n");
+        assert_eq!(server.monitor("symbol nowhere"), "Bad address nowhere\n");
+
+        assert_eq!(client.ask(&mut server, &mut cpu, "Z0,208,2"), "OK");
+        assert_eq!(client.ask(&mut server, &mut cpu, "c"), "T05swbreak:;");
+        assert_eq!(server.monitor("last-write counter"),
+                   "0x0300 was last written with 0x06 by the instruction at 0x0206 (loop+4), cycle 3\n");
+    }
+
+    #[test]
+    fn gdb_reports_faults() {
+        let (mut server, mut cpu, mut client) = setup();
+        // RET with nothing to return to.
+        assert_eq!(client.ask(&mut server, &mut cpu, "M200,2:00ee"), "OK");
+        client.send("c");
+        let output = client.reply(&mut server, &mut cpu);
+        let text = String::from_utf8(unhex(&output[1..]).unwrap()).unwrap();
+        assert!(text.starts_with("CPU fault: return with an empty stack"));
+        assert_eq!(client.reply(&mut server, &mut cpu), "S0b");
+    }
+}
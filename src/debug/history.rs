@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+
+use crate::cpu::{AccessKind, Chip8Cpu, RAM};
+
+// Events between snapshots, and how many snapshots are kept. That's a few
+// minutes of running at the usual speeds, in a couple of megabytes.
+const INTERVAL: u64 = 1024;
+const SNAPSHOTS: usize = 256;
+
+// What happened to the CPU, in order: enough to run it again exactly from
+// a snapshot, since its random numbers come from the snapshot too.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Event {
+    // An instruction, with the keys held down as bits.
+    Step(u16),
+    Tick,
+}
+
+struct Snapshot {
+    time: u64,
+    cpu: Chip8Cpu,
+    // Taken after a debugger changed the CPU, so running up to it doesn't
+    // get the same state it has.
+    edited: bool,
+}
+
+// A write the program made to memory, and the instruction that made it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteRecord {
+    pub addr: usize,
+    pub value: u8,
+    pub pc: usize,
+    pub cycle: u64,
+    time: u64,
+}
+
+// A recording of everything the CPU has done since the oldest snapshot,
+// which the debugger can go back to any point of. A time is a number of
+// events since the recording started. Going back and running forward
+// again replays what was recorded until it catches up; anything different
+// from the recording (a tick in the middle of a frame, or an edit) forgets
+// the rest of it.
+#[derive(Default)]
+pub struct History {
+    snapshots: VecDeque<Snapshot>,
+    // Events from the oldest snapshot on.
+    events: VecDeque<Event>,
+    start: u64,
+    now: u64,
+    // What the CPU's cycle count should be now. Anything else means it was
+    // reset or replaced behind the history's back.
+    cycles: u64,
+    writes: VecDeque<WriteRecord>,
+}
+
+impl History {
+    // Forgets everything, and starts recording from the CPU as it is.
+    pub fn reset(&mut self, cpu: &Chip8Cpu) {
+        self.snapshots.clear();
+        self.events.clear();
+        self.writes.clear();
+        self.start = self.now;
+        self.cycles = cpu.cycles();
+        self.snapshots.push_back(Snapshot { time: self.now, cpu: cpu.snapshot(), edited: false });
+    }
+
+    // Called after a debugger changes registers or memory. The recording
+    // carries on from the changed CPU.
+    pub fn edited(&mut self, cpu: &Chip8Cpu) {
+        if self.sync(cpu) {
+            return;
+        }
+        self.fork();
+        if self.snapshots.back().is_some_and(|snapshot| snapshot.time == self.now) {
+            self.snapshots.pop_back();
+        }
+        self.snapshots.push_back(Snapshot { time: self.now, cpu: cpu.snapshot(), edited: true });
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.now < self.end()
+    }
+
+    // True when replaying has reached the end of a recorded frame.
+    pub fn frame_ends(&self) -> bool {
+        self.next() == Some(Event::Tick)
+    }
+
+    pub fn step(&mut self, cpu: &mut Chip8Cpu, input: [bool; 16]) {
+        self.sync(cpu);
+        // Stepping runs the timers where they ran in the recording.
+        while self.frame_ends() {
+            self.replay(cpu);
+        }
+        if self.replaying() {
+            self.replay(cpu);
+            return;
+        }
+        self.record(cpu, Event::Step(keys(input)));
+        let (pc, cycle, time) = (cpu.pc(), cpu.cycles(), self.now);
+        cpu.step(input);
+        self.now += 1;
+        self.cycles = cpu.cycles();
+        let writes = cpu.accesses().iter().filter(|access| access.kind == AccessKind::Write);
+        self.writes.extend(writes.map(|access| WriteRecord { addr: access.addr, value: access.value, pc, cycle, time }));
+    }
+
+    pub fn tick(&mut self, cpu: &mut Chip8Cpu) {
+        self.sync(cpu);
+        if self.frame_ends() {
+            self.replay(cpu);
+            return;
+        }
+        self.fork();
+        self.record(cpu, Event::Tick);
+        cpu.tick_timers();
+        self.now += 1;
+    }
+
+    // The time before the last instruction, if it's still recorded.
+    pub fn last_step(&self) -> Option<u64> {
+        (self.start..self.now).rev().find(|&time| matches!(self.event(time), Event::Step(_)))
+    }
+
+    // Puts the CPU back how it was at a time, from the nearest snapshot.
+    pub fn travel(&mut self, cpu: &mut Chip8Cpu, time: u64) {
+        self.sync(cpu);
+        let time = time.clamp(self.start, self.end());
+        let snapshot = self.snapshots.iter().rev().find(|snapshot| snapshot.time <= time).unwrap();
+        // Nothing here runs for the first time, so it isn't traced or
+        // profiled again.
+        let (tracer, profiler) = (cpu.take_tracer(), cpu.take_profiler());
+        cpu.restore(&snapshot.cpu);
+        for t in snapshot.time..time {
+            apply(cpu, self.event(t));
+        }
+        cpu.set_tracer(tracer);
+        cpu.set_profiler(profiler);
+        self.now = time;
+        self.cycles = cpu.cycles();
+    }
+
+    // Looks back for the last time before now that `stop` picks. It sees
+    // the CPU after each recorded instruction, with what it accessed.
+    pub fn find_back<T>(&self, mut stop: impl FnMut(&Chip8Cpu) -> Option<T>) -> Option<(u64, T)> {
+        let mut end = self.now;
+        for snapshot in self.snapshots.iter().rev().filter(|snapshot| snapshot.time < self.now) {
+            let mut cpu = snapshot.cpu.snapshot();
+            let mut found = None;
+            for time in snapshot.time..end {
+                let event = self.event(time);
+                apply(&mut cpu, event);
+                if let (Event::Step(_), true) = (event, time + 1 < self.now) {
+                    if let Some(stopped) = stop(&cpu) {
+                        found = Some((time + 1, stopped));
+                    }
+                }
+            }
+            if found.is_some() {
+                return found;
+            }
+            end = snapshot.time;
+        }
+        None
+    }
+
+    // The last write to an address before now.
+    pub fn last_write(&self, addr: usize) -> Option<&WriteRecord> {
+        self.writes.iter().rev().find(|write| write.time < self.now && write.addr == addr % RAM)
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.events.len() as u64
+    }
+
+    fn event(&self, time: u64) -> Event {
+        self.events[(time - self.start) as usize]
+    }
+
+    fn next(&self) -> Option<Event> {
+        if self.replaying() { Some(self.event(self.now)) } else { None }
+    }
+
+    // Starts over if the CPU isn't the one recorded. Returns whether it
+    // did.
+    fn sync(&mut self, cpu: &Chip8Cpu) -> bool {
+        if self.snapshots.is_empty() || cpu.cycles() != self.cycles {
+            self.reset(cpu);
+            return true;
+        }
+        false
+    }
+
+    // Runs the next recorded event again. Like travel, it leaves the
+    // tracer and the profiler out.
+    fn replay(&mut self, cpu: &mut Chip8Cpu) {
+        let (tracer, profiler) = (cpu.take_tracer(), cpu.take_profiler());
+        apply(cpu, self.event(self.now));
+        cpu.set_tracer(tracer);
+        cpu.set_profiler(profiler);
+        self.now += 1;
+        if let Some(snapshot) = self.snapshots.iter().find(|snapshot| snapshot.time == self.now && snapshot.edited) {
+            cpu.restore(&snapshot.cpu);
+        }
+        self.cycles = cpu.cycles();
+    }
+
+    // Drops the recording from now on.
+    fn fork(&mut self) {
+        let now = self.now;
+        self.events.truncate((now - self.start) as usize);
+        self.writes.retain(|write| write.time < now);
+        self.snapshots.retain(|snapshot| snapshot.time <= now);
+    }
+
+    fn record(&mut self, cpu: &Chip8Cpu, event: Event) {
+        if self.snapshots.back().is_some_and(|snapshot| self.now - snapshot.time >= INTERVAL) {
+            self.snapshots.push_back(Snapshot { time: self.now, cpu: cpu.snapshot(), edited: false });
+            if self.snapshots.len() > SNAPSHOTS {
+                self.snapshots.pop_front();
+                let start = self.snapshots[0].time;
+                self.events.drain(..(start - self.start) as usize);
+                self.start = start;
+                while self.writes.front().is_some_and(|write| write.time < start) {
+                    self.writes.pop_front();
+                }
+            }
+        }
+        self.events.push_back(event);
+    }
+}
+
+fn keys(input: [bool; 16]) -> u16 {
+    input.iter().enumerate().fold(0, |keys, (key, &down)| keys | (down as u16) << key)
+}
+
+fn apply(cpu: &mut Chip8Cpu, event: Event) {
+    match event {
+        Event::Step(keys) => {
+            let mut input = [false; 16];
+            for (key, down) in input.iter_mut().enumerate() {
+                *down = keys & 1 << key != 0;
+            }
+            cpu.step(input);
+        },
+        Event::Tick => cpu.tick_timers(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profiler;
+
+    // RND V0, 0xFF; LD I, 0x300; ADD I, V0; LD [I], V0; LD V1, K; JP 0x200
+    // The key wait makes the run depend on the recorded input.
+    const PROGRAM: [u8; 12] = [0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x1E, 0xF0, 0x55, 0xF1, 0x0A, 0x12, 0x00];
+
+    fn run(history: &mut History, cpu: &mut Chip8Cpu, steps: usize) {
+        for step in 0..steps {
+            let mut input = [false; 16];
+            input[step % 16] = step % 3 == 0;
+            history.step(cpu, input);
+            if step % 5 == 4 {
+                history.tick(cpu);
+            }
+        }
+    }
+
+    #[test]
+    fn history_travel_and_replay() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&PROGRAM).unwrap();
+        let mut history = History::default();
+        run(&mut history, &mut cpu, 5000);
+        let time = history.now;
+        let state = (cpu.registers(), (0..RAM).map(|addr| cpu.peek(addr)).collect::<Vec<_>>());
+        assert!(history.snapshots.len() > 1);
+
+        // Back past a few snapshots, then forward again with different
+        // input, which the recording overrides.
+        history.travel(&mut cpu, 1234);
+        assert!(history.replaying());
+        assert_eq!(cpu.cycles(), 1234 - 1234 / 6);
+        while history.replaying() {
+            history.step(&mut cpu, [true; 16]);
+            if history.frame_ends() {
+                history.tick(&mut cpu);
+            }
+        }
+        assert_eq!(history.now, time);
+        assert_eq!(state, (cpu.registers(), (0..RAM).map(|addr| cpu.peek(addr)).collect::<Vec<_>>()));
+
+        let write = *history.writes.back().unwrap();
+        assert_eq!(history.last_write(write.addr), Some(&write));
+        assert_eq!(write.pc, 0x206);
+        history.travel(&mut cpu, write.time);
+        assert_eq!(cpu.pc(), 0x206);
+        assert_eq!(cpu.cycles(), write.cycle);
+    }
+
+    #[test]
+    fn history_profiles_once() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&PROGRAM).unwrap();
+        cpu.set_profiler(Some(Profiler::new()));
+        let mut history = History::default();
+        // This ends on an instruction rather than a tick, so replaying
+        // stops exactly at the end.
+        run(&mut history, &mut cpu, 3001);
+        let total = cpu.take_profiler().unwrap().total();
+        cpu.set_profiler(Some(Profiler::new()));
+
+        // Neither going back nor replaying counts anything again.
+        history.travel(&mut cpu, 1500);
+        while history.replaying() {
+            history.step(&mut cpu, [false; 16]);
+        }
+        assert_eq!(cpu.take_profiler().unwrap().total(), 0);
+        cpu.set_profiler(Some(Profiler::new()));
+        run(&mut history, &mut cpu, 10);
+        assert_eq!(cpu.take_profiler().unwrap().total(), 10);
+        assert_eq!(total, 3001);
+    }
+
+    #[test]
+    fn history_edits_and_forks() {
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&PROGRAM).unwrap();
+        let mut history = History::default();
+        run(&mut history, &mut cpu, 100);
+
+        // An edit in the past forgets what came after it.
+        history.travel(&mut cpu, 50);
+        cpu.poke(0x400, 1);
+        history.edited(&cpu);
+        assert!(!history.replaying());
+        assert!(history.writes.iter().all(|write| write.time < 50));
+        run(&mut history, &mut cpu, 10);
+
+        // Going back before it and forward again gets the edit back.
+        history.travel(&mut cpu, 40);
+        assert_eq!(cpu.peek(0x400), 0);
+        while history.replaying() {
+            history.step(&mut cpu, [false; 16]);
+        }
+        assert_eq!(cpu.peek(0x400), 1);
+
+        // A CPU that isn't the one recorded starts it over.
+        let mut other = Chip8Cpu::new();
+        history.step(&mut other, [false; 16]);
+        assert_eq!(history.last_step(), Some(history.start));
+        assert_eq!(history.events.len(), 1);
+    }
+
+    #[test]
+    fn history_traces_each_cycle_once() {
+        use crate::trace::{TraceOptions, Tracer};
+
+        let path = std::env::temp_dir().join(format!("chip8-history-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&PROGRAM).unwrap();
+        cpu.set_tracer(Some(Tracer::create(path, TraceOptions::default()).unwrap()));
+        let mut history = History::default();
+        // 21 steps end on an instruction rather than a timer tick.
+        run(&mut history, &mut cpu, 21);
+
+        // A reverse step and a step forward again replay the instruction.
+        history.travel(&mut cpu, history.last_step().unwrap());
+        history.step(&mut cpu, [false; 16]);
+        assert!(!history.replaying());
+        run(&mut history, &mut cpu, 5);
+        cpu.take_tracer().unwrap().finish().unwrap();
+
+        let trace = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let cycles: Vec<u64> = trace.lines()
+                                    .filter_map(|line| line.strip_prefix("CYC:"))
+                                    .map(|line| line.split(' ').next().unwrap().parse().unwrap())
+                                    .collect();
+        assert_eq!(cycles, (0..cpu.cycles()).collect::<Vec<_>>());
+    }
+}
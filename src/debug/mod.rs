@@ -0,0 +1,11 @@
+mod dap;
+mod debugger;
+mod expr;
+mod gdb;
+mod history;
+
+pub use self::dap::{read_message, write_message, DapServer};
+pub use self::debugger::{Breakpoint, DebugServer, Debugger, HitCondition, Stop, WatchKind, Watchpoint};
+pub use self::expr::{Expr, LogMessage};
+pub use self::gdb::{target_xml, GdbServer};
+pub use self::history::WriteRecord;
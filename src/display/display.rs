@@ -3,22 +3,33 @@ use sdl2::EventPump;
 use sdl2::pixels;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::video::{FullscreenType, Window};
 
-const SCALE: u32 = 10;
+use super::filter::{FilterMode, FlickerFilter};
+use super::font::{self, GLYPH_HEIGHT};
+use super::overlay::Overlay;
+use super::palette::Palette;
+
+// Default host pixels per CHIP-8 pixel.
+pub const SCALE: u32 = 10;
 const HEIGHT: u32 = 32;
 const WIDTH: u32 = 64;
-const SCREEN_HEIGHT: u32 = SCALE * HEIGHT;
-const SCREEN_WIDTH: u32 = SCALE * WIDTH;
+// Overlay text is drawn in host pixels, independent of the CHIP-8 scale.
+const TEXT_SCALE: u32 = 2;
+const TEXT_MARGIN: u32 = 4;
 
 pub struct Chip8Display {
     canvas: Canvas<Window>,
+    scale: u32,
+    palette: Palette,
+    filter: FlickerFilter,
+    overlay: Overlay,
 }
 
 impl Chip8Display {
-    pub fn new(sdl: &sdl2::Sdl) -> Self {
+    pub fn new(sdl: &sdl2::Sdl, scale: u32, fullscreen: bool) -> Self {
         let vid = sdl.video().unwrap();
-        let window = vid.window("CHIP-8", SCREEN_WIDTH, SCREEN_HEIGHT)
+        let window = vid.window("CHIP-8", WIDTH * scale, HEIGHT * scale)
                         .position_centered()
                         .opengl()
                         .build()
@@ -29,29 +40,131 @@ impl Chip8Display {
         canvas.clear();
         canvas.present();
 
-        Chip8Display {
+        let mut display = Chip8Display {
             canvas: canvas,
+            scale,
+            palette: Palette::default(),
+            filter: FlickerFilter::new(FilterMode::Off),
+            overlay: Overlay::new(),
+        };
+        display.set_fullscreen(fullscreen);
+        display
+    }
+
+    pub fn scale(&self) -> u32 { self.scale }
+
+    // Resizes the window; a fullscreen window just renders at the new
+    // logical size and lets SDL stretch it.
+    pub fn set_scale(&mut self, scale: u32) {
+        let scale = scale.max(1);
+        if scale != self.scale {
+            self.scale = scale;
+            let _ = self.canvas.window_mut().set_size(WIDTH * scale, HEIGHT * scale);
+            let _ = self.canvas.set_logical_size(WIDTH * scale, HEIGHT * scale);
         }
     }
 
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        let mode = if fullscreen { FullscreenType::Desktop } else { FullscreenType::Off };
+        if self.canvas.window().fullscreen_state() != mode {
+            let _ = self.canvas.window_mut().set_fullscreen(mode);
+            let _ = self.canvas.set_logical_size(WIDTH * self.scale, HEIGHT * self.scale);
+        }
+    }
+
+    fn screen_height(&self) -> u32 { HEIGHT * self.scale }
+
+    pub fn set_title(&mut self, title: &str) {
+        let _ = self.canvas.window_mut().set_title(title);
+    }
+
+    pub fn palette(&self) -> Palette { self.palette }
+    pub fn set_palette(&mut self, palette: Palette) { self.palette = palette; }
+
+    pub fn filter(&self) -> FilterMode { self.filter.mode() }
+    pub fn set_filter(&mut self, mode: FilterMode) { self.filter.set_mode(mode); }
+
+    // A filtered display keeps changing after vram stops, so it has to be
+    // redrawn every frame rather than only on `vram_update`.
+    pub fn needs_redraw(&self) -> bool { self.filter.is_active() || self.overlay.is_dirty() }
+
+    pub fn overlay(&mut self) -> &mut Overlay { &mut self.overlay }
+
     pub fn draw(&mut self, vram: &[[u8; WIDTH as usize]; HEIGHT as usize]) {
-        for (y, row) in vram.iter().enumerate() {
-            for (x, &col) in row.iter().enumerate() {
-                let x = (x as u32) * SCALE;
-                let y = (y as u32) * SCALE;
+        let intensity = self.filter.apply(vram);
+        for (y, row) in intensity.iter().enumerate() {
+            for (x, &level) in row.iter().enumerate() {
+                let x = (x as u32) * self.scale;
+                let y = (y as u32) * self.scale;
 
-                self.canvas.set_draw_color(Chip8Display::pix_color(col));
-                self.canvas.fill_rect(Rect::new(x as i32, y as i32, SCALE, SCALE)).unwrap();
+                let [r, g, b] = self.palette.blend(level);
+                self.canvas.set_draw_color(pixels::Color::RGB(r, g, b));
+                self.canvas.fill_rect(Rect::new(x as i32, y as i32, self.scale, self.scale)).unwrap();
             }
         }
+        self.draw_overlay();
         self.canvas.present();
     }
 
-    fn pix_color(pix: u8) -> pixels::Color {
-        if pix == 0 {
-            pixels::Color::RGB(0, 0, 0)
+    fn draw_overlay(&mut self) {
+        let line_height = (GLYPH_HEIGHT + 2) * TEXT_SCALE;
+        if let Some(counter) = self.overlay.counter().map(str::to_string) {
+            self.draw_text(TEXT_MARGIN, TEXT_MARGIN, &counter);
+        }
+        if let Some(message) = self.overlay.message().map(str::to_string) {
+            let y = self.screen_height().saturating_sub(TEXT_MARGIN + line_height);
+            self.draw_text(TEXT_MARGIN, y, &message);
+        }
+        self.overlay.clear_dirty();
+    }
+
+    // Full-window list menu: a heading, then one line per item with the
+    // selected one drawn inverted. Items past `menu_rows` are not drawn, so
+    // callers scroll the list themselves.
+    pub fn draw_menu(&mut self, heading: &str, items: &[String], selected: Option<usize>) {
+        let [r, g, b] = self.palette.background;
+        self.canvas.set_draw_color(pixels::Color::RGB(r, g, b));
+        self.canvas.clear();
+
+        let line_height = (GLYPH_HEIGHT + 2) * TEXT_SCALE;
+        self.draw_text(TEXT_MARGIN, TEXT_MARGIN, heading);
+        for (i, item) in items.iter().enumerate().take(self.menu_rows()) {
+            let y = TEXT_MARGIN + (i as u32 + 1) * line_height + TEXT_MARGIN;
+            self.draw_text_inverted(TEXT_MARGIN, y, item, selected == Some(i));
+        }
+        self.canvas.present();
+    }
+
+    pub fn menu_rows(&self) -> usize {
+        let line_height = (GLYPH_HEIGHT + 2) * TEXT_SCALE;
+        (self.screen_height().saturating_sub(3 * TEXT_MARGIN) / line_height).saturating_sub(1).max(1) as usize
+    }
+
+    // Text goes on a box in the palette's colours so it stays legible
+    // whatever is underneath.
+    pub fn draw_text(&mut self, x: u32, y: u32, text: &str) {
+        self.draw_text_inverted(x, y, text, false);
+    }
+
+    fn draw_text_inverted(&mut self, x: u32, y: u32, text: &str, inverted: bool) {
+        let (back, front) = if inverted {
+            (self.palette.foreground, self.palette.background)
         } else {
-            pixels::Color::RGB(255, 255, 255)
+            (self.palette.background, self.palette.foreground)
+        };
+        let [br, bg, bb] = back;
+        let [fr, fg, fb] = front;
+        let width = (font::text_width(text) + 2) * TEXT_SCALE;
+        let height = (GLYPH_HEIGHT + 2) * TEXT_SCALE;
+
+        self.canvas.set_draw_color(pixels::Color::RGB(br, bg, bb));
+        self.canvas.fill_rect(Rect::new(x as i32, y as i32, width, height)).unwrap();
+
+        self.canvas.set_draw_color(pixels::Color::RGB(fr, fg, fb));
+        for (px, py) in font::text_pixels(text) {
+            let px = x + (px + 1) * TEXT_SCALE;
+            let py = y + (py + 1) * TEXT_SCALE;
+            self.canvas.fill_rect(Rect::new(px as i32, py as i32, TEXT_SCALE, TEXT_SCALE)).unwrap();
         }
     }
 }
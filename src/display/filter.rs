@@ -0,0 +1,115 @@
+use crate::cpu::{HEIGHT, WIDTH};
+
+pub const DEFAULT_DECAY: f32 = 0.6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterMode {
+    Off,
+    // Lit pixels fade out over several frames, like the VIP's CRT phosphor.
+    // Each frame a pixel's brightness is multiplied by `decay` unless the
+    // current frame lights it again.
+    Phosphor { decay: f32 },
+    // A pixel is lit if it was lit in this frame or the previous one.
+    Or,
+}
+
+impl FilterMode {
+    pub fn from_name(name: &str, decay: f32) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" | "none" => Some(FilterMode::Off),
+            "phosphor" => Some(FilterMode::Phosphor { decay: decay.clamp(0.0, 1.0) }),
+            "or" => Some(FilterMode::Or),
+            _ => None,
+        }
+    }
+}
+
+// Reduces the flicker from XOR-drawn sprites being erased and redrawn on
+// consecutive frames. This is purely a presentation effect; the CPU's vram
+// is never touched.
+pub struct FlickerFilter {
+    mode: FilterMode,
+    intensity: [[f32; WIDTH]; HEIGHT],
+    previous: [[u8; WIDTH]; HEIGHT],
+}
+
+impl FlickerFilter {
+    pub fn new(mode: FilterMode) -> Self {
+        FlickerFilter {
+            mode,
+            intensity: [[0.0; WIDTH]; HEIGHT],
+            previous: [[0; WIDTH]; HEIGHT],
+        }
+    }
+
+    pub fn mode(&self) -> FilterMode { self.mode }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+        self.intensity = [[0.0; WIDTH]; HEIGHT];
+        self.previous = [[0; WIDTH]; HEIGHT];
+    }
+
+    pub fn is_active(&self) -> bool { self.mode != FilterMode::Off }
+
+    // Brightness of every pixel for this frame, from 0.0 to 1.0.
+    pub fn apply(&mut self, vram: &[[u8; WIDTH]; HEIGHT]) -> &[[f32; WIDTH]; HEIGHT] {
+        let rows = self.intensity.iter_mut().zip(vram.iter()).zip(self.previous.iter());
+        for ((levels, row), previous) in rows {
+            let pixels = levels.iter_mut().zip(row.iter()).zip(previous.iter());
+            for ((level, &pix), &was) in pixels {
+                let lit = pix != 0;
+                *level = match self.mode {
+                    FilterMode::Off => if lit { 1.0 } else { 0.0 },
+                    FilterMode::Phosphor { decay } => if lit { 1.0 } else { *level * decay },
+                    FilterMode::Or => if lit || was != 0 { 1.0 } else { 0.0 },
+                };
+            }
+        }
+        self.previous = *vram;
+        &self.intensity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(lit: bool) -> [[u8; WIDTH]; HEIGHT] {
+        let mut vram = [[0; WIDTH]; HEIGHT];
+        vram[3][5] = lit as u8;
+        vram
+    }
+
+    #[test]
+    fn filter_off() {
+        let mut filter = FlickerFilter::new(FilterMode::Off);
+        assert_eq!(filter.apply(&frame(true))[3][5], 1.0);
+        assert_eq!(filter.apply(&frame(false))[3][5], 0.0);
+    }
+
+    #[test]
+    fn filter_phosphor_decay() {
+        let mut filter = FlickerFilter::new(FilterMode::Phosphor { decay: 0.5 });
+        assert_eq!(filter.apply(&frame(true))[3][5], 1.0);
+        assert_eq!(filter.apply(&frame(false))[3][5], 0.5);
+        assert_eq!(filter.apply(&frame(false))[3][5], 0.25);
+        assert_eq!(filter.apply(&frame(true))[3][5], 1.0);
+        assert_eq!(filter.apply(&frame(false))[0][0], 0.0);
+    }
+
+    #[test]
+    fn filter_or_previous() {
+        let mut filter = FlickerFilter::new(FilterMode::Or);
+        assert_eq!(filter.apply(&frame(true))[3][5], 1.0);
+        assert_eq!(filter.apply(&frame(false))[3][5], 1.0);
+        assert_eq!(filter.apply(&frame(false))[3][5], 0.0);
+    }
+
+    #[test]
+    fn filter_mode_from_name() {
+        assert_eq!(FilterMode::from_name("or", 0.5), Some(FilterMode::Or));
+        assert_eq!(FilterMode::from_name("phosphor", 2.0), Some(FilterMode::Phosphor { decay: 1.0 }));
+        assert_eq!(FilterMode::from_name("blur", 0.5), None);
+    }
+}
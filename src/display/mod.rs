@@ -1,3 +1,10 @@
 mod display;
+mod filter;
+mod font;
+mod overlay;
+mod palette;
 
-pub use self::display::Chip8Display;
+pub use self::display::{Chip8Display, SCALE};
+pub use self::filter::{FilterMode, FlickerFilter, DEFAULT_DECAY};
+pub use self::overlay::Overlay;
+pub use self::palette::Palette;
@@ -0,0 +1,102 @@
+// How long a transient message stays up, in host frames.
+pub const MESSAGE_FRAMES: u32 = 120;
+
+// Text drawn over the framebuffer: one transient status message and an
+// optional performance counter. `Chip8Display` owns the rendering; this
+// only tracks what should be shown and whether it changed.
+#[derive(Debug, Default)]
+pub struct Overlay {
+    message: Option<(String, u32)>,
+    counter: Option<String>,
+    show_counter: bool,
+    dirty: bool,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Overlay::default()
+    }
+
+    pub fn show(&mut self, text: &str) {
+        self.message = Some((text.to_string(), MESSAGE_FRAMES));
+        self.dirty = true;
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|(text, _)| text.as_str())
+    }
+
+    // Call once per host frame to age out the current message.
+    pub fn tick(&mut self) {
+        if let Some((_, frames)) = self.message.as_mut() {
+            *frames -= 1;
+            if *frames == 0 {
+                self.message = None;
+                self.dirty = true;
+            }
+        }
+    }
+
+    pub fn show_counter(&self) -> bool { self.show_counter }
+
+    pub fn set_show_counter(&mut self, show: bool) {
+        self.show_counter = show;
+        self.dirty = true;
+    }
+
+    pub fn counter(&self) -> Option<&str> {
+        if self.show_counter { self.counter.as_deref() } else { None }
+    }
+
+    pub fn set_counter(&mut self, text: &str) {
+        if self.counter.as_deref() != Some(text) {
+            self.counter = Some(text.to_string());
+            self.dirty = self.dirty || self.show_counter;
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool { self.dirty }
+    pub fn clear_dirty(&mut self) { self.dirty = false; }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_message_expires() {
+        let mut overlay = Overlay::new();
+        overlay.show("Paused");
+        assert_eq!(overlay.message(), Some("Paused"));
+        for _ in 0..MESSAGE_FRAMES - 1 {
+            overlay.tick();
+        }
+        assert_eq!(overlay.message(), Some("Paused"));
+        overlay.clear_dirty();
+        overlay.tick();
+        assert_eq!(overlay.message(), None);
+        assert!(overlay.is_dirty());
+    }
+
+    #[test]
+    fn overlay_counter_hidden() {
+        let mut overlay = Overlay::new();
+        overlay.set_counter("60 FPS");
+        assert_eq!(overlay.counter(), None);
+        assert!(!overlay.is_dirty());
+        overlay.set_show_counter(true);
+        assert_eq!(overlay.counter(), Some("60 FPS"));
+    }
+
+    #[test]
+    fn overlay_counter_dirty_on_change() {
+        let mut overlay = Overlay::new();
+        overlay.set_show_counter(true);
+        overlay.set_counter("60 FPS");
+        overlay.clear_dirty();
+        overlay.set_counter("60 FPS");
+        assert!(!overlay.is_dirty());
+        overlay.set_counter("59 FPS");
+        assert!(overlay.is_dirty());
+    }
+}
@@ -0,0 +1,59 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+}
+
+impl Palette {
+    pub fn new(background: [u8; 3], foreground: [u8; 3]) -> Self {
+        Palette { background, foreground }
+    }
+
+    // Parses "#RRGGBB" or "#RGB", with or without the '#'.
+    pub fn parse_color(text: &str) -> Option<[u8; 3]> {
+        let hex = text.trim().trim_start_matches('#');
+        if !hex.is_ascii() {
+            return None;
+        }
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+        match hex.len() {
+            6 => Some([channel(&hex[0..2])?, channel(&hex[2..4])?, channel(&hex[4..6])?]),
+            3 => Some([channel(&hex[0..1])? * 0x11, channel(&hex[1..2])? * 0x11, channel(&hex[2..3])? * 0x11]),
+            _ => None,
+        }
+    }
+
+    pub fn color(&self, pix: u8) -> [u8; 3] {
+        if pix == 0 { self.background } else { self.foreground }
+    }
+
+    // Mix between background (0.0) and foreground (1.0).
+    pub fn blend(&self, level: f32) -> [u8; 3] {
+        let mix = |bg: u8, fg: u8| (bg as f32 + (fg as f32 - bg as f32) * level).round() as u8;
+        [
+            mix(self.background[0], self.foreground[0]),
+            mix(self.background[1], self.foreground[1]),
+            mix(self.background[2], self.foreground[2]),
+        ]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::new([0, 0, 0], [255, 255, 255])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_parse_color() {
+        assert_eq!(Palette::parse_color("#FF8000"), Some([255, 128, 0]));
+        assert_eq!(Palette::parse_color("0a0b0c"), Some([10, 11, 12]));
+        assert_eq!(Palette::parse_color("#f80"), Some([255, 136, 0]));
+        assert_eq!(Palette::parse_color("#12345"), None);
+        assert_eq!(Palette::parse_color("#gg0000"), None);
+    }
+}
@@ -0,0 +1,47 @@
+use sdl2::keyboard::Keycode;
+
+// Frontend keys. The keymap can't bind these, so a key is never both a
+// hotkey and part of the CHIP-8 keypad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    FrameAdvance,
+    SoftReset,
+    HardReset,
+    FastForward,
+    SpeedUp,
+    SpeedDown,
+    Record,
+    Screenshot,
+    ToggleCounter,
+}
+
+// Each hotkey's keys, with the names SDL gives them.
+const KEYS: [(Keycode, &str, Hotkey); 14] = [
+    (Keycode::Escape, "Escape", Hotkey::Quit),
+    (Keycode::P, "P", Hotkey::Pause),
+    (Keycode::Pause, "Pause", Hotkey::Pause),
+    (Keycode::N, "N", Hotkey::FrameAdvance),
+    (Keycode::F5, "F5", Hotkey::SoftReset),
+    (Keycode::F6, "F6", Hotkey::HardReset),
+    (Keycode::Tab, "Tab", Hotkey::FastForward),
+    (Keycode::Equals, "=", Hotkey::SpeedUp),
+    (Keycode::KpPlus, "Keypad +", Hotkey::SpeedUp),
+    (Keycode::Minus, "-", Hotkey::SpeedDown),
+    (Keycode::KpMinus, "Keypad -", Hotkey::SpeedDown),
+    (Keycode::F9, "F9", Hotkey::Record),
+    (Keycode::F10, "F10", Hotkey::ToggleCounter),
+    (Keycode::F12, "F12", Hotkey::Screenshot),
+];
+
+impl Hotkey {
+    pub fn from_keycode(keycode: Keycode) -> Option<Self> {
+        KEYS.iter().find(|&&(key, _, _)| key == keycode).map(|&(_, _, hotkey)| hotkey)
+    }
+
+    // SDL matches key names without regard to case.
+    pub fn from_key_name(name: &str) -> Option<Self> {
+        KEYS.iter().find(|&&(_, key, _)| key.eq_ignore_ascii_case(name)).map(|&(_, _, hotkey)| hotkey)
+    }
+}
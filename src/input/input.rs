@@ -1,46 +1,84 @@
+use std::collections::{BTreeMap, HashMap};
+
 use sdl2::{keyboard::Keycode, EventPump};
 
+// Host keys for the named game buttons a ROM profile can bind to keypad
+// keys, on top of the usual 1234/QWER/ASDF/ZXCV layout.
+const GAME_KEYS: [(&str, Keycode); 6] = [
+    ("up", Keycode::Up),
+    ("down", Keycode::Down),
+    ("left", Keycode::Left),
+    ("right", Keycode::Right),
+    ("a", Keycode::Space),
+    ("b", Keycode::Return),
+];
+
+// SDL key names for each keypad key, laid out like the COSMAC VIP's hex
+// keypad on the left of a QWERTY keyboard.
+pub const DEFAULT_KEYMAP: [(&str, u8); 16] = [
+    ("1", 0x1), ("2", 0x2), ("3", 0x3), ("4", 0xC),
+    ("Q", 0x4), ("W", 0x5), ("E", 0x6), ("R", 0xD),
+    ("A", 0x7), ("S", 0x8), ("D", 0x9), ("F", 0xE),
+    ("Z", 0xA), ("X", 0x0), ("C", 0xB), ("V", 0xF),
+];
+
 pub struct Chip8Input {
-    pub event_pump: EventPump
+    pub event_pump: EventPump,
+    keymap: Vec<(Keycode, usize)>,
+    game_keys: Vec<(Keycode, usize)>,
 }
 
 impl Chip8Input {
     pub fn new(sdl: &sdl2::Sdl) -> Self {
-        Chip8Input {
-            event_pump: sdl.event_pump().unwrap()
+        let mut input = Chip8Input {
+            event_pump: sdl.event_pump().unwrap(),
+            keymap: Vec::new(),
+            game_keys: Vec::new(),
+        };
+        let keymap: BTreeMap<String, u8> = DEFAULT_KEYMAP.iter()
+                                                         .map(|&(name, key)| (name.to_string(), key))
+                                                         .collect();
+        input.set_keymap(&keymap).unwrap();
+        input
+    }
+
+    // Host key name (as SDL spells it, e.g. "Q", "Left", "Keypad 1") to
+    // keypad key. Fails on the first name SDL doesn't recognise, leaving
+    // the current keymap in place.
+    pub fn set_keymap(&mut self, keymap: &BTreeMap<String, u8>) -> Result<(), String> {
+        let mut keys = Vec::new();
+        for (name, &key) in keymap {
+            let keycode = Keycode::from_name(name).ok_or_else(|| format!("unknown key {:?}", name))?;
+            if key >= 16 {
+                return Err(format!("key {:?} mapped to {:#X}, past the keypad", name, key));
+            }
+            keys.push((keycode, key as usize));
         }
+        self.keymap = keys;
+        Ok(())
+    }
+
+    // Replaces the game button bindings; unknown names and out-of-range
+    // keys are ignored.
+    pub fn set_game_keys(&mut self, keys: &HashMap<String, u8>) {
+        self.game_keys = GAME_KEYS.iter()
+                                  .filter_map(|&(name, keycode)| {
+                                      keys.get(name)
+                                          .filter(|&&key| key < 16)
+                                          .map(|&key| (keycode, key as usize))
+                                  })
+                                  .collect();
     }
 
     pub fn poll(&self) -> [bool; 16] {
-        let keyboard: Vec<Keycode> = self.event_pump.keyboard_state()
-                                      .pressed_scancodes()
-                                      .filter_map(Keycode::from_scancode)
-                                      .collect();
         let mut keys = [false; 16];
+        let state = self.event_pump.keyboard_state();
+        let pressed = state.pressed_scancodes().filter_map(Keycode::from_scancode);
 
-        for key in keyboard {
-            let index = match key {
-                Keycode::Num1 => Some(0x1),
-                Keycode::Num2 => Some(0x2),
-                Keycode::Num3 => Some(0x3),
-                Keycode::Num4 => Some(0xc),
-                Keycode::Q => Some(0x4),
-                Keycode::W => Some(0x5),
-                Keycode::E => Some(0x6),
-                Keycode::R => Some(0xd),
-                Keycode::A => Some(0x7),
-                Keycode::S => Some(0x8),
-                Keycode::D => Some(0x9),
-                Keycode::F => Some(0xe),
-                Keycode::Z => Some(0xa),
-                Keycode::X => Some(0x0),
-                Keycode::C => Some(0xb),
-                Keycode::V => Some(0xf),
-                _ => None,
-            };
-
-            if let Some(i) = index {
-                keys[i] = true;
+        for key in pressed {
+            let bound = self.keymap.iter().chain(self.game_keys.iter());
+            for &(_, index) in bound.filter(|&&(bound_key, _)| bound_key == key) {
+                keys[index] = true;
             }
         }
 
@@ -1,3 +1,5 @@
+mod hotkeys;
 mod input;
 
-pub use self::input::Chip8Input;
+pub use self::hotkeys::Hotkey;
+pub use self::input::{Chip8Input, DEFAULT_KEYMAP};
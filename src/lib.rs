@@ -1,4 +1,17 @@
-mod audio;
-mod cpu;
-mod display;
-mod input;
+pub mod analyzer;
+pub mod asm;
+pub mod audio;
+pub mod browser;
+pub mod capture;
+pub mod config;
+pub mod control;
+pub mod cpu;
+pub mod database;
+pub mod debug;
+pub mod display;
+pub mod input;
+pub mod octo;
+pub mod profile;
+pub mod rom;
+pub mod symbols;
+pub mod trace;
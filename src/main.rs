@@ -1,66 +1,1373 @@
 extern crate clap;
-use clap::{Arg, App};
+use clap::{AppSettings, Arg, App, ArgMatches, SubCommand};
 
 extern crate fps_clock;
 use fps_clock::FpsClock;
 
 use sdl2::event::Event;
+
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use sdl2::keyboard::Keycode;
 
-mod cpu;
-use cpu::Chip8Cpu;
-mod display;
-use display::Chip8Display;
-mod input;
-use input::Chip8Input;
-mod audio;
-use audio::Chip8Audio;
+extern crate chip8;
+use chip8::analyzer::analyze;
+use chip8::asm::{assemble_with_symbols, listing_with_symbols, mnemonic};
+use chip8::browser::{is_rom_path, RecentRoms, RomBrowser};
+use chip8::config::{AudioConfig, Config, ConfigFile, ConfigLayer};
+use chip8::cpu::{Chip8Cpu, Platform, Quirks, PROG_START};
+use chip8::database::{sha1_hex, RomDatabase, RomInfo};
+use chip8::debug::{DapServer, DebugServer, GdbServer};
+use chip8::capture::{vram_to_rgba, vram_to_text, RecordFormat, Recorder};
+use chip8::display::{Chip8Display, FilterMode, Palette, DEFAULT_DECAY};
+use chip8::control::RunControl;
+use chip8::input::{Chip8Input, Hotkey};
+use chip8::octo;
+use chip8::profile::{folded_stacks, report, Profiler};
+use chip8::rom;
+use chip8::symbols::SymbolMap;
+use chip8::trace::{diff_report, first_divergence, parse_range, parse_trace, TraceFormat, TraceOptions, Tracer};
+use chip8::audio::{Beeper, Chip8Audio, WavWriter, SAMPLE_RATE};
 
 const FPS: u32 = 60;
+const HEADLESS_FRAMES: &str = "600";
+const BENCH_FRAMES: &str = "6000";
 
-fn main() {
-    let sdl = sdl2::init().unwrap();
+// Frontend settings chosen at startup, restored by a hard reset.
+#[derive(Clone)]
+struct Settings {
+    filter: FilterMode,
+    show_counter: bool,
+    trace: Option<TraceArgs>,
+    profile: Option<ProfileArgs>,
+    // The --symbols file, if not the one beside each ROM.
+    symbols: Option<String>,
+}
+
+// Where to write an execution trace and what to put in it.
+#[derive(Clone)]
+struct TraceArgs {
+    path: String,
+    options: TraceOptions,
+}
+
+impl TraceArgs {
+    fn start(&self, symbols: &SymbolMap) -> Option<Tracer> {
+        let options = TraceOptions { symbols: symbols.clone(), ..self.options.clone() };
+        match Tracer::create(&self.path, options) {
+            Ok(tracer) => Some(tracer),
+            Err(e) => {
+                eprintln!("COULD NOT WRITE TRACE {}: {}", self.path, e);
+                None
+            },
+        }
+    }
+}
+
+// Where to write a profile of the ROM, and its call stacks.
+#[derive(Clone)]
+struct ProfileArgs {
+    path: String,
+    stacks: Option<String>,
+}
+
+impl ProfileArgs {
+    fn start(&self, rom: &[u8], symbols: &SymbolMap) -> Option<Profiler> {
+        match Profiler::create(&self.path, self.stacks.as_deref(), rom, symbols) {
+            Ok(profiler) => Some(profiler),
+            Err(e) => {
+                eprintln!("COULD NOT WRITE PROFILE {}", e);
+                None
+            },
+        }
+    }
+}
+
+// Everything that goes into a ROM's effective configuration.
+struct Profiles {
+    database: RomDatabase,
+    file: ConfigFile,
+    cli: ConfigLayer,
+    // Configure each ROM from the database or static analysis.
+    auto: bool,
+}
+
+impl Profiles {
+    // The effective config for a ROM (or for no ROM in particular), and
+    // the cartridge, database or analysis profile that went into it.
+    fn resolve(&self, rom: Option<(&str, &[u8])>, cart: Option<RomInfo>) -> (Config, Option<RomInfo>) {
+        let rom = rom.map(|(path, bytes)| (Path::new(path), bytes));
+        let profile = match rom {
+            // A cartridge's own settings are what its author ran it with.
+            Some(_) if cart.is_some() => cart,
+            Some((path, bytes)) if self.auto => {
+                self.database.lookup(bytes)
+                             .or_else(|| analyze(bytes).rom_info(bytes, path))
+            },
+            _ => None,
+        };
+        // Every layer was checked when it was loaded.
+        let config = Config::resolve(&self.file, rom, profile.as_ref(), &self.cli)
+                         .expect("configuration was validated at startup");
+        (config, profile)
+    }
+}
+
+// How a game session ended.
+enum Exit {
+    Quit,
+    Menu,
+    Load(String),
+}
+
+struct Frontend {
+    display: Chip8Display,
+    input: Chip8Input,
+    audio: Chip8Audio,
+    fps_clock: FpsClock,
+}
 
+// The WAV capture runs its own beeper off the same frames as the device,
+// so the file is identical whether or not audio is playing.
+struct AudioCapture<'a> {
+    path: &'a str,
+    beeper: Beeper,
+    wav: WavWriter<std::io::BufWriter<std::fs::File>>,
+}
+
+impl<'a> AudioCapture<'a> {
+    // Starts the file, reporting failure.
+    fn new(path: &'a str, audio: &AudioConfig) -> Option<Self> {
+        let mut beeper = Beeper::new(SAMPLE_RATE, FPS);
+        beeper.set_tone(audio.tone, audio.volume);
+        match WavWriter::create(path, SAMPLE_RATE) {
+            Ok(wav) => Some(AudioCapture {
+                path,
+                beeper,
+                wav,
+            }),
+            Err(e) => {
+                eprintln!("COULD NOT WRITE AUDIO {}: {}", path, e);
+                None
+            },
+        }
+    }
+
+    fn frame(&mut self, beep: bool) {
+        self.beeper.frame(beep, &mut self.wav);
+    }
+
+    fn save(self) {
+        if let Err(e) = self.wav.finish() {
+            eprintln!("COULD NOT WRITE AUDIO {}: {}", self.path, e);
+        }
+    }
+}
+
+fn main() {
     let args = App::new("CHIP-8 Emulator")
                     .version("1.0")
                     .author("Bryce Davis <me@bryceadavis.com>")
                     .about("CHIP-8 Emulator written in Rust")
-                        .arg(Arg::with_name("input_file")
-                            .help("Input ROM file")
-                            .required(true)
-                            .index(1))
+                        .arg(Arg::with_name("config")
+                            .long("config")
+                            .takes_value(true)
+                            .value_name("FILE")
+                            .global(true)
+                            .help("Config file to use instead of ~/.config/chip8/config.toml"))
+                        .arg(Arg::with_name("ipf")
+                            .long("ipf")
+                            .takes_value(true)
+                            .global(true)
+                            .help("Instructions executed per 60Hz frame (default: from the config, ROM database or analysis, else 1)"))
+                        .arg(Arg::with_name("platform")
+                            .long("platform")
+                            .takes_value(true)
+                            .global(true)
+                            .possible_values(&Platform::ALL.iter().map(|p| p.id()).collect::<Vec<_>>())
+                            .help("Use a platform's quirks and speed"))
+                        .arg(Arg::with_name("scale")
+                            .long("scale")
+                            .takes_value(true)
+                            .global(true)
+                            .help("Window pixels per CHIP-8 pixel"))
+                        .arg(Arg::with_name("fullscreen")
+                            .long("fullscreen")
+                            .global(true)
+                            .help("Start in fullscreen"))
+                        .arg(Arg::with_name("mute")
+                            .long("mute")
+                            .global(true)
+                            .help("Disable sound"))
+                        .arg(Arg::with_name("no_database")
+                            .long("no-database")
+                            .global(true)
+                            .help("Don't pick quirks, speed and colours from the ROM database or analysis"))
+                        .arg(Arg::with_name("trace")
+                            .long("trace")
+                            .takes_value(true)
+                            .value_name("FILE")
+                            .global(true)
+                            .help("Log every instruction executed to FILE (- for stdout)"))
+                        .arg(Arg::with_name("trace_format")
+                            .long("trace-format")
+                            .takes_value(true)
+                            .global(true)
+                            .possible_values(&["text", "json"])
+                            .help("Trace as KEY:value text lines (the default) or JSON lines"))
+                        .arg(Arg::with_name("trace_range")
+                            .long("trace-range")
+                            .takes_value(true)
+                            .value_name("START-END")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .global(true)
+                            .help("Only trace instructions at these addresses (repeatable)"))
+                        .arg(Arg::with_name("trace_last")
+                            .long("trace-last")
+                            .takes_value(true)
+                            .value_name("N")
+                            .global(true)
+                            .help("Only write the last N instructions, when the CPU hits an error"))
+                        .arg(Arg::with_name("trace_memory")
+                            .long("trace-memory")
+                            .takes_value(true)
+                            .value_name("N")
+                            .global(true)
+                            .help("Include N bytes of memory from I with each traced instruction"))
+                        .arg(Arg::with_name("profile")
+                            .long("profile")
+                            .takes_value(true)
+                            .value_name("FILE")
+                            .global(true)
+                            .help("Write a coverage and hotspot report to FILE when the ROM stops"))
+                        .arg(Arg::with_name("profile_stacks")
+                            .long("profile-stacks")
+                            .takes_value(true)
+                            .value_name("FILE")
+                            .global(true)
+                            .help("Write the profile's call stacks to FILE, folded for flame graph tools"))
+                        .arg(Arg::with_name("symbols")
+                            .long("symbols")
+                            .takes_value(true)
+                            .value_name("FILE")
+                            .global(true)
+                            .help("Labels and source lines for the ROM, as `asm` writes them \
+                                   (default: the ROM's name with .sym, if there is one)"))
+                        .args(&run_args())
+                        .subcommand(SubCommand::with_name("run")
+                            .about("Play a ROM, or browse a directory of ROMs (the default)")
+                            .args(&run_args()))
+                        .subcommand(SubCommand::with_name("disasm")
+                            .about("Disassemble a ROM into source that `asm` accepts")
+                            .arg(Arg::with_name("rom")
+                                .required(true)
+                                .help("ROM file"))
+                            .arg(Arg::with_name("output")
+                                .short("o")
+                                .long("output")
+                                .takes_value(true)
+                                .value_name("FILE")
+                                .help("Write the listing to FILE instead of stdout")))
+                        .subcommand(SubCommand::with_name("asm")
+                            .about("Assemble source into a ROM")
+                            .arg(Arg::with_name("source")
+                                .required(true)
+                                .help("Assembly source file"))
+                            .arg(Arg::with_name("output")
+                                .short("o")
+                                .long("output")
+                                .takes_value(true)
+                                .value_name("FILE")
+                                .help("ROM to write (default: the source with a .ch8 extension)")))
+                        .subcommand(SubCommand::with_name("info")
+                            .about("Show what the database and static analysis know about a ROM")
+                            .arg(Arg::with_name("rom")
+                                .required(true)
+                                .help("ROM file")))
+                        .subcommand(SubCommand::with_name("test")
+                            .about("Run a ROM headless and check the final screen")
+                            .arg(Arg::with_name("rom")
+                                .required(true)
+                                .help("ROM file"))
+                            .arg(Arg::with_name("frames")
+                                .long("frames")
+                                .takes_value(true)
+                                .default_value(HEADLESS_FRAMES)
+                                .help("Number of frames to run"))
+                            .arg(Arg::with_name("expect")
+                                .long("expect")
+                                .takes_value(true)
+                                .value_name("FILE")
+                                .help("Screen the ROM should end on, as printed by a previous run")))
+                        .subcommand(SubCommand::with_name("bench")
+                            .about("Measure how fast a ROM emulates without a window")
+                            .arg(Arg::with_name("rom")
+                                .required(true)
+                                .help("ROM file"))
+                            .arg(Arg::with_name("frames")
+                                .long("frames")
+                                .takes_value(true)
+                                .default_value(BENCH_FRAMES)
+                                .help("Number of frames to run")))
+                        .subcommand(SubCommand::with_name("profile")
+                            .about("Run a ROM headless and report where its time goes and what never ran")
+                            .arg(Arg::with_name("rom")
+                                .required(true)
+                                .help("ROM file"))
+                            .arg(Arg::with_name("frames")
+                                .long("frames")
+                                .takes_value(true)
+                                .default_value(HEADLESS_FRAMES)
+                                .help("Number of frames to run")))
+                        .subcommand(SubCommand::with_name("trace-diff")
+                            .about("Find where two execution traces first disagree")
+                            .arg(Arg::with_name("a")
+                                .required(true)
+                                .help("First trace, from --trace or another emulator"))
+                            .arg(Arg::with_name("b")
+                                .required(true)
+                                .help("Second trace"))
+                            .arg(Arg::with_name("context")
+                                .long("context")
+                                .takes_value(true)
+                                .default_value("8")
+                                .help("Matching instructions to show before the difference")))
+                        .subcommand(SubCommand::with_name("dap")
+                            .about("Serve the Debug Adapter Protocol on stdin and stdout, for editors"))
+                        .subcommand(SubCommand::with_name("config")
+                            .about("Inspect the configuration")
+                            .setting(AppSettings::SubcommandRequiredElseHelp)
+                            .subcommand(SubCommand::with_name("show")
+                                .about("Print the effective configuration, optionally for a ROM")
+                                .arg(Arg::with_name("rom")
+                                    .help("ROM file"))))
                         .get_matches();
 
-    let input_file = args.value_of("input_file").unwrap();
+    let code = match args.subcommand() {
+        ("run", Some(run_args)) => run_command(run_args),
+        ("disasm", Some(disasm_args)) => disasm(disasm_args),
+        ("asm", Some(asm_args)) => asm(asm_args),
+        ("info", Some(info_args)) => info(info_args),
+        ("test", Some(test_args)) => test_rom(test_args),
+        ("bench", Some(bench_args)) => bench(bench_args),
+        ("profile", Some(profile_args)) => profile(profile_args),
+        ("trace-diff", Some(diff_args)) => trace_diff(diff_args),
+        ("dap", Some(dap_args)) => dap(dap_args),
+        ("config", Some(config_args)) => {
+            let show_args = config_args.subcommand_matches("show").unwrap();
+            let profiles = load_profiles(show_args);
+            let rom = match show_args.value_of("rom") {
+                Some(path) => match read_rom(path) {
+                    Some((rom, cart)) => Some((path, rom, cart)),
+                    None => std::process::exit(1),
+                },
+                None => None,
+            };
+            let (config, _) = match rom {
+                Some((path, rom, cart)) => profiles.resolve(Some((path, &rom)), cart),
+                None => profiles.resolve(None, None),
+            };
+            print!("{}", config.show());
+            0
+        },
+        _ => run_command(&args),
+    };
+    std::process::exit(code);
+}
 
+// Options for playing a ROM, taken by `run` and, as before subcommands
+// existed, with no subcommand at all.
+fn run_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("input_file")
+            .help("Input ROM file, or a directory to browse")
+            .index(1),
+        Arg::with_name("headless")
+            .long("headless")
+            .help("Run without a window or audio device"),
+        Arg::with_name("frames")
+            .long("frames")
+            .takes_value(true)
+            .default_value(HEADLESS_FRAMES)
+            .help("Number of frames to run in headless mode"),
+        Arg::with_name("audio_out")
+            .long("audio-out")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write emulated audio to a WAV file"),
+        Arg::with_name("record_video")
+            .long("record-video")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Record frames to a .gif, or to a directory of numbered images"),
+        Arg::with_name("record_format")
+            .long("record-format")
+            .takes_value(true)
+            .possible_values(&["gif", "png", "ppm"])
+            .help("Format for --record-video (default: from the path)"),
+        Arg::with_name("filter")
+            .long("filter")
+            .takes_value(true)
+            .possible_values(&["off", "phosphor", "or"])
+            .default_value("off")
+            .help("Display filter to reduce sprite flicker"),
+        Arg::with_name("decay")
+            .long("decay")
+            .takes_value(true)
+            .help("Brightness kept per frame by the phosphor filter, 0.0 to 1.0"),
+        Arg::with_name("show_fps")
+            .long("show-fps")
+            .help("Show the FPS/IPS counter (toggle with F10)"),
+        Arg::with_name("gdb")
+            .long("gdb")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("Wait for a GDB remote protocol client on localhost:PORT before running"),
+    ]
+}
+
+fn run_command(args: &ArgMatches) -> i32 {
+    let input_file = args.value_of("input_file");
+    let frames: u64 = args.value_of("frames").unwrap().parse()
+                          .expect("--frames must be a number");
+    let decay: f32 = args.value_of("decay").map(|d| d.parse().expect("--decay must be a number"))
+                         .unwrap_or(DEFAULT_DECAY);
+    let settings = Settings {
+        filter: FilterMode::from_name(args.value_of("filter").unwrap(), decay).unwrap(),
+        show_counter: args.is_present("show_fps"),
+        trace: trace_args(args),
+        profile: profile_args(args),
+        symbols: args.value_of("symbols").map(str::to_string),
+    };
+
+    let profiles = load_profiles(args);
+    let (base, _) = profiles.resolve(None, None);
+
+    let mut capture = args.value_of("audio_out").map(|path| AudioCapture::new(path, &base.audio).unwrap_or_else(|| std::process::exit(1)));
+    let mut debug: Option<Box<dyn DebugServer>> = match args.value_of("gdb") {
+        Some(port) => match port.parse().map_err(|_| "not a port number".to_string()).and_then(GdbServer::bind) {
+            Ok(mut server) => {
+                if let Some(path) = input_file.filter(|path| !Path::new(path).is_dir()) {
+                    server.set_symbols(rom_symbols(settings.symbols.as_deref(), path));
+                }
+                println!("Waiting for GDB on localhost:{}", server.port());
+                Some(Box::new(server))
+            },
+            Err(e) => {
+                eprintln!("COULD NOT START GDB SERVER ON PORT {}: {}", port, e);
+                return 1;
+            },
+        },
+        None => None,
+    };
+    let mut recorder = args.value_of("record_video").and_then(|path| {
+        let format = args.value_of("record_format")
+                         .and_then(RecordFormat::from_name)
+                         .unwrap_or_else(|| RecordFormat::from_path(path));
+        start_recording(path, format, base.scale, base.palette, &mut debug)
+    });
+
+    if args.is_present("headless") {
+        let input_file = match input_file {
+            Some(path) if !Path::new(path).is_dir() => path,
+            _ => {
+                eprintln!("Headless mode needs a ROM file");
+                std::process::exit(1);
+            },
+        };
+
+        let (mut proc, config, rom) = match headless_cpu(&profiles, input_file) {
+            Some(loaded) => loaded,
+            None => return 1,
+        };
+        let symbols = rom_symbols(settings.symbols.as_deref(), input_file);
+        proc.set_tracer(settings.trace.as_ref().and_then(|trace| trace.start(&symbols)));
+        proc.set_profiler(settings.profile.as_ref().and_then(|profile| profile.start(&rom, &symbols)));
+        // Under a debugger, frames run in real time until the client kills
+        // the program, and a fault is the client's to deal with.
+        let mut remaining = frames;
+        loop {
+            match debug.as_ref() {
+                Some(server) if server.killed() => break,
+                Some(_) => sleep(Duration::from_secs(1) / FPS),
+                None if remaining == 0 => break,
+                None => remaining -= 1,
+            }
+            if !run_frame(&mut proc, &mut debug, [false; 16], config.speed) {
+                continue;
+            }
+
+            if let Some(capture) = capture.as_mut() {
+                capture.frame(proc.beep);
+            }
+            record_frame(&mut recorder, &proc);
+            if debug.is_none() && proc.fault().is_some() {
+                break;
+            }
+        }
+        report_fault(&proc);
+    } else {
+        run(input_file, settings, &profiles, &mut capture, &mut recorder, &mut debug);
+    }
+
+    if let Some(capture) = capture {
+        capture.save();
+    }
+    stop_recording(&mut recorder, &mut debug);
+    0
+}
+
+// The --trace options, exiting on mistakes. None unless --trace is given.
+fn trace_args(args: &ArgMatches) -> Option<TraceArgs> {
+    let path = args.value_of("trace")?;
+    let ranges = args.values_of("trace_range").into_iter().flatten().map(|range| {
+        parse_range(range).unwrap_or_else(|e| {
+            eprintln!("--trace-range: {}", e);
+            std::process::exit(1);
+        })
+    }).collect();
+    let number = |name: &str| args.value_of(name).map(|value| value.parse().unwrap_or_else(|_| {
+        eprintln!("--{} must be a number", name.replace('_', "-"));
+        std::process::exit(1);
+    }));
+    let format = args.value_of("trace_format").and_then(TraceFormat::from_name).unwrap_or(TraceFormat::Text);
+    // The symbols depend on the ROM, so they come in when the trace starts.
+    let options = TraceOptions {
+        format,
+        ranges,
+        last: number("trace_last"),
+        memory: number("trace_memory").unwrap_or(0),
+        ..TraceOptions::default()
+    };
+    Some(TraceArgs { path: path.to_string(), options })
+}
+
+// The --profile options. None unless --profile is given.
+fn profile_args(args: &ArgMatches) -> Option<ProfileArgs> {
+    Some(ProfileArgs {
+        path: args.value_of("profile")?.to_string(),
+        stacks: args.value_of("profile_stacks").map(str::to_string),
+    })
+}
+
+// Reads the config file and command line overrides, exiting on mistakes
+// in either.
+fn load_profiles(args: &ArgMatches) -> Profiles {
+    let path = args.value_of("config").map(PathBuf::from).or_else(ConfigFile::default_path);
+    let file = match path {
+        Some(path) => ConfigFile::load(&path).unwrap_or_else(|e| {
+            eprintln!("COULD NOT LOAD CONFIG {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        None => ConfigFile::default(),
+    };
+
+    let number = |name: &str| args.value_of(name).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("--{} must be a number", name);
+            std::process::exit(1);
+        })
+    });
+    let mut cli = ConfigLayer {
+        speed: number("ipf"),
+        platform: args.value_of("platform").map(str::to_string),
+        scale: number("scale"),
+        ..ConfigLayer::default()
+    };
+    if args.is_present("fullscreen") {
+        cli.fullscreen = Some(true);
+    }
+    if args.is_present("mute") {
+        cli.audio.enabled = Some(false);
+    }
+    if let Err(e) = Config::default().apply(&cli) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let auto = !args.is_present("no_database");
+    Profiles {
+        database: if auto { RomDatabase::load() } else { RomDatabase::empty() },
+        file,
+        cli,
+        auto,
+    }
+}
+
+// Reads a ROM from a file, stdin or an archive, reporting failure. A
+// cartridge comes with its settings.
+fn read_rom(path: &str) -> Option<(Vec<u8>, Option<RomInfo>)> {
+    match rom::read_rom_with_info(path, &mut choose_rom) {
+        Ok(rom) => Some(rom),
+        Err(e) => {
+            eprintln!("COULD NOT LOAD ROM {}: {}", path, e);
+            None
+        },
+    }
+}
+
+// Asks on the terminal which of an archive's ROMs to load.
+fn choose_rom(names: &[String]) -> Option<usize> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    for (i, name) in names.iter().enumerate() {
+        eprintln!("{:3}) {}", i + 1, name);
+    }
+    eprint!("Which ROM? ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok()?;
+    answer.trim().parse::<usize>().ok()?.checked_sub(1)
+}
+
+// Reads a ROM and resolves its config, refusing ROMs too big for the
+// platform it will run as.
+fn open_rom(profiles: &Profiles, path: &str) -> Option<(Vec<u8>, Config, Option<RomInfo>)> {
+    let (rom, cart) = read_rom(path)?;
+    let (config, info) = profiles.resolve(Some((path, &rom)), cart);
+    if let Err(e) = rom::check_size(&rom, config.platform) {
+        eprintln!("COULD NOT LOAD ROM {}: {}", path, e);
+        return None;
+    }
+    Some((rom, config, info))
+}
+
+// A CPU with the ROM loaded and configured, for running without a window.
+fn headless_cpu(profiles: &Profiles, path: &str) -> Option<(Chip8Cpu, Config, Vec<u8>)> {
+    let (rom, config, _) = open_rom(profiles, path)?;
     let mut proc = Chip8Cpu::new();
-    let mut display = Chip8Display::new(&sdl);
-    let mut input = Chip8Input::new(&sdl);
-    let audio = Chip8Audio::new(&sdl);
-    let mut fps_clock = FpsClock::new(FPS);
+    reset(&mut proc, &rom, config.quirks);
+    Some((proc, config, rom))
+}
 
-    proc.load_rom(input_file);
+// Runs a frame, under the debugger if there is one. False if the debugger
+// has the CPU halted.
+fn run_frame(proc: &mut Chip8Cpu, debug: &mut Option<Box<dyn DebugServer>>, input: [bool; 16], ipf: u32) -> bool {
+    match debug {
+        Some(server) => server.frame(proc, input, ipf),
+        None => {
+            proc.run_frame(input, ipf);
+            true
+        },
+    }
+}
 
-    'game_loop:loop {
+// Prints why the CPU stopped, if it did. True if it faulted.
+fn report_fault(proc: &Chip8Cpu) -> bool {
+    match proc.fault() {
+        Some(fault) => {
+            eprintln!("CPU FAULT after {} instructions: {}", proc.cycles(), fault);
+            true
+        },
+        None => false,
+    }
+}
+
+fn frames_arg(args: &ArgMatches) -> u64 {
+    args.value_of("frames").unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("--frames must be a number");
+        std::process::exit(1);
+    })
+}
+
+fn disasm(args: &ArgMatches) -> i32 {
+    let path = args.value_of("rom").unwrap();
+    let rom = match read_rom(path) {
+        Some((rom, _)) => rom,
+        None => return 1,
+    };
+    let text = listing_with_symbols(&rom, &rom_symbols(args.value_of("symbols"), path));
+    match args.value_of("output") {
+        Some(path) => {
+            if let Err(e) = fs::write(path, text) {
+                eprintln!("COULD NOT WRITE {}: {}", path, e);
+                return 1;
+            }
+        },
+        None => print!("{}", text),
+    }
+    0
+}
+
+fn asm(args: &ArgMatches) -> i32 {
+    let path = args.value_of("source").unwrap();
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("COULD NOT READ {}: {}", path, e);
+            return 1;
+        },
+    };
+    let output = args.value_of("output")
+                     .map(PathBuf::from)
+                     .unwrap_or_else(|| Path::new(path).with_extension("ch8"));
+
+    let (rom, mut symbols) = match assemble_with_symbols(&source) {
+        Ok(assembled) => assembled,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return 1;
+        },
+    };
+    if let Err(e) = fs::write(&output, &rom) {
+        eprintln!("COULD NOT WRITE {}: {}", output.display(), e);
+        return 1;
+    }
+    println!("Assembled {} bytes to {}", rom.len(), output.display());
+
+    // The symbols go beside the ROM, where everything else looks for them,
+    // naming the source relative to themselves when they can.
+    let symbols_path = args.value_of("symbols")
+                           .map(PathBuf::from)
+                           .unwrap_or_else(|| output.with_extension("sym"));
+    let source_path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let symbols_dir = symbols_path.parent()
+                                  .map(|dir| if dir.as_os_str().is_empty() { Path::new(".") } else { dir })
+                                  .and_then(|dir| fs::canonicalize(dir).ok());
+    let relative = symbols_dir.as_ref().and_then(|dir| source_path.strip_prefix(dir).ok());
+    symbols.source = Some(relative.unwrap_or(&source_path).to_string_lossy().to_string());
+    if let Err(e) = fs::write(&symbols_path, symbols.to_text()) {
+        eprintln!("COULD NOT WRITE {}: {}", symbols_path.display(), e);
+        return 1;
+    }
+    0
+}
+
+// Prints the database entry, analysis and effective configuration of a
+// ROM. Returns the exit code.
+fn info(args: &ArgMatches) -> i32 {
+    let path = args.value_of("rom").unwrap();
+    let (rom, cart) = match read_rom(path) {
+        Some(rom) => rom,
+        None => return 1,
+    };
+    let profiles = load_profiles(args);
+
+    println!("File:      {}", path);
+    println!("Size:      {} bytes", rom.len());
+    println!("SHA-1:     {}", sha1_hex(&rom));
+    if rom.len() >= 2 {
+        let first = (rom[0] as u16) << 8 | rom[1] as u16;
+        println!("Entry:     {:#05X}  {}", PROG_START, mnemonic(first));
+    }
+    match profiles.database.lookup(&rom) {
+        Some(entry) => println!("Database:  {} ({})", entry.title, entry.summary()),
+        None => println!("Database:  not found"),
+    }
+
+    let analysis = analyze(&rom);
+    println!("Reachable: {} instructions", analysis.reachable);
+    match analysis.platform {
+        Some(platform) => {
+            println!("Detected:  {} ({:.0}% confidence)", platform.name(), analysis.confidence * 100.0);
+            println!("Quirks:    {}", analysis.quirks.describe());
+        },
+        None => println!("Detected:  nothing platform-specific"),
+    }
+    for evidence in &analysis.evidence {
+        println!("  {}", evidence);
+    }
+
+    let (config, _) = profiles.resolve(Some((path, &rom)), cart);
+    println!("Running:   {} IPF, quirks: {}", config.speed, config.quirks.describe());
+    if let Err(e) = rom::check_size(&rom, config.platform) {
+        println!("Too big:   {}", e);
+    }
+    0
+}
+
+// Runs a ROM with no input and prints the screen it ends on, or compares
+// it with one saved earlier. Test ROMs report their results on screen.
+fn test_rom(args: &ArgMatches) -> i32 {
+    let path = args.value_of("rom").unwrap();
+    let frames = frames_arg(args);
+    let (mut proc, config, rom) = match headless_cpu(&load_profiles(args), path) {
+        Some(loaded) => loaded,
+        None => return 1,
+    };
+    let symbols = rom_symbols(args.value_of("symbols"), path);
+    proc.set_tracer(trace_args(args).and_then(|trace| trace.start(&symbols)));
+    proc.set_profiler(profile_args(args).and_then(|profile| profile.start(&rom, &symbols)));
+    for _ in 0..frames {
+        proc.run_frame([false; 16], config.speed);
+        if proc.fault().is_some() {
+            break;
+        }
+    }
+    report_fault(&proc);
+    let screen = vram_to_text(&proc.vram);
+
+    let expect_path = match args.value_of("expect") {
+        Some(expect_path) => expect_path,
+        None => {
+            print!("{}", screen);
+            return 0;
+        },
+    };
+    let expected = match fs::read_to_string(expect_path) {
+        Ok(expected) => expected,
+        Err(e) => {
+            eprintln!("COULD NOT READ {}: {}", expect_path, e);
+            return 1;
+        },
+    };
+    let mismatch = screen.lines()
+                         .zip(expected.lines().chain(std::iter::repeat("")))
+                         .position(|(got, want)| got != want.trim_end());
+    match mismatch {
+        None => {
+            println!("PASS {}", path);
+            0
+        },
+        Some(row) => {
+            println!("FAIL {}: screen differs from {} at row {}", path, expect_path, row);
+            print!("{}", screen);
+            1
+        },
+    }
+}
+
+fn bench(args: &ArgMatches) -> i32 {
+    let path = args.value_of("rom").unwrap();
+    let frames = frames_arg(args);
+    let (mut proc, config, rom) = match headless_cpu(&load_profiles(args), path) {
+        Some(loaded) => loaded,
+        None => return 1,
+    };
+    let symbols = rom_symbols(args.value_of("symbols"), path);
+    proc.set_tracer(trace_args(args).and_then(|trace| trace.start(&symbols)));
+    proc.set_profiler(profile_args(args).and_then(|profile| profile.start(&rom, &symbols)));
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        proc.run_frame([false; 16], config.speed);
+        if proc.fault().is_some() {
+            break;
+        }
+    }
+    if report_fault(&proc) {
+        return 1;
+    }
+    let secs = start.elapsed().as_secs_f64();
+    let fps = frames as f64 / secs;
+    println!("{} frames at {} IPF in {:.3}s: {:.0} frames/s, {:.1}x real time",
+             frames, config.speed, secs, fps, fps / FPS as f64);
+    0
+}
+
+// Runs a ROM with no input and prints its profile, unless --profile says
+// where else to write it.
+fn profile(args: &ArgMatches) -> i32 {
+    let path = args.value_of("rom").unwrap();
+    let frames = frames_arg(args);
+    let (mut proc, config, rom) = match headless_cpu(&load_profiles(args), path) {
+        Some(loaded) => loaded,
+        None => return 1,
+    };
+    let symbols = rom_symbols(args.value_of("symbols"), path);
+    proc.set_tracer(trace_args(args).and_then(|trace| trace.start(&symbols)));
+    let profiler = match profile_args(args) {
+        Some(profile) => match profile.start(&rom, &symbols) {
+            Some(profiler) => profiler,
+            None => return 1,
+        },
+        None => Profiler::new(),
+    };
+    proc.set_profiler(Some(profiler));
+    for _ in 0..frames {
+        proc.run_frame([false; 16], config.speed);
+        if proc.fault().is_some() {
+            break;
+        }
+    }
+    report_fault(&proc);
+
+    let mut profiler = proc.take_profiler().unwrap();
+    if args.value_of("profile").is_none() {
+        print!("{}", report(&profiler, &rom, &symbols));
+        if let Some(stacks) = args.value_of("profile_stacks") {
+            if let Err(e) = fs::write(stacks, folded_stacks(&profiler, &rom, &symbols)) {
+                eprintln!("COULD NOT WRITE PROFILE {}: {}", stacks, e);
+                return 1;
+            }
+        }
+    }
+    match profiler.finish() {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("COULD NOT WRITE PROFILE {}", e);
+            1
+        },
+    }
+}
+
+// Compares two traces. Exits with 1 if they differ.
+fn trace_diff(args: &ArgMatches) -> i32 {
+    let context: usize = match args.value_of("context").unwrap().parse() {
+        Ok(context) => context,
+        Err(_) => {
+            eprintln!("--context must be a number");
+            return 1;
+        },
+    };
+    let read = |path: &str| {
+        fs::read_to_string(path).map_err(|e| e.to_string())
+                                .and_then(|text| parse_trace(&text))
+                                .map_err(|e| eprintln!("COULD NOT READ TRACE {}: {}", path, e))
+                                .ok()
+    };
+    let (path_a, path_b) = (args.value_of("a").unwrap(), args.value_of("b").unwrap());
+    let (a, b) = match (read(path_a), read(path_b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return 1,
+    };
+    match diff_report((path_a, path_b), &a, &b, context) {
+        Some(report) => {
+            print!("{}", report);
+            1
+        },
+        None => {
+            let (start_a, _, _) = first_divergence(&a, &b);
+            println!("Traces match for {} instructions", a.len() - start_a);
+            0
+        },
+    }
+}
+
+// Serves an editor on stdin and stdout, playing the ROM it launches. The
+// client hears about load failures in its launch response.
+fn dap(args: &ArgMatches) -> i32 {
+    let mut server = DapServer::stdio();
+    let program = match server.wait_for_launch() {
+        Some(program) => program,
+        None => return 0,
+    };
+    match program_symbols(&program, args.value_of("symbols")) {
+        Ok(symbols) => server.launched(Ok(symbols)),
+        Err(e) => {
+            server.launched(Err(format!("could not load {}: {}", program, e)));
+            return 1;
+        },
+    }
+
+    let settings = Settings {
+        filter: FilterMode::from_name("off", DEFAULT_DECAY).unwrap(),
+        show_counter: false,
+        trace: trace_args(args),
+        profile: profile_args(args),
+        symbols: args.value_of("symbols").map(str::to_string),
+    };
+    let profiles = load_profiles(args);
+    let mut debug: Option<Box<dyn DebugServer>> = Some(Box::new(server));
+    run(Some(&program), settings, &profiles, &mut None, &mut None, &mut debug);
+    0
+}
+
+// The program's symbols, with the source under its full path since that's
+// what an editor sets breakpoints with. Octo source has to compile; other
+// ROMs only need to load.
+fn program_symbols(path: &str, explicit: Option<&str>) -> Result<SymbolMap, String> {
+    let mut symbols = if rom::is_octo_source_path(Path::new(path)) && explicit.is_none() {
+        let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let (_, mut symbols) = octo::compile_with_symbols(&source)?;
+        symbols.source = Some(path.to_string());
+        symbols
+    } else {
+        rom::read_rom(path, &mut |_| None)?;
+        rom_symbols(explicit, path)
+    };
+    if let Some(Ok(full)) = symbols.source.as_ref().map(fs::canonicalize) {
+        symbols.source = Some(full.to_string_lossy().to_string());
+    }
+    Ok(symbols)
+}
+
+// Labels and source lines for a ROM: from --symbols, from compiling Octo
+// source, or from the .sym file `asm` leaves beside its ROMs. A ROM with
+// none of those just goes without.
+fn rom_symbols(explicit: Option<&str>, path: &str) -> SymbolMap {
+    let beside = Path::new(path).with_extension("sym");
+    let loaded = match explicit {
+        Some(file) => SymbolMap::load(file).map_err(|e| (file.to_string(), e)),
+        None if rom::is_octo_source_path(Path::new(path)) => {
+            fs::read_to_string(path).map_err(|e| e.to_string())
+                                    .and_then(|source| octo::compile_with_symbols(&source))
+                                    .map(|(_, mut symbols)| {
+                                        symbols.source = Some(path.to_string());
+                                        symbols
+                                    })
+                                    .map_err(|e| (path.to_string(), e))
+        },
+        None if beside.is_file() => {
+            let file = beside.to_string_lossy().to_string();
+            SymbolMap::load(&file).map_err(|e| (file, e))
+        },
+        None => Ok(SymbolMap::default()),
+    };
+    loaded.unwrap_or_else(|(file, e)| {
+        eprintln!("COULD NOT LOAD SYMBOLS {}: {}", file, e);
+        SymbolMap::default()
+    })
+}
+
+// A message for the user. Under `chip8 dap` stdout is the client's
+// connection, so with a debugger it goes through the debugger.
+fn notify(debug: &mut Option<Box<dyn DebugServer>>, text: &str) {
+    match debug {
+        Some(server) => server.output(text),
+        None => println!("{}", text),
+    }
+}
+
+fn start_recording(path: &str,
+                   format: RecordFormat,
+                   scale: u32,
+                   palette: Palette,
+                   debug: &mut Option<Box<dyn DebugServer>>) -> Option<Recorder> {
+    match Recorder::start(path, format, scale, palette) {
+        Ok(recorder) => {
+            notify(debug, &format!("Recording to {}", path));
+            Some(recorder)
+        },
+        Err(e) => {
+            eprintln!("COULD NOT RECORD TO {}: {}", path, e);
+            None
+        },
+    }
+}
+
+fn record_frame(recorder: &mut Option<Recorder>, proc: &Chip8Cpu) {
+    if let Some(rec) = recorder.as_mut() {
+        if let Err(e) = rec.frame(&proc.vram) {
+            eprintln!("RECORDING FAILED: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+fn stop_recording(recorder: &mut Option<Recorder>, debug: &mut Option<Box<dyn DebugServer>>) {
+    if let Some(rec) = recorder.take() {
+        let frames = rec.frames();
+        match rec.finish() {
+            Ok(()) => notify(debug, &format!("Recorded {} frames", frames)),
+            Err(e) => eprintln!("RECORDING FAILED: {}", e),
+        }
+    }
+}
+
+fn screenshot(proc: &Chip8Cpu, scale: u32, palette: Palette, debug: &mut Option<Box<dyn DebugServer>>) -> Option<String> {
+    let path = format!("chip8-{}.png", timestamp());
+    match vram_to_rgba(&proc.vram, &palette, scale).save(&path) {
+        Ok(()) => {
+            notify(debug, &format!("Saved screenshot {}", path));
+            Some(path)
+        },
+        Err(e) => {
+            eprintln!("COULD NOT SAVE SCREENSHOT {}: {}", path, e);
+            None
+        },
+    }
+}
+
+fn timestamp() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0)
+}
+
+// The ROM has already passed `open_rom`'s size check. A trace or profile
+// carries on across the reset.
+fn reset(proc: &mut Chip8Cpu, rom: &[u8], quirks: Quirks) {
+    let tracer = proc.take_tracer();
+    let profiler = proc.take_profiler();
+    *proc = Chip8Cpu::new();
+    proc.load_rom_bytes(rom).expect("ROM size was checked when it was opened");
+    proc.set_quirks(quirks);
+    proc.set_tracer(tracer);
+    proc.set_profiler(profiler);
+}
+
+// A ROM file argument starts it straight away; a directory or no argument
+// opens the browser first. Leaving a game started from the browser
+// returns to it.
+fn run(input_file: Option<&str>,
+       settings: Settings,
+       profiles: &Profiles,
+       capture: &mut Option<AudioCapture>,
+       recorder: &mut Option<Recorder>,
+       debug: &mut Option<Box<dyn DebugServer>>) {
+    let sdl = sdl2::init().unwrap();
+    let (base, _) = profiles.resolve(None, None);
+
+    let mut frontend = Frontend {
+        display: Chip8Display::new(&sdl, base.scale, base.fullscreen),
+        input: Chip8Input::new(&sdl),
+        audio: Chip8Audio::new(&sdl, FPS),
+        fps_clock: FpsClock::new(FPS),
+    };
+    let mut recent = RecentRoms::load();
+
+    let browse_dir = match input_file {
+        Some(path) if Path::new(path).is_dir() => Some(path.to_string()),
+        Some(_) => None,
+        None => Some(".".to_string()),
+    };
+    let mut next = match input_file {
+        Some(path) if browse_dir.is_none() => Some(path.to_string()),
+        _ => None,
+    };
+
+    loop {
+        let rom = match next.take() {
+            Some(rom) => rom,
+            None => match browse(&mut frontend, browse_dir.as_deref(), &recent, profiles) {
+                Some(rom) => rom,
+                None => break,
+            },
+        };
+
+        if rom != rom::STDIN {
+            recent.add(Path::new(&rom));
+            if let Err(e) = recent.save() {
+                eprintln!("COULD NOT SAVE RECENT ROMS: {}", e);
+            }
+        }
+
+        match play(&mut frontend, &rom, &settings, profiles, capture, recorder, debug) {
+            Exit::Quit => break,
+            Exit::Menu if browse_dir.is_some() => {},
+            Exit::Menu => break,
+            Exit::Load(path) => next = Some(path),
+        }
+    }
+}
+
+fn browse(frontend: &mut Frontend,
+          dir: Option<&str>,
+          recent: &RecentRoms,
+          profiles: &Profiles) -> Option<String> {
+    let mut browser = RomBrowser::new(dir.map(Path::new), recent);
+    for entry in browser.entries_mut() {
+        if let Some(info) = profiles.database.lookup_file(&entry.path) {
+            entry.title = info.title.clone();
+            entry.info = info.platform.map(|platform| platform.name().to_string());
+        }
+    }
+    let (base, _) = profiles.resolve(None, None);
+    frontend.display.set_scale(base.scale);
+    frontend.display.set_palette(base.palette);
+    frontend.display.set_title("CHIP-8 - Select a ROM");
+    let rows = frontend.display.menu_rows();
+    frontend.audio.pause();
+
+    loop {
+        for event in frontend.input.event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => return None,
+                Event::DropFile { filename, .. } => return Some(filename),
+                Event::KeyDown { keycode: Some(key), .. } => match key {
+                    Keycode::Escape => return None,
+                    Keycode::Up => browser.move_by(-1),
+                    Keycode::Down => browser.move_by(1),
+                    Keycode::PageUp => browser.move_by(-(rows as isize)),
+                    Keycode::PageDown => browser.move_by(rows as isize),
+                    Keycode::Return | Keycode::KpEnter => {
+                        if let Some(entry) = browser.selected() {
+                            return Some(entry.path.to_string_lossy().to_string());
+                        }
+                    },
+                    _ => {},
+                },
+                _ => {},
+            }
+        }
+
+        if browser.is_empty() {
+            let items = vec!["No ROMs found.".to_string(),
+                             "Drop a ROM file on this window.".to_string()];
+            frontend.display.draw_menu("Select a ROM", &items, None);
+        } else {
+            let selected = browser.selected_index();
+            let (first, entries) = browser.visible(rows);
+            let items: Vec<String> = entries.iter().map(|entry| entry.label()).collect();
+            frontend.display.draw_menu("Select a ROM (Enter to play, Esc to quit)",
+                                       &items,
+                                       Some(selected - first));
+        }
+
+        frontend.fps_clock.tick();
+    }
+}
+
+fn play(frontend: &mut Frontend,
+        input_file: &str,
+        settings: &Settings,
+        profiles: &Profiles,
+        capture: &mut Option<AudioCapture>,
+        recorder: &mut Option<Recorder>,
+        debug: &mut Option<Box<dyn DebugServer>>) -> Exit {
+    let Frontend { display, input, audio, fps_clock } = frontend;
+
+    let (rom, config, info) = match open_rom(profiles, input_file) {
+        Some(opened) => opened,
+        None => return Exit::Menu,
+    };
+    if let Some(ref info) = info {
+        notify(debug, &format!("{}: {}", info.title, info.summary()));
+    }
+    let ipf = config.speed;
+    let mut proc = Chip8Cpu::new();
+    reset(&mut proc, &rom, config.quirks);
+    let symbols = rom_symbols(settings.symbols.as_deref(), input_file);
+    proc.set_tracer(settings.trace.as_ref().and_then(|trace| trace.start(&symbols)));
+    proc.set_profiler(settings.profile.as_ref().and_then(|profile| profile.start(&rom, &symbols)));
+
+    display.set_scale(config.scale);
+    display.set_fullscreen(config.fullscreen);
+    display.set_palette(config.palette);
+    if let Err(e) = input.set_keymap(&config.keymap) {
+        eprintln!("BAD KEYMAP: {}", e);
+    }
+    input.set_game_keys(&config.game_keys);
+    audio.set_tone(config.audio.tone, config.audio.volume);
+    audio.set_enabled(config.audio.enabled);
+    display.set_filter(settings.filter);
+    display.overlay().set_show_counter(settings.show_counter);
+    audio.resume();
+    let mut control = RunControl::new(ipf, FPS);
+    let mut title = String::new();
+    // Draw the first frame even if the ROM doesn't touch the screen, so
+    // the browser doesn't linger in the window.
+    proc.vram_update = true;
+
+    let mut counter_start = Instant::now();
+    let mut counter_frames = 0;
+    let mut counter_instructions = 0;
+    // Whether the current run's fault has been reported.
+    let mut faulted = false;
+
+    loop {
         for event in input.event_pump.poll_iter() {
             match event {
-                Event::KeyDown {
-                    keycode: Some(Keycode::Escape), ..
-                } => { break 'game_loop },
+                Event::Quit { .. } => return Exit::Quit,
+                Event::DropFile { filename, .. } if is_rom_path(Path::new(&filename)) => {
+                    return Exit::Load(filename);
+                },
+                Event::KeyDown { keycode: Some(key), repeat, .. } => {
+                    match Hotkey::from_keycode(key) {
+                        Some(Hotkey::Quit) => return Exit::Menu,
+                        Some(Hotkey::FastForward) => control.set_fast_forward(true),
+                        // Everything else acts once per press; only frame
+                        // advance makes sense to auto-repeat.
+                        Some(Hotkey::FrameAdvance) => control.advance_frame(),
+                        Some(_) if repeat => {},
+                        Some(Hotkey::Pause) => {
+                            control.toggle_pause();
+                            if control.paused() {
+                                audio.pause();
+                                display.overlay().show("Paused");
+                            } else {
+                                audio.resume();
+                                display.overlay().show("Resumed");
+                            }
+                        },
+                        Some(Hotkey::SoftReset) => {
+                            reset(&mut proc, &rom, config.quirks);
+                            faulted = false;
+                            display.overlay().show("Soft reset");
+                        },
+                        Some(Hotkey::HardReset) => {
+                            reset(&mut proc, &rom, config.quirks);
+                            faulted = false;
+                            control = RunControl::new(ipf, FPS);
+                            display.set_filter(settings.filter);
+                            audio.resume();
+                            display.overlay().show("Hard reset");
+                        },
+                        Some(Hotkey::SpeedUp) => {
+                            control.speed_up();
+                            display.overlay().show(&format!("Speed {} IPS", control.ips()));
+                        },
+                        Some(Hotkey::SpeedDown) => {
+                            control.speed_down();
+                            display.overlay().show(&format!("Speed {} IPS", control.ips()));
+                        },
+                        Some(Hotkey::Record) => {
+                            if recorder.is_some() {
+                                stop_recording(recorder, debug);
+                                display.overlay().show("Recording stopped");
+                            } else {
+                                let path = format!("chip8-{}.gif", timestamp());
+                                *recorder = start_recording(&path, RecordFormat::Gif, display.scale(), display.palette(), debug);
+                                if recorder.is_some() {
+                                    display.overlay().show("Recording");
+                                }
+                            }
+                        },
+                        Some(Hotkey::Screenshot) => {
+                            if let Some(path) = screenshot(&proc, display.scale(), display.palette(), debug) {
+                                display.overlay().show(&format!("Saved {}", path));
+                            }
+                        },
+                        Some(Hotkey::ToggleCounter) => {
+                            let show = !display.overlay().show_counter();
+                            display.overlay().set_show_counter(show);
+                        },
+                        None => {},
+                    }
+                },
+                Event::KeyUp { keycode: Some(key), .. }
+                    if Hotkey::from_keycode(key) == Some(Hotkey::FastForward) => {
+                    control.set_fast_forward(false);
+                },
                 _ => {}
             }
         }
 
-        proc.tick(input.poll());
+        let frames = control.frames_to_run();
+        for _ in 0..frames {
+            if !run_frame(&mut proc, debug, input.poll(), control.ipf()) {
+                continue;
+            }
+
+            audio.frame(proc.beep);
+            if let Some(capture) = capture.as_mut() {
+                capture.frame(proc.beep);
+            }
+            record_frame(recorder, &proc);
+        }
+        if debug.as_ref().is_some_and(|server| server.killed()) {
+            return Exit::Quit;
+        }
+        if !faulted && report_fault(&proc) {
+            faulted = true;
+            display.overlay().show("CPU fault, reset to restart");
+        }
+
+        counter_frames += 1;
+        counter_instructions += frames * control.ipf();
+        if counter_start.elapsed() >= Duration::from_secs(1) {
+            let secs = counter_start.elapsed().as_secs_f32();
+            display.overlay().set_counter(&format!("{:.0} FPS {:.0} IPS",
+                                                   counter_frames as f32 / secs,
+                                                   counter_instructions as f32 / secs));
+            counter_start = Instant::now();
+            counter_frames = 0;
+            counter_instructions = 0;
+        }
+        display.overlay().tick();
 
-        if proc.vram_update {
+        if proc.vram_update || display.needs_redraw() {
             display.draw(&proc.vram);
+            proc.vram_update = false;
         }
 
-        if proc.beep {
-            audio.play();
-        } else {
-            audio.stop();
+        if control.title() != title {
+            title = control.title();
+            display.set_title(&title);
         }
 
         fps_clock.tick();
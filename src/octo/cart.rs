@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::cpu::{Platform, Quirks};
+use crate::database::{sha1_hex, RomInfo};
+use crate::display::Palette;
+use crate::octo::compile;
+
+// Octo's own default speed, for carts saved without one.
+const OCTO_TICKRATE: u32 = 20;
+
+// The settings Octo saves with a program. Octo writes more (buzzer colours,
+// touch mode, screen rotation), which have nothing to map onto here.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CartOptions {
+    pub tickrate: Option<u32>,
+    pub background_color: Option<String>,
+    pub fill_color: Option<String>,
+    #[serde(default)]
+    pub shift_quirks: bool,
+    #[serde(default)]
+    pub load_store_quirks: bool,
+    #[serde(default)]
+    pub clip_quirks: bool,
+    #[serde(default)]
+    pub jump_quirks: bool,
+    #[serde(default)]
+    pub logic_quirks: bool,
+    #[serde(default, rename = "vBlankQuirks")]
+    pub vblank_quirks: bool,
+    pub max_size: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Cart {
+    pub program: String,
+    #[serde(default)]
+    pub options: CartOptions,
+}
+
+pub fn is_cart_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+}
+
+// An Octocart hides its payload in the colour indices of its frames: every
+// pixel carries two bits in the low bits of its index, four pixels to a
+// byte, most significant bits first, running through the frames in order.
+// The bytes are a 4-byte big-endian length followed by that much UTF-8 JSON,
+// {"program": source, "options": {...}}.
+pub fn decode_cart(gif: &[u8]) -> Result<Cart, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(Cursor::new(gif)).map_err(|e| e.to_string())?;
+
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+    let bytes: Vec<u8> = pixels.chunks_exact(4)
+                               .map(|p| (p[0] & 3) << 6 | (p[1] & 3) << 4 | (p[2] & 3) << 2 | (p[3] & 3))
+                               .collect();
+
+    if bytes.len() < 4 {
+        return Err("not an Octo cartridge".to_string());
+    }
+    let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let json = bytes.get(4..4 + len).ok_or("not an Octo cartridge")?;
+    let json = std::str::from_utf8(json).map_err(|_| "not an Octo cartridge".to_string())?;
+    serde_json::from_str(json).map_err(|e| format!("bad cartridge data: {}", e))
+}
+
+impl CartOptions {
+    pub fn quirks(&self) -> Quirks {
+        Quirks {
+            shift: self.shift_quirks,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: self.load_store_quirks,
+            wrap: !self.clip_quirks,
+            jump: self.jump_quirks,
+            vblank: self.vblank_quirks,
+            logic: self.logic_quirks,
+        }
+    }
+
+    // Octo picks a size limit rather than a platform; each limit is the
+    // space one platform leaves for programs.
+    pub fn platform(&self) -> Option<Platform> {
+        match self.max_size? {
+            0..=3232 => Some(Platform::OriginalChip8),
+            3233..=3584 => Some(Platform::Superchip),
+            _ => Some(Platform::XoChip),
+        }
+    }
+
+    pub fn palette(&self) -> Option<Palette> {
+        let background = Palette::parse_color(self.background_color.as_ref()?)?;
+        let foreground = Palette::parse_color(self.fill_color.as_ref()?)?;
+        Some(Palette::new(background, foreground))
+    }
+}
+
+impl Cart {
+    // The compiled program, and a profile carrying the cart's settings.
+    pub fn build(&self, title: &str) -> Result<(Vec<u8>, RomInfo), String> {
+        let rom = compile(&self.program)?;
+        let info = RomInfo {
+            sha1: sha1_hex(&rom),
+            title: title.to_string(),
+            description: None,
+            platform: self.options.platform(),
+            quirks: self.options.quirks(),
+            tickrate: Some(self.options.tickrate.unwrap_or(OCTO_TICKRATE)),
+            palette: self.options.palette(),
+            keys: HashMap::new(),
+        };
+        Ok((rom, info))
+    }
+}
+
+pub fn read_cart(path: &str) -> Result<(Vec<u8>, RomInfo), String> {
+    let gif = fs::read(path).map_err(|e| e.to_string())?;
+    let title = Path::new(path).file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    decode_cart(&gif)?.build(&title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Packs a payload the way Octo does, into 128x64 frames.
+    fn encode_cart(json: &str) -> Vec<u8> {
+        let mut bytes = (json.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(json.as_bytes());
+        let mut pixels: Vec<u8> = bytes.iter()
+                                       .flat_map(|b| [b >> 6, b >> 4 & 3, b >> 2 & 3, b & 3])
+                                       // The label art lives in the high bits.
+                                       .map(|bits| 0x10 | bits)
+                                       .collect();
+        let frame_size = 128 * 64;
+        pixels.resize(pixels.len().div_ceil(frame_size) * frame_size, 0);
+
+        let palette: Vec<u8> = (0..=255u8).flat_map(|i| [i, i, i]).collect();
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 128, 64, &palette).unwrap();
+            for chunk in pixels.chunks(frame_size) {
+                let frame = gif::Frame::from_indexed_pixels(128, 64, chunk, None);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        gif
+    }
+
+    #[test]
+    fn octo_cart_round_trip() {
+        let json = r##"{"program": ": main\n  clear\n", "options": {
+            "tickrate": 200, "fillColor": "#FFCC00", "backgroundColor": "#996600",
+            "shiftQuirks": true, "clipQuirks": true, "vBlankQuirks": true,
+            "maxSize": 3584, "touchInputMode": "none"}}"##;
+        let cart = decode_cart(&encode_cart(json)).unwrap();
+        assert_eq!(cart.program, ": main\n  clear\n");
+
+        let (rom, info) = cart.build("clear").unwrap();
+        assert_eq!(rom, [0x00, 0xE0]);
+        assert_eq!(info.tickrate, Some(200));
+        assert_eq!(info.platform, Some(Platform::Superchip));
+        assert!(info.quirks.shift && info.quirks.vblank && !info.quirks.wrap && !info.quirks.jump);
+        assert_eq!(info.palette, Some(Palette::new([0x99, 0x66, 0x00], [0xFF, 0xCC, 0x00])));
+    }
+
+    #[test]
+    fn octo_cart_in_zip() {
+        use std::io::Write;
+        let json = r#"{"program": ": main clear", "options": {"tickrate": 500, "shiftQuirks": true}}"#;
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("carts/game.gif", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&encode_cart(json)).unwrap();
+        let path = std::env::temp_dir().join(format!("chip8-test-cart-{}.zip", std::process::id()));
+        fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+
+        let (rom, info) = crate::rom::read_rom_with_info(path.to_str().unwrap(), &mut |_| None).unwrap();
+        fs::remove_file(&path).unwrap();
+        let info = info.unwrap();
+        assert_eq!(rom, [0x00, 0xE0]);
+        assert_eq!(info.title, "game");
+        assert_eq!(info.tickrate, Some(500));
+        assert!(info.quirks.shift);
+    }
+
+    #[test]
+    fn octo_cart_spans_frames() {
+        let program = format!(": main {}", "clear ".repeat(1500));
+        let json = format!(r#"{{"program": "{}"}}"#, program);
+        let cart = decode_cart(&encode_cart(&json)).unwrap();
+        assert_eq!(cart.program, program);
+        let (rom, info) = cart.build("long").unwrap();
+        assert_eq!(rom.len(), 3000);
+        assert_eq!(info.tickrate, Some(OCTO_TICKRATE));
+        assert_eq!(info.platform, None);
+    }
+
+    #[test]
+    fn octo_cart_rejects_plain_gifs() {
+        let palette = [0, 0, 0, 255, 255, 255];
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 4, 4, &palette).unwrap();
+            encoder.write_frame(&gif::Frame::from_indexed_pixels(4, 4, [1; 16], None)).unwrap();
+        }
+        assert_eq!(decode_cart(&gif).unwrap_err(), "not an Octo cartridge");
+        assert!(decode_cart(b"GIF89a").is_err());
+    }
+}
@@ -0,0 +1,1049 @@
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::{E, PI};
+
+use crate::cpu::PROG_START;
+use crate::symbols::SymbolMap;
+
+// Octo can address all of XO-CHIP's 64K.
+const MEMORY: usize = 0x10000;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Token {
+    text: String,
+    line: usize,
+    // A "quoted" string rather than a bare word.
+    string: bool,
+}
+
+fn tokenize(source: &str) -> Result<VecDeque<Token>, String> {
+    let mut tokens = VecDeque::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let mut chars = text.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '#' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => string.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('0') => '\0',
+                            Some(c) => c,
+                            None => return Err(format!("line {}: unterminated string", line)),
+                        }),
+                        Some(c) => string.push(c),
+                        None => return Err(format!("line {}: unterminated string", line)),
+                    }
+                }
+                tokens.push_back(Token { text: string, line, string: true });
+            } else {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push_back(Token { text: word, line, string: false });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register_index(text: &str) -> Option<u16> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(x), None) | (Some('V'), Some(x), None) => x.to_digit(16).map(|x| x as u16),
+        _ => None,
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+}
+
+// Words that can't be label, constant or macro names.
+const RESERVED: [&str; 43] = [
+    ":=", "|=", "&=", "^=", "-=", "=-", "+=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=",
+    "key", "-key", "hex", "bighex", "random", "delay", ":", ":next", ":unpack", ":breakpoint",
+    ":proto", ":alias", ":const", ":org", ";", "return", "clear", "bcd", "save", "load", "buzzer",
+    "if", "then", "begin", "else", "end", "jump", "jump0",
+];
+
+// Where a resolved address goes once a forward reference is defined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fixup {
+    // Low 12 bits of the instruction at the position.
+    Addr12,
+    // Two bytes, big-endian.
+    Addr16,
+    // `base | address >> 8` in one byte, for :unpack.
+    High(u8),
+    Low,
+}
+
+enum Target {
+    Known(usize),
+    Forward(String),
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+struct StringMode {
+    alphabet: Vec<char>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<Option<u8>>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    string_modes: HashMap<String, StringMode>,
+    // Position, kind, label and the line that used it.
+    fixups: Vec<(usize, Fixup, String, usize)>,
+    // Start of each open loop and the `while` jumps out of it.
+    loops: Vec<(usize, Vec<usize>)>,
+    // Pending jump of each open `if ... begin`, and whether `else` was seen.
+    branches: Vec<(usize, bool)>,
+    has_main: bool,
+    symbols: SymbolMap,
+}
+
+// Compiles Octo source to a ROM loaded at 0x200. Covers the language as
+// Octo 1.2 documents it, including macros, :calc and :stringmode.
+pub fn compile(source: &str) -> Result<Vec<u8>, String> {
+    compile_with_symbols(source).map(|(rom, _)| rom)
+}
+
+// Also returns the labels, and the line each byte was compiled from.
+pub fn compile_with_symbols(source: &str) -> Result<(Vec<u8>, SymbolMap), String> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source)?,
+        line: 1,
+        rom: vec![None; MEMORY],
+        // 0x200 is kept for a jump to main, dropped if main comes first.
+        here: PROG_START + 2,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: vec![("unpack-hi".to_string(), 0), ("unpack-lo".to_string(), 1)].into_iter().collect(),
+        macros: HashMap::new(),
+        string_modes: HashMap::new(),
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+        has_main: false,
+        symbols: SymbolMap::default(),
+    };
+    let rom = compiler.run().map_err(|e| format!("line {}: {}", compiler.line, e))?;
+    let mut labels: Vec<(&String, &usize)> = compiler.labels.iter().collect();
+    labels.sort();
+    for (name, &addr) in labels {
+        compiler.symbols.add_label(addr, name);
+    }
+    Ok((rom, compiler.symbols))
+}
+
+impl Compiler {
+    fn run(&mut self) -> Result<Vec<u8>, String> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if !self.has_main {
+            return Err("this program does not contain a main label".to_string());
+        }
+        if let Some(&(start, _)) = self.loops.last() {
+            return Err(format!("loop at {:#X} is missing its again", start));
+        }
+        if !self.branches.is_empty() {
+            return Err("if ... begin is missing its end".to_string());
+        }
+
+        for (pos, fixup, name, line) in std::mem::take(&mut self.fixups) {
+            self.line = line;
+            let addr = *self.labels.get(&name).ok_or_else(|| format!("undefined name {}", name))?;
+            self.patch(pos, fixup, addr)?;
+        }
+
+        let end = self.rom.iter().rposition(|b| b.is_some()).map_or(PROG_START, |last| last + 1);
+        Ok(self.rom[PROG_START..end].iter().map(|b| b.unwrap_or(0)).collect())
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.pop_front().ok_or("unexpected end of program")?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn next_word(&mut self) -> Result<String, String> {
+        Ok(self.next()?.text)
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|token| !token.string && token.text == text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        let token = self.next_word()?;
+        if token == text { Ok(()) } else { Err(format!("expected {}, got {}", text, token)) }
+    }
+
+    fn new_name(&mut self) -> Result<String, String> {
+        let name = self.next_word()?;
+        if !is_name(&name) || RESERVED.contains(&name.as_str()) || register_index(&name).is_some() {
+            return Err(format!("{} can't be used as a name", name));
+        }
+        Ok(name)
+    }
+
+    fn byte(&mut self, b: u8) -> Result<(), String> {
+        if self.here >= MEMORY {
+            return Err("program is larger than 64K".to_string());
+        }
+        if self.rom[self.here].is_some() {
+            return Err(format!("data overlap at {:#X}", self.here));
+        }
+        self.rom[self.here] = Some(b);
+        self.symbols.add_line(self.here, self.line);
+        self.here += 1;
+        Ok(())
+    }
+
+    fn inst(&mut self, op: u16) -> Result<(), String> {
+        self.byte((op >> 8) as u8)?;
+        self.byte(op as u8)
+    }
+
+    fn patch(&mut self, pos: usize, fixup: Fixup, addr: usize) -> Result<(), String> {
+        let byte = |rom: &[Option<u8>], pos: usize| rom[pos].unwrap_or(0);
+        match fixup {
+            Fixup::Addr12 => {
+                if addr > 0xFFF {
+                    return Err(format!("address {:#X} needs more than 12 bits", addr));
+                }
+                self.rom[pos] = Some(byte(&self.rom, pos) & 0xF0 | (addr >> 8) as u8);
+                self.rom[pos + 1] = Some(addr as u8);
+            },
+            Fixup::Addr16 => {
+                self.rom[pos] = Some((addr >> 8) as u8);
+                self.rom[pos + 1] = Some(addr as u8);
+            },
+            Fixup::High(base) => {
+                if base != 0 && addr > 0xFFF {
+                    return Err(format!("address {:#X} needs more than 12 bits", addr));
+                }
+                self.rom[pos] = Some(base | (addr >> 8) as u8);
+            },
+            Fixup::Low => self.rom[pos] = Some(addr as u8),
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String, addr: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return Err(format!("the name {} has already been defined", name));
+        }
+        self.labels.insert(name, addr);
+        Ok(())
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register_index(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&mut self) -> Result<u16, String> {
+        let name = self.next_word()?;
+        register_index(&name).or_else(|| self.aliases.get(&name).cloned())
+                             .ok_or_else(|| format!("expected a register, got {}", name))
+    }
+
+    fn known_value(&self, text: &str) -> Option<f64> {
+        parse_number(text).or_else(|| self.constants.get(text).cloned())
+                          .or_else(|| self.labels.get(text).map(|&addr| addr as f64))
+    }
+
+    // A number, constant, label or { expression }.
+    fn value(&mut self) -> Result<f64, String> {
+        let token = self.next_word()?;
+        if token == "{" {
+            return self.calc();
+        }
+        self.known_value(&token).ok_or_else(|| format!("undefined name {}", token))
+    }
+
+    fn ranged(&mut self, min: i64, max: i64) -> Result<u16, String> {
+        let value = self.value()?.floor() as i64;
+        if value < min || value > max {
+            return Err(format!("value {} is out of range {} to {}", value, min, max));
+        }
+        Ok(value as u16)
+    }
+
+    fn byte_value(&mut self) -> Result<u16, String> {
+        Ok(self.ranged(-128, 255)? & 0xFF)
+    }
+
+    fn nibble(&mut self) -> Result<u16, String> {
+        self.ranged(0, 15)
+    }
+
+    // An address that may be a label defined further on.
+    fn target(&mut self) -> Result<Target, String> {
+        let token = self.next_word()?;
+        if token == "{" {
+            return Ok(Target::Known(self.calc()? as usize));
+        }
+        match self.known_value(&token) {
+            Some(value) => Ok(Target::Known(value as usize)),
+            None if is_name(&token) => Ok(Target::Forward(token)),
+            None => Err(format!("expected an address, got {}", token)),
+        }
+    }
+
+    fn resolve(&mut self, target: Target, pos: usize, fixup: Fixup) -> Result<usize, String> {
+        match target {
+            Target::Known(addr) => Ok(addr),
+            Target::Forward(name) => {
+                self.fixups.push((pos, fixup, name, self.line));
+                Ok(0)
+            },
+        }
+    }
+
+    // An instruction whose low 12 bits are an address.
+    fn addr_inst(&mut self, op: u16) -> Result<(), String> {
+        let target = self.target()?;
+        let addr = self.resolve(target, self.here, Fixup::Addr12)?;
+        if addr > 0xFFF {
+            return Err(format!("address {:#X} needs more than 12 bits", addr));
+        }
+        self.inst(op | addr as u16)
+    }
+
+    fn block(&mut self) -> Result<Vec<Token>, String> {
+        self.expect("{")?;
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            if !token.string {
+                if token.text == "{" {
+                    depth += 1;
+                } else if token.text == "}" {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+            }
+            body.push(token);
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        if token.string {
+            return Err(format!("unexpected string \"{}\"", token.text));
+        }
+        let word = token.text.as_str();
+
+        if let Some(value) = parse_number(word) {
+            if !(-128.0..=255.0).contains(&value) {
+                return Err(format!("byte {} is out of range", word));
+            }
+            return self.byte(value as i64 as u8);
+        }
+        if self.is_register(word) {
+            let x = register_index(word).or_else(|| self.aliases.get(word).cloned()).unwrap();
+            return self.register_op(x);
+        }
+
+        match word {
+            ":" => {
+                let name = self.new_name()?;
+                if name == "main" {
+                    if self.here == PROG_START + 2 && !self.has_main && self.rom[PROG_START + 2].is_none() {
+                        self.here = PROG_START;
+                    } else {
+                        let jump = 0x1000 | self.here as u16;
+                        self.rom[PROG_START] = Some((jump >> 8) as u8);
+                        self.rom[PROG_START + 1] = Some(jump as u8);
+                    }
+                    self.has_main = true;
+                }
+                let here = self.here;
+                self.define_label(name, here)?;
+            },
+            ":next" => {
+                let name = self.new_name()?;
+                let here = self.here + 1;
+                self.define_label(name, here)?;
+            },
+            ":alias" => {
+                let name = self.new_name()?;
+                let x = if self.peek_is("{") { self.next()?; self.calc()? as u16 } else { self.register()? };
+                if x > 0xF {
+                    return Err(format!("register {} doesn't exist", x));
+                }
+                self.aliases.insert(name, x);
+            },
+            ":const" => {
+                let name = self.new_name()?;
+                let value = self.value()?;
+                if self.constants.insert(name.clone(), value).is_some() {
+                    return Err(format!("the name {} has already been defined", name));
+                }
+            },
+            ":calc" => {
+                let name = self.new_name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            },
+            ":org" => {
+                let addr = self.value()? as usize;
+                if addr >= MEMORY {
+                    return Err(format!("address {:#X} is past the end of memory", addr));
+                }
+                self.here = addr;
+            },
+            ":byte" => {
+                let b = self.byte_value()?;
+                self.byte(b as u8)?;
+            },
+            ":pointer" => {
+                let target = self.target()?;
+                let addr = self.resolve(target, self.here, Fixup::Addr16)?;
+                self.inst(addr as u16)?;
+            },
+            ":call" => self.addr_inst(0x2000)?,
+            ":unpack" => {
+                let base = if self.peek_is("long") {
+                    self.next()?;
+                    0
+                } else {
+                    self.nibble()? << 4
+                };
+                let target = self.target()?;
+                let (hi, lo) = (self.aliases["unpack-hi"], self.aliases["unpack-lo"]);
+                let pos = self.here;
+                let addr = self.resolve(target.clone_forward(), pos + 1, Fixup::High(base as u8))?;
+                self.resolve(target, pos + 3, Fixup::Low)?;
+                self.inst(0x6000 | hi << 8 | base | (addr >> 8) as u16)?;
+                self.inst(0x6000 | lo << 8 | (addr & 0xFF) as u16)?;
+            },
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            ":assert" => {
+                let message = match self.tokens.front() {
+                    Some(token) if token.string => Some(self.next()?.text),
+                    _ => None,
+                };
+                self.expect("{")?;
+                if self.calc()? == 0.0 {
+                    return Err(message.unwrap_or_else(|| "assertion failed".to_string()));
+                }
+            },
+            ":macro" => {
+                let name = self.new_name()?;
+                let mut params = Vec::new();
+                while !self.peek_is("{") {
+                    params.push(self.next_word()?);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { params, body, calls: 0 });
+            },
+            ":stringmode" => {
+                let name = self.new_name()?;
+                let alphabet = self.next()?;
+                if !alphabet.string {
+                    return Err("expected a string of characters".to_string());
+                }
+                let body = self.block()?;
+                let alphabet = alphabet.text.chars().collect();
+                self.string_modes.insert(name, StringMode { alphabet, body });
+            },
+
+            ";" | "return" => self.inst(0x00EE)?,
+            "clear" => self.inst(0x00E0)?,
+            "bcd" => {
+                let x = self.register()?;
+                self.inst(0xF033 | x << 8)?;
+            },
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    let op = if word == "save" { 0x5002 } else { 0x5003 };
+                    self.inst(op | x << 8 | y << 4)?;
+                } else {
+                    let op = if word == "save" { 0xF055 } else { 0xF065 };
+                    self.inst(op | x << 8)?;
+                }
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.inst(0xD000 | x << 8 | y << 4 | n)?;
+            },
+            "jump" => self.addr_inst(0x1000)?,
+            "jump0" => self.addr_inst(0xB000)?,
+            "native" => self.addr_inst(0x0000)?,
+            "audio" => self.inst(0xF002)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.inst(0x00C0 | n)?;
+            },
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.inst(0x00D0 | n)?;
+            },
+            "scroll-right" => self.inst(0x00FB)?,
+            "scroll-left" => self.inst(0x00FC)?,
+            "exit" => self.inst(0x00FD)?,
+            "lores" => self.inst(0x00FE)?,
+            "hires" => self.inst(0x00FF)?,
+            "saveflags" => {
+                let x = self.register()?;
+                self.inst(0xF075 | x << 8)?;
+            },
+            "loadflags" => {
+                let x = self.register()?;
+                self.inst(0xF085 | x << 8)?;
+            },
+            "plane" => {
+                let n = self.nibble()?;
+                self.inst(0xF001 | n << 8)?;
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let op = match word {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.inst(op | x << 8)?;
+            },
+            "i" => self.i_op()?,
+
+            "if" => self.if_statement()?,
+            "else" => {
+                let (jump, seen_else) = self.branches.pop().ok_or("else without if ... begin")?;
+                if seen_else {
+                    return Err("if ... begin has more than one else".to_string());
+                }
+                let pos = self.here;
+                self.inst(0x1000)?;
+                let here = self.here;
+                self.patch(jump, Fixup::Addr12, here)?;
+                self.branches.push((pos, true));
+            },
+            "end" => {
+                let (jump, _) = self.branches.pop().ok_or("end without if ... begin")?;
+                let here = self.here;
+                self.patch(jump, Fixup::Addr12, here)?;
+            },
+            "loop" => self.loops.push((self.here, Vec::new())),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err("while outside a loop".to_string());
+                }
+                self.condition(true)?;
+                let pos = self.here;
+                self.inst(0x1000)?;
+                self.loops.last_mut().unwrap().1.push(pos);
+            },
+            "again" => {
+                let (start, breaks) = self.loops.pop().ok_or("again without loop")?;
+                self.inst(0x1000 | start as u16)?;
+                let here = self.here;
+                for pos in breaks {
+                    self.patch(pos, Fixup::Addr12, here)?;
+                }
+            },
+
+            _ if self.macros.contains_key(word) => self.expand_macro(word)?,
+            _ if self.string_modes.contains_key(word) => self.expand_string(word)?,
+            _ if self.constants.contains_key(word) => {
+                let value = self.constants[word] as i64;
+                self.byte(value as u8)?;
+            },
+            _ if is_name(word) && !RESERVED.contains(&word) => {
+                // Anything else is a subroutine, possibly defined later.
+                self.tokens.push_front(token.clone());
+                self.addr_inst(0x2000)?;
+            },
+            _ => return Err(format!("unexpected {}", word)),
+        }
+        Ok(())
+    }
+
+    fn register_op(&mut self, x: u16) -> Result<(), String> {
+        let op = self.next_word()?;
+        let rhs = self.tokens.front().map(|token| token.text.clone()).unwrap_or_default();
+        let rhs_register = self.is_register(&rhs);
+        let y = |this: &mut Self| -> Result<u16, String> { Ok(this.register()? << 4) };
+        let x8 = x << 8;
+
+        let code = match op.as_str() {
+            ":=" if rhs == "random" => {
+                self.next()?;
+                0xC000 | x8 | self.byte_value()?
+            },
+            ":=" if rhs == "key" => {
+                self.next()?;
+                0xF00A | x8
+            },
+            ":=" if rhs == "delay" => {
+                self.next()?;
+                0xF007 | x8
+            },
+            ":=" if rhs_register => 0x8000 | x8 | y(self)?,
+            ":=" => 0x6000 | x8 | self.byte_value()?,
+            "+=" if rhs_register => 0x8004 | x8 | y(self)?,
+            "+=" => 0x7000 | x8 | self.byte_value()?,
+            "-=" if rhs_register => 0x8005 | x8 | y(self)?,
+            "-=" => 0x7000 | x8 | (self.byte_value()?.wrapping_neg() & 0xFF),
+            "=-" => 0x8007 | x8 | y(self)?,
+            "|=" => 0x8001 | x8 | y(self)?,
+            "&=" => 0x8002 | x8 | y(self)?,
+            "^=" => 0x8003 | x8 | y(self)?,
+            ">>=" => 0x8006 | x8 | y(self)?,
+            "<<=" => 0x800E | x8 | y(self)?,
+            _ => return Err(format!("unknown register operation {}", op)),
+        };
+        self.inst(code)
+    }
+
+    fn i_op(&mut self) -> Result<(), String> {
+        let op = self.next_word()?;
+        match op.as_str() {
+            "+=" => {
+                let x = self.register()?;
+                self.inst(0xF01E | x << 8)
+            },
+            ":=" if self.peek_is("hex") || self.peek_is("bighex") => {
+                let big = self.next_word()? == "bighex";
+                let x = self.register()?;
+                self.inst(if big { 0xF030 } else { 0xF029 } | x << 8)
+            },
+            ":=" if self.peek_is("long") => {
+                self.next()?;
+                self.inst(0xF000)?;
+                let target = self.target()?;
+                let addr = self.resolve(target, self.here, Fixup::Addr16)?;
+                if addr >= MEMORY {
+                    return Err(format!("address {:#X} is past the end of memory", addr));
+                }
+                self.inst(addr as u16)
+            },
+            ":=" => self.addr_inst(0xA000),
+            _ => Err(format!("unknown operation i {}", op)),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<(), String> {
+        // The condition is compiled once we know how it's used.
+        let mut lookahead = 3;
+        if let Some(op) = self.tokens.get(1) {
+            if op.text == "key" || op.text == "-key" {
+                lookahead = 2;
+            }
+        }
+        let form = self.tokens.get(lookahead).map(|token| token.text.clone()).unwrap_or_default();
+        match form.as_str() {
+            "then" => {
+                self.condition(false)?;
+                self.expect("then")
+            },
+            "begin" => {
+                self.condition(true)?;
+                self.expect("begin")?;
+                self.branches.push((self.here, false));
+                self.inst(0x1000)
+            },
+            _ => Err(format!("expected then or begin, got {}", form)),
+        }
+    }
+
+    // Emits instructions that skip the next one when the condition is false,
+    // or when it's true if `negated`.
+    fn condition(&mut self, negated: bool) -> Result<(), String> {
+        let x = self.register()?;
+        let op = self.next_word()?;
+        if op == "key" || op == "-key" {
+            let pressed = (op == "key") != negated;
+            return self.inst(if pressed { 0xE0A1 } else { 0xE09E } | x << 8);
+        }
+
+        let rhs = self.tokens.front().map(|token| token.text.clone()).unwrap_or_default();
+        // Either a register (shifted into Y) or an immediate byte.
+        let (y, immediate) = if self.is_register(&rhs) {
+            (Some(self.register()?), 0)
+        } else {
+            (None, self.byte_value()?)
+        };
+
+        match op.as_str() {
+            "==" | "!=" => {
+                let skip_if_equal = (op == "!=") != negated;
+                let code = match (y, skip_if_equal) {
+                    (Some(y), true) => 0x5000 | x << 8 | y << 4,
+                    (Some(y), false) => 0x9000 | x << 8 | y << 4,
+                    (None, true) => 0x3000 | x << 8 | immediate,
+                    (None, false) => 0x4000 | x << 8 | immediate,
+                };
+                self.inst(code)
+            },
+            "<" | ">" | "<=" | ">=" => {
+                // VF becomes the "no borrow" flag of a subtraction: 1 when
+                // the first operand is at least the second.
+                let lhs_first = op == "<" || op == ">=";
+                match (y, lhs_first) {
+                    (Some(y), true) => {
+                        self.inst(0x8F00 | x << 4)?;
+                        self.inst(0x8F05 | y << 4)?;
+                    },
+                    (Some(y), false) => {
+                        self.inst(0x8F00 | y << 4)?;
+                        self.inst(0x8F05 | x << 4)?;
+                    },
+                    (None, true) => {
+                        self.inst(0x6F00 | immediate)?;
+                        self.inst(0x8F07 | x << 4)?;
+                    },
+                    (None, false) => {
+                        self.inst(0x6F00 | immediate)?;
+                        self.inst(0x8F05 | x << 4)?;
+                    },
+                }
+                // `<` and `>` hold when the flag is 0, `<=` and `>=` when 1.
+                let flag = if op.len() == 1 { 0 } else { 1 };
+                self.inst(if negated { 0x3F00 } else { 0x4F00 } | flag)
+            },
+            _ => Err(format!("unknown comparison {}", op)),
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        let count = self.macros[name].params.len();
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+        let line = self.line;
+        let mac = self.macros.get_mut(name).unwrap();
+        let calls = mac.calls.to_string();
+        mac.calls += 1;
+
+        let expanded: Vec<Token> = mac.body.iter().map(|token| {
+            let mut token = token.clone();
+            if !token.string {
+                if let Some(i) = mac.params.iter().position(|param| *param == token.text) {
+                    token = args[i].clone();
+                } else if token.text == "CALLS" {
+                    token.text = calls.clone();
+                }
+            }
+            token.line = line;
+            token
+        }).collect();
+        for token in expanded.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn expand_string(&mut self, name: &str) -> Result<(), String> {
+        let text = self.next()?;
+        if !text.string {
+            return Err(format!("{} expects a string", name));
+        }
+        let line = self.line;
+        let mode = &self.string_modes[name];
+        let mut expanded = Vec::new();
+        for (index, c) in text.text.chars().enumerate() {
+            let char_index = mode.alphabet.iter().position(|&a| a == c)
+                                 .ok_or_else(|| format!("{} can't encode {:?}", name, c))?;
+            for token in &mode.body {
+                let mut token = token.clone();
+                if !token.string {
+                    match token.text.as_str() {
+                        "CHAR" => token.text = char_index.to_string(),
+                        "INDEX" => token.text = index.to_string(),
+                        "VALUE" => token.text = (c as u32).to_string(),
+                        _ => {},
+                    }
+                }
+                token.line = line;
+                expanded.push(token);
+            }
+        }
+        for token in expanded.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // Evaluates the rest of a { ... } expression. There is no precedence:
+    // like Octo, operators apply right to left, so use parentheses.
+    fn calc(&mut self) -> Result<f64, String> {
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next()?;
+            if !token.string && token.text == "}" {
+                break;
+            }
+            tokens.push(token);
+        }
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("unexpected {} in expression", tokens[pos].text));
+        }
+        Ok(value)
+    }
+
+    fn expression(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+        let lhs = self.term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token.text.clone(),
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.expression(tokens, pos)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        let truth = |t: bool| if t { 1.0 } else { 0.0 };
+        Ok(match op.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << b) as f64,
+            ">>" => (a >> b) as f64,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => truth(lhs < rhs),
+            "<=" => truth(lhs <= rhs),
+            ">" => truth(lhs > rhs),
+            ">=" => truth(lhs >= rhs),
+            "==" => truth(lhs == rhs),
+            "!=" => truth(lhs != rhs),
+            _ => return Err(format!("unknown operator {}", op)),
+        })
+    }
+
+    fn term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*pos).ok_or("expression ends too soon")?;
+        *pos += 1;
+        if token.string {
+            return Err(format!("unexpected string \"{}\"", token.text));
+        }
+        let text = token.text.as_str();
+        if text == "(" {
+            let value = self.expression(tokens, pos)?;
+            match tokens.get(*pos) {
+                Some(close) if close.text == ")" => *pos += 1,
+                _ => return Err("missing )".to_string()),
+            }
+            return Ok(value);
+        }
+        if text == "strlen" {
+            let string = tokens.get(*pos).filter(|token| token.string).ok_or("strlen expects a string")?;
+            *pos += 1;
+            return Ok(string.text.chars().count() as f64);
+        }
+
+        let unary: Option<fn(f64) -> f64> = match text {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| if v == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.term(tokens, pos)?));
+        }
+        if text == "@" {
+            let addr = self.term(tokens, pos)? as usize;
+            return Ok(self.rom.get(addr).cloned().flatten().unwrap_or(0) as f64);
+        }
+
+        match text {
+            "PI" => Ok(PI),
+            "E" => Ok(E),
+            "HERE" => Ok(self.here as f64),
+            _ => self.known_value(text)
+                     .or_else(|| register_index(text).map(|x| x as f64))
+                     .ok_or_else(|| format!("undefined name {}", text)),
+        }
+    }
+}
+
+impl Target {
+    // A copy for a second fixup against the same forward reference.
+    fn clone_forward(&self) -> Target {
+        match self {
+            Target::Known(addr) => Target::Known(*addr),
+            Target::Forward(name) => Target::Forward(name.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2).map(|pair| (pair[0] as u16) << 8 | *pair.get(1).unwrap_or(&0) as u16).collect()
+    }
+
+    #[test]
+    fn octo_basic_statements() {
+        let rom = compile("
+            : main
+                clear
+                v0 := 5  v1 += 2  v2 -= 1  v3 := v4  v5 := random 0xF
+                i := sprite  sprite v0 v1 4
+                i += v0  bcd v2  save v3  load v4 - v5
+                delay := v0  v6 := key
+            : sprite 0xF0 0x90
+        ").unwrap();
+        assert_eq!(words(&rom), [
+            0x00E0, 0x6005, 0x7102, 0x72FF, 0x8340, 0xC50F,
+            0xA21C, 0xD014, 0xF01E, 0xF233, 0xF355, 0x5453,
+            0xF015, 0xF60A, 0xF090,
+        ]);
+    }
+
+    #[test]
+    fn octo_jump_to_main() {
+        let rom = compile(": data 1 2 : main jump main").unwrap();
+        assert_eq!(rom, [0x12, 0x04, 1, 2, 0x12, 0x04]);
+        assert!(compile(": start clear").unwrap_err().contains("main"));
+    }
+
+    #[test]
+    fn octo_control_flow() {
+        let rom = compile("
+            : main
+                loop
+                    if v0 == 3 then v1 := 1
+                    if v0 != v2 begin
+                        v1 := 2
+                    else
+                        v1 := 3
+                    end
+                    while v0 key
+                again
+        ").unwrap();
+        assert_eq!(words(&rom), [
+            0x4003, 0x6101,
+            0x9020, 0x120C, 0x6102, 0x120E, 0x6103,
+            0xE09E, 0x1214,
+            0x1200,
+        ]);
+    }
+
+    #[test]
+    fn octo_comparisons() {
+        let rom = compile(": main if v1 < v2 then clear  if v1 >= 7 then clear").unwrap();
+        assert_eq!(words(&rom), [0x8F10, 0x8F25, 0x4F00, 0x00E0, 0x6F07, 0x8F17, 0x4F01, 0x00E0]);
+    }
+
+    #[test]
+    fn octo_forward_calls_and_unpack() {
+        let rom = compile(": main draw :unpack 0xA data ; : draw return : data").unwrap();
+        assert_eq!(words(&rom), [0x2208, 0x60A2, 0x610A, 0x00EE, 0x00EE]);
+        assert!(compile(": main nowhere").unwrap_err().contains("undefined name nowhere"));
+    }
+
+    #[test]
+    fn octo_macros_and_calc() {
+        let rom = compile("
+            :const WIDTH 8
+            :calc HALF { WIDTH / 2 }
+            :calc MIXED { 1 + 2 * 3 }
+            :macro set REG VAL { REG := VAL }
+            : main
+                set v3 HALF
+                :byte MIXED
+                :byte { 0xFF & -1 }
+            :macro counted { :byte CALLS }
+                counted counted
+        ").unwrap();
+        assert_eq!(rom, [0x63, 0x04, 7, 0xFF, 0, 1]);
+    }
+
+    #[test]
+    fn octo_stringmode() {
+        let rom = compile(r#"
+            :stringmode text "ABC" { :byte { CHAR + 1 } }
+            : main text "CAB"
+        "#).unwrap();
+        assert_eq!(rom, [3, 1, 2]);
+    }
+
+    #[test]
+    fn octo_errors_name_the_line() {
+        assert_eq!(compile(": main\nv0 := 300").unwrap_err(), "line 2: value 300 is out of range -128 to 255");
+        assert!(compile(": main\n: main").unwrap_err().starts_with("line 2:"));
+        assert!(compile(": main loop").is_err());
+    }
+}
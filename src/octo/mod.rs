@@ -0,0 +1,5 @@
+mod cart;
+mod compiler;
+
+pub use self::cart::{decode_cart, is_cart_path, read_cart, Cart, CartOptions};
+pub use self::compiler::{compile, compile_with_symbols};
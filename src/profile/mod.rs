@@ -0,0 +1,5 @@
+mod profile;
+mod report;
+
+pub use self::profile::{opcode_kind, Profiler};
+pub use self::report::{folded_stacks, report};
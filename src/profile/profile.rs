@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::cpu::{AccessKind, MemoryAccess, RAM};
+use crate::symbols::SymbolMap;
+use super::report::{folded_stacks, report};
+
+// The instruction an opcode is, named by its pattern, like "8xy4".
+pub fn opcode_kind(op: u16) -> &'static str {
+    match (op >> 12, op & 0xF, op & 0xFF) {
+        (0x0, _, _) if op == 0x00E0 => "00E0",
+        (0x0, _, _) if op == 0x00EE => "00EE",
+        (0x0, _, _) => "0nnn",
+        (0x1, _, _) => "1nnn",
+        (0x2, _, _) => "2nnn",
+        (0x3, _, _) => "3xkk",
+        (0x4, _, _) => "4xkk",
+        (0x5, 0x0, _) => "5xy0",
+        (0x6, _, _) => "6xkk",
+        (0x7, _, _) => "7xkk",
+        (0x8, 0x0, _) => "8xy0",
+        (0x8, 0x1, _) => "8xy1",
+        (0x8, 0x2, _) => "8xy2",
+        (0x8, 0x3, _) => "8xy3",
+        (0x8, 0x4, _) => "8xy4",
+        (0x8, 0x5, _) => "8xy5",
+        (0x8, 0x6, _) => "8xy6",
+        (0x8, 0x7, _) => "8xy7",
+        (0x8, 0xE, _) => "8xyE",
+        (0x9, 0x0, _) => "9xy0",
+        (0xA, _, _) => "Annn",
+        (0xB, _, _) => "Bnnn",
+        (0xC, _, _) => "Cxkk",
+        (0xD, _, _) => "Dxyn",
+        (0xE, _, 0x9E) => "Ex9E",
+        (0xE, _, 0xA1) => "ExA1",
+        (0xF, _, 0x07) => "Fx07",
+        (0xF, _, 0x0A) => "Fx0A",
+        (0xF, _, 0x15) => "Fx15",
+        (0xF, _, 0x18) => "Fx18",
+        (0xF, _, 0x1E) => "Fx1E",
+        (0xF, _, 0x29) => "Fx29",
+        (0xF, _, 0x33) => "Fx33",
+        (0xF, _, 0x55) => "Fx55",
+        (0xF, _, 0x65) => "Fx65",
+        _ => "unknown",
+    }
+}
+
+// A subroutine in the call tree: the address it was called at, and the
+// instructions run in it, not counting the ones it called.
+struct Frame {
+    addr: usize,
+    parent: usize,
+    children: HashMap<usize, usize>,
+    instructions: u64,
+}
+
+// Where the report goes when the profiler is done with.
+struct Output {
+    path: String,
+    report: File,
+    stacks: Option<(String, File)>,
+    rom: Vec<u8>,
+    symbols: SymbolMap,
+}
+
+// Counts what a program does as it runs: how often each address and kind
+// of instruction runs, how often each byte of memory is read and written,
+// and which subroutines the time goes in.
+pub struct Profiler {
+    executed: Vec<u64>,
+    kinds: BTreeMap<&'static str, u64>,
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    // The call tree, rooted at wherever the program started. CALL goes
+    // down it and RET back up.
+    frames: Vec<Frame>,
+    current: usize,
+    output: Option<Output>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            executed: vec![0; RAM],
+            kinds: BTreeMap::new(),
+            reads: vec![0; RAM],
+            writes: vec![0; RAM],
+            frames: Vec::new(),
+            current: 0,
+            output: None,
+        }
+    }
+
+    // A profiler that writes its report, and optionally its call stacks,
+    // once it's finished or dropped. The files are created straight away,
+    // so a bad path shows up before the run rather than after.
+    pub fn create(path: &str, stacks: Option<&str>, rom: &[u8], symbols: &SymbolMap) -> Result<Self, String> {
+        let report = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let stacks = match stacks {
+            Some(stacks) => Some((stacks.to_string(), File::create(stacks).map_err(|e| format!("{}: {}", stacks, e))?)),
+            None => None,
+        };
+        let mut profiler = Profiler::new();
+        profiler.output = Some(Output {
+            path: path.to_string(),
+            report,
+            stacks,
+            rom: rom.to_vec(),
+            symbols: symbols.clone(),
+        });
+        Ok(profiler)
+    }
+
+    // One instruction, with the stack depths before and after it and the
+    // address it went on to, which is where a CALL went.
+    pub fn record(&mut self, pc: usize, op: u16, accesses: &[MemoryAccess], depth: (usize, usize), next: usize) {
+        if self.frames.is_empty() {
+            self.frames.push(Frame { addr: pc, parent: 0, children: HashMap::new(), instructions: 0 });
+        }
+        self.executed[pc % RAM] += 1;
+        *self.kinds.entry(opcode_kind(op)).or_insert(0) += 1;
+        for access in accesses {
+            match access.kind {
+                AccessKind::Read => self.reads[access.addr] += 1,
+                AccessKind::Write => self.writes[access.addr] += 1,
+            }
+        }
+        self.frames[self.current].instructions += 1;
+        if depth.1 > depth.0 {
+            self.current = self.call(next);
+        } else if depth.1 < depth.0 {
+            self.current = self.frames[self.current].parent;
+        }
+    }
+
+    pub fn executed(&self, addr: usize) -> u64 { self.executed[addr % RAM] }
+
+    pub fn total(&self) -> u64 { self.executed.iter().sum() }
+
+    pub fn kinds(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.kinds.iter().map(|(&kind, &count)| (kind, count))
+    }
+
+    pub fn reads(&self) -> &[u64] { &self.reads }
+
+    pub fn writes(&self) -> &[u64] { &self.writes }
+
+    // Each chain of calls from the root that ran any instructions itself,
+    // outermost first, with how many.
+    pub fn stacks(&self) -> Vec<(Vec<usize>, u64)> {
+        self.frames.iter().enumerate().filter(|(_, frame)| frame.instructions > 0).map(|(mut id, frame)| {
+            let mut stack = vec![self.frames[id].addr];
+            while id != 0 {
+                id = self.frames[id].parent;
+                stack.push(self.frames[id].addr);
+            }
+            stack.reverse();
+            (stack, frame.instructions)
+        }).collect()
+    }
+
+    // Writes the files given to create(), once.
+    pub fn finish(&mut self) -> Result<(), String> {
+        let mut output = match self.output.take() {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let Output { path, report: file, stacks, rom, symbols } = &mut output;
+        let mut out = BufWriter::new(file);
+        out.write_all(report(self, rom, symbols).as_bytes())
+           .and_then(|_| out.flush())
+           .map_err(|e| format!("{}: {}", path, e))?;
+        if let Some((path, file)) = stacks {
+            file.write_all(folded_stacks(self, rom, symbols).as_bytes()).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, addr: usize) -> usize {
+        if let Some(&child) = self.frames[self.current].children.get(&addr) {
+            return child;
+        }
+        let child = self.frames.len();
+        self.frames.push(Frame { addr, parent: self.current, children: HashMap::new(), instructions: 0 });
+        self.frames[self.current].children.insert(addr, child);
+        child
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("COULD NOT WRITE PROFILE {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Chip8Cpu;
+
+    #[test]
+    fn profile_counts_and_stacks() {
+        let mut cpu = Chip8Cpu::new();
+        // CALL 0x204; JP 0x200; then LD I, 0x300; LD [I], V0; RET
+        cpu.load_rom_bytes(&[0x22, 0x04, 0x12, 0x00, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0xEE]).unwrap();
+        cpu.set_profiler(Some(Profiler::new()));
+        for _ in 0..50 {
+            cpu.step([false; 16]);
+        }
+        let profiler = cpu.take_profiler().unwrap();
+
+        assert_eq!(profiler.total(), 50);
+        assert_eq!(profiler.executed(0x200), 10);
+        assert_eq!(profiler.executed(0x208), 10);
+        assert_eq!(profiler.kinds().collect::<Vec<_>>(), [("00EE", 10), ("1nnn", 10), ("2nnn", 10), ("Annn", 10), ("Fx55", 10)]);
+        assert_eq!(profiler.writes()[0x300], 10);
+        assert_eq!(profiler.reads().iter().sum::<u64>(), 0);
+        // The CALL and JP run in the root, the rest in the subroutine.
+        assert_eq!(profiler.stacks(), [(vec![0x200], 20), (vec![0x200, 0x204], 30)]);
+    }
+}
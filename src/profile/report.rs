@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use crate::asm::{disassemble_with_symbols, Line};
+use crate::cpu::RAM;
+use crate::symbols::SymbolMap;
+use super::profile::Profiler;
+
+// How many of the busiest addresses the report lists.
+const HOTSPOTS: usize = 20;
+// Bytes per row of a heatmap.
+const HEAT_WIDTH: usize = 64;
+// Darker is busier. Counts go on a log scale, so a loop run a million
+// times doesn't wash out one run a thousand.
+const HEAT: &[u8] = b" .:-=+*#%@";
+
+// Everything the profiler counted: the opcodes and addresses the time went
+// on, the ROM's listing with how often each line ran, the code that never
+// ran, and where memory was read and written.
+pub fn report(profiler: &Profiler, rom: &[u8], symbols: &SymbolMap) -> String {
+    let lines = disassemble_with_symbols(rom, symbols);
+    let total = profiler.total();
+    let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+    let mut out = String::new();
+
+    let addresses = (0..RAM).filter(|&addr| profiler.executed(addr) > 0).count();
+    out.push_str(&format!("Executed {} instructions at {} addresses\n", total, addresses));
+
+    out.push_str("\nOpcodes:\n");
+    let mut kinds: Vec<(&str, u64)> = profiler.kinds().collect();
+    kinds.sort_by_key(|&(kind, count)| (std::cmp::Reverse(count), kind));
+    for (kind, count) in kinds {
+        out.push_str(&format!("  {:<8}{:>12}  {:5.1}%\n", kind, count, percent(count)));
+    }
+
+    out.push_str("\nHotspots:\n");
+    let text: HashMap<usize, &str> = lines.iter().map(|line| (line.addr, line.text.as_str())).collect();
+    let mut hot: Vec<usize> = (0..RAM).filter(|&addr| profiler.executed(addr) > 0).collect();
+    hot.sort_by_key(|&addr| (std::cmp::Reverse(profiler.executed(addr)), addr));
+    for addr in hot.into_iter().take(HOTSPOTS) {
+        let count = profiler.executed(addr);
+        out.push_str(&format!("  {:#05X}{:>12}  {:5.1}%  {}\n", addr, count, percent(count), text.get(&addr).unwrap_or(&"")));
+    }
+
+    // Code lines that never ran show a dash. Data only gets a count if
+    // something ran it anyway.
+    out.push_str("\nListing:\n");
+    for line in &lines {
+        if let Some(label) = &line.label {
+            out.push_str(&format!("{:>12}  {}:\n", "", label));
+        }
+        let count = line_count(profiler, line);
+        let count = match count {
+            0 if is_code(line) => "-".to_string(),
+            0 => String::new(),
+            count => count.to_string(),
+        };
+        let hex: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        out.push_str(&format!("{:>12}      {:<28}; {:03X}  {}\n", count, line.text, line.addr, hex.join(" ")));
+    }
+
+    out.push_str("\nNever executed:\n");
+    let unrun = never_executed(profiler, &lines);
+    if unrun.is_empty() {
+        out.push_str("  nothing\n");
+    }
+    for (start, end, instructions) in unrun {
+        let plural = if instructions == 1 { "" } else { "s" };
+        out.push_str(&format!("  {:#05X}-{:#05X}  {} instruction{}\n", start, end, instructions, plural));
+    }
+
+    out.push_str(&heatmap("Memory reads", profiler.reads()));
+    out.push_str(&heatmap("Memory writes", profiler.writes()));
+    out
+}
+
+// The call stacks as "outer;inner count" lines, which flamegraph.pl and
+// most other flame graph tools read. Subroutines go by their symbols, or
+// else their labels in the listing.
+pub fn folded_stacks(profiler: &Profiler, rom: &[u8], symbols: &SymbolMap) -> String {
+    let lines = disassemble_with_symbols(rom, symbols);
+    let labels: HashMap<usize, &str> = lines.iter().filter_map(|line| Some((line.addr, line.label.as_deref()?))).collect();
+    let name = |addr: usize| match symbols.label(addr).or_else(|| labels.get(&addr).copied()) {
+        Some(label) => label.to_string(),
+        None => format!("{:#05X}", addr),
+    };
+    let mut lines: Vec<String> = profiler.stacks().into_iter().map(|(stack, count)| {
+        let names: Vec<String> = stack.into_iter().map(name).collect();
+        format!("{} {}\n", names.join(";"), count)
+    }).collect();
+    lines.sort();
+    lines.concat()
+}
+
+fn is_code(line: &Line) -> bool {
+    !line.text.starts_with("DB ")
+}
+
+fn line_count(profiler: &Profiler, line: &Line) -> u64 {
+    (line.addr..line.addr + line.bytes.len()).map(|addr| profiler.executed(addr)).sum()
+}
+
+// Runs of code lines that never ran, as first and last address and how
+// many instructions. Data between them doesn't split a run.
+fn never_executed(profiler: &Profiler, lines: &[Line]) -> Vec<(usize, usize, usize)> {
+    let mut runs: Vec<(usize, usize, usize)> = Vec::new();
+    let mut in_run = false;
+    for line in lines.iter().filter(|line| is_code(line)) {
+        if line_count(profiler, line) > 0 {
+            in_run = false;
+            continue;
+        }
+        let end = line.addr + line.bytes.len() - 1;
+        match runs.last_mut() {
+            Some(run) if in_run => {
+                run.1 = end;
+                run.2 += 1;
+            },
+            _ => runs.push((line.addr, end, 1)),
+        }
+        in_run = true;
+    }
+    runs
+}
+
+// A row per 64 bytes of memory that saw any accesses.
+fn heatmap(title: &str, counts: &[u64]) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    let mut out = format!("\n{} (busiest byte {}):\n", title, max);
+    if max == 0 {
+        out.push_str("  none\n");
+        return out;
+    }
+    for (row, counts) in counts.chunks(HEAT_WIDTH).enumerate() {
+        if counts.iter().all(|&count| count == 0) {
+            continue;
+        }
+        let cells: String = counts.iter().map(|&count| HEAT[heat(count, max)] as char).collect();
+        out.push_str(&format!("  {:#05X} |{}|\n", row * HEAT_WIDTH, cells));
+    }
+    out
+}
+
+fn heat(count: u64, max: u64) -> usize {
+    let top = HEAT.len() - 1;
+    match count {
+        0 => 0,
+        _ if max <= 1 => top,
+        _ => 1 + ((count as f64).ln() / (max as f64).ln() * (top - 1) as f64).round() as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Chip8Cpu;
+
+    // CALL 0x208; SE V0, 0; CLS, which is always skipped; JP 0x200; then
+    // LD I, 0x300; LD [I], V0; RET, and a byte of data.
+    const ROM: [u8; 15] = [0x22, 0x08, 0x30, 0x00, 0x00, 0xE0, 0x12, 0x00, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0xEE, 0xFF];
+
+    fn profile() -> Profiler {
+        let mut cpu = Chip8Cpu::new();
+        cpu.load_rom_bytes(&ROM).unwrap();
+        cpu.set_profiler(Some(Profiler::new()));
+        for _ in 0..60 {
+            cpu.step([false; 16]);
+        }
+        cpu.take_profiler().unwrap()
+    }
+
+    #[test]
+    fn profile_report() {
+        let profiler = profile();
+        let text = report(&profiler, &ROM, &SymbolMap::default());
+        assert!(text.starts_with("Executed 60 instructions at 6 addresses\n"));
+        assert!(text.contains("\n  2nnn              10   16.7%\n"));
+        assert!(text.contains("\n  0x208          10   16.7%  LD I, 0x300\n"));
+        assert!(text.contains("\n          10      CALL L208                   ; 200  22 08\n"));
+        assert!(text.contains("\n           -      CLS                         ; 204  00 E0\n"));
+        assert!(text.contains("\n                  DB 0xFF                     ; 20E  FF\n"));
+        assert!(text.contains("\nNever executed:\n  0x204-0x205  1 instruction\n"));
+        assert!(text.contains("\nMemory reads (busiest byte 0):\n  none\n"));
+        assert!(text.contains(&format!("\n  0x300 |@{}|\n", " ".repeat(63))));
+
+        assert_eq!(folded_stacks(&profiler, &ROM, &SymbolMap::default()), "L200 30\nL200;L208 30\n");
+        let mut symbols = SymbolMap::default();
+        symbols.add_label(0x200, "main");
+        symbols.add_label(0x208, "save-byte");
+        assert_eq!(folded_stacks(&profiler, &ROM, &symbols), "main 30\nmain;save-byte 30\n");
+        assert!(report(&profiler, &ROM, &symbols).contains("\n          10      CALL save_byte              ; 200  22 08\n"));
+        assert_eq!(heat(1, 1000), 1);
+        assert_eq!(heat(1000, 1000), 9);
+    }
+}
@@ -0,0 +1,3 @@
+mod rom;
+
+pub use self::rom::{check_size, is_octo_source_path, is_zip_path, read_rom, read_rom_with_info, zip_member, zip_roms, STDIN};
@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use zip::ZipArchive;
+
+use crate::browser::is_rom_path;
+use crate::cpu::{Platform, MAX_ROM_SIZE};
+use crate::database::RomInfo;
+use crate::octo::{compile, decode_cart, is_cart_path};
+
+// Path that means "read the ROM from standard input".
+pub const STDIN: &str = "-";
+
+pub fn is_zip_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+pub fn is_octo_source_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("8o"))
+}
+
+// Reads a ROM from a file, from stdin for "-", or out of a .zip archive.
+// An archive holding more than one ROM asks `choose` which to use; `None`
+// gives up. Octo source and Octo cartridges are compiled.
+pub fn read_rom(path: &str, choose: &mut dyn FnMut(&[String]) -> Option<usize>) -> Result<Vec<u8>, String> {
+    read_rom_with_info(path, choose).map(|(rom, _)| rom)
+}
+
+// Like read_rom, along with the settings saved in the cartridge if the ROM
+// that was loaded is one.
+pub fn read_rom_with_info(path: &str, choose: &mut dyn FnMut(&[String]) -> Option<usize>)
+                          -> Result<(Vec<u8>, Option<RomInfo>), String> {
+    if path == STDIN {
+        let mut rom = Vec::new();
+        io::stdin().read_to_end(&mut rom).map_err(|e| e.to_string())?;
+        return Ok((rom, None));
+    }
+
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    if !is_zip_path(Path::new(path)) {
+        return compile_rom(Path::new(path), bytes);
+    }
+
+    let names = zip_roms(&bytes)?;
+    let name = match names.len() {
+        0 => return Err("no ROMs in archive".to_string()),
+        1 => &names[0],
+        _ => match choose(&names) {
+            Some(i) if i < names.len() => &names[i],
+            _ => return Err(format!("archive holds {} ROMs: {}", names.len(), names.join(", "))),
+        },
+    };
+    compile_rom(Path::new(name), zip_member(&bytes, name)?)
+}
+
+fn compile_rom(path: &Path, bytes: Vec<u8>) -> Result<(Vec<u8>, Option<RomInfo>), String> {
+    if is_cart_path(path) {
+        let title = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        let (rom, info) = decode_cart(&bytes)?.build(&title)?;
+        Ok((rom, Some(info)))
+    } else if is_octo_source_path(path) {
+        let source = String::from_utf8(bytes).map_err(|_| "Octo source isn't UTF-8".to_string())?;
+        Ok((compile(&source)?, None))
+    } else {
+        Ok((bytes, None))
+    }
+}
+
+// Files in an archive that look like ROMs, in archive order.
+pub fn zip_roms(archive: &[u8]) -> Result<Vec<String>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(archive)).map_err(|e| e.to_string())?;
+    let mut names = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i).map_err(|e| e.to_string())?;
+        let name = file.name();
+        if file.is_file() && !name.starts_with("__MACOSX") &&
+           is_rom_path(Path::new(name)) && !is_zip_path(Path::new(name)) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+pub fn zip_member(archive: &[u8], name: &str) -> Result<Vec<u8>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(archive)).map_err(|e| e.to_string())?;
+    let mut file = archive.by_name(name).map_err(|e| e.to_string())?;
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom).map_err(|e| e.to_string())?;
+    Ok(rom)
+}
+
+// Refuses ROMs bigger than the platform allows, or bigger than this
+// interpreter's 4K of memory can hold.
+pub fn check_size(rom: &[u8], platform: Option<Platform>) -> Result<(), String> {
+    let (limit, name) = match platform {
+        Some(platform) => (platform.max_rom_size(), platform.name()),
+        None => (MAX_ROM_SIZE, "CHIP-8"),
+    };
+    if rom.len() > limit {
+        Err(format!("ROM is {} bytes, but {} programs can be at most {} bytes", rom.len(), name, limit))
+    } else if rom.len() > MAX_ROM_SIZE {
+        Err(format!("ROM is {} bytes, but only {} fit in this interpreter's 4K of memory", rom.len(), MAX_ROM_SIZE))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn rom_zip_single() {
+        let bytes = archive(&[("readme.txt", b"hi"), ("game.ch8", b"\x00\xE0")]);
+        assert_eq!(zip_roms(&bytes).unwrap(), ["game.ch8"]);
+        assert_eq!(zip_member(&bytes, "game.ch8").unwrap(), b"\x00\xE0");
+    }
+
+    #[test]
+    fn rom_zip_choose() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.zip", std::process::id()));
+        fs::write(&path, archive(&[("a.ch8", b"A"), ("b.ch8", b"B")])).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut offered = Vec::new();
+        let rom = read_rom(path, &mut |names| {
+            offered = names.to_vec();
+            Some(1)
+        });
+        assert_eq!(rom.unwrap(), b"B");
+        assert_eq!(offered, ["a.ch8", "b.ch8"]);
+        assert!(read_rom(path, &mut |_| None).unwrap_err().contains("a.ch8, b.ch8"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rom_zip_octo_source() {
+        let bytes = archive(&[("game.8o", b": main clear")]);
+        let path = std::env::temp_dir().join(format!("chip8-test-octo-{}.zip", std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(read_rom(path.to_str().unwrap(), &mut |_| None).unwrap(), [0x00, 0xE0]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rom_size_limits() {
+        let rom = vec![0; 0x1000];
+        assert!(check_size(&rom[..0xE00], None).is_ok());
+        let err = check_size(&rom, Some(Platform::Chip48)).unwrap_err();
+        assert_eq!(err, "ROM is 4096 bytes, but CHIP-48 programs can be at most 3584 bytes");
+        let err = check_size(&rom, Some(Platform::XoChip)).unwrap_err();
+        assert!(err.contains("4K of memory"));
+    }
+}
@@ -0,0 +1,3 @@
+mod symbols;
+
+pub use self::symbols::{parse_address, SymbolMap};
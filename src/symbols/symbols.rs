@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// Labels and source lines for the addresses of a program, from the
+// compiler that built it. As a file, it's a line per fact:
+//
+//     source game.asm
+//     label 0x200 main_loop
+//     line 0x200 12
+//
+// with `#` starting a comment.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolMap {
+    // The source file the lines are in, when there is one.
+    pub source: Option<String>,
+    labels: BTreeMap<usize, String>,
+    lines: BTreeMap<usize, usize>,
+}
+
+impl SymbolMap {
+    // The first label given for an address is the one it goes by.
+    pub fn add_label(&mut self, addr: usize, name: &str) {
+        self.labels.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn add_line(&mut self, addr: usize, line: usize) {
+        self.lines.insert(addr, line);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.lines.is_empty()
+    }
+
+    pub fn label(&self, addr: usize) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.labels.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.labels.iter().find(|(_, label)| label.as_str() == name).map(|(&addr, _)| addr)
+    }
+
+    // The nearest label at or before an address, and how far past it the
+    // address is.
+    pub fn locate(&self, addr: usize) -> Option<(&str, usize)> {
+        self.labels.range(..=addr).next_back().map(|(&start, name)| (name.as_str(), addr - start))
+    }
+
+    // An address as the label before it and how far past, like
+    // "main_loop+4".
+    pub fn describe(&self, addr: usize) -> Option<String> {
+        self.locate(addr).map(|(label, offset)| match offset {
+            0 => label.to_string(),
+            offset => format!("{}+{}", label, offset),
+        })
+    }
+
+    pub fn line(&self, addr: usize) -> Option<usize> {
+        self.lines.get(&addr).copied()
+    }
+
+    // Where a breakpoint on a line goes: the first address compiled from
+    // it, or from the next line with any code. Returns the address and
+    // the line it's really on.
+    pub fn line_address(&self, line: usize) -> Option<(usize, usize)> {
+        self.lines.iter()
+                  .filter(|&(_, &l)| l >= line)
+                  .min_by_key(|&(&addr, &l)| (l, addr))
+                  .map(|(&addr, &l)| (addr, l))
+    }
+
+    // The source line an address was built from, like "game.asm:12".
+    pub fn location(&self, addr: usize) -> Option<String> {
+        let line = self.line(addr)?;
+        Some(match &self.source {
+            Some(source) => {
+                let name = Path::new(source).file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+                format!("{}:{}", name, line)
+            },
+            None => format!("line {}", line),
+        })
+    }
+
+    // Reads a symbol file. A relative source path is relative to the
+    // file's directory.
+    pub fn load(path: &str) -> Result<SymbolMap, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut symbols = SymbolMap::parse(&text)?;
+        if let Some(source) = &symbols.source {
+            let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+            symbols.source = Some(dir.join(source).to_string_lossy().to_string());
+        }
+        Ok(symbols)
+    }
+
+    // The symbol file format. Errors name the offending line.
+    pub fn parse(text: &str) -> Result<SymbolMap, String> {
+        let mut symbols = SymbolMap::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let result = match fields.as_slice() {
+                [] => Ok(()),
+                ["source", ..] => {
+                    symbols.source = Some(line["source".len()..].trim().to_string());
+                    Ok(())
+                },
+                ["label", addr, name] => parse_address(addr).map(|addr| symbols.add_label(addr, name)),
+                ["line", addr, number] => parse_address(addr).and_then(|addr| {
+                    let number = number.parse().map_err(|_| format!("bad line number {:?}", number))?;
+                    symbols.add_line(addr, number);
+                    Ok(())
+                }),
+                _ => Err(format!("expected source, label or line, not {:?}", line)),
+            };
+            result.map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        Ok(symbols)
+    }
+
+    // The file that `parse` reads back.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        if let Some(source) = &self.source {
+            out.push_str(&format!("source {}\n", source));
+        }
+        for (addr, name) in &self.labels {
+            out.push_str(&format!("label {:#05X} {}\n", addr, name));
+        }
+        for (addr, line) in &self.lines {
+            out.push_str(&format!("line {:#05X} {}\n", addr, line));
+        }
+        out
+    }
+}
+
+// An address written as 0x-prefixed hex or in decimal.
+pub fn parse_address(text: &str) -> Result<usize, String> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("bad address {:?}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_lookup() {
+        let mut symbols = SymbolMap::default();
+        symbols.add_label(0x200, "main");
+        symbols.add_label(0x200, "start");
+        symbols.add_label(0x210, "loop");
+        for (addr, line) in [(0x200, 3), (0x202, 3), (0x204, 5), (0x210, 8)].iter() {
+            symbols.add_line(*addr, *line);
+        }
+
+        assert_eq!(symbols.label(0x200), Some("main"));
+        assert_eq!(symbols.address_of("loop"), Some(0x210));
+        assert_eq!(symbols.locate(0x20E), Some(("main", 0xE)));
+        assert_eq!(symbols.locate(0x210), Some(("loop", 0)));
+        assert_eq!(symbols.locate(0x100), None);
+
+        assert_eq!(symbols.line(0x202), Some(3));
+        assert_eq!(symbols.line_address(3), Some((0x200, 3)));
+        // Nothing on line 4, so the breakpoint moves down to line 5.
+        assert_eq!(symbols.line_address(4), Some((0x204, 5)));
+        assert_eq!(symbols.line_address(9), None);
+
+        assert_eq!(symbols.describe(0x20E).as_deref(), Some("main+14"));
+        assert_eq!(symbols.describe(0x210).as_deref(), Some("loop"));
+        assert_eq!(symbols.location(0x204).as_deref(), Some("line 5"));
+        symbols.source = Some("src/game.asm".to_string());
+        assert_eq!(symbols.location(0x204).as_deref(), Some("game.asm:5"));
+        assert_eq!(symbols.location(0x206), None);
+    }
+
+    #[test]
+    fn symbols_file_round_trips() {
+        let text = "# built by chip8 asm\nsource game.asm\nlabel 0x200 main\nlabel 0x20A draw\nline 0x200 3\nline 514 5\n";
+        let symbols = SymbolMap::parse(text).unwrap();
+        assert_eq!(symbols.source.as_deref(), Some("game.asm"));
+        assert_eq!(symbols.label(0x20A), Some("draw"));
+        assert_eq!(symbols.line(0x202), Some(5));
+        assert_eq!(SymbolMap::parse(&symbols.to_text()).unwrap(), symbols);
+
+        assert_eq!(SymbolMap::parse("label 0x200 main\nlabel here there").unwrap_err(), "line 2: bad address \"here\"");
+        assert!(SymbolMap::parse("frobnicate 0x200").is_err());
+        assert!(SymbolMap::parse("line 0x200 three").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use serde_json::Value;
+
+use crate::asm::mnemonic;
+
+// One instruction from a trace. Traces from other emulators may leave
+// fields out, so every field is optional and only fields both traces have
+// are compared.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceStep {
+    // Line of the trace file, from 1.
+    pub line: usize,
+    pub text: String,
+    pub cycle: Option<u64>,
+    pub pc: Option<usize>,
+    pub opcode: Option<u16>,
+    pub v: [Option<u8>; 16],
+    pub i: Option<usize>,
+    pub sp: Option<usize>,
+    pub dt: Option<u8>,
+    pub st: Option<u8>,
+    pub mem: Option<Vec<u8>>,
+}
+
+fn hex(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+// Reads a trace in either of the formats `Tracer` writes. Text lines are
+// `KEY:value` (or `KEY=value`) fields in any order with hex values,
+// except the decimal cycle count; anything after `;` is ignored. Blank
+// lines, `#` comments and JSON error objects are skipped.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceStep>, String> {
+    let mut steps = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let step = if trimmed.starts_with('{') {
+            let json: Value = serde_json::from_str(trimmed).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            if json.get("error").is_some() {
+                continue;
+            }
+            json_step(&json)
+        } else {
+            text_step(trimmed)
+        };
+        let step = step.map_err(|e| format!("line {}: {}", i + 1, e))?;
+        steps.push(TraceStep { line: i + 1, text: trimmed.to_string(), ..step });
+    }
+    Ok(steps)
+}
+
+fn text_step(line: &str) -> Result<TraceStep, String> {
+    let mut step = TraceStep::default();
+    let fields = line.split(';').next().unwrap();
+    for field in fields.split_whitespace() {
+        let (key, value) = match field.split_once(':').or_else(|| field.split_once('=')) {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let key = key.to_ascii_uppercase();
+        let bad = || format!("bad value {:?} for {}", value, key);
+        let number = || hex(value).ok_or_else(bad);
+        match key.as_str() {
+            "CYC" | "CYCLE" => step.cycle = Some(value.parse().map_err(|_| bad())?),
+            "PC" => step.pc = Some(number()? as usize),
+            "OP" | "OPCODE" => step.opcode = Some(number()? as u16),
+            "I" => step.i = Some(number()? as usize),
+            "SP" => step.sp = Some(number()? as usize),
+            "DT" => step.dt = Some(number()? as u8),
+            "ST" => step.st = Some(number()? as u8),
+            "M" | "MEM" => {
+                let bytes: Option<Vec<u8>> = (0..value.len()).step_by(2)
+                                                             .map(|i| value.get(i..i + 2).and_then(hex).map(|b| b as u8))
+                                                             .collect();
+                step.mem = Some(bytes.ok_or_else(bad)?);
+            },
+            _ => match key.strip_prefix('V').and_then(|x| usize::from_str_radix(x, 16).ok()) {
+                Some(x) if x < 16 && key.len() == 2 => step.v[x] = Some(number()? as u8),
+                // Fields this tool doesn't know about are left alone.
+                _ => {},
+            },
+        }
+    }
+    if step.pc.is_none() && step.opcode.is_none() {
+        return Err("no PC or opcode".to_string());
+    }
+    Ok(step)
+}
+
+fn json_step(json: &Value) -> Result<TraceStep, String> {
+    let number = |key: &str| json.get(key).and_then(Value::as_u64);
+    let mut step = TraceStep {
+        cycle: number("cycle"),
+        pc: number("pc").map(|pc| pc as usize),
+        opcode: number("opcode").map(|op| op as u16),
+        i: number("i").map(|i| i as usize),
+        sp: number("sp").map(|sp| sp as usize),
+        dt: number("dt").map(|dt| dt as u8),
+        st: number("st").map(|st| st as u8),
+        mem: json.get("mem").and_then(Value::as_array)
+                 .map(|mem| mem.iter().map(|b| b.as_u64().unwrap_or(0) as u8).collect()),
+        ..TraceStep::default()
+    };
+    if let Some(v) = json.get("v").and_then(Value::as_array) {
+        for (x, value) in v.iter().take(16).enumerate() {
+            step.v[x] = value.as_u64().map(|value| value as u8);
+        }
+    }
+    if step.pc.is_none() && step.opcode.is_none() {
+        return Err("no pc or opcode".to_string());
+    }
+    Ok(step)
+}
+
+// A field of both steps, formatted, and whether they differ. None when
+// either trace leaves it out.
+type Field = (String, String, String, bool);
+
+fn compare<T: PartialEq>(name: &str, a: &Option<T>, b: &Option<T>, show: &dyn Fn(&T) -> String) -> Option<Field> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some((name.to_string(), show(a), show(b), a != b)),
+        _ => None,
+    }
+}
+
+fn fields(a: &TraceStep, b: &TraceStep) -> Vec<Field> {
+    let mut fields = Vec::new();
+    fields.extend(compare("PC", &a.pc, &b.pc, &|pc| format!("{:04X}", pc)));
+    fields.extend(compare("OP", &a.opcode, &b.opcode, &|op| format!("{:04X}", op)));
+    for x in 0..16 {
+        fields.extend(compare(&format!("V{:X}", x), &a.v[x], &b.v[x], &|v| format!("{:02X}", v)));
+    }
+    fields.extend(compare("I", &a.i, &b.i, &|i| format!("{:04X}", i)));
+    fields.extend(compare("SP", &a.sp, &b.sp, &|sp| format!("{:X}", sp)));
+    fields.extend(compare("DT", &a.dt, &b.dt, &|dt| format!("{:02X}", dt)));
+    fields.extend(compare("ST", &a.st, &b.st, &|st| format!("{:02X}", st)));
+    // Memory is compared over the bytes both traces recorded.
+    if let (Some(ma), Some(mb)) = (&a.mem, &b.mem) {
+        let len = ma.len().min(mb.len());
+        let show = |mem: &[u8]| mem.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+        fields.push(("M[I]".to_string(), show(&ma[..len]), show(&mb[..len]), ma[..len] != mb[..len]));
+    }
+    fields
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Divergence {
+    // The steps at these indices (into each trace) differ.
+    Step(usize, usize),
+    // One trace ran out; the other carries on from these indices.
+    Length(usize, usize),
+}
+
+// Lines the two traces up and finds the first place they disagree. When
+// both traces count cycles, they're aligned on the first cycle both have,
+// so a trace that starts later (or was cut down by --trace-last) still
+// lines up.
+pub fn first_divergence(a: &[TraceStep], b: &[TraceStep]) -> (usize, usize, Option<Divergence>) {
+    let (mut ia, mut ib) = (0, 0);
+    if let (Some(ca), Some(cb)) = (a.first().and_then(|s| s.cycle), b.first().and_then(|s| s.cycle)) {
+        let start = ca.max(cb);
+        ia = a.iter().position(|s| s.cycle >= Some(start)).unwrap_or(a.len());
+        ib = b.iter().position(|s| s.cycle >= Some(start)).unwrap_or(b.len());
+    }
+    let (start_a, start_b) = (ia, ib);
+    while ia < a.len() && ib < b.len() {
+        let differs = a[ia].cycle.zip(b[ib].cycle).is_some_and(|(ca, cb)| ca != cb) ||
+                      fields(&a[ia], &b[ib]).iter().any(|field| field.3);
+        if differs {
+            return (start_a, start_b, Some(Divergence::Step(ia, ib)));
+        }
+        ia += 1;
+        ib += 1;
+    }
+    let divergence = if ia < a.len() || ib < b.len() { Some(Divergence::Length(ia, ib)) } else { None };
+    (start_a, start_b, divergence)
+}
+
+// A report of where two traces part ways, with `context` instructions of
+// lead-up. None if they agree all the way through.
+pub fn diff_report(names: (&str, &str), a: &[TraceStep], b: &[TraceStep], context: usize) -> Option<String> {
+    let (start_a, _, divergence) = first_divergence(a, b);
+    let (ia, ib) = match divergence? {
+        Divergence::Step(ia, ib) => (ia, ib),
+        Divergence::Length(ia, ib) => {
+            let (shorter, longer, step) = if ia < a.len() { (names.1, names.0, &a[ia]) } else { (names.0, names.1, &b[ib]) };
+            return Some(format!("{} ends after {} matching instructions; {} carries on at line {}:\n  {}\n",
+                                shorter, ia - start_a, longer, step.line, step.text));
+        },
+    };
+
+    let mut out = String::new();
+    let matched = ia - start_a;
+    let cycle = a[ia].cycle.map(|cycle| format!(" (cycle {})", cycle)).unwrap_or_default();
+    out.push_str(&format!("Traces diverge after {} matching instructions{}: {} line {}, {} line {}\n",
+                          matched, cycle, names.0, a[ia].line, names.1, b[ib].line));
+
+    let lead_up = &a[ia - matched.min(context)..ia];
+    if !lead_up.is_empty() {
+        out.push_str("\nLead-up (the same in both):\n");
+        for step in lead_up {
+            out.push_str(&format!("  {}\n", step.text));
+        }
+    }
+
+    let row = |name: &str, va: &str, vb: &str, differs: bool| {
+        let row = format!("{:<6} {:<24} {:<24}{}", name, va, vb, if differs { " <--" } else { "" });
+        format!("{}\n", row.trim_end())
+    };
+    out.push('\n');
+    out.push_str(&row("", names.0, names.1, false));
+    for (name, va, vb, differs) in fields(&a[ia], &b[ib]) {
+        out.push_str(&row(&name, &va, &vb, differs));
+    }
+    if let (Some(ca), Some(cb)) = (a[ia].cycle, b[ib].cycle) {
+        if ca != cb {
+            out.push_str(&row("CYC", &ca.to_string(), &cb.to_string(), true));
+        }
+    }
+
+    // State is traced before each instruction runs, so a difference in
+    // registers was made by the one before.
+    if let Some(previous) = ia.checked_sub(1).filter(|&p| p >= start_a).map(|p| &a[p]) {
+        if a[ia].pc == b[ib].pc || a[ia].opcode == b[ib].opcode {
+            if let (Some(pc), Some(op)) = (previous.pc, previous.opcode) {
+                out.push_str(&format!("\nThe difference comes from the previous instruction, {:#05X}: {} ({:04X})\n",
+                                      pc, mnemonic(op), op));
+            }
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{format_text, TraceRecord};
+
+    fn trace(records: &[TraceRecord]) -> Vec<TraceStep> {
+        let text: Vec<String> = records.iter().map(format_text).collect();
+        parse_trace(&text.join("\n")).unwrap()
+    }
+
+    fn run(ops: &[(usize, u16)]) -> Vec<TraceRecord> {
+        ops.iter().enumerate().map(|(cycle, &(pc, opcode))| {
+            TraceRecord { cycle: cycle as u64, pc, opcode, ..TraceRecord::default() }
+        }).collect()
+    }
+
+    #[test]
+    fn trace_diff_parses_both_formats() {
+        let record = TraceRecord { cycle: 3, pc: 0x204, opcode: 0xF11E, i: 0xFFF, v: [7; 16], mem: vec![1, 2], ..TraceRecord::default() };
+        let text = parse_trace(&format_text(&record)).unwrap();
+        let json = parse_trace(&serde_json::to_string(&record).unwrap()).unwrap();
+        for steps in [text, json].iter() {
+            assert_eq!(steps[0].cycle, Some(3));
+            assert_eq!(steps[0].pc, Some(0x204));
+            assert_eq!(steps[0].opcode, Some(0xF11E));
+            assert_eq!(steps[0].v[0xF], Some(7));
+            assert_eq!(steps[0].i, Some(0xFFF));
+            assert_eq!(steps[0].mem, Some(vec![1, 2]));
+        }
+
+        // Another emulator's lowercase, partial fields.
+        let other = parse_trace("# header\npc=0x200 op=00E0 vf=1\n\n{\"error\": \"panic\"}").unwrap();
+        assert_eq!(other.len(), 1);
+        assert_eq!(other[0].line, 2);
+        assert_eq!(other[0].v[0xF], Some(1));
+        assert_eq!(other[0].i, None);
+        assert!(parse_trace("hello world").unwrap_err().starts_with("line 1:"));
+    }
+
+    #[test]
+    fn trace_diff_finds_register_divergence() {
+        let ops = [(0x200, 0x6001), (0x202, 0xF01E), (0x204, 0x00E0)];
+        let a = run(&ops);
+        let mut b = run(&ops);
+        b[2].v[0xF] = 1;
+        let (a, b) = (trace(&a), trace(&b));
+        assert_eq!(first_divergence(&a, &b).2, Some(Divergence::Step(2, 2)));
+
+        let report = diff_report(("a", "b"), &a, &b, 5).unwrap();
+        assert!(report.starts_with("Traces diverge after 2 matching instructions (cycle 2): a line 3, b line 3"));
+        assert!(report.contains("VF     00                       01                       <--"));
+        assert!(report.contains("previous instruction, 0x202: ADD I, V0 (F01E)"));
+        assert!(diff_report(("a", "a"), &a, &a, 5).is_none());
+    }
+
+    #[test]
+    fn trace_diff_aligns_on_cycles() {
+        let full = run(&[(0x200, 0x6001), (0x202, 0x6102), (0x204, 0x6203), (0x206, 0x1200)]);
+        let tail = trace(&full[2..]);
+        let full = trace(&full);
+        assert_eq!(first_divergence(&full, &tail), (2, 0, None));
+
+        let short = &full[..3];
+        let report = diff_report(("full", "short"), &full, short, 5).unwrap();
+        assert!(report.starts_with("short ends after 3 matching instructions; full carries on at line 4"));
+    }
+}
@@ -0,0 +1,5 @@
+mod diff;
+mod trace;
+
+pub use self::diff::{diff_report, first_divergence, parse_trace, Divergence, TraceStep};
+pub use self::trace::{format_text, format_text_with_symbols, parse_range, TraceFormat, TraceOptions, TraceRecord, Tracer};
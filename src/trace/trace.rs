@@ -0,0 +1,353 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::asm::mnemonic_with_symbols;
+use crate::symbols::{parse_address, SymbolMap};
+
+// CPU state just before one instruction runs.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: usize,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: usize,
+    pub sp: usize,
+    pub dt: u8,
+    pub st: u8,
+    // Memory from I onwards, when the trace asks for it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mem: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    // One line per instruction of `KEY:value` fields, the layout most
+    // CHIP-8 emulators' trace logs use, with the mnemonic after a `;`:
+    // CYC:0 PC:0200 OP:00E0 V0:00 ... VF:00 I:0000 SP:0 DT:00 ST:00 ; CLS
+    // with M:<hex bytes> before the `;` when memory is traced. With symbols
+    // the comment becomes `; main_loop+4: CLS  game.asm:12`.
+    Text,
+    // One JSON object per line, the fields of TraceRecord plus "mnemonic",
+    // and "symbol" and "location" when the symbols have them.
+    Json,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" => Some(TraceFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    #[serde(flatten)]
+    record: &'a TraceRecord,
+    mnemonic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+}
+
+// What to trace, as chosen on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    // Inclusive PC ranges to trace; empty traces everything.
+    pub ranges: Vec<(usize, usize)>,
+    // Keep only the last N instructions, written out when the CPU faults.
+    pub last: Option<usize>,
+    // Bytes of memory from I to include with each instruction.
+    pub memory: usize,
+    // Labels and source lines to show with each instruction.
+    pub symbols: SymbolMap,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            format: TraceFormat::Text,
+            ranges: Vec::new(),
+            last: None,
+            memory: 0,
+            symbols: SymbolMap::default(),
+        }
+    }
+}
+
+// Parses "0x200-0x2FF" or a single address.
+pub fn parse_range(text: &str) -> Result<(usize, usize), String> {
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (parse_address(start)?, parse_address(end)?),
+        None => {
+            let addr = parse_address(text)?;
+            (addr, addr)
+        },
+    };
+    if start > end {
+        return Err(format!("range {} ends before it starts", text));
+    }
+    Ok((start, end))
+}
+
+pub fn format_text(record: &TraceRecord) -> String {
+    format_text_with_symbols(record, &SymbolMap::default())
+}
+
+pub fn format_text_with_symbols(record: &TraceRecord, symbols: &SymbolMap) -> String {
+    let mut line = format!("CYC:{} PC:{:04X} OP:{:04X}", record.cycle, record.pc, record.opcode);
+    for (x, v) in record.v.iter().enumerate() {
+        line.push_str(&format!(" V{:X}:{:02X}", x, v));
+    }
+    line.push_str(&format!(" I:{:04X} SP:{:X} DT:{:02X} ST:{:02X}", record.i, record.sp, record.dt, record.st));
+    if !record.mem.is_empty() {
+        let hex: String = record.mem.iter().map(|b| format!("{:02X}", b)).collect();
+        line.push_str(&format!(" M:{}", hex));
+    }
+    line.push_str(" ; ");
+    if let Some(symbol) = symbols.describe(record.pc) {
+        line.push_str(&format!("{}: ", symbol));
+    }
+    line.push_str(&mnemonic_with_symbols(record.opcode, symbols));
+    if let Some(location) = symbols.location(record.pc) {
+        line.push_str(&format!("  {}", location));
+    }
+    line
+}
+
+// Writes the instructions a CPU runs, either as they run or, in ring
+// buffer mode, only the last few once something goes wrong.
+pub struct Tracer {
+    options: TraceOptions,
+    // Where the trace goes, for error messages.
+    name: String,
+    out: Box<dyn Write>,
+    ring: VecDeque<TraceRecord>,
+    // The first write error; tracing stops after one.
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(name: &str, out: Box<dyn Write>, options: TraceOptions) -> Self {
+        Tracer {
+            ring: VecDeque::with_capacity(options.last.unwrap_or(0)),
+            options,
+            name: name.to_string(),
+            out,
+            error: None,
+        }
+    }
+
+    pub fn create(path: &str, options: TraceOptions) -> Result<Self, String> {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(BufWriter::new(File::create(path).map_err(|e| e.to_string())?))
+        };
+        Ok(Tracer::new(path, out, options))
+    }
+
+    pub fn memory(&self) -> usize { self.options.memory }
+
+    pub fn wants(&self, pc: usize) -> bool {
+        self.options.ranges.is_empty() ||
+        self.options.ranges.iter().any(|&(start, end)| pc >= start && pc <= end)
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        match self.options.last {
+            Some(0) => {},
+            Some(last) => {
+                if self.ring.len() == last {
+                    self.ring.pop_front();
+                }
+                self.ring.push_back(record);
+            },
+            None => self.write(&record),
+        }
+    }
+
+    // Notes an error in the trace, preceded by the buffered instructions
+    // that led up to it.
+    pub fn fault(&mut self, reason: &str) {
+        let line = match self.options.format {
+            TraceFormat::Text => format!("# {}", reason),
+            TraceFormat::Json => serde_json::json!({ "error": reason }).to_string(),
+        };
+        for record in std::mem::take(&mut self.ring) {
+            self.write(&record);
+        }
+        self.write_line(&line);
+    }
+
+    // Flushes the output, reporting the first error writing the trace.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.error.is_none() {
+            if let Err(e) = self.out.flush() {
+                self.error = Some(e);
+            }
+        }
+        match self.error.take() {
+            Some(e) => Err(e.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, record: &TraceRecord) {
+        let line = match self.options.format {
+            TraceFormat::Text => format_text_with_symbols(record, &self.options.symbols),
+            TraceFormat::Json => {
+                let symbols = &self.options.symbols;
+                let line = JsonLine {
+                    record,
+                    mnemonic: mnemonic_with_symbols(record.opcode, symbols),
+                    symbol: symbols.describe(record.pc),
+                    location: symbols.location(record.pc),
+                };
+                serde_json::to_string(&line).expect("trace records always serialize")
+            },
+        };
+        self.write_line(&line);
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+// A panic in the emulator unwinds through here, which
+// is the last chance to write out what led up to it.
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.fault("panic");
+        }
+        if let Err(e) = self.finish() {
+            eprintln!("COULD NOT WRITE TRACE {}: {}", self.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A Write that tests can read back after the tracer is done with it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(str::to_string).collect()
+        }
+    }
+
+    fn record(cycle: u64, pc: usize, opcode: u16) -> TraceRecord {
+        TraceRecord { cycle, pc, opcode, ..TraceRecord::default() }
+    }
+
+    #[test]
+    fn trace_text_format() {
+        let mut state = record(7, 0x202, 0xA123);
+        state.v[0xF] = 1;
+        state.i = 0x300;
+        state.dt = 0x3C;
+        assert_eq!(format_text(&state),
+                   "CYC:7 PC:0202 OP:A123 V0:00 V1:00 V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 \
+                    V8:00 V9:00 VA:00 VB:00 VC:00 VD:00 VE:00 VF:01 I:0300 SP:0 DT:3C ST:00 ; LD I, 0x123");
+    }
+
+    #[test]
+    fn trace_json_lines() {
+        let out = Shared::default();
+        let options = TraceOptions { format: TraceFormat::Json, ..TraceOptions::default() };
+        let mut tracer = Tracer::new("test", Box::new(out.clone()), options);
+        tracer.record(record(0, 0x200, 0x00E0));
+        tracer.finish().unwrap();
+
+        let line: serde_json::Value = serde_json::from_str(&out.lines()[0]).unwrap();
+        assert_eq!(line["pc"], 0x200);
+        assert_eq!(line["mnemonic"], "CLS");
+        assert!(line.get("symbol").is_none());
+        let parsed: TraceRecord = serde_json::from_str(&out.lines()[0]).unwrap();
+        assert_eq!(parsed, record(0, 0x200, 0x00E0));
+    }
+
+    #[test]
+    fn trace_with_symbols() {
+        let mut symbols = SymbolMap::default();
+        symbols.add_label(0x200, "main_loop");
+        symbols.add_label(0x300, "sprite");
+        symbols.add_line(0x204, 12);
+        symbols.source = Some("game.asm".to_string());
+
+        let text = format_text_with_symbols(&record(3, 0x204, 0xA300), &symbols);
+        assert!(text.ends_with(" ST:00 ; main_loop+4: LD I, sprite  game.asm:12"), "{}", text);
+        // trace-diff only reads what comes before the `;`.
+        let steps = crate::trace::parse_trace(&text).unwrap();
+        assert_eq!((steps[0].pc, steps[0].opcode), (Some(0x204), Some(0xA300)));
+
+        let out = Shared::default();
+        let options = TraceOptions { format: TraceFormat::Json, symbols, ..TraceOptions::default() };
+        let mut tracer = Tracer::new("test", Box::new(out.clone()), options);
+        tracer.record(record(3, 0x204, 0xA300));
+        tracer.finish().unwrap();
+        let line: serde_json::Value = serde_json::from_str(&out.lines()[0]).unwrap();
+        assert_eq!(line["mnemonic"], "LD I, sprite");
+        assert_eq!(line["symbol"], "main_loop+4");
+        assert_eq!(line["location"], "game.asm:12");
+    }
+
+    #[test]
+    fn trace_ranges() {
+        assert_eq!(parse_range("0x200-0x2FF"), Ok((0x200, 0x2FF)));
+        assert_eq!(parse_range("512"), Ok((512, 512)));
+        assert!(parse_range("0x300-0x200").is_err());
+        assert!(parse_range("here").is_err());
+
+        let options = TraceOptions { ranges: vec![(0x200, 0x20F), (0x300, 0x300)], ..TraceOptions::default() };
+        let tracer = Tracer::new("test", Box::new(io::sink()), options);
+        assert!(tracer.wants(0x20F) && tracer.wants(0x300));
+        assert!(!tracer.wants(0x210) && !tracer.wants(0x302));
+    }
+
+    #[test]
+    fn trace_ring_dumps_on_fault() {
+        let out = Shared::default();
+        let options = TraceOptions { last: Some(2), ..TraceOptions::default() };
+        let mut tracer = Tracer::new("test", Box::new(out.clone()), options);
+        for cycle in 0..5 {
+            tracer.record(record(cycle, 0x200 + 2 * cycle as usize, 0x00E0));
+        }
+        assert!(out.lines().is_empty());
+
+        tracer.fault("unimplemented opcode 0x5121");
+        let lines = out.lines();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("CYC:3 PC:0206"));
+        assert!(lines[1].starts_with("CYC:4 PC:0208"));
+        assert_eq!(lines[2], "# unimplemented opcode 0x5121");
+    }
+}